@@ -0,0 +1,138 @@
+use bincode::Options;
+use grubbnet::{
+    packet::{wire_config, HasPacketId, PacketBody},
+    ConnectionId, Error, PacketRecipient, PacketRegistry, Result, Server, ServerEvent,
+    TickScheduler,
+};
+use std::{cell::RefCell, collections::HashMap};
+
+/// 0x00 - Sent by the client.
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct PingPacket {
+    pub msg: String,
+}
+
+impl HasPacketId for PingPacket {
+    const PACKET_ID: u8 = 0x00;
+}
+
+impl PacketBody for PingPacket {
+    fn box_clone(&self) -> Box<dyn PacketBody> {
+        Box::new((*self).clone())
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        wire_config()
+            .serialize(self)
+            .map_err(|_e| Error::InvalidData)
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self> {
+        wire_config()
+            .deserialize(data)
+            .map_err(|_e| Error::InvalidData)
+    }
+
+    fn id(&self) -> u8 {
+        Self::PACKET_ID
+    }
+}
+
+/// 0x01 - Sent by the server in response to a ping.
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct PongPacket {
+    pub msg: String,
+}
+
+impl HasPacketId for PongPacket {
+    const PACKET_ID: u8 = 0x01;
+}
+
+impl PacketBody for PongPacket {
+    fn box_clone(&self) -> Box<dyn PacketBody> {
+        Box::new((*self).clone())
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        wire_config()
+            .serialize(self)
+            .map_err(|_e| Error::InvalidData)
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self> {
+        wire_config()
+            .deserialize(data)
+            .map_err(|_e| Error::InvalidData)
+    }
+
+    fn id(&self) -> u8 {
+        Self::PACKET_ID
+    }
+}
+
+// The same ping server as `simple_server`, but untyped, with incoming packets handed out by a `PacketRegistry`.
+// It speaks the same packets, so `simple_client` works with it too.
+fn main() -> Result<()> {
+    let mut server = Server::host("127.0.0.1", 7667, 32)?;
+    println!("Hosting on 127.0.0.1:7667...");
+
+    // Handlers can't use the server while it's dispatching, so they note down the pings and the loop answers them
+    let pings: RefCell<Vec<ConnectionId>> = RefCell::new(Vec::new());
+    let mut registry = PacketRegistry::new();
+    registry.register::<PingPacket, _>(PingPacket::PACKET_ID, |id, ping| {
+        println!("Got ping from client {}: {}", id, ping.msg);
+        pings.borrow_mut().push(id);
+    })?;
+    registry.register::<PongPacket, _>(PongPacket::PACKET_ID, |id, _pong| {
+        eprintln!("Client {} sent a server-only packet!", id);
+    })?;
+
+    // The # of pings we've received from each client.
+    let mut ping_counters: HashMap<ConnectionId, u32> = HashMap::new();
+
+    let mut scheduler = TickScheduler::new(30);
+    for _tick in scheduler.ticks() {
+        for event in server.tick()? {
+            match event {
+                ServerEvent::ClientConnected(id, addr) => {
+                    println!("Client {} connected from {}", id, addr.ip());
+                }
+                ServerEvent::ClientDisconnected(id, reason) => {
+                    println!("Client {} disconnected ({:?}).", id, reason);
+                    ping_counters.remove(&id);
+                }
+                ServerEvent::ProtocolError { from, id } => {
+                    println!("Client {} sent an invalid packet (id: {})", from, id);
+                }
+                _ => {}
+            }
+        }
+
+        for (id, packet) in server.dispatch_incoming(&mut registry) {
+            eprintln!(
+                "Client {} sent an unknown packet (id: {})",
+                id, packet.header.id
+            );
+        }
+
+        for id in pings.borrow_mut().drain(..) {
+            // Increment the ping counter for this client
+            let counter = ping_counters.entry(id).or_insert(0);
+            *counter += 1;
+
+            if *counter >= 5 {
+                // Kick the client when they reach 5 pings.
+                println!("Client {} sent 5 pings. Kicking them.", id);
+                let _ = server.kick(id);
+            } else {
+                // Otherwise just send a ping response (pong).
+                let pong = PongPacket {
+                    msg: "Pong!".to_owned(),
+                };
+                server.send(PacketRecipient::Single(id), pong)?;
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -1,92 +1,114 @@
-use grubbnet::{packet::PacketBody, Client, ClientEvent, Result};
+use bincode::Options;
+use grubbnet::{
+    packet::wire_config, ClientConfig, ClientEvent, Error, Protocol, Result, TickScheduler,
+};
 
-/// 0x00 - Ping Packet
-/// Client
+/// Sent by the client.
 #[derive(serde::Serialize, serde::Deserialize, Clone)]
 pub struct PingPacket {
     pub msg: String,
 }
 
-impl PacketBody for PingPacket {
-    fn box_clone(&self) -> Box<dyn PacketBody> {
-        Box::new((*self).clone())
-    }
-
-    fn serialize(&self) -> Result<Vec<u8>> {
-        match bincode::config().big_endian().serialize::<Self>(&self) {
-            Ok(d) => Ok(d),
-            Err(_e) => Err(grubbnet::Error::InvalidData),
-        }
-    }
-
-    fn deserialize(_data: &[u8]) -> Result<Self> {
-        panic!("Attempted to deserialize a client-only packet!");
-    }
-
-    fn id(&self) -> u8 {
-        0x00
-    }
-}
-
-/// 0x00 - Pong Packet
-/// Server
+/// Sent by the server in response to a ping.
 #[derive(serde::Serialize, serde::Deserialize, Clone)]
 pub struct PongPacket {
     pub msg: String,
 }
 
-impl PacketBody for PongPacket {
-    fn box_clone(&self) -> Box<dyn PacketBody> {
-        Box::new((*self).clone())
-    }
+/// Sent by the server just before it kicks the client.
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct GoodbyePacket {
+    pub reason: String,
+}
 
-    fn serialize(&self) -> Result<Vec<u8>> {
-        panic!("Attempted to serialize a server-only packet!");
-    }
+/// Every message the example client and server exchange.
+#[derive(Clone)]
+pub enum PingProtocol {
+    /// 0x00 - Ping Packet
+    Ping(PingPacket),
+    /// 0x01 - Pong Packet
+    Pong(PongPacket),
+    /// 0x02 - Goodbye Packet
+    Goodbye(GoodbyePacket),
+}
 
-    fn deserialize(data: &[u8]) -> Result<Self> {
-        match bincode::config().big_endian().deserialize::<Self>(data) {
-            Ok(p) => Ok(p),
-            Err(_e) => Err(grubbnet::Error::InvalidData),
+impl Protocol for PingProtocol {
+    fn id(&self) -> u8 {
+        match self {
+            PingProtocol::Ping(_) => 0x00,
+            PingProtocol::Pong(_) => 0x01,
+            PingProtocol::Goodbye(_) => 0x02,
         }
     }
 
-    fn id(&self) -> u8 {
-        0x00
+    fn encode(&self) -> Result<Vec<u8>> {
+        let encoded = match self {
+            PingProtocol::Ping(p) => wire_config().serialize(p),
+            PingProtocol::Pong(p) => wire_config().serialize(p),
+            PingProtocol::Goodbye(p) => wire_config().serialize(p),
+        };
+
+        encoded.map_err(|_e| Error::InvalidData)
+    }
+
+    fn decode(id: u8, body: &[u8]) -> Result<Self> {
+        let decoded = match id {
+            0x00 => wire_config().deserialize(body).map(PingProtocol::Ping),
+            0x01 => wire_config().deserialize(body).map(PingProtocol::Pong),
+            0x02 => wire_config().deserialize(body).map(PingProtocol::Goodbye),
+            _ => return Err(Error::UnknownPacket(id)),
+        };
+
+        decoded.map_err(|_e| Error::InvalidData)
     }
 }
 
 fn main() -> Result<()> {
     // Create a client and connect to localhost
-    let mut client = Client::connect("127.0.0.1", 7667)?;
+    let mut client = ClientConfig::new().connect_typed::<PingProtocol, _>("127.0.0.1:7667")?;
 
-    let mut counter = 0;
-    loop {
-        // Sleep for a lil bit so we don't hog the CPU
-        std::thread::sleep(std::time::Duration::from_millis(100));
+    // Tick 10 times a second, sleeping in between so we don't hog the CPU
+    let mut scheduler = TickScheduler::new(10);
+    for tick in scheduler.ticks() {
+        if tick.is_behind {
+            eprintln!("Tick {} started late, catching up", tick.number);
+        }
 
         // Send a ping packet every 20 ticks
-        counter += 1;
-        if (counter % 20) == 0 {
+        if tick.number > 0 && (tick.number % 20) == 0 {
             let ping = PingPacket {
-                msg: format!("Ping! Tick {}", counter),
+                msg: format!("Ping! Tick {}", tick.number),
             };
-            client.send(ping);
+            client.send(PingProtocol::Ping(ping))?;
         }
 
         // Run the network tick and process any events it generates
-        for event in client.tick().iter() {
-            match event {
-                ClientEvent::Disconnected => {
-                    println!("Disconnected from server!");
+        for timed in client.tick_timed()?.iter() {
+            match &timed.event {
+                ClientEvent::Connected => {
+                    println!("Connected to server!");
+                }
+                ClientEvent::Disconnected(reason) => {
+                    println!("Disconnected from server! ({:?})", reason);
                     break;
                 }
-                ClientEvent::ReceivedPacket(byte_count) => {
-                    println!("Received packet from server ({} bytes)", byte_count);
+                ClientEvent::ReceivedPacket(info) => {
+                    println!(
+                        "Received packet {} from server ({} bytes, observed {:?} ago)",
+                        info.id,
+                        info.wire_size,
+                        timed.at.elapsed()
+                    );
                 }
                 ClientEvent::SentPacket(byte_count) => {
                     println!("Sent packet to server ({} bytes)", byte_count);
                 }
+                ClientEvent::ProtocolError(id) => {
+                    println!("Server sent an invalid packet (id: {})", id);
+                }
+                ClientEvent::Kicked(reason) => {
+                    println!("Kicked by server: {}", reason);
+                }
                 _ => eprintln!("Unhandled ClientEvent!"),
             }
         }
@@ -95,17 +117,21 @@ fn main() -> Result<()> {
             break;
         }
 
-        // Process incoming packets
-        for packet in client.drain_incoming_packets().iter() {
-            match packet.header.id {
-                0x00 => {
-                    let packet = PongPacket::deserialize(&packet.body);
-                    println!("Got pong: {}", packet.unwrap().msg);
-                }
-                _ => eprintln!("Unhandled packet! id: {}", packet.header.id),
+        // Process incoming messages
+        for message in client.drain_incoming_typed() {
+            match message {
+                PingProtocol::Pong(pong) => println!("Got pong: {}", pong.msg),
+                PingProtocol::Goodbye(goodbye) => println!("Kicked by server: {}", goodbye.reason),
+                PingProtocol::Ping(_) => eprintln!("Server sent a client-only packet!"),
             }
         }
     }
 
+    let stats = scheduler.stats();
+    println!(
+        "Ran {} ticks, averaging {:?} each ({} overran)",
+        stats.ticks, stats.average_tick_duration, stats.overruns
+    );
+
     Ok(())
 }
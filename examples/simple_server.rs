@@ -1,138 +1,191 @@
-use grubbnet::{packet::PacketBody, PacketRecipient, Result, Server, ServerEvent, Token};
-use std::collections::HashMap;
-
-/// 0x00 - Ping Packet
-/// Client
+use bincode::Options;
+use grubbnet::{
+    packet::wire_config, ConnectionId, Error, PacketRecipient, Protocol, Result, Server,
+    ServerConfig, ServerEvent, ServerHandler,
+};
+use std::{
+    collections::HashMap,
+    io::BufRead,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// Sent by the client.
 #[derive(serde::Serialize, serde::Deserialize, Clone)]
 pub struct PingPacket {
     pub msg: String,
 }
 
-impl PacketBody for PingPacket {
-    fn box_clone(&self) -> Box<dyn PacketBody> {
-        Box::new((*self).clone())
-    }
-
-    fn serialize(&self) -> Result<Vec<u8>> {
-        panic!("Attempted to serialize a client-only packet!");
-    }
-
-    fn deserialize(data: &[u8]) -> Result<Self> {
-        match bincode::config().big_endian().deserialize::<Self>(data) {
-            Ok(p) => Ok(p),
-            Err(_e) => Err(grubbnet::Error::InvalidData),
-        }
-    }
-
-    fn id(&self) -> u8 {
-        0x00
-    }
-}
-
-/// 0x00 - Pong Packet
-/// Server
+/// Sent by the server in response to a ping.
 #[derive(serde::Serialize, serde::Deserialize, Clone)]
 pub struct PongPacket {
     pub msg: String,
 }
 
-impl PacketBody for PongPacket {
-    fn box_clone(&self) -> Box<dyn PacketBody> {
-        Box::new((*self).clone())
-    }
+/// Sent by the server just before it kicks the client.
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct GoodbyePacket {
+    pub reason: String,
+}
+
+/// Every message the example client and server exchange.
+#[derive(Clone)]
+pub enum PingProtocol {
+    /// 0x00 - Ping Packet
+    Ping(PingPacket),
+    /// 0x01 - Pong Packet
+    Pong(PongPacket),
+    /// 0x02 - Goodbye Packet
+    Goodbye(GoodbyePacket),
+}
 
-    fn serialize(&self) -> Result<Vec<u8>> {
-        match bincode::config().big_endian().serialize::<Self>(&self) {
-            Ok(d) => Ok(d),
-            Err(_e) => Err(grubbnet::Error::InvalidData),
+impl Protocol for PingProtocol {
+    fn id(&self) -> u8 {
+        match self {
+            PingProtocol::Ping(_) => 0x00,
+            PingProtocol::Pong(_) => 0x01,
+            PingProtocol::Goodbye(_) => 0x02,
         }
     }
 
-    fn deserialize(_data: &[u8]) -> Result<Self> {
-        panic!("Attempted to deserialize a server-only packet!");
+    fn encode(&self) -> Result<Vec<u8>> {
+        let encoded = match self {
+            PingProtocol::Ping(p) => wire_config().serialize(p),
+            PingProtocol::Pong(p) => wire_config().serialize(p),
+            PingProtocol::Goodbye(p) => wire_config().serialize(p),
+        };
+
+        encoded.map_err(|_e| Error::InvalidData)
     }
 
-    fn id(&self) -> u8 {
-        0x00
+    fn decode(id: u8, body: &[u8]) -> Result<Self> {
+        let decoded = match id {
+            0x00 => wire_config().deserialize(body).map(PingProtocol::Ping),
+            0x01 => wire_config().deserialize(body).map(PingProtocol::Pong),
+            0x02 => wire_config().deserialize(body).map(PingProtocol::Goodbye),
+            _ => return Err(Error::UnknownPacket(id)),
+        };
+
+        decoded.map_err(|_e| Error::InvalidData)
     }
 }
 
-fn main() -> Result<()> {
-    // Begin hosting a TCP server
-    let mut server = Server::host("127.0.0.1", 7667, 32)?;
-    println!("Hosting on 127.0.0.1:7667...");
+/// Answers pings, and kicks clients once they've sent a certain amount.
+#[derive(Default)]
+struct PingServer {
+    /// The # of pings we've received from each client.
+    ping_counters: HashMap<ConnectionId, u32>,
+}
 
-    // We are going to keep track of the # of pings we receive from each client, and kick them
-    // after they have sent a certain amount.
-    let mut ping_counters: HashMap<Token, u32> = HashMap::new();
-
-    loop {
-        // Sleep for a lil bit so we don't hog the CPU
-        std::thread::sleep(std::time::Duration::from_millis(100));
-
-        // Run the network tick and process any events it generates
-        for event in server.tick().iter() {
-            match event {
-                ServerEvent::ClientConnected(token, addr) => {
-                    println!(
-                        "Client {} connected from {} ({}/{})",
-                        token.0,
-                        addr.ip(),
-                        server.num_connections(),
-                        server.connection_limit(),
-                    );
-                }
-                ServerEvent::ClientDisconnected(token) => {
-                    println!("Client {} disconnected.", token.0);
-                }
-                ServerEvent::ConnectionRejected(addr) => {
-                    println!(
-                        "Rejected connection from {} (Connection limit reached)",
-                        addr.ip(),
-                    );
-                }
-                ServerEvent::ReceivedPacket(token, byte_count) => {
-                    println!(
-                        "Received packet from client {} ({} bytes)",
-                        token.0, byte_count
-                    );
-                }
-                ServerEvent::SentPacket(token, byte_count) => {
-                    println!("Sent packet to client {} ({} bytes)", token.0, byte_count);
-                }
-                _ => eprintln!("Unhandled ServerEvent!"),
+impl ServerHandler<PingProtocol> for PingServer {
+    fn on_event(&mut self, server: &mut Server<PingProtocol>, event: ServerEvent) {
+        match event {
+            ServerEvent::ClientConnected(id, addr) => {
+                println!(
+                    "Client {} connected from {} ({}/{})",
+                    id,
+                    addr.ip(),
+                    server.num_connections(),
+                    server.connection_limit(),
+                );
+            }
+            ServerEvent::ClientDisconnected(id, reason) => {
+                println!("Client {} disconnected ({:?}).", id, reason);
+                self.ping_counters.remove(&id);
+            }
+            ServerEvent::ConnectionRejected(addr, reason) => {
+                println!("Rejected connection from {} ({})", addr.ip(), reason);
+            }
+            ServerEvent::ReceivedPacket(id, info) => {
+                println!(
+                    "Received packet {} from client {} ({} bytes)",
+                    info.id, id, info.wire_size
+                );
             }
+            ServerEvent::SentPacket(id, byte_count) => {
+                println!("Sent packet to client {} ({} bytes)", id, byte_count);
+            }
+            ServerEvent::ProtocolError { from, id } => {
+                println!("Client {} sent an invalid packet (id: {})", from, id);
+            }
+            ServerEvent::ShutdownComplete(summary) => {
+                println!(
+                    "Shut down. Closed {} connections, flushed {} packets and dropped {}.",
+                    summary.connections_closed, summary.packets_flushed, summary.packets_dropped
+                );
+            }
+            _ => eprintln!("Unhandled ServerEvent!"),
         }
+    }
 
-        // Process incoming packets
-        for (token, packet) in server.drain_incoming_packets().iter() {
-            match packet.header.id {
-                0x00 => {
-                    let packet = PingPacket::deserialize(&packet.body);
-                    println!("Got ping from client {}: {}", token.0, packet.unwrap().msg);
-
-                    // Increment the ping counter for this client
-                    let counter = ping_counters.entry(*token).or_insert(0);
-                    *counter += 1;
-
-                    if *counter >= 5 {
-                        // Kick the client when they reach 5 pings.
-                        println!("Client {} sent 5 pings. Kicking them.", token.0);
-                        server.kick(*token)?;
-
-                        ping_counters.remove_entry(token);
-                    } else {
-                        // Otherwise just send a ping response (pong).
-                        let pong = PongPacket {
-                            msg: "Pong!".to_owned(),
-                        };
-                        server.send(PacketRecipient::Single(*token), pong);
+    // Only pings can arrive here, anything else shows up as a ProtocolError event.
+    fn on_message(
+        &mut self,
+        server: &mut Server<PingProtocol>,
+        id: ConnectionId,
+        message: PingProtocol,
+    ) {
+        match message {
+            PingProtocol::Ping(ping) => {
+                println!("Got ping from client {}: {}", id, ping.msg);
+
+                // Increment the ping counter for this client
+                let counter = self.ping_counters.entry(id).or_insert(0);
+                *counter += 1;
+
+                if *counter >= 5 {
+                    // Kick the client when they reach 5 pings, telling them why first. The kick waits for the
+                    // goodbye to be written before closing the connection.
+                    println!("Client {} sent 5 pings. Kicking them.", id);
+                    let goodbye = GoodbyePacket {
+                        reason: "You sent 5 pings.".to_owned(),
+                    };
+                    if let Err(e) =
+                        server.send(PacketRecipient::Single(id), PingProtocol::Goodbye(goodbye))
+                    {
+                        eprintln!("Failed to send client {} a goodbye! {}", id, e);
+                    }
+                    let _ = server.kick(id);
+                } else {
+                    // Otherwise just send a ping response (pong).
+                    let pong = PongPacket {
+                        msg: "Pong!".to_owned(),
+                    };
+                    if let Err(e) =
+                        server.send(PacketRecipient::Single(id), PingProtocol::Pong(pong))
+                    {
+                        eprintln!("Failed to send client {} a pong! {}", id, e);
                     }
                 }
-                _ => eprintln!("Unhandled packet! id: {}", packet.header.id),
+            }
+            PingProtocol::Pong(_) | PingProtocol::Goodbye(_) => {
+                eprintln!("Client {} sent a server-only packet!", id);
             }
         }
     }
+}
+
+fn main() -> Result<()> {
+    // Begin hosting a TCP server
+    let mut server = ServerConfig::new()
+        .connection_limit(32)
+        .bind_typed::<PingProtocol, _>("127.0.0.1:7667")?;
+    println!("Hosting on 127.0.0.1:7667...");
 
-    Ok(())
+    // Shut down cleanly when enter is pressed. A Ctrl-C handler would set the flag the same way.
+    let stop = Arc::new(AtomicBool::new(false));
+    let wakeup = server.wakeup();
+    let stop_flag = stop.clone();
+    std::thread::spawn(move || {
+        let _ = std::io::stdin().lock().lines().next();
+        stop_flag.store(true, Ordering::Release);
+        let _ = wakeup.wake();
+    });
+    println!("Press enter to shut down.");
+
+    // Tick, blocking while there's nothing to do, and hand everything that happens to the handler. Once the flag is
+    // set, every client is sent what's queued for it and a shutdown notice before the server closes.
+    server.run(&mut PingServer::default(), &stop)
 }
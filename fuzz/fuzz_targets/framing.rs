@@ -0,0 +1,41 @@
+#![no_main]
+
+use grubbnet::{
+    buffer::{NetworkBuffer, MAX_BUFFER_SIZE},
+    packet::{parse_packets, Framing, MAX_PACKET_BODY_SIZE, PACKET_HEADER_SIZE},
+};
+use libfuzzer_sys::fuzz_target;
+
+// Feeds arbitrary chunks through a `NetworkBuffer` and the same parse loop the server and client run after every read.
+fuzz_target!(|chunks: Vec<Vec<u8>>| {
+    let mut buffer = NetworkBuffer::new();
+    let mut bytes_fed = 0;
+    let mut bytes_delivered = 0;
+
+    for chunk in chunks.iter() {
+        // Only write what fits, like a socket read into the remaining space would
+        let len = chunk.len().min(MAX_BUFFER_SIZE - buffer.len());
+        buffer.append(&chunk[..len]).unwrap();
+        bytes_fed += len;
+
+        let parsed = parse_packets(
+            &mut buffer,
+            Framing::Standard,
+            MAX_PACKET_BODY_SIZE,
+            |packet| {
+                assert!(packet.body.len() <= MAX_PACKET_BODY_SIZE);
+                assert_eq!(packet.body.len(), packet.header.size as usize);
+
+                bytes_delivered += PACKET_HEADER_SIZE + packet.body.len();
+            },
+        );
+
+        assert!(buffer.len() <= MAX_BUFFER_SIZE);
+        assert!(bytes_delivered <= bytes_fed);
+
+        // The connection gets dropped after an invalid header, so stop feeding it
+        if parsed.is_err() {
+            break;
+        }
+    }
+});
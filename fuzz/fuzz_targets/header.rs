@@ -0,0 +1,27 @@
+#![no_main]
+
+use grubbnet::{
+    buffer::{NetworkBuffer, MAX_BUFFER_SIZE},
+    packet::{deserialize_packet_header, Framing, MAX_PACKET_BODY_SIZE, SEQUENCE_SIZE},
+};
+use libfuzzer_sys::fuzz_target;
+
+// Arbitrary bytes straight into the header parser, with each framing.
+fuzz_target!(|data: &[u8]| {
+    let len = data.len().min(MAX_BUFFER_SIZE);
+
+    let mut buffer = NetworkBuffer::new();
+    buffer.append(&data[..len]).unwrap();
+
+    for framing in [Framing::Standard, Framing::Extended].iter() {
+        match deserialize_packet_header(&mut buffer, *framing, MAX_PACKET_BODY_SIZE) {
+            Ok(Some(header)) => {
+                // A header can only come from bytes that were actually written
+                assert!(len >= header.header_size(*framing));
+                assert!((header.size as usize) <= MAX_PACKET_BODY_SIZE);
+            }
+            Ok(None) => assert!(len < framing.header_size() + SEQUENCE_SIZE),
+            Err(_) => {}
+        }
+    }
+});
@@ -1,38 +1,329 @@
+use crate::error::Error;
+use std::fmt;
+
+/// The most a receive buffer grows to, unless configured otherwise.
 pub const MAX_BUFFER_SIZE: usize = 1024 * 16;
 
-// TODO (Declan, 10/12/2019)
-// We should probably be using a ring buffer instead.
+/// The size a receive buffer starts at. It grows from here as reads fill it up.
+pub const INITIAL_BUFFER_SIZE: usize = 1024;
+
+/// How many receive buffers a server keeps around for reuse, unless configured otherwise.
+pub const DEFAULT_BUFFER_POOL_SIZE: usize = 32;
+
+/// How many bytes from the front of a buffer its `Debug` output shows.
+const DEBUG_PREVIEW_LEN: usize = 16;
 
-/// A simple byte buffer, useful for storing bytes that are going to be consumed in packets.
+/// A ring buffer of bytes, useful for storing bytes that are going to be consumed in packets.
+/// Bytes are written at the tail and drained from the head, so draining never has to move what's left. The readable
+/// bytes can wrap around the end of the ring; `make_contiguous` lines them up when they need to be read as one slice.
+/// The buffer starts out small, and doubles each time `grow` is called, up to its max capacity.
+/// The fields are private and every safe method checks its counts, so the readable length can't be pushed past what
+/// was written or past the capacity. Use `readable` or `make_contiguous` to look at the bytes.
 pub struct NetworkBuffer {
-    pub data: [u8; MAX_BUFFER_SIZE],
-    pub offset: usize,
+    data: Vec<u8>,
+    head: usize,
+    len: usize,
+    max_capacity: usize,
 }
 
 impl NetworkBuffer {
     pub fn new() -> Self {
+        NetworkBuffer::with_max_capacity(MAX_BUFFER_SIZE)
+    }
+
+    /// Create a buffer that grows to at most `max_capacity` bytes.
+    /// It starts at `INITIAL_BUFFER_SIZE`, or `max_capacity` if that's smaller.
+    /// Panics if `max_capacity` is zero, since a buffer that can't hold a single byte has no ring to wrap around.
+    pub fn with_max_capacity(max_capacity: usize) -> Self {
+        assert!(max_capacity > 0, "buffer capacity must be at least 1");
+
         NetworkBuffer {
-            data: [0; MAX_BUFFER_SIZE],
-            offset: 0,
+            data: vec![0; INITIAL_BUFFER_SIZE.min(max_capacity)],
+            head: 0,
+            len: 0,
+            max_capacity,
         }
     }
 
-    /// Deletes `count` bytes from the front of the buffer, then shifts the rest of the buffer back in place at index 0.
-    pub fn drain(&mut self, count: usize) {
-        unsafe {
-            use std::ptr;
-            ptr::copy(
-                self.data.as_ptr().offset(count as isize),
-                self.data.as_mut_ptr(),
-                self.offset - count,
-            );
+    /// Get the number of readable bytes in the buffer.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Get the number of bytes the buffer can hold before it has to grow.
+    pub fn capacity(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Get the number of bytes that can be written before the buffer has to grow.
+    pub fn remaining_capacity(&self) -> usize {
+        self.capacity() - self.len
+    }
+
+    /// Get the number of bytes the buffer can grow to.
+    pub fn max_capacity(&self) -> usize {
+        self.max_capacity
+    }
+
+    /// Double the capacity, up to the max capacity. Returns false if the buffer is already as big as it gets.
+    pub fn grow(&mut self) -> bool {
+        let capacity = self.capacity();
+        if capacity >= self.max_capacity {
+            return false;
         }
 
-        self.offset -= count;
+        // Unwrap the readable bytes first, so they stay in order when the ring gets longer
+        self.make_contiguous();
+        self.data.resize((capacity * 2).min(self.max_capacity), 0);
+
+        true
     }
 
+    /// Get the free space straight after the readable bytes, for a socket read to write into.
+    /// Once the free space wraps around the end of the ring, this only covers the part up to the end; the rest is
+    /// handed out after `advance_write` moves past it. This is empty when the buffer is full.
+    pub fn writable_slice(&mut self) -> &mut [u8] {
+        let capacity = self.capacity();
+        let tail = (self.head + self.len) % capacity;
+        let end = if tail < self.head || self.len == capacity {
+            self.head
+        } else {
+            capacity
+        };
+
+        &mut self.data[tail..end]
+    }
+
+    /// Mark `count` bytes written into `writable_slice` as readable.
+    /// Returns `InvalidData` and leaves the buffer alone if `count` is more than was free.
+    pub fn advance_write(&mut self, count: usize) -> Result<(), Error> {
+        if count > self.capacity() - self.len {
+            return Err(Error::InvalidData);
+        }
+
+        self.len += count;
+
+        Ok(())
+    }
+
+    /// Copy `bytes` onto the end of the buffer, growing it if they don't fit.
+    /// Returns `InvalidData` and leaves the buffer alone if they don't fit even at the max capacity.
+    pub fn append(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        if bytes.len() > self.max_capacity - self.len {
+            return Err(Error::InvalidData);
+        }
+
+        while self.capacity() - self.len < bytes.len() {
+            self.grow();
+        }
+
+        let mut written = 0;
+        while written < bytes.len() {
+            let writable = self.writable_slice();
+            let count = writable.len().min(bytes.len() - written);
+            writable[..count].copy_from_slice(&bytes[written..(written + count)]);
+            self.len += count;
+            written += count;
+        }
+
+        Ok(())
+    }
+
+    /// Get the readable bytes without moving anything. They come in two parts when they wrap around the end of the
+    /// ring, otherwise the second part is empty. Either way the first part comes first, and the two together are
+    /// exactly `len` bytes. Use `make_contiguous` instead when they're needed as one slice.
+    pub fn readable(&self) -> (&[u8], &[u8]) {
+        let end = self.head + self.len;
+        if end <= self.capacity() {
+            (&self.data[self.head..end], &[])
+        } else {
+            (
+                &self.data[self.head..],
+                &self.data[..(end - self.capacity())],
+            )
+        }
+    }
+
+    /// Get the first `count` readable bytes in one piece, or `None` if fewer than that have been written.
+    pub fn peek(&mut self, count: usize) -> Option<&[u8]> {
+        if count > self.len {
+            return None;
+        }
+
+        Some(&self.make_contiguous()[..count])
+    }
+
+    /// Rotate the ring so the readable bytes are in one piece, and return them.
+    /// This only moves anything if the readable bytes wrap around the end of the ring.
+    pub fn make_contiguous(&mut self) -> &[u8] {
+        if self.head + self.len > self.capacity() {
+            self.data.rotate_left(self.head);
+            self.head = 0;
+        }
+
+        &self.data[self.head..(self.head + self.len)]
+    }
+
+    /// Deletes `count` bytes from the front of the buffer.
+    /// Returns `InvalidData` and leaves the buffer alone if `count` is more than the buffer holds.
+    pub fn drain(&mut self, count: usize) -> Result<(), Error> {
+        if count > self.len {
+            return Err(Error::InvalidData);
+        }
+
+        // Safety: the bound was just checked
+        unsafe { self.drain_unchecked(count) };
+
+        Ok(())
+    }
+
+    /// Deletes `count` bytes from the front of the buffer, and returns them.
+    /// Returns `InvalidData` and leaves the buffer alone if `count` is more than the buffer holds.
+    pub fn drain_into(&mut self, count: usize) -> Result<Vec<u8>, Error> {
+        if count > self.len {
+            return Err(Error::InvalidData);
+        }
+
+        let (front, back) = self.readable();
+        let mut bytes = Vec::with_capacity(count);
+        if count <= front.len() {
+            bytes.extend_from_slice(&front[..count]);
+        } else {
+            bytes.extend_from_slice(front);
+            bytes.extend_from_slice(&back[..(count - front.len())]);
+        }
+
+        // Safety: the bound was just checked
+        unsafe { self.drain_unchecked(count) };
+
+        Ok(bytes)
+    }
+
+    /// Same as `drain`, without checking `count`.
+    ///
+    /// # Safety
+    /// `count` must be no more than `len`.
+    pub unsafe fn drain_unchecked(&mut self, count: usize) {
+        self.len -= count;
+
+        // Start over at the front of the ring once it's empty, so the next bytes don't wrap. Otherwise the new head
+        // is less than one lap ahead, so it can wrap without a division.
+        let head = self.head + count;
+        self.head = if self.len == 0 {
+            0
+        } else if head >= self.capacity() {
+            head - self.capacity()
+        } else {
+            head
+        };
+    }
+
+    /// Forget everything in the buffer. The old bytes are left in memory, but can't be read back through the buffer.
     pub fn clear(&mut self) {
-        self.data = [0; MAX_BUFFER_SIZE];
-        self.offset = 0;
+        self.head = 0;
+        self.len = 0;
+    }
+
+    /// Clear the buffer, and overwrite its memory with zeroes too. Use this after handling anything sensitive, like
+    /// credentials.
+    pub fn zeroize(&mut self) {
+        for byte in self.data.iter_mut() {
+            *byte = 0;
+        }
+
+        self.clear();
+    }
+}
+
+impl Default for NetworkBuffer {
+    fn default() -> Self {
+        NetworkBuffer::new()
+    }
+}
+
+/// Shows the sizes, and a hex preview of the first few readable bytes rather than the whole buffer.
+impl fmt::Debug for NetworkBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (front, back) = self.readable();
+        let preview: Vec<String> = front
+            .iter()
+            .chain(back)
+            .take(DEBUG_PREVIEW_LEN)
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let more = if self.len > DEBUG_PREVIEW_LEN {
+            " .."
+        } else {
+            ""
+        };
+
+        f.debug_struct("NetworkBuffer")
+            .field("len", &self.len)
+            .field("capacity", &self.capacity())
+            .field("max_capacity", &self.max_capacity)
+            .field("preview", &format_args!("[{}{}]", preview.join(" "), more))
+            .finish()
+    }
+}
+
+/// BufferPool
+/// Receive buffers from closed connections, kept so new connections can reuse them instead of allocating.
+/// Reused buffers keep whatever capacity they grew to.
+pub struct BufferPool {
+    buffers: Vec<NetworkBuffer>,
+    max_pooled: usize,
+    max_capacity: usize,
+    allocated: u64,
+}
+
+impl BufferPool {
+    /// Create an empty pool that holds on to at most `max_pooled` buffers, and hands out buffers that grow to at
+    /// most `max_capacity` bytes.
+    pub fn new(max_pooled: usize, max_capacity: usize) -> Self {
+        BufferPool {
+            buffers: Vec::new(),
+            max_pooled,
+            max_capacity,
+            allocated: 0,
+        }
+    }
+
+    /// Take a buffer from the pool, or allocate a new one if the pool is empty.
+    pub fn check_out(&mut self) -> NetworkBuffer {
+        match self.buffers.pop() {
+            Some(buffer) => buffer,
+            None => {
+                self.allocated += 1;
+                NetworkBuffer::with_max_capacity(self.max_capacity)
+            }
+        }
+    }
+
+    /// Clear a buffer and put it back in the pool, or drop it if the pool is full.
+    pub fn check_in(&mut self, mut buffer: NetworkBuffer) {
+        if self.buffers.len() >= self.max_pooled || buffer.max_capacity() != self.max_capacity {
+            return;
+        }
+
+        buffer.clear();
+        self.buffers.push(buffer);
+    }
+
+    /// Get the number of buffers waiting in the pool.
+    pub fn len(&self) -> usize {
+        self.buffers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffers.is_empty()
+    }
+
+    /// Get the number of buffers `check_out` has had to allocate over the life of the pool.
+    pub fn allocated(&self) -> u64 {
+        self.allocated
     }
 }
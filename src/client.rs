@@ -1,182 +1,1592 @@
 use crate::{
     buffer::NetworkBuffer,
-    error::Result,
-    packet::{deserialize_packet_header, serialize_packet, Packet, PacketBody, PACKET_HEADER_SIZE},
-    send_bytes,
+    cancel_queued,
+    config::ClientConfig,
+    control::{
+        is_control_packet, Heartbeat, Hello, HelloAck, Kick, Ping, Pong, ServerFull,
+        HEARTBEAT_PACKET_ID, HELLO_PACKET_ID, KICK_PACKET_ID, PING_PACKET_ID, PONG_PACKET_ID,
+        SERVER_FULL_PACKET_ID, SHUTDOWN_PACKET_ID,
+    },
+    delivery::{MessageId, MessageIds, Outgoing, OutgoingQueue, PartialWrite, Payload, Priority},
+    disconnect::DisconnectReason,
+    dispatch::{Dispatched, PacketRegistry},
+    error::{Error, Result},
+    flush_packets,
+    fragment::Reassembly,
+    handler::{ClientHandler, Wakeup},
+    health::{tcp_health, TcpHealth},
+    into_events,
+    packet::{
+        is_application_packet_id, serialize_packet_with, time_since, Endianness, Packet,
+        PacketBody, PacketInfo, RawPacket, WriteFormat, DEFAULT_CHANNEL,
+    },
+    packet_log::{log_incoming, log_outgoing, PacketLogger},
+    poll_events,
+    protocol::{Encoded, Protocol, RawProtocol},
+    receive::{read_packets, ReadError, ReadState},
+    schedule::{PacketSchedule, ScheduledId},
+    sequence::{SequenceCounter, SequenceTracker},
+    stats::{RttEstimator, TrafficStats},
+    TimedEvent, CLOSE_FLUSH_TIMEOUT, RUN_POLL_TIMEOUT,
+};
+use mio::{net::TcpStream, Events, Interest, Poll, Token, Waker};
+use std::{
+    collections::VecDeque,
+    net::{Shutdown, SocketAddr},
+    ops::ControlFlow,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
-use mio::{net::TcpStream, Events, Interest, Poll, Token};
-use std::{collections::VecDeque, io::Read};
 
 const LOCAL_TOKEN: Token = Token(0);
+const WAKER_TOKEN: Token = Token(usize::MAX);
 const EVENTS_CAPACITY: usize = 4096;
 
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum ClientEvent {
-    Disconnected,
-    ReceivedPacket(usize),
+    Connected,
+    Disconnected(DisconnectReason),
+    Reconnecting(u32),
+    ReceivedPacket(PacketInfo),
     SentPacket(usize),
+    SendQueueFull,
+    /// The unread incoming packet queue reached `max_incoming`, and the client stopped reading from the socket until
+    /// it drains. Emitted once each time the cap is reached.
+    IncomingQueueFull,
+    /// A typed client received a packet with this id that didn't decode into its protocol, or a packet run through
+    /// `dispatch_incoming` didn't deserialize into the type registered for its id. The packet is dropped.
+    ProtocolError(u8),
+    /// The server sent a packet on this channel, which is past the ones set with `ClientConfig::channels`. The packet
+    /// is dropped.
+    UnknownChannel(u8),
+    /// The server sent a packet header that couldn't be parsed, like one advertising a body bigger than
+    /// `max_packet_size` or with flags the client doesn't know about. The framing can't be recovered, so the client
+    /// disconnects right after this. Holds the parse error's message.
+    InvalidHeader(String),
+    /// The server sent a packet fragment that was malformed, out of order, or part of a packet bigger than
+    /// `max_reassembly_size`. The client disconnects right after this. Holds the error's message.
+    InvalidFragment(String),
+    /// The server sent a compressed packet that was corrupt or inflated bigger than the client accepts, or sent one
+    /// without compression enabled on the client. The client disconnects right after this. Holds the error's message.
+    InvalidCompression(String),
+    /// The server kicked the client with `Server::kick_with_reason`, giving this reason. The client disconnects right
+    /// after this, and doesn't try to reconnect.
+    Kicked(String),
+    /// The server speaks a different protocol version. The client disconnects, and doesn't try to reconnect.
+    VersionMismatch {
+        server: u32,
+        client: u32,
+    },
+    /// The server exchanges packet bodies up to a different `max_packet_size`, which was caught during the version
+    /// handshake. The client disconnects, and doesn't try to reconnect.
+    PacketSizeMismatch {
+        server: usize,
+        client: usize,
+    },
+    /// The server writes its packet headers in a different `Endianness`, which was caught during the version
+    /// handshake. The client disconnects, and doesn't try to reconnect.
+    EndiannessMismatch {
+        server: Endianness,
+        client: Endianness,
+    },
+    /// The server sent a packet whose sequence number wasn't the one after its last packet's. A `received` number past
+    /// `expected` means packets were skipped, and one before it means the numbers went backwards. Only packets that
+    /// carry sequence numbers are checked, and the client stays connected.
+    OutOfSequence {
+        expected: u32,
+        received: u32,
+    },
+    /// The server sent a packet stamped with the time it was queued, which was this long ago by the client's clock.
+    /// Only sent by servers with `timestamps` enabled, and only as accurate as the two clocks are in sync.
+    PacketLatency(Duration),
+    /// A packet sat in the outgoing queue for this long before it was written. Emitted after `SentPacket`, with
+    /// `timestamps` enabled.
+    QueueDelay(Duration),
+    /// The server answered a ping sent with `Client::ping`, after this round trip time.
+    Pong(Duration),
+    /// The server didn't answer a ping within `ping_timeout`. The ping is given up on, but the client stays connected.
+    PingTimedOut,
+    /// The server sent a pong that doesn't answer any outstanding ping, because it was given up on or never sent. The
+    /// pong is ignored.
+    UnexpectedPong,
+    /// A packet sent with `send_tracked` was fully written to the socket.
+    Delivered(MessageId),
+    /// A packet sent with `send_tracked` will never be written. It didn't fit in the outgoing queue, was cancelled,
+    /// failed to serialize, or the connection closed with it still queued. Untracked packets stay queued across a
+    /// reconnect, but tracked ones are failed here instead so they aren't left waiting on a connection that may never
+    /// come back.
+    DeliveryFailed(MessageId),
+    /// A queued packet couldn't be framed, so it was never sent. Nothing of it was written, so the packets after it
+    /// still arrive intact. `error` is the error's message, which is `Error::PacketTooLarge` for bodies that only
+    /// outgrow the limit once compressed. Bodies that fail to serialize at all, or are too big before compression,
+    /// come back from `send` instead.
+    SerializeFailed {
+        packet_id: u8,
+        error: String,
+    },
+}
 
-    #[doc(hidden)]
-    __Nonexhaustive,
+/// ClientItem
+/// An event or a received packet, handed out by `Client::poll_next` in the order the client observed them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientItem {
+    Event(ClientEvent),
+    Packet(Packet),
 }
 
-pub struct Client {
+pub struct Client<P = RawProtocol> {
+    address: SocketAddr,
+    config: ClientConfig,
     tcp_stream: TcpStream,
     events: Events,
     poll: Poll,
     buffer: NetworkBuffer,
+    read_state: ReadState,
+    reassembly: Reassembly,
+    /// Numbers packets sent to the server, and checks the numbers on packets from it. Both restart with each connection.
+    sequence: SequenceCounter,
+    received_sequence: SequenceTracker,
+    /// Pings sent with `ping` that are waiting on a pong, and the round trip time measured from the answered ones.
+    rtt: RttEstimator,
     incoming_packets: VecDeque<Packet>,
-    outgoing_packets: VecDeque<Box<dyn PacketBody>>,
+    typed_incoming: VecDeque<P>,
+    outgoing_packets: OutgoingQueue,
+    /// The packet that was partway out when the socket last filled up, which has to finish before the next one starts.
+    unsent: Option<PartialWrite>,
+    pending_events: Vec<TimedEvent<ClientEvent>>,
+    message_ids: MessageIds,
+    /// Where each packet received this tick falls among the tick's events, for `poll_next`.
+    arrivals: Vec<usize>,
+    polled: VecDeque<ClientItem>,
+    scheduled: PacketSchedule<Payload>,
+    is_connecting: bool,
     is_disconnected: bool,
+    connect_started: Instant,
+    /// When the server's hello has to arrive by, while waiting for it.
+    handshake_deadline: Option<Instant>,
+    last_read: Instant,
+    last_write: Instant,
+    reconnect_attempts: u32,
+    next_reconnect: Option<Instant>,
+    packet_logger: Option<PacketLogger>,
+    read_pending: bool,
+    is_incoming_capped: bool,
+    is_write_armed: bool,
+    /// Whether the stream has taken everything written to it since its last writable event. Queued packets are written
+    /// every tick while this holds, whether or not the tick brought a writable event, and only a write that would block
+    /// clears it to wait for the next one.
+    is_writable: bool,
+    waker: Arc<Waker>,
+    stats: TrafficStats,
 }
 
 impl Client {
+    /// Connect to a server.
+    /// This is shorthand for connecting with a default `ClientConfig`.
     pub fn connect(ip: &str, port: u16) -> Result<Client> {
-        let address = format!("{}:{}", ip, port).parse().unwrap();
-        let mut tcp_stream = TcpStream::connect(address)?;
-
-        // Register for reading/writing
-        let poll = Poll::new().unwrap();
-        poll.registry().register(
-            &mut tcp_stream,
-            LOCAL_TOKEN,
-            Interest::READABLE | Interest::WRITABLE,
+        ClientConfig::new().connect(format!("{}:{}", ip, port))
+    }
+
+    /// Take over a stream that's already connected to a server, for example one set up through a proxy.
+    /// This is shorthand for `ClientConfig::adopt_stream` with a default config.
+    pub fn from_stream(stream: std::net::TcpStream) -> Result<Client> {
+        ClientConfig::new().adopt_stream(stream)
+    }
+
+    pub fn drain_incoming_packets(&mut self) -> Vec<Packet> {
+        self.incoming_packets.drain(..).collect()
+    }
+
+    /// Hand incoming packets to `f` one at a time, without collecting them into a `Vec` first.
+    /// Stops early if `f` returns `ControlFlow::Break`, leaving the rest queued.
+    pub fn for_each_incoming<F>(&mut self, mut f: F)
+    where
+        F: FnMut(Packet) -> ControlFlow<()>,
+    {
+        while let Some(packet) = self.incoming_packets.pop_front() {
+            if f(packet).is_break() {
+                break;
+            }
+        }
+    }
+
+    /// Take the next incoming packet, if there is one.
+    pub fn next_incoming(&mut self) -> Option<Packet> {
+        self.incoming_packets.pop_front()
+    }
+
+    /// Drain incoming packets through `registry`, handing each one to the handler registered for its id.
+    /// Packets with no handler are returned. Packets that don't deserialize are dropped, with a `ProtocolError` event
+    /// for each on the next tick.
+    pub fn dispatch_incoming(&mut self, registry: &mut PacketRegistry<()>) -> Vec<Packet> {
+        let mut unhandled = Vec::new();
+        while let Some(packet) = self.incoming_packets.pop_front() {
+            match registry.dispatch((), packet) {
+                Dispatched::Handled => {}
+                Dispatched::Unknown(packet) => unhandled.push(packet),
+                Dispatched::Invalid(id) => {
+                    self.pending_events
+                        .push(TimedEvent::now(ClientEvent::ProtocolError(id)));
+                }
+            }
+        }
+
+        unhandled
+    }
+
+    /// Drain the incoming packets that arrived on `channel`.
+    /// Packets on other channels stay queued in the order they arrived.
+    pub fn drain_incoming_on(&mut self, channel: u8) -> Vec<Packet> {
+        let mut drained = Vec::new();
+        let mut rest = VecDeque::with_capacity(self.incoming_packets.len());
+        for packet in self.incoming_packets.drain(..) {
+            if packet.header.channel() == channel {
+                drained.push(packet);
+            } else {
+                rest.push_back(packet);
+            }
+        }
+        self.incoming_packets = rest;
+
+        drained
+    }
+
+    /// Drain the incoming packets with this id, deserialized as `T`.
+    /// Packets with other ids stay queued in the order they arrived. A body that doesn't deserialize comes back as an
+    /// error in its place, rather than being dropped.
+    pub fn drain_incoming_as<T: PacketBody>(&mut self, id: u8) -> Vec<Result<T>> {
+        let mut drained = Vec::new();
+        self.incoming_packets.retain(|packet| {
+            if packet.header.id != id {
+                return true;
+            }
+
+            drained.push(T::deserialize(&packet.body));
+            false
+        });
+
+        drained
+    }
+
+    /// Queue a packet to be sent on the next tick.
+    /// The packet is serialized straight away, and the error is returned without queuing anything if that fails.
+    /// Packet ids from `RESERVED_PACKET_IDS_START` up belong to grubbnet, and sending one returns `ReservedPacketId`.
+    /// A body too big to send even in fragments returns `PacketTooLarge`.
+    /// If the outgoing queue is at its configured limit, the packet is dropped and `SendQueueFull` is emitted.
+    pub fn send(&mut self, packet: impl PacketBody) -> Result<()> {
+        self.queue(Box::new(packet), None, Priority::Normal, DEFAULT_CHANNEL)
+    }
+
+    /// Queue a packet with the given priority.
+    /// When the socket can't keep up, higher priority packets are written before anything with a lower priority that
+    /// was queued first. Packets with the same priority are still written in the order they were sent.
+    pub fn send_with_priority(&mut self, packet: impl PacketBody, priority: Priority) -> Result<()> {
+        self.queue(Box::new(packet), None, priority, DEFAULT_CHANNEL)
+    }
+
+    /// Queue a body that's already serialized with the given packet id, like one relayed from elsewhere. The bytes are
+    /// framed and sent as they are.
+    /// The packet is checked the same way as with `send`, so this returns `ReservedPacketId` if the id belongs to
+    /// grubbnet, or `PacketTooLarge` if the body is too big to send even in fragments, without queuing anything.
+    pub fn send_raw(&mut self, id: u8, body: Vec<u8>) -> Result<()> {
+        self.send(RawPacket::new(id, body))
+    }
+
+    /// Queue a packet on one of the channels set with `ClientConfig::channels`.
+    /// The channels take turns writing their packets, so a channel with a lot queued can't hold up the others, and the
+    /// server can tell them apart with `packet.header.channel()` or `drain_incoming_on`. Packets on the same channel
+    /// are still written in the order they were sent. Sending on a channel past the configured ones returns
+    /// `UnknownChannel` without queuing anything.
+    pub fn send_on_channel(&mut self, channel: u8, packet: impl PacketBody) -> Result<()> {
+        self.queue(Box::new(packet), None, Priority::Normal, channel)
+    }
+
+    /// Queue a packet, and find out when it's been handed to the OS.
+    /// The returned `MessageId` comes back in a `Delivered` event once the packet has been fully written to the socket,
+    /// or a `DeliveryFailed` event if it never will be. Fails like `send` does, in which case there's no `MessageId`
+    /// to wait on.
+    pub fn send_tracked(&mut self, packet: impl PacketBody) -> Result<MessageId> {
+        let message = self.message_ids.next();
+        self.queue(
+            Box::new(packet),
+            Some(message),
+            Priority::Normal,
+            DEFAULT_CHANNEL,
         )?;
+        Ok(message)
+    }
+
+    /// Queue a packet to be sent once `delay` has passed, at the start of the first tick after that.
+    /// The packet is serialized and checked straight away, so this fails if that does, the same as `send` would, or
+    /// with `ScheduleFull` if `ClientConfig::max_scheduled` packets are already waiting.
+    pub fn send_after(&mut self, packet: impl PacketBody, delay: Duration) -> Result<ScheduledId> {
+        self.schedule(Box::new(packet), delay)
+    }
+
+    /// Runs a network tick, which sends/receives packets based on socket readiness.
+    /// Returns an error if polling fails. Problems with the connection show up as events instead.
+    pub fn tick(&mut self) -> Result<Vec<ClientEvent>> {
+        Ok(into_events(self.tick_timed()?))
+    }
+
+    /// Same as `tick`, but each event carries the time it was observed.
+    pub fn tick_timed(&mut self) -> Result<Vec<TimedEvent<ClientEvent>>> {
+        self.run_tick(Some(self.config.tick_timeout))
+    }
+
+    /// Same as `tick`, but waits in poll until there's socket activity or `timeout` passes, sleeping the thread
+    /// instead of spinning. `None` waits for as long as it takes. While disconnected it waits for the next reconnect
+    /// attempt instead. Scheduled packets still cut the wait short, and `Wakeup::wake` ends it from another thread.
+    pub fn tick_blocking(&mut self, timeout: Option<Duration>) -> Result<Vec<ClientEvent>> {
+        Ok(into_events(self.run_tick_blocking(timeout)?))
+    }
+
+    /// Get the next event or received packet, in the order the client observed them. Each packet comes just before its
+    /// `ReceivedPacket` event, and packets left unread from earlier ticks come before anything new.
+    /// This ticks whenever everything from the last tick has been handed out, and returns `None` if that tick turned
+    /// up nothing, so a `while let` loop handles whatever is ready and then returns. Fails if the tick does.
+    /// `tick` and `drain_incoming_packets` still work alongside this, but only `poll_next` keeps the two in order.
+    pub fn poll_next(&mut self) -> Result<Option<ClientItem>> {
+        if self.polled.is_empty() {
+            let events = self.tick()?;
+            self.queue_polled(events);
+        }
+
+        Ok(self.polled.pop_front())
+    }
+
+    /// Interleave a tick's events with the packets received during it, and queue them up for `poll_next`.
+    fn queue_polled(&mut self, events: Vec<ClientEvent>) {
+        let incoming_packets = &mut self.incoming_packets;
+        let polled = &mut self.polled;
+        let mut arrivals = self.arrivals.drain(..).peekable();
+
+        // Whatever is ahead of this tick's packets was received earlier
+        let earlier = incoming_packets.len().saturating_sub(arrivals.len());
+        for packet in incoming_packets.drain(..earlier) {
+            polled.push_back(ClientItem::Packet(packet));
+        }
+
+        for (index, event) in events.into_iter().enumerate() {
+            while arrivals.next_if_eq(&index).is_some() {
+                if let Some(packet) = incoming_packets.pop_front() {
+                    polled.push_back(ClientItem::Packet(packet));
+                }
+            }
+
+            polled.push_back(ClientItem::Event(event));
+        }
+
+        for packet in incoming_packets.drain(..) {
+            polled.push_back(ClientItem::Packet(packet));
+        }
+    }
+
+    /// Tick until `stop` is set or the connection is gone for good, handing every event and packet to `handler`.
+    /// Each tick blocks in poll until there's something to do, and waiting for a reconnect doesn't spin either. Call
+    /// `Wakeup::wake` after setting `stop` from another thread to have it noticed straight away.
+    /// Once stopped, queued packets are flushed and the handler gets a `Disconnected` if the client was still
+    /// connected. Returns an error if polling fails.
+    pub fn run<H: ClientHandler>(&mut self, handler: &mut H, stop: &AtomicBool) -> Result<()> {
+        while !stop.load(Ordering::Acquire) && self.is_running() {
+            self.wait_for_reconnect(Some(RUN_POLL_TIMEOUT))?;
+            for event in into_events(self.run_tick(Some(RUN_POLL_TIMEOUT))?) {
+                handler.on_event(self, event);
+            }
+
+            while let Some(packet) = self.incoming_packets.pop_front() {
+                handler.on_packet(self, packet);
+            }
+
+            handler.on_tick(self);
+
+            // Send whatever the handler queued without waiting for the next tick
+            self.flush();
+        }
+
+        if let Some(event) = self.stop_running() {
+            handler.on_event(self, event);
+        }
+
+        Ok(())
+    }
+}
+
+impl<P: Protocol> Client<P> {
+    /// Queue a protocol message to be sent on the next tick.
+    /// Returns the error without queuing anything if the message fails to encode, or is too big to send. If the
+    /// outgoing queue is at its configured limit, the message is dropped and `SendQueueFull` is emitted.
+    pub fn send(&mut self, packet: P) -> Result<()> {
+        self.queue(
+            Box::new(Encoded(packet)),
+            None,
+            Priority::Normal,
+            DEFAULT_CHANNEL,
+        )
+    }
+
+    /// Queue a protocol message with the given priority. This works just like `Client::send_with_priority`.
+    pub fn send_with_priority(&mut self, packet: P, priority: Priority) -> Result<()> {
+        self.queue(Box::new(Encoded(packet)), None, priority, DEFAULT_CHANNEL)
+    }
+
+    /// Queue a protocol message on one of the configured channels. This works just like `Client::send_on_channel`.
+    pub fn send_on_channel(&mut self, channel: u8, packet: P) -> Result<()> {
+        self.queue(Box::new(Encoded(packet)), None, Priority::Normal, channel)
+    }
+
+    /// Queue a protocol message, and find out when it's been handed to the OS. This works just like
+    /// `Client::send_tracked`.
+    pub fn send_tracked(&mut self, packet: P) -> Result<MessageId> {
+        let message = self.message_ids.next();
+        self.queue(
+            Box::new(Encoded(packet)),
+            Some(message),
+            Priority::Normal,
+            DEFAULT_CHANNEL,
+        )?;
+        Ok(message)
+    }
+
+    /// Queue a protocol message to be sent once `delay` has passed. This works just like `Client::send_after`.
+    pub fn send_after(&mut self, packet: P, delay: Duration) -> Result<ScheduledId> {
+        self.schedule(Box::new(Encoded(packet)), delay)
+    }
+
+    /// Drain any incoming messages that decoded into the protocol, and return them.
+    pub fn drain_incoming_typed(&mut self) -> Vec<P> {
+        self.typed_incoming.drain(..).collect()
+    }
+
+    /// Runs a network tick, then decodes the packets it received into the protocol.
+    /// Packets that don't decode are dropped, with a `ProtocolError` event for each.
+    pub fn tick(&mut self) -> Result<Vec<ClientEvent>> {
+        Ok(into_events(self.tick_timed()?))
+    }
+
+    /// Same as `tick`, but each event carries the time it was observed.
+    pub fn tick_timed(&mut self) -> Result<Vec<TimedEvent<ClientEvent>>> {
+        let mut net_events = self.run_tick(Some(self.config.tick_timeout))?;
+        self.decode_incoming(&mut net_events);
+
+        Ok(net_events)
+    }
+
+    /// Like the raw client's `tick_blocking`, then decodes the packets it received into the protocol.
+    pub fn tick_blocking(&mut self, timeout: Option<Duration>) -> Result<Vec<ClientEvent>> {
+        let mut net_events = self.run_tick_blocking(timeout)?;
+        self.decode_incoming(&mut net_events);
+
+        Ok(into_events(net_events))
+    }
 
+    /// Like the raw client's `run`, but received packets are decoded and handed to `ClientHandler::on_message`.
+    pub fn run<H: ClientHandler<P>>(&mut self, handler: &mut H, stop: &AtomicBool) -> Result<()> {
+        while !stop.load(Ordering::Acquire) && self.is_running() {
+            self.wait_for_reconnect(Some(RUN_POLL_TIMEOUT))?;
+            let mut net_events = self.run_tick(Some(RUN_POLL_TIMEOUT))?;
+            self.decode_incoming(&mut net_events);
+
+            for event in into_events(net_events) {
+                handler.on_event(self, event);
+            }
+
+            while let Some(message) = self.typed_incoming.pop_front() {
+                handler.on_message(self, message);
+            }
+
+            handler.on_tick(self);
+
+            // Send whatever the handler queued without waiting for the next tick
+            self.flush();
+        }
+
+        if let Some(event) = self.stop_running() {
+            handler.on_event(self, event);
+        }
+
+        Ok(())
+    }
+
+    /// Decode the packets the last tick received into the protocol.
+    fn decode_incoming(&mut self, net_events: &mut Vec<TimedEvent<ClientEvent>>) {
+        for packet in self.incoming_packets.drain(..) {
+            match P::decode(packet.header.id, &packet.body) {
+                Ok(message) => self.typed_incoming.push_back(message),
+                Err(e) => {
+                    eprintln!("Failed to decode packet {}! {}", packet.header.id, e);
+                    net_events.push(TimedEvent::now(ClientEvent::ProtocolError(
+                        packet.header.id,
+                    )));
+                }
+            }
+        }
+    }
+}
+
+impl<P> Client<P> {
+    /// Connect to a server with an already validated config.
+    pub(crate) fn with_config(address: SocketAddr, config: ClientConfig) -> Result<Client<P>> {
+        let poll = Poll::new()?;
+        let tcp_stream = open_stream(address, &config, &poll)?;
+
+        Client::with_stream(address, config, tcp_stream, poll)
+    }
+
+    /// Take over an already connected stream with an already validated config.
+    /// The client starts out connected, and a `Connected` event is emitted on the first tick.
+    pub(crate) fn adopt_stream(
+        stream: std::net::TcpStream,
+        config: ClientConfig,
+    ) -> Result<Client<P>> {
+        let address = stream.peer_addr()?;
+        stream.set_nonblocking(true)?;
+
+        let poll = Poll::new()?;
+        let mut tcp_stream = TcpStream::from_std(stream);
+        register_stream(&mut tcp_stream, &config, &poll)?;
+
+        let mut client = Client::with_stream(address, config, tcp_stream, poll)?;
+        client.is_connecting = false;
+        if !client.start_handshake() {
+            client
+                .pending_events
+                .push(TimedEvent::now(ClientEvent::Connected));
+        }
+
+        Ok(client)
+    }
+
+    fn with_stream(
+        address: SocketAddr,
+        config: ClientConfig,
+        tcp_stream: TcpStream,
+        poll: Poll,
+    ) -> Result<Client<P>> {
+        // Lets other threads wake up a client blocked in `run`
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER_TOKEN)?);
+
+        let now = Instant::now();
         Ok(Client {
+            address,
+            buffer: NetworkBuffer::with_max_capacity(config.max_buffer_size),
+            sequence: SequenceCounter::new(config.sequence_numbers),
+            config,
             tcp_stream,
             events: Events::with_capacity(EVENTS_CAPACITY),
             poll,
-            buffer: NetworkBuffer::new(),
+            read_state: ReadState::ReadingHeader,
+            reassembly: Reassembly::new(),
+            received_sequence: SequenceTracker::new(),
+            rtt: RttEstimator::new(),
             incoming_packets: VecDeque::new(),
-            outgoing_packets: VecDeque::new(),
+            typed_incoming: VecDeque::new(),
+            outgoing_packets: OutgoingQueue::new(),
+            unsent: None,
+            pending_events: Vec::new(),
+            message_ids: MessageIds::new(),
+            arrivals: Vec::new(),
+            polled: VecDeque::new(),
+            scheduled: PacketSchedule::new(),
+            is_connecting: true,
             is_disconnected: false,
+            connect_started: now,
+            handshake_deadline: None,
+            last_read: now,
+            last_write: now,
+            reconnect_attempts: 0,
+            next_reconnect: None,
+            packet_logger: None,
+            read_pending: false,
+            is_incoming_capped: false,
+            is_write_armed: true,
+            is_writable: false,
+            waker,
+            stats: TrafficStats::default(),
         })
     }
 
+    /// Get the config the client was connected with.
+    pub fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+
+    /// Whether the connection is still being established, including waiting for the server's hello.
+    pub fn is_connecting(&self) -> bool {
+        self.is_connecting || self.handshake_deadline.is_some()
+    }
+
     pub fn is_disconnected(&self) -> bool {
         self.is_disconnected
     }
 
-    pub fn drain_incoming_packets(&mut self) -> Vec<Packet> {
-        self.incoming_packets.drain(..).collect()
+    /// Cancel a packet scheduled with `send_after`. Returns false if it has already been sent or cancelled.
+    pub fn cancel_scheduled(&mut self, id: ScheduledId) -> bool {
+        self.scheduled.cancel(id)
+    }
+
+    /// Get the number of packets scheduled with `send_after` that haven't been sent yet.
+    pub fn scheduled_count(&self) -> usize {
+        self.scheduled.len()
+    }
+
+    /// Ping the server to measure the round trip time.
+    /// Servers built on grubbnet answer pings without involving the application, and the answer comes back in a
+    /// `Pong` event, or a `PingTimedOut` event if it doesn't arrive within `ClientConfig::ping_timeout`. Returns
+    /// `ConnectionNotFound` while disconnected.
+    pub fn ping(&mut self) -> Result<()> {
+        if self.is_disconnected {
+            return Err(Error::ConnectionNotFound);
+        }
+
+        let ping = self.rtt.ping(Instant::now());
+        self.outgoing_packets.push_back(Outgoing::new(ping));
+
+        Ok(())
+    }
+
+    /// Get the smoothed round trip time to the server, measured from answered pings.
+    /// Returns `None` if no ping has been answered on this connection yet.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt.rtt()
+    }
+
+    /// Get the kernel's view of the connection: its round trip time, retransmits and unacknowledged bytes.
+    /// Returns `None` while connecting or disconnected, or if the platform doesn't support this (anything but Linux).
+    pub fn health(&self) -> Option<TcpHealth> {
+        if self.is_connecting || self.is_disconnected {
+            return None;
+        }
+
+        tcp_health(&self.tcp_stream)
     }
 
-    pub fn send(&mut self, packet: impl PacketBody) {
-        let boxed = Box::new(packet);
-        self.outgoing_packets.push_back(boxed);
+    /// Set a hook that sees every packet as it's received (after parsing) and sent (after serialization), or `None`
+    /// to remove it. Grubbnet's own packets are included, and marked as internal.
+    pub fn set_packet_logger(&mut self, logger: Option<PacketLogger>) {
+        self.packet_logger = logger;
+    }
+
+    /// Cap how many received packets may sit unread, or remove the cap with `None`. See `ClientConfig::max_incoming`.
+    pub fn set_max_incoming_queue(&mut self, max: Option<usize>) {
+        self.config.max_incoming = max;
+    }
+
+    /// Get totals for the connection to the server, across reconnects.
+    pub fn stats(&self) -> &TrafficStats {
+        &self.stats
+    }
+
+    /// Get how many bytes are waiting to be written, including the rest of a packet that's partway out.
+    pub fn queued_bytes(&self) -> usize {
+        let queued = self.outgoing_packets.bytes()
+            + self.outgoing_packets.len() * self.config.framing.header_size();
+        queued + self.unsent.as_ref().map_or(0, PartialWrite::remaining)
+    }
+
+    /// Treat the connection as dead if nothing is received from the server for `timeout`, or remove the timeout with
+    /// `None`. See `ClientConfig::read_timeout`. Returns `Error::InvalidConfig` for a zero timeout, like
+    /// `ClientConfig::connect` does, since the connection would time out on the next tick.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        if timeout == Some(Duration::from_secs(0)) {
+            return Err(Error::InvalidConfig("read_timeout can't be zero"));
+        }
+
+        self.config.read_timeout = timeout;
+        Ok(())
+    }
+
+    /// Write queued packets straight away if the stream is known to be writable, rather than waiting for the next tick.
+    /// Call this after queuing responses to packets from the last tick to save a tick of latency. `SentPacket` events
+    /// for these writes come with the next tick.
+    pub fn flush(&mut self) {
+        let mut net_events = std::mem::take(&mut self.pending_events);
+        self.flush_writable(&mut net_events);
+        self.pending_events = net_events;
+    }
+
+    /// Remove queued packets whose id matches `predicate`, and return how many were removed.
+    /// Queued packets haven't been written to the socket at all yet, so cancelling them never cuts a frame short.
+    pub fn cancel_pending<F>(&mut self, predicate: F) -> usize
+    where
+        F: Fn(u8) -> bool,
+    {
+        let mut cancelled = Vec::new();
+        let removed = cancel_queued(&mut self.outgoing_packets, predicate, &mut cancelled);
+        self.pending_events.extend(
+            cancelled
+                .into_iter()
+                .map(|message| TimedEvent::now(ClientEvent::DeliveryFailed(message))),
+        );
+
+        removed
+    }
+
+    /// Get a handle for waking the client out of a blocking `run` from other threads.
+    pub fn wakeup(&self) -> Wakeup {
+        Wakeup::new(self.waker.clone())
+    }
+
+    /// Flush whatever can be sent without blocking, then close the connection.
+    /// No `Disconnected` event is emitted for this, and any pending reconnect is cancelled. Tracked packets get their
+    /// `Delivered` or `DeliveryFailed` event on the next tick, if there is one.
+    /// Dropping the client does the same thing, but calling this first makes the intent explicit.
+    pub fn disconnect(&mut self) {
+        self.next_reconnect = None;
+        if self.is_disconnected {
+            return;
+        }
+
+        let unsent = self.unsent.as_ref().and_then(|unsent| unsent.message);
+        let tracked: Vec<MessageId> = unsent
+            .into_iter()
+            .chain(self.outgoing_packets.iter().filter_map(|o| o.message))
+            .collect();
+        let mut delivered = Vec::new();
+        if !self.is_connecting {
+            let deadline = Instant::now() + CLOSE_FLUSH_TIMEOUT;
+            let packet_logger = &mut self.packet_logger;
+            delivered = flush_packets(
+                &mut self.tcp_stream,
+                self.unsent.take(),
+                &mut self.outgoing_packets,
+                &self.config.write_format(),
+                &mut self.sequence,
+                deadline,
+                |packet_id, sequence, body| {
+                    log_outgoing(packet_logger, None, packet_id, sequence, body)
+                },
+            )
+            .0;
+        }
+
+        // Whatever wasn't written, whether it's still queued or was lost to an error, is never going out now
+        for message in tracked {
+            let event = if delivered.contains(&message) {
+                ClientEvent::Delivered(message)
+            } else {
+                ClientEvent::DeliveryFailed(message)
+            };
+            self.pending_events.push(TimedEvent::now(event));
+        }
+        self.outgoing_packets
+            .retain(|outgoing| outgoing.message.is_none());
+
+        let _ = self.tcp_stream.shutdown(Shutdown::Both);
+        let _ = self.poll.registry().deregister(&mut self.tcp_stream);
+
+        self.is_disconnected = true;
+        self.is_connecting = false;
+    }
+
+    /// Hold a packet until `delay` has passed, unless the schedule is full.
+    fn schedule(&mut self, packet: Box<dyn PacketBody>, delay: Duration) -> Result<ScheduledId> {
+        if let Some(max) = self.config.max_scheduled {
+            if self.scheduled.len() >= max {
+                return Err(Error::ScheduleFull);
+            }
+        }
+
+        let payload = Payload::serialized(packet.as_ref())?;
+        self.config
+            .write_format()
+            .check_sendable(payload.id(), DEFAULT_CHANNEL, payload.len())?;
+        Ok(self.scheduled.insert(Instant::now() + delay, payload))
+    }
+
+    /// Serialize a packet and queue it, unless the outgoing queue is at its configured limit.
+    /// It's serialized up front, so its size counts towards `max_outgoing_bytes`, and a failure is returned to the
+    /// caller rather than hit while writing. The same goes for a reserved id, a channel that isn't configured, or a
+    /// body too big to send.
+    fn queue(
+        &mut self,
+        packet: Box<dyn PacketBody>,
+        message: Option<MessageId>,
+        priority: Priority,
+        channel: u8,
+    ) -> Result<()> {
+        let payload = Payload::serialized(packet.as_ref())?;
+        self.config
+            .write_format()
+            .check_sendable(payload.id(), channel, payload.len())?;
+        self.queue_payload(payload, message, priority, channel);
+
+        Ok(())
+    }
+
+    /// Queue an already serialized packet, unless the outgoing queue is at its configured limit.
+    fn queue_payload(
+        &mut self,
+        payload: Payload,
+        message: Option<MessageId>,
+        priority: Priority,
+        channel: u8,
+    ) {
+        let outgoing = Outgoing::payload(payload, message, priority).on_channel(channel);
+        let is_count_full = match self.config.max_outgoing {
+            Some(max) => self.outgoing_packets.len() >= max,
+            None => false,
+        };
+
+        // A packet bigger than the whole limit can still go out on its own, rather than never being sent
+        let size = self.config.framing.header_size() + outgoing.packet.len();
+        let is_bytes_full = match self.config.max_outgoing_bytes {
+            Some(max) => self.has_unwritten() && self.queued_bytes() + size > max,
+            None => false,
+        };
+
+        if is_count_full || is_bytes_full {
+            self.pending_events
+                .push(TimedEvent::now(ClientEvent::SendQueueFull));
+            if let Some(message) = message {
+                self.pending_events
+                    .push(TimedEvent::now(ClientEvent::DeliveryFailed(message)));
+            }
+
+            return;
+        }
+
+        self.outgoing_packets.push_back(outgoing);
+    }
+
+    /// Take the tracked packets out of the outgoing queue, and report them as failed. Untracked packets are left
+    /// queued for the next connection, apart from one that was partway out, since the next connection can't pick up
+    /// halfway through it.
+    fn fail_tracked(&mut self, net_events: &mut Vec<TimedEvent<ClientEvent>>) {
+        if let Some(message) = self.unsent.take().and_then(|unsent| unsent.message) {
+            net_events.push(TimedEvent::now(ClientEvent::DeliveryFailed(message)));
+        }
+
+        self.outgoing_packets
+            .retain(|outgoing| match outgoing.message {
+                Some(message) => {
+                    net_events.push(TimedEvent::now(ClientEvent::DeliveryFailed(message)));
+                    false
+                }
+                None => true,
+            });
     }
 
-    /// Runs a network tick, which sends/receives packets based on socket readiness
-    pub fn tick(&mut self) -> Vec<ClientEvent> {
+    /// Sends/receives packets based on socket readiness.
+    /// Received packets are left undecoded in `incoming_packets`.
+    /// Waits in poll for up to `timeout`, or until there's activity if it's `None`.
+    fn run_tick(&mut self, timeout: Option<Duration>) -> Result<Vec<TimedEvent<ClientEvent>>> {
+        for payload in self.scheduled.take_due(Instant::now()) {
+            self.queue_payload(payload, None, Priority::Normal, DEFAULT_CHANNEL);
+        }
+
+        let mut net_events: Vec<TimedEvent<ClientEvent>> = self.pending_events.drain(..).collect();
+        self.arrivals.clear();
+
         if self.is_disconnected {
-            return Vec::new();
+            self.try_reconnect(&mut net_events);
+            if self.is_disconnected {
+                return Ok(net_events);
+            }
         }
 
-        let timeout_dur = std::time::Duration::from_millis(1);
-        self.poll
-            .poll(&mut self.events, Some(timeout_dur))
-            .unwrap_or_else(|e| panic!("Failed to poll for events! {}", e));
+        // Writable events are only asked for while there's something to write, so an idle client can block in poll.
+        // Packets queued since the last tick need the interest added back, and a queue that was flushed since needs it
+        // taken away.
+        if self.is_interest_stale() {
+            self.reregister(&mut net_events);
+        }
 
-        let mut net_events: Vec<ClientEvent> = Vec::new();
+        // Don't sleep through the next scheduled packet
+        let timeout = match self.scheduled.next_due() {
+            Some(due) => {
+                let until_due = due.saturating_duration_since(Instant::now());
+                Some(timeout.map_or(until_due, |timeout| timeout.min(until_due)))
+            }
+            None => timeout,
+        };
+
+        poll_events(&mut self.poll, &mut self.events, timeout)?;
+
+        let mut readable = false;
+        let mut writable = false;
         for event in self.events.iter() {
             match event.token() {
                 // Local socket is ready to read/write
                 LOCAL_TOKEN => {
-                    // Handle reading
-                    if event.is_readable() {
-                        loop {
-                            // Read until there are no more incoming bytes
-                            match self
-                                .tcp_stream
-                                .read(&mut self.buffer.data[self.buffer.offset..])
-                            {
-                                Ok(0) => {
-                                    // "Read" 0 bytes, which means we have been disconnected
-                                    net_events.push(ClientEvent::Disconnected);
-                                    self.is_disconnected = true;
-                                    break;
-                                }
-                                Ok(read_bytes) => {
-                                    // Read some bytes
-                                    self.buffer.offset += read_bytes;
-                                }
-                                Err(e) => {
-                                    // Socket is not ready anymore, stop reading
-                                    if e.kind() == std::io::ErrorKind::WouldBlock {
-                                        break;
-                                    } else {
-                                        net_events.push(ClientEvent::Disconnected);
-
-                                        eprintln!("Unexpected error when reading bytes! {}", e);
-                                        self.is_disconnected = true;
-                                        break;
-                                    }
-                                }
-                            }
-                        }
+                    readable |= event.is_readable();
+                    writable |= event.is_writable();
+                }
+                // Another thread wants a blocking `run` to check its stop flag
+                WAKER_TOKEN => {}
+                _ => unreachable!(),
+            }
+        }
 
-                        // Process incoming bytes into packets
-                        while let Ok(header) = deserialize_packet_header(&mut self.buffer) {
-                            // Now make sure we have enough bytes for at the rest of this packet
-                            let packet_size = PACKET_HEADER_SIZE + (header.size as usize);
-                            if self.buffer.offset < packet_size {
-                                break;
-                            }
+        // Until the connection is established, readiness just tells us the connect attempt finished
+        if self.is_connecting && (readable || writable) {
+            self.finish_connecting(&mut net_events);
+        }
+
+        if !self.is_connecting && !self.is_disconnected {
+            // Leave the bytes in the socket while the application is behind, and let TCP push back on the server.
+            // Since polling is edge-triggered, remember that there's data to read once the queue drains.
+            let queued = self.incoming_packets.len() + self.typed_incoming.len();
+            if matches!(self.config.max_incoming, Some(max) if queued >= max) {
+                self.read_pending |= readable;
+                if self.read_pending && !self.is_incoming_capped {
+                    self.is_incoming_capped = true;
+                    net_events.push(TimedEvent::now(ClientEvent::IncomingQueueFull));
+                }
+            } else {
+                self.is_incoming_capped = false;
+
+                // Handle reading
+                if readable || self.read_pending {
+                    self.read_pending = false;
+                    self.read(&mut net_events);
+                }
+            }
 
-                            // Drain the packet bytes from the front of the buffer
-                            let bytes: &[u8] = &self.buffer.data[PACKET_HEADER_SIZE..packet_size];
-                            let body = bytes.to_vec();
-                            self.buffer.drain(packet_size);
+            // Handle writing
+            if writable && !self.is_disconnected {
+                self.is_writable = true;
+                self.write(&mut net_events);
+            }
+        }
 
-                            let packet = Packet { header, body };
+        self.check_timers(&mut net_events);
 
-                            self.incoming_packets.push_back(packet);
+        // Write whatever was queued while handling this tick (pongs, heartbeats...) now, instead of waiting for the
+        // next tick's writable event
+        self.flush_writable(&mut net_events);
+
+        // We're done processing events for this tick. The registration is edge triggered, so it only needs touching
+        // when writable events should start or stop.
+        if !self.is_disconnected && self.is_interest_stale() {
+            self.reregister(&mut net_events);
+        }
+
+        Ok(net_events)
+    }
+
+    /// Write queued packets if the stream has been writable since its last writable event.
+    fn flush_writable(&mut self, net_events: &mut Vec<TimedEvent<ClientEvent>>) {
+        if self.is_writable && !self.is_disconnected && self.has_unwritten() {
+            self.write(net_events);
+        }
+    }
+
+    /// Whether the stream is registered for writable events when they aren't wanted, or the other way around.
+    fn is_interest_stale(&self) -> bool {
+        self.is_write_armed != (self.is_connecting || self.has_unwritten())
+    }
+
+    /// Reregister the stream for the next tick.
+    /// Writable events are only asked for while connecting, or while there's something to write.
+    fn reregister(&mut self, net_events: &mut Vec<TimedEvent<ClientEvent>>) {
+        self.is_write_armed = self.is_connecting || self.has_unwritten();
+        let interest = if self.is_write_armed {
+            Interest::READABLE | Interest::WRITABLE
+        } else {
+            Interest::READABLE
+        };
+
+        let registry = self.poll.registry();
+        if let Err(e) = registry.reregister(&mut self.tcp_stream, LOCAL_TOKEN, interest) {
+            eprintln!("Failed to reregister poll for {}! {}", self.address, e);
+            self.drop_connection(net_events, DisconnectReason::IoError(e.kind()));
+        }
+    }
+
+    /// Whether `run` should keep going. It stops once the connection is gone and no reconnect is coming.
+    fn is_running(&self) -> bool {
+        !self.is_disconnected || self.next_reconnect.is_some() || !self.pending_events.is_empty()
+    }
+
+    /// `run_tick`, after waiting for the next reconnect attempt if disconnected. The whole thing takes no longer than
+    /// `timeout`.
+    fn run_tick_blocking(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<TimedEvent<ClientEvent>>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        self.wait_for_reconnect(timeout)?;
+
+        self.run_tick(deadline.map(|deadline| deadline.saturating_duration_since(Instant::now())))
+    }
+
+    /// While disconnected, block until the next reconnect attempt is due (or `timeout` passes), instead of spinning.
+    fn wait_for_reconnect(&mut self, timeout: Option<Duration>) -> Result<()> {
+        if !self.is_disconnected {
+            return Ok(());
+        }
+
+        let wait = match self.next_reconnect {
+            Some(at) => {
+                let until_due = at.saturating_duration_since(Instant::now());
+                timeout.map_or(until_due, |timeout| timeout.min(until_due))
+            }
+            None => return Ok(()),
+        };
+
+        poll_events(&mut self.poll, &mut self.events, Some(wait))?;
+        Ok(())
+    }
 
-                            net_events.push(ClientEvent::ReceivedPacket(packet_size));
+    /// Disconnect at the end of `run`, returning a `Disconnected` event if the client was still connected.
+    fn stop_running(&mut self) -> Option<ClientEvent> {
+        let was_connected = !self.is_disconnected && !self.is_connecting();
+        self.disconnect();
+
+        if was_connected {
+            Some(ClientEvent::Disconnected(DisconnectReason::Closed))
+        } else {
+            None
+        }
+    }
+
+    /// Start waiting for the server's hello, if a protocol version is configured.
+    /// Returns false if there's nothing to wait for, so the connection is ready straight away.
+    fn start_handshake(&mut self) -> bool {
+        if self.config.protocol_version.is_none() {
+            return false;
+        }
+
+        self.handshake_deadline = Some(Instant::now() + self.config.handshake_timeout);
+        true
+    }
+
+    /// Check whether a pending connect attempt succeeded.
+    fn finish_connecting(&mut self, net_events: &mut Vec<TimedEvent<ClientEvent>>) {
+        match self.tcp_stream.take_error() {
+            Ok(None) => {}
+            Ok(Some(e)) | Err(e) => {
+                eprintln!("Failed to connect to {}! {}", self.address, e);
+                self.drop_connection(net_events, DisconnectReason::IoError(e.kind()));
+                return;
+            }
+        }
+
+        match self.tcp_stream.peer_addr() {
+            Ok(_) => {
+                let now = Instant::now();
+                self.is_connecting = false;
+                self.reconnect_attempts = 0;
+                self.last_read = now;
+                self.last_write = now;
+
+                if !self.start_handshake() {
+                    net_events.push(TimedEvent::now(ClientEvent::Connected));
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotConnected => {
+                // Not connected yet, wait for the next readiness event
+            }
+            Err(e) => {
+                eprintln!("Failed to connect to {}! {}", self.address, e);
+                self.drop_connection(net_events, DisconnectReason::IoError(e.kind()));
+            }
+        }
+    }
+
+    fn read(&mut self, net_events: &mut Vec<TimedEvent<ClientEvent>>) {
+        // Read incoming bytes and process them into packets, until there are no more incoming bytes
+        let incoming_packets = &mut self.incoming_packets;
+        let arrivals = &mut self.arrivals;
+        let outgoing_packets = &mut self.outgoing_packets;
+        let packet_logger = &mut self.packet_logger;
+        let handshake_deadline = &mut self.handshake_deadline;
+        let received_sequence = &mut self.received_sequence;
+        let rtt = &mut self.rtt;
+        let protocol_version = self.config.protocol_version;
+        let max_body_size = self.config.max_body_size();
+        let channels = self.config.channels;
+        let mut mismatch = None;
+        let mut kicked = None;
+        let mut is_server_shutdown = false;
+        let mut server_full = None;
+        let framing = self.config.framing;
+        let mut limits = self.config.read_limits();
+        limits.handshake = handshake_deadline.map(|_| HELLO_PACKET_ID);
+        let outcome = read_packets(
+            &mut self.tcp_stream,
+            &mut self.buffer,
+            &mut self.read_state,
+            &mut self.reassembly,
+            limits,
+            |packet| {
+                log_incoming(packet_logger, None, &packet.header, &packet.body);
+
+                if let Some(received) = packet.header.sequence {
+                    if let Some(expected) = received_sequence.observe(received) {
+                        net_events.push(TimedEvent::now(ClientEvent::OutOfSequence {
+                            expected,
+                            received,
+                        }));
+                    }
+                }
+
+                // Pings and heartbeats are answered straight away, and control packets never reach the application
+                if packet.header.id == HEARTBEAT_PACKET_ID {
+                    if let Ok(heartbeat) = Heartbeat::deserialize(&packet.body) {
+                        if !heartbeat.reply {
+                            outgoing_packets.push_back(Outgoing::new(Heartbeat { reply: true }));
                         }
                     }
+                    return;
+                }
+
+                if packet.header.id == PING_PACKET_ID {
+                    if let Ok(ping) = Ping::deserialize(&packet.body) {
+                        outgoing_packets.push_back(Outgoing::new(Pong { nonce: ping.nonce }));
+                    }
+                    return;
+                }
+
+                if packet.header.id == PONG_PACKET_ID {
+                    if let Ok(pong) = Pong::deserialize(&packet.body) {
+                        let event = match rtt.on_pong(pong.nonce, Instant::now()) {
+                            Some(rtt) => ClientEvent::Pong(rtt),
+                            None => {
+                                eprintln!("The server sent a pong for a ping it wasn't sent!");
+                                ClientEvent::UnexpectedPong
+                            }
+                        };
+                        net_events.push(TimedEvent::now(event));
+                    }
+                    return;
+                }
 
-                    // Handle writing
-                    if event.is_writable() {
-                        while let Some(packet) = self.outgoing_packets.pop_front() {
-                            let data = match serialize_packet(packet) {
-                                Ok(d) => d,
-                                Err(e) => {
-                                    eprintln!("Failed to serialize packet! {}", e);
-                                    continue;
-                                }
-                            };
-
-                            match send_bytes(&mut self.tcp_stream, &data) {
-                                Ok(sent_bytes) => {
-                                    net_events.push(ClientEvent::SentPacket(sent_bytes));
-                                }
-                                Err(e) => {
-                                    net_events.push(ClientEvent::Disconnected);
-
-                                    eprintln!("Unexpected error when sending bytes! {}", e);
-                                    self.is_disconnected = true;
-                                    break;
-                                }
+                if packet.header.id == HELLO_PACKET_ID {
+                    if let (Ok(hello), Some(version)) =
+                        (Hello::deserialize(&packet.body), protocol_version)
+                    {
+                        if handshake_deadline.is_some() && mismatch.is_none() {
+                            if hello.version != version {
+                                mismatch = Some(ClientEvent::VersionMismatch {
+                                    server: hello.version,
+                                    client: version,
+                                });
+                            } else if hello.max_body_size as usize != max_body_size {
+                                mismatch = Some(ClientEvent::PacketSizeMismatch {
+                                    server: hello.max_body_size as usize,
+                                    client: max_body_size,
+                                });
+                            } else {
+                                // The answer goes ahead of anything the application queued while waiting
+                                outgoing_packets.push_front(Outgoing::new(HelloAck { version }));
+                                *handshake_deadline = None;
+                                net_events.push(TimedEvent::now(ClientEvent::Connected));
                             }
                         }
                     }
+                    return;
                 }
-                _ => unreachable!(),
+
+                // The server closes the connection right after this, so keep the reason for the disconnect
+                if packet.header.id == KICK_PACKET_ID {
+                    if let Ok(kick) = Kick::deserialize(&packet.body) {
+                        kicked = Some(kick.reason);
+                    }
+                    return;
+                }
+
+                // Likewise for a shutdown notice, which is the last thing the server writes before closing
+                if packet.header.id == SHUTDOWN_PACKET_ID {
+                    is_server_shutdown = true;
+                    return;
+                }
+
+                // And for a server full notice, which is the only thing a full server writes
+                if packet.header.id == SERVER_FULL_PACKET_ID {
+                    if let Ok(full) = ServerFull::deserialize(&packet.body) {
+                        server_full = Some(full);
+                    }
+                    return;
+                }
+
+                // The rest of the reserved ids aren't used yet, and nothing from the server can be trusted until its
+                // hello has matched
+                if !is_application_packet_id(packet.header.id) || handshake_deadline.is_some() {
+                    return;
+                }
+
+                let channel = packet.header.channel();
+                if channel >= channels {
+                    net_events.push(TimedEvent::now(ClientEvent::UnknownChannel(channel)));
+                    return;
+                }
+
+                let info = PacketInfo::new(&packet, framing);
+                let timestamp = packet.header.timestamp;
+                arrivals.push(net_events.len());
+                incoming_packets.push_back(packet);
+
+                net_events.push(TimedEvent::now(ClientEvent::ReceivedPacket(info)));
+                if let Some(timestamp) = timestamp {
+                    net_events.push(TimedEvent::now(ClientEvent::PacketLatency(time_since(
+                        timestamp,
+                    ))));
+                }
+            },
+        );
+
+        if outcome.bytes_read > 0 {
+            self.last_read = Instant::now();
+        }
+        self.stats
+            .record_received(outcome.packets, outcome.bytes_read);
+
+        if let Some(mismatch) = mismatch {
+            eprintln!("Handshake with the server failed! {:?}", mismatch);
+            net_events.push(TimedEvent::now(mismatch));
+            self.drop_connection(net_events, DisconnectReason::ProtocolError);
+
+            // Reconnecting would only find the same settings again
+            self.next_reconnect = None;
+            return;
+        }
+
+        if let Some(reason) = kicked {
+            eprintln!("Kicked by the server! {}", reason);
+            net_events.push(TimedEvent::now(ClientEvent::Kicked(reason)));
+            self.drop_connection(net_events, DisconnectReason::Kicked);
+
+            // The server asked us to go away
+            self.next_reconnect = None;
+            return;
+        }
+
+        // A slot may free up, so this reconnects like any other disconnect
+        if let Some(full) = server_full {
+            eprintln!(
+                "The server is full! ({}/{} connections)",
+                full.connections, full.limit
+            );
+            self.drop_connection(net_events, DisconnectReason::ServerFull);
+            return;
+        }
+
+        // The server may come back, so this reconnects like any other disconnect
+        if is_server_shutdown {
+            eprintln!("The server is shutting down!");
+            self.drop_connection(net_events, DisconnectReason::ServerShutdown);
+            return;
+        }
+
+        match outcome.error {
+            None => {}
+            Some(ReadError::Closed) => {
+                // "Read" 0 bytes, which means we have been disconnected
+                self.drop_connection(net_events, DisconnectReason::RemoteClosed);
+            }
+            Some(ReadError::BufferFull) => {
+                eprintln!("Receive buffer is full!");
+                self.drop_connection(net_events, DisconnectReason::ProtocolError);
+            }
+            Some(ReadError::Io(e)) => {
+                eprintln!("Unexpected error when reading bytes! {}", e);
+                self.drop_connection(net_events, DisconnectReason::IoError(e.kind()));
+            }
+            Some(ReadError::InvalidHeader(Error::EndiannessMismatch)) => {
+                let client = self.config.endianness;
+                let server = client.swapped();
+                eprintln!(
+                    "Server writes {:?} endian headers, but this client writes {:?} endian ones!",
+                    server, client
+                );
+                net_events.push(TimedEvent::now(ClientEvent::EndiannessMismatch {
+                    server,
+                    client,
+                }));
+                self.drop_connection(net_events, DisconnectReason::ProtocolError);
+
+                // Reconnecting would only find the same byte order again
+                self.next_reconnect = None;
+            }
+            // The framing can't be recovered after a bad header, so drop the connection
+            Some(ReadError::InvalidHeader(e)) => {
+                eprintln!("Received an invalid packet header! {} {:?}", e, self.buffer);
+                net_events.push(TimedEvent::now(ClientEvent::InvalidHeader(e.to_string())));
+                self.drop_connection(net_events, DisconnectReason::ProtocolError);
+            }
+            Some(ReadError::InvalidFragment(e)) => {
+                eprintln!("Received an invalid packet fragment! {}", e);
+                net_events.push(TimedEvent::now(ClientEvent::InvalidFragment(e.to_string())));
+                self.drop_connection(net_events, DisconnectReason::ProtocolError);
+            }
+            Some(ReadError::InvalidCompression(e)) => {
+                eprintln!("Received an invalid compressed packet! {}", e);
+                net_events.push(TimedEvent::now(ClientEvent::InvalidCompression(
+                    e.to_string(),
+                )));
+                self.drop_connection(net_events, DisconnectReason::ProtocolError);
             }
         }
+    }
 
-        // We're done processing events for this tick.
-        // Reregister for next tick.
-        self.poll
-            .registry()
-            .reregister(
-                &mut self.tcp_stream,
-                LOCAL_TOKEN,
-                Interest::READABLE | Interest::WRITABLE,
-            )
-            .unwrap();
+    /// Write queued packets until the queue is empty, the socket fills up, or the connection fails.
+    /// A packet the socket only takes part of is finished on the next writable event, before anything else is written.
+    fn write(&mut self, net_events: &mut Vec<TimedEvent<ClientEvent>>) {
+        // Hold everything back until the server's hello has matched, so it never sees a packet from the wrong version
+        if self.handshake_deadline.is_some() {
+            return;
+        }
+
+        let format = self.config.write_format();
+        loop {
+            let mut unsent = match self.unsent.take() {
+                Some(unsent) => unsent,
+                None => match self.outgoing_packets.pop_front() {
+                    Some(outgoing) => match self.serialize(outgoing, &format, net_events) {
+                        Some(unsent) => unsent,
+                        None => continue,
+                    },
+                    None => break,
+                },
+            };
+
+            match unsent.write_to(&mut self.tcp_stream) {
+                Ok(true) => {
+                    self.last_write = Instant::now();
+                    self.stats.record_sent(unsent.len());
+                    if !is_control_packet(unsent.packet_id) {
+                        net_events.push(TimedEvent::now(ClientEvent::SentPacket(unsent.len())));
+                        if format.timestamps {
+                            net_events.push(TimedEvent::now(ClientEvent::QueueDelay(
+                                unsent.queued_at.elapsed(),
+                            )));
+                        }
+                    }
+                    if let Some(message) = unsent.message {
+                        net_events.push(TimedEvent::now(ClientEvent::Delivered(message)));
+                    }
+                }
+                // The socket is full, so pick this packet back up once it's writable again
+                Ok(false) => {
+                    self.unsent = Some(unsent);
+                    self.is_writable = false;
+                    break;
+                }
+                Err(e) => {
+                    eprintln!("Unexpected error when sending bytes! {}", e);
+                    if let Some(message) = unsent.message {
+                        net_events.push(TimedEvent::now(ClientEvent::DeliveryFailed(message)));
+                    }
+                    self.drop_connection(net_events, DisconnectReason::SendFailed);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Serialize a queued packet, ready to be written. Packets that fail to serialize are reported, and skipped.
+    fn serialize(
+        &mut self,
+        outgoing: Outgoing,
+        format: &WriteFormat,
+        net_events: &mut Vec<TimedEvent<ClientEvent>>,
+    ) -> Option<PartialWrite> {
+        let Outgoing {
+            packet,
+            message,
+            channel,
+            queued_at,
+            ..
+        } = outgoing;
+        let packet_id = packet.id();
+        let packet_logger = &mut self.packet_logger;
+        let sequence = self.sequence.peek();
+        let serialized =
+            serialize_packet_with(&packet, format, sequence, channel, queued_at, |body| {
+                log_outgoing(packet_logger, None, packet_id, sequence, body)
+            });
+        match serialized {
+            Ok(data) => {
+                // The number is on the wire now, whenever the last of the bytes make it out
+                self.sequence.advance();
+                Some(PartialWrite::new(data, packet_id, message, queued_at))
+            }
+            Err(e) => {
+                eprintln!("Failed to serialize packet {}! {}", packet_id, e);
+                net_events.push(TimedEvent::now(ClientEvent::SerializeFailed {
+                    packet_id,
+                    error: e.to_string(),
+                }));
+                if let Some(message) = message {
+                    net_events.push(TimedEvent::now(ClientEvent::DeliveryFailed(message)));
+                }
+                None
+            }
+        }
+    }
+
+    /// Whether there's anything left to write, whether it's queued or partway out.
+    fn has_unwritten(&self) -> bool {
+        self.unsent.is_some() || !self.outgoing_packets.is_empty()
+    }
+
+    /// Handle the connect timeout, ping timeouts and heartbeats.
+    fn check_timers(&mut self, net_events: &mut Vec<TimedEvent<ClientEvent>>) {
+        if self.is_disconnected {
+            return;
+        }
+
+        if self.is_connecting {
+            if let Some(timeout) = self.config.connect_timeout {
+                if self.connect_started.elapsed() >= timeout {
+                    eprintln!("Timed out connecting to {}!", self.address);
+                    self.drop_connection(net_events, DisconnectReason::TimedOut);
+                }
+            }
+
+            return;
+        }
+
+        if let Some(deadline) = self.handshake_deadline {
+            if Instant::now() >= deadline {
+                eprintln!("Timed out waiting for a hello from {}!", self.address);
+                self.drop_connection(net_events, DisconnectReason::TimedOut);
+            }
+
+            return;
+        }
+
+        for _ in 0..self.rtt.expire(Instant::now(), self.config.ping_timeout) {
+            net_events.push(TimedEvent::now(ClientEvent::PingTimedOut));
+        }
+
+        // Any byte counts as hearing from the server, not just complete packets
+        if let Some(timeout) = self.config.read_timeout {
+            if self.last_read.elapsed() >= timeout {
+                eprintln!(
+                    "Haven't received anything from the server in {:?}, disconnecting!",
+                    timeout
+                );
+                self.drop_connection(net_events, DisconnectReason::TimedOut);
+                return;
+            }
+        }
+
+        if let Some((interval, timeout)) = self.config.heartbeat {
+            if self.last_read.elapsed() >= timeout {
+                eprintln!(
+                    "Haven't heard from the server in {:?}, disconnecting!",
+                    timeout
+                );
+                self.drop_connection(net_events, DisconnectReason::TimedOut);
+                return;
+            }
+
+            if self.last_write.elapsed() >= interval && !self.has_unwritten() {
+                self.outgoing_packets
+                    .push_back(Outgoing::new(Heartbeat { reply: false }));
+            }
+        }
+    }
+
+    /// Close the connection, and schedule a reconnect if the config allows one.
+    fn drop_connection(
+        &mut self,
+        net_events: &mut Vec<TimedEvent<ClientEvent>>,
+        reason: DisconnectReason,
+    ) {
+        if self.is_disconnected {
+            return;
+        }
+
+        self.is_disconnected = true;
+        self.is_connecting = false;
+        self.handshake_deadline = None;
+        self.is_writable = false;
+        let _ = self.poll.registry().deregister(&mut self.tcp_stream);
+
+        // A half reassembled packet is never going to be finished, so don't hold on to it
+        self.reassembly.clear();
+
+        // The next connection numbers its packets from the start again
+        self.sequence = SequenceCounter::new(self.config.sequence_numbers);
+        self.received_sequence = SequenceTracker::new();
+
+        // Pings still waiting for this connection would only be answered by the next one, if at all
+        self.rtt = RttEstimator::new();
+        self.outgoing_packets
+            .retain(|outgoing| outgoing.packet.id() != PING_PACKET_ID);
+
+        self.fail_tracked(net_events);
+        net_events.push(TimedEvent::now(ClientEvent::Disconnected(reason)));
+
+        let policy = &self.config.reconnect;
+        if policy.allows_attempt(self.reconnect_attempts) {
+            self.next_reconnect = Some(Instant::now() + policy.delay(self.reconnect_attempts));
+        }
+    }
+
+    /// Start a new connect attempt if one is due.
+    fn try_reconnect(&mut self, net_events: &mut Vec<TimedEvent<ClientEvent>>) {
+        match self.next_reconnect {
+            Some(at) if Instant::now() >= at => {}
+            _ => return,
+        }
+
+        self.next_reconnect = None;
+        self.reconnect_attempts += 1;
+        net_events.push(TimedEvent::now(ClientEvent::Reconnecting(
+            self.reconnect_attempts,
+        )));
+
+        match open_stream(self.address, &self.config, &self.poll) {
+            Ok(tcp_stream) => {
+                let now = Instant::now();
+                self.tcp_stream = tcp_stream;
+                self.buffer.clear();
+                self.read_state = ReadState::ReadingHeader;
+                self.is_disconnected = false;
+                self.is_connecting = true;
+                self.is_write_armed = true;
+                self.is_writable = false;
+                self.connect_started = now;
+                self.last_read = now;
+                self.last_write = now;
+            }
+            Err(e) => {
+                eprintln!("Failed to reconnect to {}! {}", self.address, e);
+
+                let policy = &self.config.reconnect;
+                if policy.allows_attempt(self.reconnect_attempts) {
+                    self.next_reconnect =
+                        Some(Instant::now() + policy.delay(self.reconnect_attempts));
+                }
+            }
+        }
+    }
+}
+
+/// Start connecting to `address`, and register the stream for reading/writing.
+fn open_stream(address: SocketAddr, config: &ClientConfig, poll: &Poll) -> Result<TcpStream> {
+    let mut tcp_stream = TcpStream::connect(address)?;
+    register_stream(&mut tcp_stream, config, poll)?;
+
+    Ok(tcp_stream)
+}
+
+/// Apply the config's socket options to a stream, and register it for reading/writing.
+fn register_stream(tcp_stream: &mut TcpStream, config: &ClientConfig, poll: &Poll) -> Result<()> {
+    if config.nodelay {
+        tcp_stream.set_nodelay(true)?;
+    }
+
+    poll.registry().register(
+        tcp_stream,
+        LOCAL_TOKEN,
+        Interest::READABLE | Interest::WRITABLE,
+    )?;
+
+    Ok(())
+}
 
-        net_events
+impl<P> Drop for Client<P> {
+    fn drop(&mut self) {
+        self.disconnect();
     }
 }
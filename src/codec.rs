@@ -0,0 +1,72 @@
+use crate::{
+    error::{Error, Result},
+    packet::wire_config,
+};
+use bincode::Options;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Codec
+/// How a `SerdePacket` turns its value into a packet body and back. Both sides of a connection have to use the same
+/// codec for a packet.
+pub trait Codec: Send + Sync + 'static {
+    /// Serialize `value` into a packet body.
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>>;
+
+    /// Deserialize a packet body. Return `Error::InvalidData` for bodies that don't decode.
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T>;
+}
+
+/// BincodeCodec
+/// The codec `SerdePacket` uses when no other is given. Encodes with `wire_config`, which is big endian with
+/// fixed size integers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BincodeCodec;
+
+impl Codec for BincodeCodec {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+        wire_config()
+            .serialize(value)
+            .map_err(|_e| Error::InvalidData)
+    }
+
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+        wire_config()
+            .deserialize(data)
+            .map_err(|_e| Error::InvalidData)
+    }
+}
+
+/// JsonCodec
+/// Encodes packet bodies as JSON text, which is bigger and slower than bincode but readable in a packet log.
+#[cfg(feature = "json")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct JsonCodec;
+
+#[cfg(feature = "json")]
+impl Codec for JsonCodec {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+        serde_json::to_vec(value).map_err(|_e| Error::InvalidData)
+    }
+
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+        serde_json::from_slice(data).map_err(|_e| Error::InvalidData)
+    }
+}
+
+/// MessagePackCodec
+/// Encodes packet bodies as MessagePack, with structs written as maps keyed by field name so other MessagePack
+/// implementations can read them.
+#[cfg(feature = "msgpack")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MessagePackCodec;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePackCodec {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+        rmp_serde::to_vec_named(value).map_err(|_e| Error::InvalidData)
+    }
+
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+        rmp_serde::from_slice(data).map_err(|_e| Error::InvalidData)
+    }
+}
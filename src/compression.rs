@@ -0,0 +1,133 @@
+use crate::error::Error;
+use bytes::Bytes;
+#[cfg(feature = "compression")]
+use std::io::{Read, Write};
+
+/// Compression
+/// An algorithm for compressing packet bodies. Compressed bodies start with a byte saying which algorithm was used,
+/// so the two sides of a connection don't have to pick the same one.
+#[cfg(feature = "compression")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// LZ4, which is very fast but doesn't compress as well.
+    Lz4,
+    /// Deflate, which compresses better but is slower.
+    Deflate,
+}
+
+#[cfg(feature = "compression")]
+impl Compression {
+    fn tag(self) -> u8 {
+        match self {
+            Compression::Lz4 => 0,
+            Compression::Deflate => 1,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Compression> {
+        match tag {
+            0 => Some(Compression::Lz4),
+            1 => Some(Compression::Deflate),
+            _ => None,
+        }
+    }
+}
+
+/// How a connection compresses the bodies it sends, taken from its config. Without the `compression` feature,
+/// nothing is ever compressed.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct CompressionSettings {
+    /// The algorithm to use, and the body size above which it's used.
+    #[cfg(feature = "compression")]
+    pub(crate) algorithm: Option<(Compression, usize)>,
+}
+
+impl CompressionSettings {
+    /// Whether compression is enabled, which is also what allows compressed bodies to be received.
+    pub(crate) fn is_enabled(&self) -> bool {
+        #[cfg(feature = "compression")]
+        return self.algorithm.is_some();
+
+        #[cfg(not(feature = "compression"))]
+        false
+    }
+
+    /// Compress `body` if it's over the threshold, and compressing actually makes it smaller.
+    /// Returns the body to send, and whether it was compressed.
+    pub(crate) fn compress(&self, body: Bytes) -> Result<(Bytes, bool), Error> {
+        #[cfg(feature = "compression")]
+        if let Some((algorithm, threshold)) = self.algorithm {
+            if body.len() > threshold {
+                let compressed = compress(algorithm, &body)?;
+                if compressed.len() < body.len() {
+                    return Ok((compressed.into(), true));
+                }
+            }
+        }
+
+        Ok((body, false))
+    }
+}
+
+#[cfg(feature = "compression")]
+fn compress(algorithm: Compression, body: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut data = vec![algorithm.tag()];
+    match algorithm {
+        Compression::Lz4 => data.extend_from_slice(&lz4_flex::compress_prepend_size(body)),
+        Compression::Deflate => {
+            let mut encoder =
+                flate2::write::DeflateEncoder::new(data, flate2::Compression::default());
+            encoder.write_all(body)?;
+            data = encoder.finish()?;
+        }
+    }
+
+    Ok(data)
+}
+
+/// Decompress a body sent with the compressed flag set.
+/// Returns `DecompressedTooLarge` without inflating any further if the body would be bigger than `max_size`, and
+/// `InvalidData` if it's corrupt or uses an unknown algorithm.
+#[cfg(feature = "compression")]
+pub(crate) fn decompress(data: &[u8], max_size: usize) -> Result<Vec<u8>, Error> {
+    let (tag, payload) = data.split_first().ok_or(Error::InvalidData)?;
+    match Compression::from_tag(*tag).ok_or(Error::InvalidData)? {
+        Compression::Lz4 => {
+            // The inflated size comes first, so a bomb can be turned away before allocating for it
+            if payload.len() < 4 {
+                return Err(Error::InvalidData);
+            }
+
+            let size =
+                u32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]]) as usize;
+            if size > max_size {
+                return Err(Error::DecompressedTooLarge);
+            }
+
+            let mut body = vec![0; size];
+            match lz4_flex::block::decompress_into(&payload[4..], &mut body) {
+                Ok(len) if len == size => Ok(body),
+                _ => Err(Error::InvalidData),
+            }
+        }
+        Compression::Deflate => {
+            // Read one byte past the limit, to tell a body that's exactly at it from one that's over
+            let mut body = Vec::new();
+            flate2::read::DeflateDecoder::new(payload)
+                .take(max_size as u64 + 1)
+                .read_to_end(&mut body)
+                .map_err(|_| Error::InvalidData)?;
+            if body.len() > max_size {
+                return Err(Error::DecompressedTooLarge);
+            }
+
+            Ok(body)
+        }
+    }
+}
+
+/// Without the `compression` feature there's nothing to decompress with, and compressed bodies are never accepted.
+#[cfg(not(feature = "compression"))]
+pub(crate) fn decompress(_data: &[u8], _max_size: usize) -> Result<Vec<u8>, Error> {
+    Err(Error::UnexpectedCompression)
+}
@@ -0,0 +1,1061 @@
+use crate::{
+    buffer::{DEFAULT_BUFFER_POOL_SIZE, INITIAL_BUFFER_SIZE, MAX_BUFFER_SIZE},
+    client::Client,
+    compression::CompressionSettings,
+    error::{Error, Result},
+    fragment::{DEFAULT_MAX_REASSEMBLY_SIZE, FRAGMENT_HEADER_SIZE},
+    packet::{Endianness, Framing, WriteFormat},
+    protocol::Protocol,
+    receive::ReadLimits,
+    server::Server,
+};
+use std::{
+    net::{SocketAddr, ToSocketAddrs},
+    time::Duration,
+};
+
+#[cfg(feature = "compression")]
+use crate::compression::Compression;
+
+/// The connection limit used when one isn't given.
+pub const DEFAULT_CONNECTION_LIMIT: usize = 32;
+
+/// How long the version handshake may take when one isn't given.
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a ping may go unanswered when no timeout is given.
+pub const DEFAULT_PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long `tick` waits in poll for socket events when no timeout is given.
+pub const DEFAULT_TICK_TIMEOUT: Duration = Duration::from_millis(1);
+
+/// How many ticks a kicked connection gets to flush its outgoing queue when no limit is given.
+pub const DEFAULT_KICK_DRAIN_TICKS: u32 = 30;
+
+/// How many ticks connections get to flush their outgoing queues after `Server::begin_shutdown` when no limit is given.
+pub const DEFAULT_SHUTDOWN_DRAIN_TICKS: u32 = 30;
+
+/// How many bytes are read from each connection per tick when no budget is given.
+pub const DEFAULT_READ_BUDGET: usize = 64 * 1024;
+
+/// ServerConfig
+/// Options for hosting a `Server`. Build one up, then call `bind` to start hosting.
+/// The default config matches `Server::host`, apart from the connection limit which defaults to `DEFAULT_CONNECTION_LIMIT`.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub(crate) connection_limit: usize,
+    pub(crate) connection_limit_per_ip: Option<usize>,
+    pub(crate) protocol_error_ban: Option<Duration>,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) heartbeat: Option<(Duration, Duration)>,
+    pub(crate) kick_drain_ticks: u32,
+    pub(crate) tick_timeout: Duration,
+    pub(crate) shutdown_drain_ticks: u32,
+    pub(crate) shutdown_notice: bool,
+    pub(crate) server_full_notice: bool,
+    pub(crate) nodelay: bool,
+    pub(crate) framing: Framing,
+    pub(crate) endianness: Endianness,
+    pub(crate) max_packet_size: Option<usize>,
+    pub(crate) max_reassembly_size: usize,
+    pub(crate) protocol_version: Option<u32>,
+    pub(crate) handshake_timeout: Duration,
+    pub(crate) ping_timeout: Duration,
+    pub(crate) sequence_numbers: bool,
+    pub(crate) timestamps: bool,
+    pub(crate) channels: u8,
+    pub(crate) compression: CompressionSettings,
+    pub(crate) ignore_unknown_flags: bool,
+    pub(crate) diagnostics: bool,
+    pub(crate) ping_interval: Option<Duration>,
+    pub(crate) max_outgoing: Option<usize>,
+    pub(crate) max_outgoing_bytes: Option<usize>,
+    pub(crate) slow_consumer_policy: SlowConsumerPolicy,
+    pub(crate) max_packets_per_tick: Option<usize>,
+    pub(crate) max_bytes_per_tick: Option<usize>,
+    pub(crate) rate_limit_policy: RateLimitPolicy,
+    pub(crate) read_budget: usize,
+    pub(crate) max_incoming: Option<usize>,
+    pub(crate) decode_workers: usize,
+    pub(crate) max_scheduled: Option<usize>,
+    pub(crate) max_buffer_size: usize,
+    pub(crate) buffer_pool_size: usize,
+}
+
+impl ServerConfig {
+    pub fn new() -> Self {
+        ServerConfig {
+            connection_limit: DEFAULT_CONNECTION_LIMIT,
+            connection_limit_per_ip: None,
+            protocol_error_ban: None,
+            idle_timeout: None,
+            heartbeat: None,
+            kick_drain_ticks: DEFAULT_KICK_DRAIN_TICKS,
+            tick_timeout: DEFAULT_TICK_TIMEOUT,
+            shutdown_drain_ticks: DEFAULT_SHUTDOWN_DRAIN_TICKS,
+            shutdown_notice: true,
+            server_full_notice: false,
+            nodelay: false,
+            framing: Framing::Standard,
+            endianness: Endianness::Big,
+            max_packet_size: None,
+            max_reassembly_size: DEFAULT_MAX_REASSEMBLY_SIZE,
+            protocol_version: None,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            ping_timeout: DEFAULT_PING_TIMEOUT,
+            sequence_numbers: false,
+            timestamps: false,
+            channels: 1,
+            ignore_unknown_flags: false,
+            compression: CompressionSettings::default(),
+            diagnostics: false,
+            ping_interval: None,
+            max_outgoing: None,
+            max_outgoing_bytes: None,
+            slow_consumer_policy: SlowConsumerPolicy::DropNewest,
+            max_packets_per_tick: None,
+            max_bytes_per_tick: None,
+            rate_limit_policy: RateLimitPolicy::Throttle,
+            read_budget: DEFAULT_READ_BUDGET,
+            max_incoming: None,
+            decode_workers: 0,
+            max_scheduled: None,
+            max_buffer_size: MAX_BUFFER_SIZE,
+            buffer_pool_size: DEFAULT_BUFFER_POOL_SIZE,
+        }
+    }
+
+    /// Set the maximum number of connections allowed at once.
+    pub fn connection_limit(mut self, limit: usize) -> Self {
+        self.connection_limit = limit;
+        self
+    }
+
+    /// Set the maximum number of connections allowed from a single IP address.
+    pub fn connection_limit_per_ip(mut self, limit: usize) -> Self {
+        self.connection_limit_per_ip = Some(limit);
+        self
+    }
+
+    /// Ban the address of any connection that sends a malformed packet header, fragment or compressed packet for
+    /// `duration`. Connections from a banned address are rejected with `RejectReason::Banned` until it runs out.
+    pub fn protocol_error_ban(mut self, duration: Duration) -> Self {
+        self.protocol_error_ban = Some(duration);
+        self
+    }
+
+    /// Disconnect connections that haven't sent any bytes for `timeout`, with a `TimedOut` event and
+    /// `DisconnectReason::TimedOut`. Only bytes received count, so a connection the server keeps writing to still
+    /// times out if it never answers.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Send a heartbeat to each connection that hasn't been sent anything for `interval`, and disconnect any that
+    /// haven't sent anything back for `timeout`. Clients built on grubbnet answer heartbeats automatically, so this
+    /// catches half-open connections even in a lobby where nobody is talking. `timeout` must be longer than `interval`.
+    pub fn heartbeat(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.heartbeat = Some((interval, timeout));
+        self
+    }
+
+    /// Set how many ticks a connection closed with `Server::kick` keeps writing its outgoing queue before it's closed
+    /// anyway, so a goodbye sent just before the kick still gets out. Defaults to `DEFAULT_KICK_DRAIN_TICKS`. Zero
+    /// still gives it the rest of the tick it was kicked in.
+    pub fn kick_drain_ticks(mut self, ticks: u32) -> Self {
+        self.kick_drain_ticks = ticks;
+        self
+    }
+
+    /// Set how long `Server::tick` waits in poll for socket events when there's nothing to do yet. Defaults to
+    /// `DEFAULT_TICK_TIMEOUT`. Zero never blocks, which suits loops that keep their own frame timing. See
+    /// `Server::tick_blocking` for waiting until something happens.
+    pub fn tick_timeout(mut self, timeout: Duration) -> Self {
+        self.tick_timeout = timeout;
+        self
+    }
+
+    /// Set how many ticks connections keep writing their outgoing queues after `Server::begin_shutdown` before they're
+    /// closed anyway. Defaults to `DEFAULT_SHUTDOWN_DRAIN_TICKS`.
+    pub fn shutdown_drain_ticks(mut self, ticks: u32) -> Self {
+        self.shutdown_drain_ticks = ticks;
+        self
+    }
+
+    /// Set whether `Server::begin_shutdown` queues a notice for every connection, behind everything else queued for
+    /// it. A grubbnet client disconnects with `DisconnectReason::ServerShutdown` when it gets one. Turn this off for
+    /// clients that don't speak grubbnet's control packets. Defaults to on.
+    pub fn shutdown_notice(mut self, enabled: bool) -> Self {
+        self.shutdown_notice = enabled;
+        self
+    }
+
+    /// Set whether a connection turned away because the server is full is told so before it's closed, with a notice
+    /// carrying the current and largest connection counts. It's written once without waiting, so it's lost if the
+    /// socket won't take it right away. A grubbnet client disconnects with `DisconnectReason::ServerFull` when it gets
+    /// one. Defaults to off.
+    pub fn server_full_notice(mut self, enabled: bool) -> Self {
+        self.server_full_notice = enabled;
+        self
+    }
+
+    /// Set `TCP_NODELAY` on accepted connections, disabling Nagle's algorithm.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Set how packet lengths are written on the wire. Defaults to `Framing::Standard`.
+    /// Clients have to be configured with the same framing.
+    pub fn framing(mut self, framing: Framing) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    /// Set the byte order of packet headers. Defaults to `Endianness::Big`.
+    /// Clients have to be configured with the same endianness. With a `protocol_version` set, a client that isn't is
+    /// caught during the version handshake and disconnected, rather than having its packets misread.
+    pub fn endianness(mut self, endianness: Endianness) -> Self {
+        self.endianness = endianness;
+        self
+    }
+
+    /// Set the largest packet body exchanged with clients, in bytes.
+    /// Bodies from a client bigger than this disconnect it with `InvalidHeader`, and bodies sent bigger than this are
+    /// split into fragments. Clients have to be configured with the same size, which they check during the version
+    /// handshake if there is one. Must be more than `FRAGMENT_HEADER_SIZE`, and no more than the framing's
+    /// `max_body_size`. Defaults to the framing's `default_body_size`.
+    pub fn max_packet_size(mut self, size: usize) -> Self {
+        self.max_packet_size = Some(size);
+        self
+    }
+
+    /// Set the largest packet a client may send in fragments, in bytes. Defaults to `DEFAULT_MAX_REASSEMBLY_SIZE`.
+    /// Clients announcing anything bigger are disconnected before any of it is buffered.
+    pub fn max_reassembly_size(mut self, size: usize) -> Self {
+        self.max_reassembly_size = size;
+        self
+    }
+
+    /// Check every new connection speaks this version of the application's protocol before trusting its packets.
+    /// The server sends the version in a hello, and clients configured with the same version answer it. Packets from
+    /// a connection that hasn't answered yet are dropped, and it's disconnected if it doesn't answer within the
+    /// handshake timeout.
+    pub fn protocol_version(mut self, version: u32) -> Self {
+        self.protocol_version = Some(version);
+        self
+    }
+
+    /// Set how long a new connection has to answer the version hello. Defaults to `DEFAULT_HANDSHAKE_TIMEOUT`.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Stamp each packet sent to a connection with a sequence number, counting up from 0 for every new connection.
+    /// Clients check the numbers whether they send their own or not, and emit `OutOfSequence` if one is skipped or
+    /// goes backwards. Useful for tracking down packets lost or reordered by the application.
+    pub fn sequence_numbers(mut self, enabled: bool) -> Self {
+        self.sequence_numbers = enabled;
+        self
+    }
+
+    /// Stamp each packet sent to a connection with the wall clock time it was queued, in milliseconds since the Unix
+    /// epoch. Clients built on grubbnet turn the stamps into `PacketLatency` events, which are only as accurate as
+    /// the two clocks are in sync. Also emits `QueueDelay` for each packet as it's written. Off by default, since it
+    /// adds 8 bytes to every packet.
+    pub fn timestamps(mut self, enabled: bool) -> Self {
+        self.timestamps = enabled;
+        self
+    }
+
+    /// Set how many channels packets can be sent on with `Server::send_on_channel`, numbered from `DEFAULT_CHANNEL` up.
+    /// Within a priority, each connection's channels take turns writing, so a channel with a lot queued can't hold up
+    /// the others. Packets that arrive on a channel past these are dropped, with an `UnknownChannel` event. Defaults to
+    /// 1, which is only the default channel.
+    pub fn channels(mut self, count: u8) -> Self {
+        self.channels = count;
+        self
+    }
+
+    /// Compress packet bodies bigger than `threshold` bytes with `algorithm`, when it makes them smaller.
+    /// Compressed bodies are only accepted from the peer with compression enabled, so it has to be enabled on both
+    /// sides, although they don't need the same algorithm. A compressed body received without it is a protocol error.
+    #[cfg(feature = "compression")]
+    pub fn compression(mut self, algorithm: Compression, threshold: usize) -> Self {
+        self.compression.algorithm = Some((algorithm, threshold));
+        self
+    }
+
+    /// Accept packets with header flags this version of grubbnet doesn't know about, instead of disconnecting the
+    /// connection that sent them with `InvalidHeader`. Only useful if the peer may be on a newer version, and only safe
+    /// for flags that don't change the layout of the packet.
+    pub fn ignore_unknown_flags(mut self, ignore: bool) -> Self {
+        self.ignore_unknown_flags = ignore;
+        self
+    }
+
+    /// Start with diagnostics gathering enabled. See `Server::set_diagnostics`.
+    pub fn diagnostics(mut self, enabled: bool) -> Self {
+        self.diagnostics = enabled;
+        self
+    }
+
+    /// Ping every connection on this interval to measure its round trip time. See `Server::rtt`.
+    /// Clients built on grubbnet answer pings automatically.
+    pub fn ping_interval(mut self, interval: Duration) -> Self {
+        self.ping_interval = Some(interval);
+        self
+    }
+
+    /// Set how long a ping may go unanswered before it's given up on, with a `PingTimedOut` event. This covers pings
+    /// sent with `Server::ping` and for `ping_interval`. Defaults to `DEFAULT_PING_TIMEOUT`.
+    pub fn ping_timeout(mut self, timeout: Duration) -> Self {
+        self.ping_timeout = timeout;
+        self
+    }
+
+    /// Cap how many packets may be queued for a single connection.
+    /// What happens to a connection that falls further behind is decided by the slow consumer policy.
+    pub fn max_outgoing(mut self, max: usize) -> Self {
+        self.max_outgoing = Some(max);
+        self
+    }
+
+    /// Cap how many bytes may be queued for a single connection, counting headers and the rest of a packet that's
+    /// partway out. A packet bigger than the cap is only queued when nothing else is. What happens to a connection
+    /// that falls further behind is decided by the slow consumer policy, and `Server::queued_bytes` shows how far
+    /// behind each one is.
+    pub fn max_outgoing_bytes(mut self, max: usize) -> Self {
+        self.max_outgoing_bytes = Some(max);
+        self
+    }
+
+    /// Set what happens when a connection's outgoing queue is full. Defaults to `SlowConsumerPolicy::DropNewest`.
+    /// This can be overridden per connection with `Server::set_connection_policy`.
+    pub fn slow_consumer_policy(mut self, policy: SlowConsumerPolicy) -> Self {
+        self.slow_consumer_policy = policy;
+        self
+    }
+
+    /// Cap how many packets each connection may send per tick. Anything past the cap is handled by the
+    /// `rate_limit_policy`, with a `RateLimited` event.
+    pub fn max_packets_per_tick(mut self, max: usize) -> Self {
+        self.max_packets_per_tick = Some(max);
+        self
+    }
+
+    /// Cap how many bytes each connection may send per tick. Anything past the cap is handled by the
+    /// `rate_limit_policy`, with a `RateLimited` event.
+    pub fn max_bytes_per_tick(mut self, max: usize) -> Self {
+        self.max_bytes_per_tick = Some(max);
+        self
+    }
+
+    /// Set what happens when a connection sends more than `max_packets_per_tick` or `max_bytes_per_tick`. Defaults to
+    /// `RateLimitPolicy::Throttle`.
+    pub fn rate_limit_policy(mut self, policy: RateLimitPolicy) -> Self {
+        self.rate_limit_policy = policy;
+        self
+    }
+
+    /// Set how many bytes are read from each connection per tick, so one sending as fast as it can doesn't keep the
+    /// others waiting. Whatever's left is read next tick, and unlike `max_bytes_per_tick` going over it isn't held
+    /// against the connection. Defaults to `DEFAULT_READ_BUDGET`.
+    pub fn read_budget(mut self, bytes: usize) -> Self {
+        self.read_budget = bytes;
+        self
+    }
+
+    /// Cap how many received packets may sit unread in the incoming queue.
+    /// Once it's full the server stops reading from sockets, so TCP pushes back on clients instead of packets being
+    /// dropped. Reading picks back up once the queue is drained below the cap.
+    pub fn max_incoming(mut self, max: usize) -> Self {
+        self.max_incoming = Some(max);
+        self
+    }
+
+    /// Decode incoming packets on this many worker threads instead of during the tick. Only typed servers decode.
+    /// Each connection's messages still come out in the order they arrived, but may show up a tick or two later.
+    /// Zero (the default) decodes inline.
+    pub fn decode_workers(mut self, count: usize) -> Self {
+        self.decode_workers = count;
+        self
+    }
+
+    /// Cap how many packets may be waiting to be sent with `Server::send_after`.
+    /// Scheduling more while it's full fails with `Error::ScheduleFull`.
+    pub fn max_scheduled(mut self, max: usize) -> Self {
+        self.max_scheduled = Some(max);
+        self
+    }
+
+    /// Set how big each connection's receive buffer may grow, in bytes. Defaults to `MAX_BUFFER_SIZE`.
+    /// Buffers start at `INITIAL_BUFFER_SIZE` and grow while reads keep filling them, so idle connections stay small
+    /// and busy ones read more per call. Must be at least `INITIAL_BUFFER_SIZE`.
+    pub fn max_buffer_size(mut self, size: usize) -> Self {
+        self.max_buffer_size = size;
+        self
+    }
+
+    /// Keep up to this many receive buffers from closed connections for new connections to reuse, instead of
+    /// allocating a fresh one for every connection. Defaults to `DEFAULT_BUFFER_POOL_SIZE`. Zero turns pooling off.
+    pub fn buffer_pool_size(mut self, count: usize) -> Self {
+        self.buffer_pool_size = count;
+        self
+    }
+
+    /// Check the config for invalid or inconsistent options.
+    pub fn validate(&self) -> Result<()> {
+        if self.connection_limit == 0 {
+            return Err(Error::InvalidConfig("connection_limit must be at least 1"));
+        }
+
+        if let Some(limit) = self.connection_limit_per_ip {
+            if limit == 0 {
+                return Err(Error::InvalidConfig(
+                    "connection_limit_per_ip must be at least 1",
+                ));
+            }
+
+            if limit > self.connection_limit {
+                return Err(Error::InvalidConfig(
+                    "connection_limit_per_ip can't be larger than connection_limit",
+                ));
+            }
+        }
+
+        if self.idle_timeout == Some(Duration::from_secs(0)) {
+            return Err(Error::InvalidConfig("idle_timeout can't be zero"));
+        }
+
+        if let Some((interval, timeout)) = self.heartbeat {
+            if interval == Duration::from_secs(0) {
+                return Err(Error::InvalidConfig("heartbeat interval can't be zero"));
+            }
+
+            if timeout <= interval {
+                return Err(Error::InvalidConfig(
+                    "heartbeat timeout must be longer than the interval",
+                ));
+            }
+        }
+
+        if self.ping_interval == Some(Duration::from_secs(0)) {
+            return Err(Error::InvalidConfig("ping_interval can't be zero"));
+        }
+
+        if self.max_outgoing == Some(0) {
+            return Err(Error::InvalidConfig("max_outgoing must be at least 1"));
+        }
+
+        if self.max_outgoing_bytes == Some(0) {
+            return Err(Error::InvalidConfig(
+                "max_outgoing_bytes must be at least 1",
+            ));
+        }
+
+        if self.max_incoming == Some(0) {
+            return Err(Error::InvalidConfig("max_incoming must be at least 1"));
+        }
+
+        if self.max_packets_per_tick == Some(0) {
+            return Err(Error::InvalidConfig(
+                "max_packets_per_tick must be at least 1",
+            ));
+        }
+
+        if self.read_budget == 0 {
+            return Err(Error::InvalidConfig("read_budget must be at least 1"));
+        }
+
+        if self.max_bytes_per_tick == Some(0) {
+            return Err(Error::InvalidConfig(
+                "max_bytes_per_tick must be at least 1",
+            ));
+        }
+
+        if self.max_scheduled == Some(0) {
+            return Err(Error::InvalidConfig("max_scheduled must be at least 1"));
+        }
+
+        if self.max_buffer_size < INITIAL_BUFFER_SIZE {
+            return Err(Error::InvalidConfig(
+                "max_buffer_size must be at least INITIAL_BUFFER_SIZE",
+            ));
+        }
+
+        if self.handshake_timeout == Duration::from_secs(0) {
+            return Err(Error::InvalidConfig("handshake_timeout can't be zero"));
+        }
+
+        if self.ping_timeout == Duration::from_secs(0) {
+            return Err(Error::InvalidConfig("ping_timeout can't be zero"));
+        }
+
+        if self.protocol_error_ban == Some(Duration::from_secs(0)) {
+            return Err(Error::InvalidConfig("protocol_error_ban can't be zero"));
+        }
+
+        if self.channels == 0 {
+            return Err(Error::InvalidConfig("channels must be at least 1"));
+        }
+
+        if self.max_reassembly_size == 0 {
+            return Err(Error::InvalidConfig(
+                "max_reassembly_size must be at least 1",
+            ));
+        }
+
+        if let Some(size) = self.max_packet_size {
+            if size <= FRAGMENT_HEADER_SIZE || size > self.framing.max_body_size() {
+                return Err(Error::InvalidConfig(
+                    "max_packet_size must be more than FRAGMENT_HEADER_SIZE and at most the framing's max_body_size",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the limits to read with. The largest packet body to accept falls back to the framing's default.
+    pub(crate) fn read_limits(&self) -> ReadLimits {
+        ReadLimits {
+            framing: self.framing,
+            endianness: self.endianness,
+            max_body_size: self.max_body_size(),
+            max_reassembly_size: self.max_reassembly_size,
+            decompress: self.compression.is_enabled(),
+            ignore_unknown_flags: self.ignore_unknown_flags,
+            handshake: None,
+            max_packets: None,
+            max_bytes: None,
+            read_budget: None,
+        }
+    }
+
+    /// Get the format to write packets with.
+    pub(crate) fn write_format(&self) -> WriteFormat {
+        WriteFormat {
+            framing: self.framing,
+            endianness: self.endianness,
+            compression: self.compression,
+            timestamps: self.timestamps,
+            max_body_size: self.max_body_size(),
+            max_total_size: self.max_reassembly_size,
+            channels: self.channels,
+        }
+    }
+
+    /// Get the largest packet body to send or accept, which falls back to the framing's default.
+    pub(crate) fn max_body_size(&self) -> usize {
+        self.max_packet_size
+            .unwrap_or_else(|| self.framing.default_body_size())
+    }
+
+    /// Validate the config, then begin hosting a TCP server on `address`.
+    pub fn bind<A: ToSocketAddrs>(self, address: A) -> Result<Server> {
+        let address = self.bind_address(address)?;
+        Server::with_config(address, self)
+    }
+
+    /// Like `bind`, but the server only sends and receives messages belonging to the protocol `P`.
+    pub fn bind_typed<P: Protocol, A: ToSocketAddrs>(self, address: A) -> Result<Server<P>> {
+        let address = self.bind_address(address)?;
+        Server::with_config(address, self)
+    }
+
+    /// Validate the config, then begin hosting on a listener that's already bound.
+    /// The listener is switched to non-blocking mode. Fails with `InvalidListener` if it isn't in a state to accept
+    /// connections.
+    pub fn bind_listener(self, listener: std::net::TcpListener) -> Result<Server> {
+        self.validate()?;
+        Server::adopt_listener(listener, self)
+    }
+
+    fn bind_address<A: ToSocketAddrs>(&self, address: A) -> Result<SocketAddr> {
+        self.validate()?;
+
+        match address.to_socket_addrs()?.next() {
+            Some(a) => Ok(a),
+            None => Err(Error::InvalidConfig("bind address didn't resolve")),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig::new()
+    }
+}
+
+/// What to do with a connection whose outgoing queue is full. See `ServerConfig::max_outgoing`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlowConsumerPolicy {
+    /// Drop the oldest queued packet to make room, for connections that only care about the latest state.
+    /// The oldest packet with the lowest priority goes first, and a packet with a lower priority than everything
+    /// queued is dropped instead.
+    DropOldest,
+    /// Drop the packet being queued.
+    DropNewest,
+    /// Disconnect the connection rather than let it miss anything.
+    Disconnect,
+}
+
+/// What to do with a connection that sends more than `ServerConfig::max_packets_per_tick` or `max_bytes_per_tick`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimitPolicy {
+    /// Stop reading from the connection for the rest of the tick, and pick up where it left off next tick. TCP pushes
+    /// back on the sender once its bytes pile up.
+    Throttle,
+    /// Disconnect the connection with `DisconnectReason::ProtocolError`.
+    Disconnect,
+}
+
+/// ReconnectPolicy
+/// How a `Client` should try to reconnect after losing its connection.
+/// The delay between attempts doubles after each failed attempt, up to `max_delay`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReconnectPolicy {
+    pub max_attempts: Option<u32>,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl ReconnectPolicy {
+    /// Never reconnect. This is the default.
+    pub fn never() -> Self {
+        ReconnectPolicy {
+            max_attempts: Some(0),
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(1),
+        }
+    }
+
+    /// Reconnect up to `max_attempts` times, backing off exponentially from `initial_delay` to `max_delay`.
+    /// `None` keeps trying forever.
+    pub fn backoff(
+        max_attempts: Option<u32>,
+        initial_delay: Duration,
+        max_delay: Duration,
+    ) -> Self {
+        ReconnectPolicy {
+            max_attempts,
+            initial_delay,
+            max_delay,
+        }
+    }
+
+    /// Whether another attempt is allowed after `attempts` attempts have already been made.
+    pub(crate) fn allows_attempt(&self, attempts: u32) -> bool {
+        match self.max_attempts {
+            Some(max) => attempts < max,
+            None => true,
+        }
+    }
+
+    /// How long to wait before the attempt after `attempts` attempts have already been made.
+    pub(crate) fn delay(&self, attempts: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempts.min(31)).unwrap_or(u32::MAX);
+        self.initial_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy::never()
+    }
+}
+
+/// ClientConfig
+/// Options for connecting a `Client`. Build one up, then call `connect`.
+/// Configs are cheap to clone, so the same one can be reused for many connections.
+/// The default config matches `Client::connect`.
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    pub(crate) connect_timeout: Option<Duration>,
+    pub(crate) nodelay: bool,
+    pub(crate) heartbeat: Option<(Duration, Duration)>,
+    pub(crate) reconnect: ReconnectPolicy,
+    pub(crate) max_outgoing: Option<usize>,
+    pub(crate) max_outgoing_bytes: Option<usize>,
+    pub(crate) framing: Framing,
+    pub(crate) endianness: Endianness,
+    pub(crate) max_packet_size: Option<usize>,
+    pub(crate) max_reassembly_size: usize,
+    pub(crate) protocol_version: Option<u32>,
+    pub(crate) handshake_timeout: Duration,
+    pub(crate) ping_timeout: Duration,
+    pub(crate) sequence_numbers: bool,
+    pub(crate) timestamps: bool,
+    pub(crate) channels: u8,
+    pub(crate) compression: CompressionSettings,
+    pub(crate) ignore_unknown_flags: bool,
+    pub(crate) max_incoming: Option<usize>,
+    pub(crate) read_timeout: Option<Duration>,
+    pub(crate) tick_timeout: Duration,
+    pub(crate) max_scheduled: Option<usize>,
+    pub(crate) max_buffer_size: usize,
+}
+
+impl ClientConfig {
+    pub fn new() -> Self {
+        ClientConfig {
+            connect_timeout: None,
+            nodelay: false,
+            heartbeat: None,
+            reconnect: ReconnectPolicy::never(),
+            max_outgoing: None,
+            max_outgoing_bytes: None,
+            framing: Framing::Standard,
+            endianness: Endianness::Big,
+            max_packet_size: None,
+            max_reassembly_size: DEFAULT_MAX_REASSEMBLY_SIZE,
+            protocol_version: None,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            ping_timeout: DEFAULT_PING_TIMEOUT,
+            sequence_numbers: false,
+            timestamps: false,
+            channels: 1,
+            ignore_unknown_flags: false,
+            compression: CompressionSettings::default(),
+            max_incoming: None,
+            read_timeout: None,
+            tick_timeout: DEFAULT_TICK_TIMEOUT,
+            max_scheduled: None,
+            max_buffer_size: MAX_BUFFER_SIZE,
+        }
+    }
+
+    /// Give up on connecting if the connection hasn't been established within `timeout`.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Set `TCP_NODELAY` on the connection, disabling Nagle's algorithm.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Send a heartbeat every `interval` while the connection is quiet, and disconnect if nothing has been
+    /// received from the server for `timeout`. The server answers heartbeats automatically. `timeout` must be longer
+    /// than `interval`.
+    pub fn heartbeat(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.heartbeat = Some((interval, timeout));
+        self
+    }
+
+    /// Set how to reconnect after the connection is lost.
+    pub fn reconnect(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect = policy;
+        self
+    }
+
+    /// Limit how many packets can be waiting to be sent.
+    /// Packets sent while the queue is full are dropped, and a `SendQueueFull` event is emitted.
+    pub fn max_outgoing(mut self, count: usize) -> Self {
+        self.max_outgoing = Some(count);
+        self
+    }
+
+    /// Limit how many bytes can be waiting to be sent, counting headers and the rest of a packet that's partway out.
+    /// Packets that would go over it are dropped, and a `SendQueueFull` event is emitted. A packet bigger than the
+    /// limit is only queued when nothing else is.
+    pub fn max_outgoing_bytes(mut self, max: usize) -> Self {
+        self.max_outgoing_bytes = Some(max);
+        self
+    }
+
+    /// Set how packet lengths are written on the wire. Defaults to `Framing::Standard`.
+    /// The server has to be configured with the same framing.
+    pub fn framing(mut self, framing: Framing) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    /// Set the byte order of packet headers. Defaults to `Endianness::Big`.
+    /// The server has to be configured with the same endianness. If it has a `protocol_version` set, a mismatch is
+    /// caught during the version handshake, and the client gets an `EndiannessMismatch` event and doesn't reconnect.
+    pub fn endianness(mut self, endianness: Endianness) -> Self {
+        self.endianness = endianness;
+        self
+    }
+
+    /// Set the largest packet body exchanged with the server, in bytes.
+    /// Bodies from the server bigger than this disconnect it, and bodies sent bigger than this are split into
+    /// fragments. The server has to be configured with the same size. If it has a `protocol_version` set, a mismatch
+    /// is caught during the handshake, and the client gets a `PacketSizeMismatch` event. Must be more than
+    /// `FRAGMENT_HEADER_SIZE`, and no more than the framing's `max_body_size`. Defaults to the framing's
+    /// `default_body_size`.
+    pub fn max_packet_size(mut self, size: usize) -> Self {
+        self.max_packet_size = Some(size);
+        self
+    }
+
+    /// Set the largest packet the server may send in fragments, in bytes. Defaults to `DEFAULT_MAX_REASSEMBLY_SIZE`.
+    /// If the server announces anything bigger, the client disconnects before any of it is buffered.
+    pub fn max_reassembly_size(mut self, size: usize) -> Self {
+        self.max_reassembly_size = size;
+        self
+    }
+
+    /// Only talk to servers that speak this version of the application's protocol. The server has to be configured
+    /// with a protocol version too. The `Connected` event waits until the server's hello has arrived and matched, and
+    /// nothing is sent before then. A mismatch emits `VersionMismatch` and disconnects without reconnecting.
+    pub fn protocol_version(mut self, version: u32) -> Self {
+        self.protocol_version = Some(version);
+        self
+    }
+
+    /// Set how long to wait for the server's hello once connected. Defaults to `DEFAULT_HANDSHAKE_TIMEOUT`.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Set how long a ping sent with `Client::ping` may go unanswered before it's given up on, with a `PingTimedOut`
+    /// event. Defaults to `DEFAULT_PING_TIMEOUT`.
+    pub fn ping_timeout(mut self, timeout: Duration) -> Self {
+        self.ping_timeout = timeout;
+        self
+    }
+
+    /// Stamp each packet sent to the server with a sequence number, counting up from 0 again after every reconnect.
+    /// The server checks the numbers whether it sends its own or not, and emits `OutOfSequence` if one is skipped or
+    /// goes backwards. Useful for tracking down packets lost or reordered by the application.
+    pub fn sequence_numbers(mut self, enabled: bool) -> Self {
+        self.sequence_numbers = enabled;
+        self
+    }
+
+    /// Stamp each packet sent to the server with the wall clock time it was queued, in milliseconds since the Unix
+    /// epoch. The server turns the stamps into `PacketLatency` events, which are only as accurate as the two clocks
+    /// are in sync. Also emits `QueueDelay` for each packet as it's written. Off by default, since it adds 8 bytes to
+    /// every packet.
+    pub fn timestamps(mut self, enabled: bool) -> Self {
+        self.timestamps = enabled;
+        self
+    }
+
+    /// Set how many channels packets can be sent on with `Client::send_on_channel`, numbered from `DEFAULT_CHANNEL` up.
+    /// Within a priority, the channels take turns writing, so a channel with a lot queued can't hold up the others.
+    /// Packets that arrive on a channel past these are dropped, with an `UnknownChannel` event. Defaults to 1, which is
+    /// only the default channel.
+    pub fn channels(mut self, count: u8) -> Self {
+        self.channels = count;
+        self
+    }
+
+    /// Compress packet bodies bigger than `threshold` bytes with `algorithm`, when it makes them smaller.
+    /// Compressed bodies are only accepted from the peer with compression enabled, so it has to be enabled on both
+    /// sides, although they don't need the same algorithm. A compressed body received without it is a protocol error.
+    #[cfg(feature = "compression")]
+    pub fn compression(mut self, algorithm: Compression, threshold: usize) -> Self {
+        self.compression.algorithm = Some((algorithm, threshold));
+        self
+    }
+
+    /// Accept packets with header flags this version of grubbnet doesn't know about, instead of disconnecting with a
+    /// protocol error. Only useful if the server may be on a newer version, and only safe for flags that don't change
+    /// the layout of the packet.
+    pub fn ignore_unknown_flags(mut self, ignore: bool) -> Self {
+        self.ignore_unknown_flags = ignore;
+        self
+    }
+
+    /// Cap how many received packets may sit unread in the incoming queue.
+    /// Once it's full the client stops reading from the socket until the queue is drained below the cap.
+    pub fn max_incoming(mut self, max: usize) -> Self {
+        self.max_incoming = Some(max);
+        self
+    }
+
+    /// Treat the connection as dead if no bytes are received from the server for this long, even if the socket hasn't
+    /// errored (like when a NAT mapping is silently dropped). The client disconnects with `DisconnectReason::TimedOut`.
+    /// This only applies once connected, and unlike `heartbeat` it never sends anything, so the server has to be
+    /// sending something regularly on its own.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Set how long `Client::tick` waits in poll for socket events when there's nothing to do yet. Defaults to
+    /// `DEFAULT_TICK_TIMEOUT`. Zero never blocks, which suits loops that keep their own frame timing. See
+    /// `Client::tick_blocking` for waiting until something happens.
+    pub fn tick_timeout(mut self, timeout: Duration) -> Self {
+        self.tick_timeout = timeout;
+        self
+    }
+
+    /// Cap how many packets may be waiting to be sent with `Client::send_after`.
+    /// Scheduling more while it's full fails with `Error::ScheduleFull`.
+    pub fn max_scheduled(mut self, max: usize) -> Self {
+        self.max_scheduled = Some(max);
+        self
+    }
+
+    /// Set how big the receive buffer may grow, in bytes. Defaults to `MAX_BUFFER_SIZE`.
+    /// The buffer starts at `INITIAL_BUFFER_SIZE` and grows while reads keep filling it. Must be at least
+    /// `INITIAL_BUFFER_SIZE`.
+    pub fn max_buffer_size(mut self, size: usize) -> Self {
+        self.max_buffer_size = size;
+        self
+    }
+
+    /// Check the config for invalid or inconsistent options.
+    pub fn validate(&self) -> Result<()> {
+        if self.connect_timeout == Some(Duration::from_secs(0)) {
+            return Err(Error::InvalidConfig("connect_timeout can't be zero"));
+        }
+
+        if let Some((interval, timeout)) = self.heartbeat {
+            if interval == Duration::from_secs(0) {
+                return Err(Error::InvalidConfig("heartbeat interval can't be zero"));
+            }
+
+            if timeout <= interval {
+                return Err(Error::InvalidConfig(
+                    "heartbeat timeout must be longer than the interval",
+                ));
+            }
+        }
+
+        if self.reconnect.initial_delay > self.reconnect.max_delay {
+            return Err(Error::InvalidConfig(
+                "reconnect initial_delay can't be longer than max_delay",
+            ));
+        }
+
+        if self.max_outgoing == Some(0) {
+            return Err(Error::InvalidConfig("max_outgoing must be at least 1"));
+        }
+
+        if self.max_outgoing_bytes == Some(0) {
+            return Err(Error::InvalidConfig(
+                "max_outgoing_bytes must be at least 1",
+            ));
+        }
+
+        if self.read_timeout == Some(Duration::from_secs(0)) {
+            return Err(Error::InvalidConfig("read_timeout can't be zero"));
+        }
+
+        if self.max_incoming == Some(0) {
+            return Err(Error::InvalidConfig("max_incoming must be at least 1"));
+        }
+
+        if self.max_scheduled == Some(0) {
+            return Err(Error::InvalidConfig("max_scheduled must be at least 1"));
+        }
+
+        if self.max_buffer_size < INITIAL_BUFFER_SIZE {
+            return Err(Error::InvalidConfig(
+                "max_buffer_size must be at least INITIAL_BUFFER_SIZE",
+            ));
+        }
+
+        if self.handshake_timeout == Duration::from_secs(0) {
+            return Err(Error::InvalidConfig("handshake_timeout can't be zero"));
+        }
+
+        if self.ping_timeout == Duration::from_secs(0) {
+            return Err(Error::InvalidConfig("ping_timeout can't be zero"));
+        }
+
+        if self.channels == 0 {
+            return Err(Error::InvalidConfig("channels must be at least 1"));
+        }
+
+        if self.max_reassembly_size == 0 {
+            return Err(Error::InvalidConfig(
+                "max_reassembly_size must be at least 1",
+            ));
+        }
+
+        if let Some(size) = self.max_packet_size {
+            if size <= FRAGMENT_HEADER_SIZE || size > self.framing.max_body_size() {
+                return Err(Error::InvalidConfig(
+                    "max_packet_size must be more than FRAGMENT_HEADER_SIZE and at most the framing's max_body_size",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the limits to read with. The largest packet body to accept falls back to the framing's default.
+    pub(crate) fn read_limits(&self) -> ReadLimits {
+        ReadLimits {
+            framing: self.framing,
+            endianness: self.endianness,
+            max_body_size: self.max_body_size(),
+            max_reassembly_size: self.max_reassembly_size,
+            decompress: self.compression.is_enabled(),
+            ignore_unknown_flags: self.ignore_unknown_flags,
+            handshake: None,
+            max_packets: None,
+            max_bytes: None,
+            read_budget: None,
+        }
+    }
+
+    /// Get the format to write packets with.
+    pub(crate) fn write_format(&self) -> WriteFormat {
+        WriteFormat {
+            framing: self.framing,
+            endianness: self.endianness,
+            compression: self.compression,
+            timestamps: self.timestamps,
+            max_body_size: self.max_body_size(),
+            max_total_size: self.max_reassembly_size,
+            channels: self.channels,
+        }
+    }
+
+    /// Get the largest packet body to send or accept, which falls back to the framing's default.
+    pub(crate) fn max_body_size(&self) -> usize {
+        self.max_packet_size
+            .unwrap_or_else(|| self.framing.default_body_size())
+    }
+
+    /// Validate the config, then connect to a server at `address`.
+    pub fn connect<A: ToSocketAddrs>(self, address: A) -> Result<Client> {
+        let address = self.connect_address(address)?;
+        Client::with_config(address, self)
+    }
+
+    /// Like `connect`, but the client only sends and receives messages belonging to the protocol `P`.
+    pub fn connect_typed<P: Protocol, A: ToSocketAddrs>(self, address: A) -> Result<Client<P>> {
+        let address = self.connect_address(address)?;
+        Client::with_config(address, self)
+    }
+
+    /// Validate the config, then take over a stream that's already connected to a server.
+    /// The stream is switched to non-blocking mode, and the client starts out connected. Reconnecting dials the
+    /// stream's peer address directly, so it won't go back through whatever set the stream up.
+    pub fn adopt_stream(self, stream: std::net::TcpStream) -> Result<Client> {
+        self.validate()?;
+        Client::adopt_stream(stream, self)
+    }
+
+    fn connect_address<A: ToSocketAddrs>(&self, address: A) -> Result<SocketAddr> {
+        self.validate()?;
+
+        match address.to_socket_addrs()?.next() {
+            Some(a) => Ok(a),
+            None => Err(Error::InvalidConfig("connect address didn't resolve")),
+        }
+    }
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig::new()
+    }
+}
@@ -0,0 +1,338 @@
+use crate::{
+    error::Result,
+    packet::{read_body_size, Endianness, Framing, PacketBody},
+};
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use std::io::Cursor;
+
+/// Reserved packet id for heartbeats.
+/// Heartbeats are handled by grubbnet and never show up in the incoming packet queues.
+pub const HEARTBEAT_PACKET_ID: u8 = 0xF1;
+
+/// Reserved packet id for pings, which the receiving side answers with a pong carrying the same nonce.
+pub const PING_PACKET_ID: u8 = 0xF2;
+
+/// Reserved packet id for pongs.
+pub const PONG_PACKET_ID: u8 = 0xF3;
+
+/// Reserved packet id for the hello a server sends each new connection when it has a protocol version configured.
+pub const HELLO_PACKET_ID: u8 = 0xF5;
+
+/// Reserved packet id for a client's answer to a hello, once it's checked the versions match.
+pub const HELLO_ACK_PACKET_ID: u8 = 0xF6;
+
+/// Reserved packet id for the reason a server gives when it kicks a client with `Server::kick_with_reason`.
+pub const KICK_PACKET_ID: u8 = 0xF7;
+
+/// Reserved packet id for the notice a server sends every connection when it begins shutting down.
+pub const SHUTDOWN_PACKET_ID: u8 = 0xF8;
+
+/// Reserved packet id for the notice a full server writes to a connection it's turning away.
+pub const SERVER_FULL_PACKET_ID: u8 = 0xF9;
+
+/// Whether a packet id belongs to one of the control packets above, which don't produce sent/received events.
+pub(crate) fn is_control_packet(id: u8) -> bool {
+    id == HEARTBEAT_PACKET_ID
+        || id == PING_PACKET_ID
+        || id == PONG_PACKET_ID
+        || id == HELLO_PACKET_ID
+        || id == HELLO_ACK_PACKET_ID
+        || id == KICK_PACKET_ID
+        || id == SHUTDOWN_PACKET_ID
+        || id == SERVER_FULL_PACKET_ID
+}
+
+/// Size of the body of a `Hello`.
+const HELLO_BODY_SIZE: u32 = 8;
+
+/// Size of the body of a `HelloAck`.
+const HELLO_ACK_BODY_SIZE: u32 = 4;
+
+/// Size of the body of a `ServerFull`.
+const SERVER_FULL_BODY_SIZE: usize = 8;
+
+/// Whether `bytes` start with the handshake packet `id`, but with its header written in the other endianness.
+/// The id doesn't depend on the byte order, so it's found in the same place either way, and the body size only makes
+/// sense when read the other way around.
+pub(crate) fn is_swapped_handshake(
+    bytes: &[u8],
+    framing: Framing,
+    endianness: Endianness,
+    id: u8,
+) -> bool {
+    let header_size = framing.header_size();
+    if bytes.len() < header_size || bytes[header_size - 3] != id {
+        return false;
+    }
+
+    let expected = if id == HELLO_PACKET_ID {
+        HELLO_BODY_SIZE
+    } else {
+        HELLO_ACK_BODY_SIZE
+    };
+    let size = |endianness| read_body_size(&mut Cursor::new(bytes), framing, endianness).ok();
+    size(endianness) != Some(expected) && size(endianness.swapped()) == Some(expected)
+}
+
+/// Heartbeat
+/// A tiny packet that keeps a quiet connection alive.
+/// Either side sends these on an interval when configured to, and the other side answers each one with a reply, so the
+/// sender hears back even if the application has nothing to say. Replies are never answered. A request has an empty
+/// body, and a reply has a single byte.
+#[derive(Clone)]
+pub(crate) struct Heartbeat {
+    pub(crate) reply: bool,
+}
+
+impl PacketBody for Heartbeat {
+    fn box_clone(&self) -> Box<dyn PacketBody> {
+        Box::new((*self).clone())
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        if self.reply {
+            Ok(vec![1])
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self> {
+        Ok(Heartbeat {
+            reply: !data.is_empty(),
+        })
+    }
+
+    fn id(&self) -> u8 {
+        HEARTBEAT_PACKET_ID
+    }
+}
+
+/// Ping
+/// Asks the other side to send back a `Pong` with the same nonce, for measuring round trip time.
+#[derive(Clone)]
+pub(crate) struct Ping {
+    pub(crate) nonce: u32,
+}
+
+impl PacketBody for Ping {
+    fn box_clone(&self) -> Box<dyn PacketBody> {
+        Box::new((*self).clone())
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        let mut data: Vec<u8> = Vec::with_capacity(4);
+        data.write_u32::<NetworkEndian>(self.nonce)?;
+
+        Ok(data)
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self> {
+        let nonce = Cursor::new(data).read_u32::<NetworkEndian>()?;
+
+        Ok(Ping { nonce })
+    }
+
+    fn id(&self) -> u8 {
+        PING_PACKET_ID
+    }
+}
+
+/// Pong
+/// The answer to a `Ping`.
+#[derive(Clone)]
+pub(crate) struct Pong {
+    pub(crate) nonce: u32,
+}
+
+impl PacketBody for Pong {
+    fn box_clone(&self) -> Box<dyn PacketBody> {
+        Box::new((*self).clone())
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        let mut data: Vec<u8> = Vec::with_capacity(4);
+        data.write_u32::<NetworkEndian>(self.nonce)?;
+
+        Ok(data)
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self> {
+        let nonce = Cursor::new(data).read_u32::<NetworkEndian>()?;
+
+        Ok(Pong { nonce })
+    }
+
+    fn id(&self) -> u8 {
+        PONG_PACKET_ID
+    }
+}
+
+/// Hello
+/// Tells a new connection which protocol version the server speaks, and the largest packet body it exchanges.
+#[derive(Clone)]
+pub(crate) struct Hello {
+    pub(crate) version: u32,
+    pub(crate) max_body_size: u32,
+}
+
+impl PacketBody for Hello {
+    fn box_clone(&self) -> Box<dyn PacketBody> {
+        Box::new((*self).clone())
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        let mut data: Vec<u8> = Vec::with_capacity(HELLO_BODY_SIZE as usize);
+        data.write_u32::<NetworkEndian>(self.version)?;
+        data.write_u32::<NetworkEndian>(self.max_body_size)?;
+
+        Ok(data)
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self> {
+        let mut reader = Cursor::new(data);
+        let version = reader.read_u32::<NetworkEndian>()?;
+        let max_body_size = reader.read_u32::<NetworkEndian>()?;
+
+        Ok(Hello {
+            version,
+            max_body_size,
+        })
+    }
+
+    fn id(&self) -> u8 {
+        HELLO_PACKET_ID
+    }
+}
+
+/// HelloAck
+/// The answer to a `Hello`, carrying the client's protocol version.
+#[derive(Clone)]
+pub(crate) struct HelloAck {
+    pub(crate) version: u32,
+}
+
+impl PacketBody for HelloAck {
+    fn box_clone(&self) -> Box<dyn PacketBody> {
+        Box::new((*self).clone())
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        let mut data: Vec<u8> = Vec::with_capacity(4);
+        data.write_u32::<NetworkEndian>(self.version)?;
+
+        Ok(data)
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self> {
+        let version = Cursor::new(data).read_u32::<NetworkEndian>()?;
+
+        Ok(HelloAck { version })
+    }
+
+    fn id(&self) -> u8 {
+        HELLO_ACK_PACKET_ID
+    }
+}
+
+/// Kick
+/// Tells a client why it's being kicked, as the last thing written before the server closes the connection. The body
+/// is the reason as UTF-8.
+#[derive(Clone)]
+pub(crate) struct Kick {
+    pub(crate) reason: String,
+}
+
+impl Kick {
+    /// A kick with `reason` cut short at a character boundary so it fits in a body of `max_body_size` bytes.
+    pub(crate) fn truncated(mut reason: String, max_body_size: usize) -> Self {
+        if reason.len() > max_body_size {
+            let mut end = max_body_size;
+            while !reason.is_char_boundary(end) {
+                end -= 1;
+            }
+            reason.truncate(end);
+        }
+
+        Kick { reason }
+    }
+}
+
+impl PacketBody for Kick {
+    fn box_clone(&self) -> Box<dyn PacketBody> {
+        Box::new((*self).clone())
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(self.reason.as_bytes().to_vec())
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self> {
+        Ok(Kick {
+            reason: String::from_utf8_lossy(data).into_owned(),
+        })
+    }
+
+    fn id(&self) -> u8 {
+        KICK_PACKET_ID
+    }
+}
+
+/// ShutdownNotice
+/// Tells a client the server is shutting down, as the last thing queued for it by `Server::begin_shutdown`. The body is
+/// empty.
+#[derive(Clone)]
+pub(crate) struct ShutdownNotice;
+
+impl PacketBody for ShutdownNotice {
+    fn box_clone(&self) -> Box<dyn PacketBody> {
+        Box::new(ShutdownNotice)
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(Vec::new())
+    }
+
+    fn deserialize(_data: &[u8]) -> Result<Self> {
+        Ok(ShutdownNotice)
+    }
+
+    fn id(&self) -> u8 {
+        SHUTDOWN_PACKET_ID
+    }
+}
+
+/// ServerFull
+/// Tells a client the server has no room for it, as the only thing written before the server closes the connection.
+/// The body is the number of connections the server has and the most it allows, so the client can show them.
+#[derive(Clone)]
+pub(crate) struct ServerFull {
+    pub(crate) connections: u32,
+    pub(crate) limit: u32,
+}
+
+impl PacketBody for ServerFull {
+    fn box_clone(&self) -> Box<dyn PacketBody> {
+        Box::new((*self).clone())
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        let mut data: Vec<u8> = Vec::with_capacity(SERVER_FULL_BODY_SIZE);
+        data.write_u32::<NetworkEndian>(self.connections)?;
+        data.write_u32::<NetworkEndian>(self.limit)?;
+
+        Ok(data)
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self> {
+        let mut reader = Cursor::new(data);
+        let connections = reader.read_u32::<NetworkEndian>()?;
+        let limit = reader.read_u32::<NetworkEndian>()?;
+
+        Ok(ServerFull { connections, limit })
+    }
+
+    fn id(&self) -> u8 {
+        SERVER_FULL_PACKET_ID
+    }
+}
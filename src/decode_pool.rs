@@ -0,0 +1,84 @@
+use crate::{error::Result, packet::Packet, protocol::Protocol, slab::ConnectionId};
+use mio::Waker;
+use std::{
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread::JoinHandle,
+};
+
+/// A packet decoded by a worker, along with its packet id for reporting failures.
+pub(crate) type Decoded<P> = (ConnectionId, u8, Result<P>);
+
+/// Worker threads that decode incoming packets off of the network thread.
+/// Each connection always goes to the same worker, and workers decode in the order they're given packets, so messages
+/// from one connection come back in the order they arrived.
+pub(crate) struct DecodePool<P> {
+    jobs: Vec<Sender<(ConnectionId, Packet)>>,
+    results: Receiver<Decoded<P>>,
+    workers: Vec<JoinHandle<()>>,
+    /// Packets handed to workers that haven't come back yet.
+    pub(crate) in_flight: usize,
+}
+
+impl<P: Protocol> DecodePool<P> {
+    /// Start `size` workers. Each wakes `waker` when it finishes a packet, so a blocking tick picks up the result.
+    pub(crate) fn new(size: usize, waker: Arc<Waker>) -> Result<Self> {
+        let (result_sender, results) = mpsc::channel();
+        let mut jobs = Vec::with_capacity(size);
+        let mut workers = Vec::with_capacity(size);
+        for index in 0..size {
+            let (job_sender, job_receiver) = mpsc::channel::<(ConnectionId, Packet)>();
+            let result_sender: Sender<Decoded<P>> = result_sender.clone();
+            let waker = waker.clone();
+
+            let worker = std::thread::Builder::new()
+                .name(format!("grubbnet-decode-{}", index))
+                .spawn(move || {
+                    for (id, packet) in job_receiver {
+                        let decoded = P::decode(packet.header.id, &packet.body);
+                        if result_sender.send((id, packet.header.id, decoded)).is_err() {
+                            break;
+                        }
+
+                        let _ = waker.wake();
+                    }
+                })?;
+
+            jobs.push(job_sender);
+            workers.push(worker);
+        }
+
+        Ok(DecodePool {
+            jobs,
+            results,
+            workers,
+            in_flight: 0,
+        })
+    }
+
+    pub(crate) fn submit(&mut self, id: ConnectionId, packet: Packet) {
+        let shard = id.index as usize % self.jobs.len();
+        if self.jobs[shard].send((id, packet)).is_ok() {
+            self.in_flight += 1;
+        }
+    }
+
+    /// Take a decoded packet if one is ready, without blocking.
+    pub(crate) fn try_recv(&mut self) -> Option<Decoded<P>> {
+        let decoded = self.results.try_recv().ok()?;
+        self.in_flight -= 1;
+        Some(decoded)
+    }
+}
+
+impl<P> Drop for DecodePool<P> {
+    fn drop(&mut self) {
+        // Closing the job channels lets each worker finish up and exit
+        self.jobs.clear();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
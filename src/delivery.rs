@@ -0,0 +1,358 @@
+use crate::{
+    error::Result,
+    packet::{PacketBody, DEFAULT_CHANNEL},
+};
+use bytes::Bytes;
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{self, ErrorKind, Write},
+    time::Instant,
+};
+
+/// MessageId
+/// A handle for a packet queued with `send_tracked`, which comes back in the events saying whether it was delivered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MessageId(u64);
+
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// Priority
+/// How urgently a packet should be written, compared to the others queued for the same connection.
+/// Packets with a higher priority are written first when the socket can't keep up, and packets with the same priority
+/// are written in the order they were sent.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Priority {
+    /// Bulk packets that can wait, like chat history or asset chunks.
+    Low,
+    /// What `send` uses.
+    #[default]
+    Normal,
+    /// Packets that shouldn't wait behind anything else, like kick notices or state corrections.
+    High,
+}
+
+impl Priority {
+    fn index(self) -> usize {
+        match self {
+            Priority::High => 0,
+            Priority::Normal => 1,
+            Priority::Low => 2,
+        }
+    }
+}
+
+/// Hands out a new `MessageId` for each tracked send.
+pub(crate) struct MessageIds {
+    next: u64,
+}
+
+impl MessageIds {
+    pub(crate) fn new() -> Self {
+        MessageIds { next: 0 }
+    }
+
+    pub(crate) fn next(&mut self) -> MessageId {
+        let id = MessageId(self.next);
+        self.next += 1;
+        id
+    }
+}
+
+/// What's queued to go out: a packet that's serialized when it's written, or a body that was serialized ahead of time
+/// so one broadcast can share it between every connection it's queued for.
+#[derive(Clone)]
+pub(crate) enum Payload {
+    Packet(Box<dyn PacketBody>),
+    Serialized { id: u8, body: Bytes },
+}
+
+impl Payload {
+    /// Serialize `packet` now, so cloning the payload for each recipient is only a reference count bump.
+    /// A packet that fails to serialize is kept as it is, so the failure is reported for each recipient as it's
+    /// written, same as any other.
+    pub(crate) fn shared(packet: Box<dyn PacketBody>) -> Self {
+        match Payload::serialized(packet.as_ref()) {
+            Ok(payload) => payload,
+            Err(_) => Payload::Packet(packet),
+        }
+    }
+
+    /// Serialize `packet` now, like `shared`, but hand back the error if it fails.
+    pub(crate) fn serialized(packet: &dyn PacketBody) -> Result<Self> {
+        Ok(Payload::Serialized {
+            id: packet.id(),
+            body: packet.serialize()?.into(),
+        })
+    }
+
+    pub(crate) fn id(&self) -> u8 {
+        match self {
+            Payload::Packet(packet) => packet.id(),
+            Payload::Serialized { id, .. } => *id,
+        }
+    }
+
+    /// Get the size of the serialized body, or 0 if it hasn't been serialized.
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Payload::Packet(_) => 0,
+            Payload::Serialized { body, .. } => body.len(),
+        }
+    }
+
+    /// Get the serialized body, serializing it first if that hasn't happened yet.
+    pub(crate) fn serialize(&self) -> Result<Bytes> {
+        match self {
+            Payload::Packet(packet) => packet.serialize().map(Bytes::from),
+            Payload::Serialized { body, .. } => Ok(body.clone()),
+        }
+    }
+}
+
+/// A packet in a connection's outgoing queue, along with the message it belongs to if it's being tracked, its
+/// priority and channel, and when it was queued.
+pub(crate) struct Outgoing {
+    pub(crate) packet: Payload,
+    pub(crate) message: Option<MessageId>,
+    pub(crate) priority: Priority,
+    pub(crate) channel: u8,
+    pub(crate) queued_at: Instant,
+}
+
+impl Outgoing {
+    /// An untracked packet with normal priority on the default channel, like one of grubbnet's own control packets.
+    pub(crate) fn new(packet: impl PacketBody) -> Self {
+        Outgoing::boxed(Box::new(packet), None, Priority::Normal)
+    }
+
+    pub(crate) fn boxed(
+        packet: Box<dyn PacketBody>,
+        message: Option<MessageId>,
+        priority: Priority,
+    ) -> Self {
+        Outgoing::payload(Payload::Packet(packet), message, priority)
+    }
+
+    pub(crate) fn payload(packet: Payload, message: Option<MessageId>, priority: Priority) -> Self {
+        Outgoing {
+            packet,
+            message,
+            priority,
+            channel: DEFAULT_CHANNEL,
+            queued_at: Instant::now(),
+        }
+    }
+
+    /// Send the packet on `channel` instead of the default one.
+    pub(crate) fn on_channel(mut self, channel: u8) -> Self {
+        self.channel = channel;
+        self
+    }
+}
+
+/// A serialized packet that's partway out to its socket, kept until the socket has room for the rest.
+/// Holds on to what's needed to report the packet once its last byte is written.
+pub(crate) struct PartialWrite {
+    data: Vec<u8>,
+    written: usize,
+    pub(crate) packet_id: u8,
+    pub(crate) message: Option<MessageId>,
+    pub(crate) queued_at: Instant,
+}
+
+impl PartialWrite {
+    pub(crate) fn new(
+        data: Vec<u8>,
+        packet_id: u8,
+        message: Option<MessageId>,
+        queued_at: Instant,
+    ) -> Self {
+        PartialWrite {
+            data,
+            written: 0,
+            packet_id,
+            message,
+            queued_at,
+        }
+    }
+
+    /// Get the size of the whole packet on the wire.
+    pub(crate) fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Get how many bytes of the packet are still to be written.
+    pub(crate) fn remaining(&self) -> usize {
+        self.data.len() - self.written
+    }
+
+    /// Write as much of the rest of the packet as `socket` takes without blocking.
+    /// Returns true once the last byte is written, or false if the socket filled up first. Interrupted writes are
+    /// retried, and a socket that takes nothing is an error, since it would never take the rest.
+    pub(crate) fn write_to<W: Write>(&mut self, socket: &mut W) -> io::Result<bool> {
+        while self.written < self.data.len() {
+            match socket.write(&self.data[self.written..]) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(sent_bytes) => self.written += sent_bytes,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+/// The packets queued with one priority, with a queue for each channel they were sent on.
+/// Channels take turns, so one with a lot queued can't hold up the packets on the others.
+#[derive(Default)]
+struct Lane {
+    channels: Vec<VecDeque<Outgoing>>,
+    /// The channel to look at first for the next packet.
+    next: usize,
+}
+
+impl Lane {
+    fn len(&self) -> usize {
+        self.channels.iter().map(VecDeque::len).sum()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.channels.iter().all(VecDeque::is_empty)
+    }
+
+    /// Get the queue for `channel`, adding queues up to it if it's the first packet sent on it.
+    fn channel(&mut self, channel: u8) -> &mut VecDeque<Outgoing> {
+        let index = channel as usize;
+        if self.channels.len() <= index {
+            self.channels.resize_with(index + 1, VecDeque::new);
+        }
+
+        &mut self.channels[index]
+    }
+
+    /// Take the next packet from the first channel with one, starting where the last one left off.
+    fn pop_front(&mut self) -> Option<Outgoing> {
+        let count = self.channels.len();
+        for offset in 0..count {
+            let index = (self.next + offset) % count;
+            if let Some(outgoing) = self.channels[index].pop_front() {
+                self.next = (index + 1) % count;
+                return Some(outgoing);
+            }
+        }
+
+        None
+    }
+
+    /// Take the packet that was queued first, whichever channel it's on.
+    fn pop_oldest(&mut self) -> Option<Outgoing> {
+        self.channels
+            .iter_mut()
+            .filter(|queue| !queue.is_empty())
+            .min_by_key(|queue| queue.front().map(|outgoing| outgoing.queued_at))
+            .and_then(VecDeque::pop_front)
+    }
+}
+
+/// A connection's outgoing packets, with a queue for each priority, and within that for each channel.
+/// Packets come out highest priority first. Within a priority the channels take turns, and each channel's packets come
+/// out in the order they were pushed.
+#[derive(Default)]
+pub(crate) struct OutgoingQueue {
+    lanes: [Lane; 3],
+    /// The total size of the serialized bodies queued.
+    bytes: usize,
+}
+
+impl OutgoingQueue {
+    pub(crate) fn new() -> Self {
+        OutgoingQueue::default()
+    }
+
+    /// Get the number of packets queued, across every priority and channel.
+    pub(crate) fn len(&self) -> usize {
+        self.lanes.iter().map(Lane::len).sum()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.lanes.iter().all(Lane::is_empty)
+    }
+
+    /// Get the total size of the bodies queued, leaving out any that haven't been serialized yet.
+    pub(crate) fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Queue a packet behind everything else with its priority and channel.
+    pub(crate) fn push_back(&mut self, outgoing: Outgoing) {
+        self.bytes += outgoing.packet.len();
+        let lane = &mut self.lanes[outgoing.priority.index()];
+        lane.channel(outgoing.channel).push_back(outgoing);
+    }
+
+    /// Queue a packet ahead of everything else, whatever its priority.
+    pub(crate) fn push_front(&mut self, outgoing: Outgoing) {
+        self.bytes += outgoing.packet.len();
+        let lane = &mut self.lanes[Priority::High.index()];
+        let channel = outgoing.channel;
+        lane.channel(channel).push_front(outgoing);
+        lane.next = channel as usize;
+    }
+
+    /// Take the next packet to write.
+    pub(crate) fn pop_front(&mut self) -> Option<Outgoing> {
+        let outgoing = self.lanes.iter_mut().find_map(Lane::pop_front)?;
+        self.bytes -= outgoing.packet.len();
+        Some(outgoing)
+    }
+
+    /// Get the lowest priority with anything queued.
+    pub(crate) fn lowest_priority(&self) -> Option<Priority> {
+        [Priority::Low, Priority::Normal, Priority::High]
+            .iter()
+            .copied()
+            .find(|priority| !self.lanes[priority.index()].is_empty())
+    }
+
+    /// Take the oldest packet with the given priority, whichever channel it's on.
+    pub(crate) fn pop_oldest(&mut self, priority: Priority) -> Option<Outgoing> {
+        let outgoing = self.lanes[priority.index()].pop_oldest()?;
+        self.bytes -= outgoing.packet.len();
+        Some(outgoing)
+    }
+
+    /// Iterate over the queued packets, highest priority first.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Outgoing> {
+        self.lanes
+            .iter()
+            .flat_map(|lane| lane.channels.iter().flatten())
+    }
+
+    /// Keep only the packets `keep` returns true for, leaving the rest in order.
+    pub(crate) fn retain<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(&Outgoing) -> bool,
+    {
+        let bytes = &mut self.bytes;
+        for queue in self
+            .lanes
+            .iter_mut()
+            .flat_map(|lane| lane.channels.iter_mut())
+        {
+            queue.retain(|outgoing| {
+                let kept = keep(outgoing);
+                if !kept {
+                    *bytes -= outgoing.packet.len();
+                }
+                kept
+            });
+        }
+    }
+}
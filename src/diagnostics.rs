@@ -0,0 +1,102 @@
+use crate::slab::ConnectionId;
+use std::{collections::VecDeque, time::Duration};
+
+/// How many recent ticks the diagnostics window covers.
+pub const DIAGNOSTICS_WINDOW: usize = 128;
+
+/// ServerDiagnostics
+/// A snapshot of how the server's event loop has been spending its time over the last `DIAGNOSTICS_WINDOW` ticks.
+/// Timings are only gathered while diagnostics are enabled with `Server::set_diagnostics`.
+#[derive(Clone, Debug, Default)]
+pub struct ServerDiagnostics {
+    /// Total ticks recorded since diagnostics were enabled.
+    pub tick_count: u64,
+    /// Number of ticks the averages below were taken over.
+    pub window_ticks: usize,
+    pub mean_tick_duration: Duration,
+    pub max_tick_duration: Duration,
+    pub mean_poll_duration: Duration,
+    pub max_poll_duration: Duration,
+    pub mean_events_per_tick: f64,
+    pub mean_packets_parsed_per_tick: f64,
+    pub mean_packets_written_per_tick: f64,
+    /// Total reregister calls made during the window.
+    pub reregister_calls: u64,
+    /// Number of packets waiting in the incoming queue right now.
+    pub incoming_queue_len: usize,
+    /// Number of packets waiting in each connection's outgoing queue right now.
+    pub outgoing_queue_lens: Vec<(ConnectionId, usize)>,
+}
+
+/// Counters for a single tick.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct TickSample {
+    pub duration: Duration,
+    pub poll_duration: Duration,
+    pub events: usize,
+    pub packets_parsed: usize,
+    pub packets_written: usize,
+    pub reregisters: usize,
+}
+
+/// Keeps the samples for the most recent ticks.
+pub(crate) struct DiagnosticsRecorder {
+    tick_count: u64,
+    samples: VecDeque<TickSample>,
+}
+
+impl DiagnosticsRecorder {
+    pub fn new() -> Self {
+        DiagnosticsRecorder {
+            tick_count: 0,
+            samples: VecDeque::with_capacity(DIAGNOSTICS_WINDOW),
+        }
+    }
+
+    pub fn record(&mut self, sample: TickSample) {
+        if self.samples.len() >= DIAGNOSTICS_WINDOW {
+            self.samples.pop_front();
+        }
+
+        self.samples.push_back(sample);
+        self.tick_count += 1;
+    }
+
+    /// Summarize the window. Queue sizes are left for the caller to fill in.
+    pub fn summarize(&self) -> ServerDiagnostics {
+        let mut diagnostics = ServerDiagnostics {
+            tick_count: self.tick_count,
+            window_ticks: self.samples.len(),
+            ..Default::default()
+        };
+
+        if self.samples.is_empty() {
+            return diagnostics;
+        }
+
+        let mut total_duration = Duration::from_secs(0);
+        let mut total_poll_duration = Duration::from_secs(0);
+        let mut total_events = 0;
+        let mut total_parsed = 0;
+        let mut total_written = 0;
+        for sample in self.samples.iter() {
+            total_duration += sample.duration;
+            total_poll_duration += sample.poll_duration;
+            total_events += sample.events;
+            total_parsed += sample.packets_parsed;
+            total_written += sample.packets_written;
+            diagnostics.reregister_calls += sample.reregisters as u64;
+            diagnostics.max_tick_duration = diagnostics.max_tick_duration.max(sample.duration);
+            diagnostics.max_poll_duration = diagnostics.max_poll_duration.max(sample.poll_duration);
+        }
+
+        let count = self.samples.len();
+        diagnostics.mean_tick_duration = total_duration / count as u32;
+        diagnostics.mean_poll_duration = total_poll_duration / count as u32;
+        diagnostics.mean_events_per_tick = total_events as f64 / count as f64;
+        diagnostics.mean_packets_parsed_per_tick = total_parsed as f64 / count as f64;
+        diagnostics.mean_packets_written_per_tick = total_written as f64 / count as f64;
+
+        diagnostics
+    }
+}
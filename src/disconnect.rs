@@ -0,0 +1,28 @@
+use std::io::ErrorKind;
+
+/// Why a connection ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DisconnectReason {
+    /// This side closed the connection.
+    Closed,
+    /// The other side closed the connection.
+    RemoteClosed,
+    /// Reading from or connecting the socket failed.
+    IoError(ErrorKind),
+    /// Writing to the socket failed.
+    SendFailed,
+    /// The other side sent something that can't be parsed, like an invalid packet header.
+    ProtocolError,
+    /// Connecting took too long, or nothing was heard from the other side for too long.
+    TimedOut,
+    /// The server kicked the connection.
+    Kicked,
+    /// The other side fell too far behind reading what was sent to it, under `SlowConsumerPolicy::Disconnect`.
+    SlowConsumer,
+    /// The server shut down, with `Server::begin_shutdown`.
+    ServerShutdown,
+    /// The server turned the connection away because it already had as many connections as it allows, see
+    /// `ServerConfig::server_full_notice`.
+    ServerFull,
+}
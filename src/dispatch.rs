@@ -0,0 +1,79 @@
+use crate::{
+    error::{Error, Result},
+    packet::{Packet, PacketBody},
+    slab::ConnectionId,
+};
+use std::collections::HashMap;
+
+type Handler<'a, S> = Box<dyn FnMut(S, &[u8]) -> Result<()> + 'a>;
+
+/// What happened to a packet handed to `PacketRegistry::dispatch`.
+#[derive(Debug)]
+pub enum Dispatched {
+    /// The packet was deserialized and handed to its handler.
+    Handled,
+    /// Nothing is registered for the packet's id, so it's handed back.
+    Unknown(Packet),
+    /// The packet's body didn't deserialize into the type registered for its id. The packet is dropped.
+    Invalid(u8),
+}
+
+/// PacketRegistry
+/// Deserializes incoming packets by id, and hands each one to the handler registered for that id, instead of every
+/// application matching on `packet.header.id` itself. `S` is who sent the packet: a `ConnectionId` on a server, and
+/// `()` on a client. Use `Server::dispatch_incoming` or `Client::dispatch_incoming` to run the incoming queue
+/// through it.
+pub struct PacketRegistry<'a, S = ConnectionId> {
+    handlers: HashMap<u8, Handler<'a, S>>,
+}
+
+impl<'a, S> PacketRegistry<'a, S> {
+    pub fn new() -> Self {
+        PacketRegistry {
+            handlers: HashMap::new(),
+        }
+    }
+
+    /// Register `handler` for packets with `id`. Their bodies are deserialized into `T` before it's called.
+    /// Returns `DuplicatePacketId` if something is already registered for `id`.
+    pub fn register<T, F>(&mut self, id: u8, mut handler: F) -> Result<()>
+    where
+        T: PacketBody,
+        F: FnMut(S, T) + 'a,
+    {
+        if self.handlers.contains_key(&id) {
+            return Err(Error::DuplicatePacketId(id));
+        }
+
+        let handler: Handler<'a, S> = Box::new(move |from, body| {
+            handler(from, T::deserialize(body)?);
+            Ok(())
+        });
+        self.handlers.insert(id, handler);
+
+        Ok(())
+    }
+
+    /// Whether a handler is registered for `id`.
+    pub fn is_registered(&self, id: u8) -> bool {
+        self.handlers.contains_key(&id)
+    }
+
+    /// Deserialize a packet from `from`, and hand it to the handler registered for its id.
+    pub fn dispatch(&mut self, from: S, packet: Packet) -> Dispatched {
+        let id = packet.header.id;
+        match self.handlers.get_mut(&id) {
+            Some(handler) => match handler(from, &packet.body) {
+                Ok(()) => Dispatched::Handled,
+                Err(_) => Dispatched::Invalid(id),
+            },
+            None => Dispatched::Unknown(packet),
+        }
+    }
+}
+
+impl<S> Default for PacketRegistry<'_, S> {
+    fn default() -> Self {
+        PacketRegistry::new()
+    }
+}
@@ -1,8 +1,10 @@
+use crate::server::RejectReason;
 use derive_more::{Display, From};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(From, Display, Debug)]
+#[non_exhaustive]
 pub enum Error {
     Io(std::io::Error),
 
@@ -16,7 +18,74 @@ pub enum Error {
     FailedToRegisterForEvents,
     InvalidData,
     ConnectionNotFound,
+    ServerClosed,
+    ScheduleFull,
+    ReassemblyTooLarge,
+    DecompressedTooLarge,
+    UnexpectedCompression,
 
-    #[doc(hidden)]
-    __Nonexhaustive,
+    #[from(ignore)]
+    InvalidConfig(&'static str),
+
+    #[from(ignore)]
+    UnknownPacket(u8),
+
+    #[from(ignore)]
+    DuplicatePacketId(u8),
+
+    #[from(ignore)]
+    UnknownPacketFlags(u8),
+
+    /// A packet was sent with an id from `RESERVED_PACKET_IDS_START` up, which belong to grubbnet.
+    #[from(ignore)]
+    ReservedPacketId(u8),
+
+    /// A packet was sent on a channel past the ones set with `ServerConfig::channels`.
+    #[from(ignore)]
+    UnknownChannel(u8),
+
+    /// A packet body was bigger than the configured limit, whether it was received or about to be sent.
+    #[from(ignore)]
+    #[display(fmt = "packet body is {} bytes, over the limit of {} bytes", size, max)]
+    PacketTooLarge {
+        size: usize,
+        max: usize,
+    },
+
+    /// A packet header's check byte didn't match its body size, id and flags, so the header was damaged.
+    #[from(ignore)]
+    #[display(
+        fmt = "header check byte was {:#04x}, expected {:#04x}",
+        received,
+        expected
+    )]
+    HeaderChecksumMismatch {
+        expected: u8,
+        received: u8,
+    },
+
+    /// The peer's handshake arrived with its header in the other byte order.
+    EndiannessMismatch,
+
+    /// A packet was read as a type with a different packet id.
+    #[from(ignore)]
+    #[display(fmt = "expected packet {}, got packet {}", expected, actual)]
+    WrongPacketId {
+        expected: u8,
+        actual: u8,
+    },
+
+    /// The body of a packet with this id didn't deserialize.
+    #[from(ignore)]
+    #[display(fmt = "packet {} body didn't deserialize: {}", id, error)]
+    InvalidPacketBody {
+        id: u8,
+        error: Box<Error>,
+    },
+
+    #[from(ignore)]
+    InvalidListener(&'static str),
+
+    #[from(ignore)]
+    ConnectionRejected(RejectReason),
 }
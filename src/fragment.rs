@@ -0,0 +1,151 @@
+use crate::{
+    error::Error,
+    packet::{write_header, Packet, PacketHeader, WriteFormat},
+};
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use std::io::Cursor;
+
+/// Reserved packet id for fragments of a body too big to send as one packet.
+/// Fragments are reassembled by grubbnet, and only the whole packet shows up in the incoming packet queues.
+pub const FRAGMENT_PACKET_ID: u8 = 0xF4;
+
+/// Size of the fields in front of each fragment's bytes (1 byte for the packet's id, then 4 bytes each for the
+/// packet's total body size and the fragment's offset into it).
+pub const FRAGMENT_HEADER_SIZE: usize = 9;
+
+/// The most bytes a peer may reassemble into one packet, unless configured otherwise.
+pub const DEFAULT_MAX_REASSEMBLY_SIZE: usize = 1024 * 1024;
+
+/// Write `body` as a run of fragment packets, each no bigger than the format's `max_body_size`.
+/// Every fragment copies `header`'s flags, sequence number, timestamp and channel, and carries its id in front of the
+/// chunk.
+pub(crate) fn write_fragments(
+    data: &mut Vec<u8>,
+    format: &WriteFormat,
+    header: &PacketHeader,
+    body: &[u8],
+) -> Result<(), Error> {
+    if body.len() > u32::MAX as usize {
+        return Err(Error::PacketTooLarge {
+            size: body.len(),
+            max: u32::MAX as usize,
+        });
+    }
+
+    let chunk_size = format.max_body_size - FRAGMENT_HEADER_SIZE;
+    for (index, chunk) in body.chunks(chunk_size).enumerate() {
+        let fragment = PacketHeader {
+            size: (FRAGMENT_HEADER_SIZE + chunk.len()) as u32,
+            id: FRAGMENT_PACKET_ID,
+            ..header.clone()
+        };
+        write_header(data, &fragment, format.framing, format.endianness)?;
+        data.write_u8(header.id)?;
+        data.write_u32::<NetworkEndian>(body.len() as u32)?;
+        data.write_u32::<NetworkEndian>((index * chunk_size) as u32)?;
+        data.extend_from_slice(chunk);
+    }
+
+    Ok(())
+}
+
+/// A packet that's partway through being reassembled.
+struct Partial {
+    id: u8,
+    flags: u8,
+    sequence: Option<u32>,
+    timestamp: Option<u64>,
+    channel: Option<u8>,
+    total: usize,
+    body: Vec<u8>,
+}
+
+/// Reassembly
+/// Puts fragmented packets back together. Fragments of one packet have to arrive in order, with nothing else
+/// fragmented in between; anything else is treated as a protocol error.
+pub(crate) struct Reassembly {
+    partial: Option<Partial>,
+}
+
+impl Reassembly {
+    pub(crate) fn new() -> Self {
+        Reassembly { partial: None }
+    }
+
+    /// Drop whatever has been reassembled so far.
+    pub(crate) fn clear(&mut self) {
+        self.partial = None;
+    }
+
+    /// Add a fragment packet. Returns the whole packet once its last fragment has arrived, with the flags, sequence
+    /// number, timestamp and channel of its first fragment.
+    /// Returns `InvalidData` for malformed or out of order fragments, and `ReassemblyTooLarge` if the packet would
+    /// be bigger than `max_size`.
+    pub(crate) fn push(
+        &mut self,
+        fragment: &Packet,
+        max_size: usize,
+    ) -> Result<Option<Packet>, Error> {
+        let mut reader = Cursor::new(&fragment.body);
+        let id = reader.read_u8()?;
+        let total = reader.read_u32::<NetworkEndian>()? as usize;
+        let offset = reader.read_u32::<NetworkEndian>()? as usize;
+        let chunk = &fragment.body[FRAGMENT_HEADER_SIZE..];
+
+        // An empty fragment would never make any progress
+        if chunk.is_empty() || id == FRAGMENT_PACKET_ID {
+            return Err(Error::InvalidData);
+        }
+
+        let partial = match self.partial.as_mut() {
+            Some(partial) => {
+                // Fragments of a different packet, or a gap, mean the stream can't be trusted
+                if id != partial.id || total != partial.total || offset != partial.body.len() {
+                    return Err(Error::InvalidData);
+                }
+
+                partial
+            }
+            None => {
+                if offset != 0 {
+                    return Err(Error::InvalidData);
+                }
+
+                if total > max_size {
+                    return Err(Error::ReassemblyTooLarge);
+                }
+
+                self.partial.get_or_insert(Partial {
+                    id,
+                    flags: fragment.header.flags,
+                    sequence: fragment.header.sequence,
+                    timestamp: fragment.header.timestamp,
+                    channel: fragment.header.channel,
+                    total,
+                    body: Vec::new(),
+                })
+            }
+        };
+
+        if partial.body.len() + chunk.len() > partial.total {
+            return Err(Error::InvalidData);
+        }
+
+        partial.body.extend_from_slice(chunk);
+        if partial.body.len() < partial.total {
+            return Ok(None);
+        }
+
+        Ok(self.partial.take().map(|partial| Packet {
+            header: PacketHeader {
+                size: partial.total as u32,
+                id: partial.id,
+                flags: partial.flags,
+                sequence: partial.sequence,
+                timestamp: partial.timestamp,
+                channel: partial.channel,
+            },
+            body: partial.body.into(),
+        }))
+    }
+}
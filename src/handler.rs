@@ -0,0 +1,60 @@
+use crate::{
+    client::{Client, ClientEvent},
+    error::Result,
+    packet::Packet,
+    protocol::RawProtocol,
+    server::{Server, ServerEvent},
+    slab::ConnectionId,
+};
+use mio::Waker;
+use std::sync::Arc;
+
+/// ServerHandler
+/// Callbacks for `Server::run`. Every method does nothing by default, so only implement the ones you need.
+/// The server is handed back to each callback, so handlers can send, kick, or shut down from inside them.
+pub trait ServerHandler<P = RawProtocol> {
+    /// Called for each event the server generates.
+    fn on_event(&mut self, _server: &mut Server<P>, _event: ServerEvent) {}
+
+    /// Called for each packet a raw server receives.
+    fn on_packet(&mut self, _server: &mut Server<P>, _from: ConnectionId, _packet: Packet) {}
+
+    /// Called for each message a typed server receives.
+    fn on_message(&mut self, _server: &mut Server<P>, _from: ConnectionId, _message: P) {}
+
+    /// Called once per loop, after that tick's events and packets have been handled.
+    fn on_tick(&mut self, _server: &mut Server<P>) {}
+}
+
+/// ClientHandler
+/// Callbacks for `Client::run`. Every method does nothing by default, so only implement the ones you need.
+pub trait ClientHandler<P = RawProtocol> {
+    /// Called for each event the client generates.
+    fn on_event(&mut self, _client: &mut Client<P>, _event: ClientEvent) {}
+
+    /// Called for each packet a raw client receives.
+    fn on_packet(&mut self, _client: &mut Client<P>, _packet: Packet) {}
+
+    /// Called for each message a typed client receives.
+    fn on_message(&mut self, _client: &mut Client<P>, _message: P) {}
+
+    /// Called once per loop, after that tick's events and packets have been handled.
+    fn on_tick(&mut self, _client: &mut Client<P>) {}
+}
+
+/// Wakeup
+/// Wakes a `Server` or `Client` that's blocked in `run`, so a stop flag set from another thread (or a Ctrl-C handler)
+/// is noticed straight away instead of on the next poll timeout.
+#[derive(Clone)]
+pub struct Wakeup(Arc<Waker>);
+
+impl Wakeup {
+    pub(crate) fn new(waker: Arc<Waker>) -> Self {
+        Wakeup(waker)
+    }
+
+    pub fn wake(&self) -> Result<()> {
+        self.0.wake()?;
+        Ok(())
+    }
+}
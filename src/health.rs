@@ -0,0 +1,62 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// TcpHealth
+/// The kernel's view of a connection, for telling network trouble apart from trouble in the application.
+/// This is only available on Linux. Elsewhere, anything returning it returns `None` instead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TcpHealth {
+    /// The kernel's smoothed round trip time.
+    pub rtt: Duration,
+    /// How much the kernel's round trip time varies.
+    pub rtt_variance: Duration,
+    /// Segments retransmitted over the life of the connection.
+    pub retransmits: u32,
+    /// Bytes written to the socket that the other side hasn't acknowledged yet.
+    pub send_queue_bytes: usize,
+}
+
+/// Ask the kernel how a connection is doing, through `TCP_INFO` and `SIOCOUTQ`.
+/// Returns `None` if either call fails, which it does for sockets that aren't connected.
+#[cfg(target_os = "linux")]
+pub(crate) fn tcp_health<S: std::os::unix::io::AsRawFd>(socket: &S) -> Option<TcpHealth> {
+    use std::mem;
+
+    let fd = socket.as_raw_fd();
+
+    // Safety: both calls only write into the locals they're handed, which are sized for what they write
+    let mut info: libc::tcp_info = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut libc::tcp_info as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return None;
+    }
+
+    // SIOCOUTQ shares its value with TIOCOUTQ
+    let mut send_queue: libc::c_int = 0;
+    let result = unsafe { libc::ioctl(fd, libc::TIOCOUTQ, &mut send_queue) };
+    if result != 0 {
+        return None;
+    }
+
+    Some(TcpHealth {
+        rtt: Duration::from_micros(info.tcpi_rtt as u64),
+        rtt_variance: Duration::from_micros(info.tcpi_rttvar as u64),
+        retransmits: info.tcpi_total_retrans,
+        send_queue_bytes: send_queue as usize,
+    })
+}
+
+/// The kernel's view isn't read on this platform.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn tcp_health<S>(_socket: &S) -> Option<TcpHealth> {
+    None
+}
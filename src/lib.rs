@@ -1,48 +1,267 @@
 mod client;
+mod codec;
+mod compression;
+mod config;
+mod control;
+mod decode_pool;
+mod delivery;
+mod diagnostics;
+mod disconnect;
+mod dispatch;
 mod error;
+mod fragment;
+mod handler;
+mod health;
+mod packet_log;
+mod protocol;
+mod receive;
+mod relay;
+mod schedule;
+mod scheduler;
+mod sender;
+mod sequence;
 mod server;
+mod slab;
+mod stats;
 
 pub mod buffer;
 pub mod packet;
+/// Helpers for integration tests that run a server and client against each other in one process.
+pub mod testing;
 
 #[cfg(feature = "crypto")]
 pub mod crypto;
 
-use mio::net::TcpStream;
-use std::io::Write;
+use delivery::{Outgoing, OutgoingQueue, PartialWrite};
+use mio::{net::TcpStream, Events, Poll};
+use packet::{serialize_packet_with, WriteFormat};
+use sequence::SequenceCounter;
+use std::{
+    io::{self, ErrorKind, Write},
+    time::{Duration, Instant, SystemTime},
+};
 
-pub use client::{Client, ClientEvent};
+pub use bytes::Bytes;
+pub use client::{Client, ClientEvent, ClientItem};
+#[cfg(feature = "json")]
+pub use codec::JsonCodec;
+#[cfg(feature = "msgpack")]
+pub use codec::MessagePackCodec;
+pub use codec::{BincodeCodec, Codec};
+#[cfg(feature = "compression")]
+pub use compression::Compression;
+pub use config::{
+    ClientConfig, RateLimitPolicy, ReconnectPolicy, ServerConfig, SlowConsumerPolicy,
+    DEFAULT_CONNECTION_LIMIT, DEFAULT_HANDSHAKE_TIMEOUT, DEFAULT_KICK_DRAIN_TICKS,
+    DEFAULT_PING_TIMEOUT, DEFAULT_READ_BUDGET, DEFAULT_SHUTDOWN_DRAIN_TICKS, DEFAULT_TICK_TIMEOUT,
+};
+pub use control::{
+    HEARTBEAT_PACKET_ID, HELLO_ACK_PACKET_ID, HELLO_PACKET_ID, KICK_PACKET_ID, PING_PACKET_ID,
+    PONG_PACKET_ID, SERVER_FULL_PACKET_ID, SHUTDOWN_PACKET_ID,
+};
+pub use delivery::{MessageId, Priority};
+pub use diagnostics::{ServerDiagnostics, DIAGNOSTICS_WINDOW};
+pub use disconnect::DisconnectReason;
+pub use dispatch::{Dispatched, PacketRegistry};
 pub use error::{Error, Result};
+pub use fragment::{DEFAULT_MAX_REASSEMBLY_SIZE, FRAGMENT_HEADER_SIZE, FRAGMENT_PACKET_ID};
+pub use handler::{ClientHandler, ServerHandler, Wakeup};
+pub use health::TcpHealth;
 pub use mio::Token;
-pub use server::{Server, ServerEvent};
+pub use packet::{EmptyPacket, Endianness, Framing, PacketInfo, RawPacket};
+pub use packet_log::{PacketDirection, PacketLogEntry, PacketLogger};
+pub use protocol::{Protocol, RawProtocol};
+pub use relay::{RelayEnvelope, RelayPolicy, RELAY_PACKET_ID};
+pub use schedule::ScheduledId;
+pub use scheduler::{TickInfo, TickScheduler, TickStats, DEFAULT_MAX_CATCH_UP};
+pub use sender::PacketSender;
+pub use server::{RejectReason, Server, ServerEvent, ServerItem};
+pub use slab::ConnectionId;
+pub use stats::{
+    AcceptState, ConnectionSnapshot, ConnectionStats, ServerSnapshot, ServerStats, ShutdownSummary,
+    TrafficStats,
+};
+
+/// How long closing a connection may spend flushing queued packets before giving up on them.
+pub(crate) const CLOSE_FLUSH_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// The longest `run` blocks in poll, which bounds how late it notices timers and a stop flag set without a wakeup.
+pub(crate) const RUN_POLL_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// TimedEvent
+/// An event along with when grubbnet observed it, which can be a while before the application gets around to handling it.
+pub struct TimedEvent<E> {
+    pub event: E,
+    /// When the event was generated during the tick. Use this for measuring latency.
+    pub at: Instant,
+    /// The wall clock time at the same moment, for logging.
+    pub time: SystemTime,
+}
+
+impl<E> TimedEvent<E> {
+    pub(crate) fn now(event: E) -> Self {
+        TimedEvent {
+            event,
+            at: Instant::now(),
+            time: SystemTime::now(),
+        }
+    }
+}
+
+/// Strip the timestamps off of some events.
+pub(crate) fn into_events<E>(timed: Vec<TimedEvent<E>>) -> Vec<E> {
+    timed.into_iter().map(|e| e.event).collect()
+}
+
+/// Wait for events for up to `timeout`, or until there are some if it's `None`.
+/// A signal interrupting the wait isn't an error, so the poll is retried for whatever is left of the timeout.
+pub(crate) fn poll_events(
+    poll: &mut Poll,
+    events: &mut Events,
+    timeout: Option<Duration>,
+) -> io::Result<()> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        match poll.poll(events, remaining) {
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            result => return result,
+        }
+    }
+}
 
 pub enum PacketRecipient {
     All,
-    Single(Token),
-    Exclude(Token),
-    ExcludeMany(Vec<Token>),
-    Include(Vec<Token>),
+    Single(ConnectionId),
+    Exclude(ConnectionId),
+    ExcludeMany(Vec<ConnectionId>),
+    Include(Vec<ConnectionId>),
+    /// Every connection the predicate returns true for, like everyone in one room, without collecting their ids first.
+    /// It's asked about each connection when the packet is queued.
+    Where(Box<dyn Fn(ConnectionId) -> bool + Send>),
+}
+
+impl PacketRecipient {
+    /// Every connection `predicate` returns true for. Shorthand for boxing it into `PacketRecipient::Where`.
+    pub fn filter(predicate: impl Fn(ConnectionId) -> bool + Send + 'static) -> Self {
+        PacketRecipient::Where(Box::new(predicate))
+    }
 }
 
 /// Send some bytes to a socket.
+/// Partial writes pick up where the last one left off, and interrupted writes are retried.
 /// Returns the number of bytes sent, or an `Error`.
-pub fn send_bytes(socket: &mut TcpStream, buffer: &[u8]) -> Result<usize> {
-    let mut len = buffer.len();
-    if len == 0 {
+pub fn send_bytes<W: Write>(socket: &mut W, buffer: &[u8]) -> Result<usize> {
+    if buffer.is_empty() {
         return Err(Error::InvalidData);
     }
 
     // Keep sending until we've sent the entire buffer
-    while len > 0 {
-        match socket.write(buffer) {
-            Ok(sent_bytes) => {
-                len -= sent_bytes;
-            }
-            Err(_) => {
-                return Err(Error::FailedToSendBytes);
+    let mut written = 0;
+    while written < buffer.len() {
+        match socket.write(&buffer[written..]) {
+            // The socket won't take any more, so trying again would spin forever
+            Ok(0) => return Err(Error::FailedToSendBytes),
+            Ok(sent_bytes) => written += sent_bytes,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(_) => return Err(Error::FailedToSendBytes),
+        }
+    }
+
+    Ok(written)
+}
+
+/// Write queued packets to a socket for as long as it accepts them without blocking, starting with the rest of the
+/// packet that was partway out, if there was one.
+/// Stops at the first error, or once `deadline` has passed. `on_serialized` sees each packet's id, sequence number and
+/// serialized body before it's written.
+/// Returns the tracked messages that were written, and how many packets were written. Any others are either still in
+/// `packets`, or were lost to an error.
+pub(crate) fn flush_packets<F>(
+    socket: &mut TcpStream,
+    unsent: Option<PartialWrite>,
+    packets: &mut OutgoingQueue,
+    format: &WriteFormat,
+    sequence: &mut SequenceCounter,
+    deadline: Instant,
+    mut on_serialized: F,
+) -> (Vec<MessageId>, usize)
+where
+    F: FnMut(u8, Option<u32>, &[u8]),
+{
+    let mut delivered = Vec::new();
+    let mut written = 0;
+
+    // Anything written after half a packet would be garbled, so give up if the rest of it doesn't fit
+    if let Some(mut unsent) = unsent {
+        if !matches!(unsent.write_to(socket), Ok(true)) {
+            return (delivered, written);
+        }
+
+        written += 1;
+        delivered.extend(unsent.message);
+    }
+
+    while Instant::now() < deadline {
+        let Outgoing {
+            packet,
+            message,
+            channel,
+            queued_at,
+            ..
+        } = match packets.pop_front() {
+            Some(p) => p,
+            None => break,
+        };
+
+        // The connection is closing, so there's nobody left to report a failure to
+        let packet_id = packet.id();
+        let number = sequence.peek();
+        let serialized =
+            serialize_packet_with(&packet, format, number, channel, queued_at, |body| {
+                on_serialized(packet_id, number, body)
+            });
+        let data = match serialized {
+            Ok(d) => d,
+            Err(e) => {
+                eprintln!("Failed to serialize packet while closing! {}", e);
+                continue;
             }
+        };
+
+        if send_bytes(socket, &data).is_err() {
+            break;
         }
+
+        sequence.advance();
+        written += 1;
+        delivered.extend(message);
     }
 
-    Ok(buffer.len())
+    (delivered, written)
+}
+
+/// Remove queued packets whose id matches `predicate`, keeping the rest in order.
+/// Grubbnet's own control packets are always kept. Tracked messages that are removed are added to `cancelled`.
+/// Returns the number of packets removed.
+pub(crate) fn cancel_queued<F>(
+    queue: &mut OutgoingQueue,
+    predicate: F,
+    cancelled: &mut Vec<MessageId>,
+) -> usize
+where
+    F: Fn(u8) -> bool,
+{
+    let len = queue.len();
+    queue.retain(|outgoing| {
+        let id = outgoing.packet.id();
+        let keep = control::is_control_packet(id) || !predicate(id);
+        if !keep {
+            cancelled.extend(outgoing.message);
+        }
+
+        keep
+    });
+
+    len - queue.len()
 }
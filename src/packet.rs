@@ -2,21 +2,318 @@ extern crate byteorder;
 extern crate mio;
 
 use crate::buffer::NetworkBuffer;
+use crate::codec::{BincodeCodec, Codec};
+use crate::compression::CompressionSettings;
+use crate::delivery::Payload;
+use crate::fragment::write_fragments;
+use crate::relay::RELAY_PACKET_ID;
 use crate::Error;
-use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use bincode::Options;
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
+use bytes::Bytes;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::any::Any;
 use std::io::Cursor;
+use std::marker::PhantomData;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-pub const PACKET_HEADER_SIZE: usize = 3; // 2 bytes for size, 1 byte for id
+// Protocol note: headers grew a flags byte after the id, and then a check byte after that, so peers from before either
+// can't talk to peers from after it.
+pub const PACKET_HEADER_SIZE: usize = 5; // 2 bytes for size, 1 byte for id, 1 byte for flags, 1 check byte
 pub const MAX_PACKET_BODY_SIZE: usize = 8192;
 pub const MAX_PACKET_SIZE: usize = PACKET_HEADER_SIZE + MAX_PACKET_BODY_SIZE;
 
+pub const EXTENDED_PACKET_HEADER_SIZE: usize = 7; // 4 bytes for size, 1 byte for id, 1 byte for flags, 1 check byte
+/// The largest packet body accepted in extended framing, unless configured otherwise.
+pub const DEFAULT_EXTENDED_BODY_SIZE: usize = 1024 * 1024;
+
+/// Size of the sequence number that follows the header of a sequenced packet.
+pub const SEQUENCE_SIZE: usize = 4;
+
+/// Size of the send timestamp that follows the header (and sequence number, if there is one) of a stamped packet.
+pub const TIMESTAMP_SIZE: usize = 8;
+
+/// Size of the channel number that follows the header (and sequence number and timestamp, if there are any) of a
+/// packet sent on a channel other than `DEFAULT_CHANNEL`.
+pub const CHANNEL_SIZE: usize = 1;
+
+/// The channel packets are sent on unless they're sent with `send_on_channel`. Packets on it don't carry a channel
+/// number, so they look the same as they did before channels existed.
+pub const DEFAULT_CHANNEL: u8 = 0;
+
+/// The first of the packet ids reserved for grubbnet's own packets. Everything from here to 0xFF belongs to grubbnet:
+///  - 0xF0 `RELAY_PACKET_ID`, relay envelopes
+///  - 0xF1 `HEARTBEAT_PACKET_ID`, heartbeats
+///  - 0xF2 `PING_PACKET_ID` and 0xF3 `PONG_PACKET_ID`, round trip time probes
+///  - 0xF4 `FRAGMENT_PACKET_ID`, pieces of a body too big for one packet
+///  - 0xF5 `HELLO_PACKET_ID` and 0xF6 `HELLO_ACK_PACKET_ID`, the protocol version handshake
+///  - 0xF7 `KICK_PACKET_ID`, the reason given for a kick
+///  - 0xF8 `SHUTDOWN_PACKET_ID`, the notice that the server is shutting down
+///  - 0xF9 `SERVER_FULL_PACKET_ID`, the notice that the server has no room for a connection
+///  - 0xFA to 0xFF, unassigned
+///
+/// Applications can't send packets with these ids, besides relay envelopes through `RelayEnvelope`. Packets that
+/// arrive with them are handled by grubbnet, or dropped, and never reach the incoming packet queues. Again, relay
+/// envelopes are the exception, since they carry application payloads.
+pub const RESERVED_PACKET_IDS_START: u8 = 0xF0;
+
+/// Whether a packet id is reserved for grubbnet's own packets. See `RESERVED_PACKET_IDS_START`.
+pub fn is_reserved_packet_id(id: u8) -> bool {
+    id >= RESERVED_PACKET_IDS_START
+}
+
+/// Whether the application may send, and be handed, packets with this id.
+pub(crate) fn is_application_packet_id(id: u8) -> bool {
+    !is_reserved_packet_id(id) || id == RELAY_PACKET_ID
+}
+
+/// Header flag set when a sequence number follows the header.
+pub const FLAG_SEQUENCE: u8 = 0b0000_0001;
+/// Header flag set when the body was compressed before it was sent.
+pub const FLAG_COMPRESSED: u8 = 0b0000_0010;
+/// Header flag set when a send timestamp follows the header.
+pub const FLAG_TIMESTAMP: u8 = 0b0000_0100;
+/// Header flag set when a channel number follows the header.
+pub const FLAG_CHANNEL: u8 = 0b0000_1000;
+/// Every header flag this version knows about. Any other bit set in a received header is a protocol error, unless the
+/// connection is configured to ignore unknown flags.
+pub const KNOWN_FLAGS: u8 = FLAG_SEQUENCE | FLAG_COMPRESSED | FLAG_TIMESTAMP | FLAG_CHANNEL;
+
+/// Framing
+/// How the length of each packet body is written in its header. Both ends of a connection have to use the same one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// A 2 byte length, with bodies of up to `MAX_PACKET_BODY_SIZE` bytes.
+    #[default]
+    Standard,
+    /// A 4 byte length, for bodies too big for standard framing, like world snapshots or assets.
+    Extended,
+}
+
+impl Framing {
+    /// Get the size of a packet header, in bytes.
+    pub fn header_size(self) -> usize {
+        match self {
+            Framing::Standard => PACKET_HEADER_SIZE,
+            Framing::Extended => EXTENDED_PACKET_HEADER_SIZE,
+        }
+    }
+
+    /// Get the largest body size a connection may be configured to accept.
+    pub fn max_body_size(self) -> usize {
+        match self {
+            Framing::Standard => MAX_PACKET_BODY_SIZE,
+            Framing::Extended => u32::MAX as usize,
+        }
+    }
+
+    /// Get the largest body size accepted when it isn't configured.
+    pub fn default_body_size(self) -> usize {
+        match self {
+            Framing::Standard => MAX_PACKET_BODY_SIZE,
+            Framing::Extended => DEFAULT_EXTENDED_BODY_SIZE,
+        }
+    }
+}
+
+/// Endianness
+/// The byte order of the size and sequence number fields in packet headers. Both ends of a connection have to use the
+/// same one. Only the headers change: grubbnet's own packet bodies are always big endian, and application bodies are
+/// in whatever order they were serialized in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Endianness {
+    /// Network byte order, which every peer uses unless configured otherwise.
+    #[default]
+    Big,
+    /// For talking to peers that write their headers little endian, like some older C servers.
+    Little,
+}
+
+impl Endianness {
+    fn write_u16(self, data: &mut Vec<u8>, value: u16) -> std::io::Result<()> {
+        match self {
+            Endianness::Big => data.write_u16::<BigEndian>(value),
+            Endianness::Little => data.write_u16::<LittleEndian>(value),
+        }
+    }
+
+    fn write_u32(self, data: &mut Vec<u8>, value: u32) -> std::io::Result<()> {
+        match self {
+            Endianness::Big => data.write_u32::<BigEndian>(value),
+            Endianness::Little => data.write_u32::<LittleEndian>(value),
+        }
+    }
+
+    fn write_u64(self, data: &mut Vec<u8>, value: u64) -> std::io::Result<()> {
+        match self {
+            Endianness::Big => data.write_u64::<BigEndian>(value),
+            Endianness::Little => data.write_u64::<LittleEndian>(value),
+        }
+    }
+
+    fn read_u16(self, reader: &mut impl ReadBytesExt) -> std::io::Result<u16> {
+        match self {
+            Endianness::Big => reader.read_u16::<BigEndian>(),
+            Endianness::Little => reader.read_u16::<LittleEndian>(),
+        }
+    }
+
+    fn read_u32(self, reader: &mut impl ReadBytesExt) -> std::io::Result<u32> {
+        match self {
+            Endianness::Big => reader.read_u32::<BigEndian>(),
+            Endianness::Little => reader.read_u32::<LittleEndian>(),
+        }
+    }
+
+    fn read_u64(self, reader: &mut impl ReadBytesExt) -> std::io::Result<u64> {
+        match self {
+            Endianness::Big => reader.read_u64::<BigEndian>(),
+            Endianness::Little => reader.read_u64::<LittleEndian>(),
+        }
+    }
+
+    /// Get the other byte order.
+    pub(crate) fn swapped(self) -> Endianness {
+        match self {
+            Endianness::Big => Endianness::Little,
+            Endianness::Little => Endianness::Big,
+        }
+    }
+}
+
 /// PacketHeader
-/// The header included with every packet. Contains the packet body size and packet id.
-#[derive(Clone)]
+/// The header included with every packet. Contains the packet body size, packet id and flags, the sequence number if
+/// the sender has sequence numbers enabled, the send timestamp if it has those enabled, and the channel if the packet
+/// wasn't sent on `DEFAULT_CHANNEL`.
+/// `FLAG_COMPRESSED` is set if the body was compressed on the wire. Received bodies are decompressed before they're
+/// handed out, and `size` is updated to match.
+/// Headers also derive serde's traits, so tools can log or store the headers they decode.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PacketHeader {
-    pub size: u16,
+    pub size: u32,
     pub id: u8,
+    pub flags: u8,
+    pub sequence: Option<u32>,
+    /// When the sender queued the packet, in milliseconds since the Unix epoch by the sender's clock.
+    pub timestamp: Option<u64>,
+    /// The channel the packet was sent on, if it wasn't `DEFAULT_CHANNEL`.
+    pub channel: Option<u8>,
+}
+
+impl PacketHeader {
+    /// Whether the body was compressed on the wire.
+    pub fn is_compressed(&self) -> bool {
+        self.flags & FLAG_COMPRESSED != 0
+    }
+
+    /// Get the flags set in this header that this version doesn't know about.
+    pub fn unknown_flags(&self) -> u8 {
+        self.flags & !KNOWN_FLAGS
+    }
+
+    /// Get the channel the packet was sent on.
+    pub fn channel(&self) -> u8 {
+        self.channel.unwrap_or(DEFAULT_CHANNEL)
+    }
+
+    /// Get the size of this header on the wire, including its sequence number, timestamp and channel if it has them.
+    pub fn header_size(&self, framing: Framing) -> usize {
+        trailer_size(self.flags) + framing.header_size()
+    }
+
+    /// Encode this header the way a peer with the default config writes it: standard framing and big endian.
+    /// That's the 2 byte body size, the id, the flags and the check byte, followed by the 4 byte sequence number if `FLAG_SEQUENCE`
+    /// is set, the 8 byte timestamp if `FLAG_TIMESTAMP` is, and the 1 byte channel if `FLAG_CHANNEL` is, so the
+    /// result is `PACKET_HEADER_SIZE` bytes for a header with none of them.
+    /// Returns `InvalidData` if the size doesn't fit in 16 bits. Use `write_header` for other framings.
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::with_capacity(self.header_size(Framing::Standard));
+        write_header(&mut data, self, Framing::Standard, Endianness::Big)?;
+
+        Ok(data)
+    }
+
+    /// Decode a header written by `encode`, from the front of `bytes`. Anything after the header is ignored.
+    /// Returns an `Io` error if `bytes` is too short to hold it. Use `read_header` for other framings.
+    pub fn decode(bytes: &[u8]) -> Result<PacketHeader, Error> {
+        read_header(bytes, Framing::Standard, Endianness::Big)
+    }
+}
+
+/// Get the size of the fields that follow a header with these flags.
+fn trailer_size(flags: u8) -> usize {
+    let mut size = 0;
+    if flags & FLAG_SEQUENCE != 0 {
+        size += SEQUENCE_SIZE;
+    }
+    if flags & FLAG_TIMESTAMP != 0 {
+        size += TIMESTAMP_SIZE;
+    }
+    if flags & FLAG_CHANNEL != 0 {
+        size += CHANNEL_SIZE;
+    }
+
+    size
+}
+
+/// WriteFormat
+/// How a connection writes its packets, taken from its config.
+#[derive(Clone, Copy)]
+pub(crate) struct WriteFormat {
+    pub(crate) framing: Framing,
+    pub(crate) endianness: Endianness,
+    pub(crate) compression: CompressionSettings,
+    /// Whether packets are stamped with the time they were queued.
+    pub(crate) timestamps: bool,
+    /// The biggest body sent as one packet. Bigger bodies are split into fragments of up to this size.
+    pub(crate) max_body_size: usize,
+    /// The biggest body sent at all, whether it's split into fragments or not.
+    pub(crate) max_total_size: usize,
+    /// How many channels packets may be sent on, counting up from `DEFAULT_CHANNEL`.
+    pub(crate) channels: u8,
+}
+
+impl WriteFormat {
+    /// Big endian, uncompressed and without timestamps, with bodies fragmented at the framing's default body size and
+    /// only the default channel.
+    pub(crate) fn new(framing: Framing) -> Self {
+        WriteFormat {
+            framing,
+            endianness: Endianness::Big,
+            compression: CompressionSettings::default(),
+            timestamps: false,
+            max_body_size: framing.default_body_size(),
+            max_total_size: u32::MAX as usize,
+            channels: 1,
+        }
+    }
+
+    /// Get the biggest body that can be sent at all, whether it's split into fragments or not.
+    pub(crate) fn max_size(&self) -> usize {
+        self.max_body_size.max(self.max_total_size)
+    }
+
+    /// Check that a body of `size` bytes with `id` can be sent on `channel`, before it's queued.
+    /// Returns `ReservedPacketId` if the id belongs to grubbnet, `UnknownChannel` if the channel isn't configured, or
+    /// `PacketTooLarge` if the body is over `max_size`.
+    pub(crate) fn check_sendable(&self, id: u8, channel: u8, size: usize) -> Result<(), Error> {
+        if !is_application_packet_id(id) {
+            return Err(Error::ReservedPacketId(id));
+        }
+
+        if channel >= self.channels {
+            return Err(Error::UnknownChannel(channel));
+        }
+
+        if size > self.max_size() {
+            return Err(Error::PacketTooLarge {
+                size,
+                max: self.max_size(),
+            });
+        }
+
+        Ok(())
+    }
 }
 
 /// PacketBody
@@ -37,55 +334,590 @@ impl Clone for Box<dyn PacketBody> {
     }
 }
 
+/// HasPacketId
+/// Gives a type the packet id it's always sent with, so it can be wrapped in a `SerdePacket` or read with
+/// `Packet::deserialize_body`. This is separate from `PacketBody`, which can't have an associated const and still be
+/// boxed, so `PacketBody` impls for these types can return `Self::PACKET_ID` from `id`.
+pub trait HasPacketId {
+    const PACKET_ID: u8;
+}
+
+impl<T: HasPacketId, C> HasPacketId for SerdePacket<T, C> {
+    const PACKET_ID: u8 = T::PACKET_ID;
+}
+
+/// The canonical bincode configuration for packet bodies: big endian, fixed size integers, and trailing bytes allowed.
+/// `BincodeCodec` encodes with it, and so do the examples' hand-written packets, so the bytes on the wire are
+/// identical. Big endian matches the headers in the default `Endianness`, so a whole packet reads the same way.
+pub fn wire_config() -> impl bincode::Options {
+    bincode::DefaultOptions::new()
+        .with_big_endian()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+}
+
+/// SerdePacket
+/// A `PacketBody` for any serde type with a packet id, encoded with the codec `C`, so each message doesn't need a
+/// `PacketBody` impl of its own. The codec defaults to `BincodeCodec`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SerdePacket<T, C = BincodeCodec>(pub T, PhantomData<C>);
+
+impl<T> SerdePacket<T> {
+    pub fn new(value: T) -> Self {
+        SerdePacket(value, PhantomData)
+    }
+}
+
+impl<T, C> SerdePacket<T, C> {
+    /// Wrap `value` to be encoded with a codec other than the default, like `SerdePacket::<_, JsonCodec>::with_codec`.
+    pub fn with_codec(value: T) -> Self {
+        SerdePacket(value, PhantomData)
+    }
+
+    /// Unwrap the value.
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T, C> PacketBody for SerdePacket<T, C>
+where
+    T: Serialize + DeserializeOwned + HasPacketId + Clone + Send + Sync + 'static,
+    C: Codec + Clone,
+{
+    fn box_clone(&self) -> Box<dyn PacketBody> {
+        Box::new((*self).clone())
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>, Error> {
+        C::encode(&self.0)
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self, Error> {
+        C::decode(data).map(SerdePacket::with_codec)
+    }
+
+    fn id(&self) -> u8 {
+        T::PACKET_ID
+    }
+}
+
+/// RawPacket
+/// A `PacketBody` for bytes that are already serialized, like ones relayed from another server or written by a program
+/// in another language. The body is sent as it is.
+/// Raw packets can't be deserialized, since the id isn't part of the body. Make one from a received `Packet` with
+/// `RawPacket::from` instead, to forward it on without decoding it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RawPacket {
+    pub id: u8,
+    pub body: Vec<u8>,
+}
+
+impl RawPacket {
+    pub fn new(id: u8, body: Vec<u8>) -> Self {
+        RawPacket { id, body }
+    }
+}
+
+impl From<Packet> for RawPacket {
+    fn from(packet: Packet) -> Self {
+        RawPacket::new(packet.header.id, packet.body.into())
+    }
+}
+
+impl PacketBody for RawPacket {
+    fn box_clone(&self) -> Box<dyn PacketBody> {
+        Box::new((*self).clone())
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.body.clone())
+    }
+
+    fn deserialize(_data: &[u8]) -> Result<Self, Error> {
+        Err(Error::InvalidData)
+    }
+
+    fn id(&self) -> u8 {
+        self.id
+    }
+}
+
+/// EmptyPacket
+/// A packet with no body, for pure signals like ready or logout where the id says everything.
+/// Bodies of 0 bytes are legal, so these go out as a bare header and arrive as a `Packet` with an empty body. A unit
+/// struct encoded with `wire_config` is empty too, so a `SerdePacket` of one works the same way.
+/// Like `RawPacket`, empty packets can't be deserialized since the id isn't part of the body. Match on the received
+/// packet's `header.id` instead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct EmptyPacket(pub u8);
+
+impl PacketBody for EmptyPacket {
+    fn box_clone(&self) -> Box<dyn PacketBody> {
+        Box::new(*self)
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>, Error> {
+        Ok(Vec::new())
+    }
+
+    fn deserialize(_data: &[u8]) -> Result<Self, Error> {
+        Err(Error::InvalidData)
+    }
+
+    fn id(&self) -> u8 {
+        self.0
+    }
+}
+
 /// Packet
 /// A header and a variable size body.
-#[derive(Clone)]
+/// The body is a `Bytes`, which derefs to a byte slice. Received bodies may share one allocation with the other
+/// packets read alongside them, so cloning a packet or slicing its body never copies. Use `to_vec` to get a body of
+/// your own.
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Packet {
     pub header: PacketHeader,
-    pub body: Vec<u8>,
+    pub body: Bytes,
+}
+
+impl Packet {
+    /// Deserialize the body as `T`, after checking the packet has the `expected` id.
+    /// Returns `WrongPacketId` if it doesn't, or `InvalidPacketBody` if the body doesn't deserialize.
+    pub fn body_as<T: PacketBody>(&self, expected: u8) -> Result<T, Error> {
+        if self.header.id != expected {
+            return Err(Error::WrongPacketId {
+                expected,
+                actual: self.header.id,
+            });
+        }
+
+        T::deserialize(&self.body).map_err(|e| Error::InvalidPacketBody {
+            id: self.header.id,
+            error: Box::new(e),
+        })
+    }
+
+    /// Same as `body_as`, with the id taken from `T`'s `HasPacketId` impl.
+    pub fn deserialize_body<T: PacketBody + HasPacketId>(&self) -> Result<T, Error> {
+        self.body_as(T::PACKET_ID)
+    }
+}
+
+/// PacketInfo
+/// A summary of a received packet, carried by the `ReceivedPacket` events so they can be told apart without draining
+/// the packets themselves.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PacketInfo {
+    pub id: u8,
+    pub body_len: usize,
+    /// The size of the header and body together. Packets that were fragmented or compressed are counted as they are
+    /// after being put back together or inflated.
+    pub wire_size: usize,
 }
 
+impl PacketInfo {
+    pub(crate) fn new(packet: &Packet, framing: Framing) -> Self {
+        PacketInfo {
+            id: packet.header.id,
+            body_len: packet.body.len(),
+            wire_size: packet.header.header_size(framing) + packet.body.len(),
+        }
+    }
+}
+
+/// Serialize a packet with standard framing. See `serialize_framed_packet`.
 pub fn serialize_packet(body: Box<dyn PacketBody>) -> Result<Vec<u8>, Error> {
+    serialize_framed_packet(body, Framing::Standard)
+}
+
+/// Serialize a packet with the given framing.
+/// Bodies bigger than the framing's `default_body_size` are split into fragments, which the receiving side puts back
+/// together, so the returned bytes may hold several packets.
+/// Returns `PacketTooLarge` if the body is too big to send at all.
+pub fn serialize_framed_packet(
+    body: Box<dyn PacketBody>,
+    framing: Framing,
+) -> Result<Vec<u8>, Error> {
+    serialize_packet_with(
+        &Payload::Packet(body),
+        &WriteFormat::new(framing),
+        None,
+        DEFAULT_CHANNEL,
+        Instant::now(),
+        |_| {},
+    )
+}
+
+/// Same as `serialize_framed_packet`, but for a queued payload written the way `format` says, stamped with `sequence`
+/// if there is one and with `channel` if it isn't the default, and with the serialized body handed to `inspect` before
+/// it's compressed. If `format` has timestamps enabled, the packet is stamped with `queued_at`.
+/// Bodies are compressed before they're fragmented, and every fragment carries the same sequence number, timestamp and
+/// channel.
+/// Returns `PacketTooLarge` if the compressed body is bigger than `format` allows.
+pub(crate) fn serialize_packet_with<F>(
+    payload: &Payload,
+    format: &WriteFormat,
+    sequence: Option<u32>,
+    channel: u8,
+    queued_at: Instant,
+    inspect: F,
+) -> Result<Vec<u8>, Error>
+where
+    F: FnOnce(&[u8]),
+{
     // Serialize the packet body first so we know the size
-    let mut body_data: Vec<u8> = body.serialize()?;
+    let body_data = payload.serialize()?;
+    inspect(&body_data);
+    let (body_data, compressed) = format.compression.compress(body_data)?;
 
-    // Create payload and write header (body size and id)
-    let mut data: Vec<u8> = Vec::new();
-    data.write_u16::<NetworkEndian>(body_data.len() as u16)?;
-    data.write_u8(body.id())?;
+    let timestamp = format.timestamps.then(|| unix_millis(queued_at));
+    let channel = Some(channel).filter(|&channel| channel != DEFAULT_CHANNEL);
+    let mut flags = 0;
+    if sequence.is_some() {
+        flags |= FLAG_SEQUENCE;
+    }
+    if compressed {
+        flags |= FLAG_COMPRESSED;
+    }
+    if timestamp.is_some() {
+        flags |= FLAG_TIMESTAMP;
+    }
+    if channel.is_some() {
+        flags |= FLAG_CHANNEL;
+    }
 
-    // TODO (Declan, 4/26/2019)
-    // Need to add some sort of magic number to the header to make sure the packet was meant for us
+    let max = format.max_size();
+    if body_data.len() > max {
+        return Err(Error::PacketTooLarge {
+            size: body_data.len(),
+            max,
+        });
+    }
 
-    // Combine the body and header
-    data.append(&mut body_data);
+    let framing = format.framing;
+    let mut header = PacketHeader {
+        size: 0,
+        id: payload.id(),
+        flags,
+        sequence,
+        timestamp,
+        channel,
+    };
+    let mut data: Vec<u8> = Vec::with_capacity(header.header_size(framing) + body_data.len());
+    if body_data.len() > format.max_body_size {
+        write_fragments(&mut data, format, &header, &body_data)?;
+    } else {
+        // Combine the body and header
+        header.size = body_data.len() as u32;
+        write_header(&mut data, &header, framing, format.endianness)?;
+        data.extend_from_slice(&body_data);
+    }
 
     Ok(data)
 }
 
-pub fn deserialize_packet_header(buffer: &mut NetworkBuffer) -> Result<PacketHeader, Error> {
-    let mut reader = Cursor::new(&buffer.data[..]);
+/// Get the wall clock time `at` happened, in milliseconds since the Unix epoch.
+pub(crate) fn unix_millis(at: Instant) -> u64 {
+    let now = SystemTime::now();
+    now.checked_sub(at.elapsed())
+        .unwrap_or(now)
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or(0)
+}
 
-    // Read body size
-    let body_size = reader.read_u16::<NetworkEndian>()? as usize;
+/// Get how long ago a packet stamped with `timestamp` was queued by the peer, by this machine's clock.
+/// Clocks that disagree can put the stamp in the future, which counts as no time at all.
+pub(crate) fn time_since(timestamp: u64) -> Duration {
+    Duration::from_millis(unix_millis(Instant::now()).saturating_sub(timestamp))
+}
 
-    // If the packet is too big, kick the client so we have some basic protection from being overloaded
-    if body_size >= MAX_PACKET_BODY_SIZE {
-        eprintln!(
-            "Packet body is {} bytes, but max body size is ({} bytes)!",
-            body_size, MAX_PACKET_BODY_SIZE
-        );
+/// Get the check byte for the body size, id and flags at the front of a header, as they're written on the wire.
+/// It's a CRC-8 (polynomial 0x07, starting from 0), which catches any single corrupted byte, so a damaged size is
+/// caught before it's trusted instead of throwing off the framing for the rest of the connection.
+pub fn header_check(bytes: &[u8]) -> u8 {
+    bytes
+        .iter()
+        .fold(0, |crc, &byte| HEADER_CHECK_TABLE[(crc ^ byte) as usize])
+}
 
-        return Err(Error::InvalidData);
+/// The CRC of every byte on its own, so `header_check` takes one lookup per byte instead of a branch per bit.
+const HEADER_CHECK_TABLE: [u8; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+};
+
+/// Write `header` the way it goes on the wire: the body size, id and flags, the check byte for them, then the sequence
+/// number, timestamp and channel if there are any.
+/// The flags are written as they are, so set `FLAG_SEQUENCE`, `FLAG_TIMESTAMP` and `FLAG_CHANNEL` to match
+/// `sequence`, `timestamp` and `channel`.
+/// Returns `InvalidData` if the size doesn't fit in the framing's length field.
+pub fn write_header(
+    data: &mut Vec<u8>,
+    header: &PacketHeader,
+    framing: Framing,
+    endianness: Endianness,
+) -> Result<(), Error> {
+    let start = data.len();
+    match framing {
+        Framing::Standard if header.size <= u16::MAX as u32 => {
+            endianness.write_u16(data, header.size as u16)?
+        }
+        Framing::Extended => endianness.write_u32(data, header.size)?,
+        _ => return Err(Error::InvalidData),
     }
+    data.write_u8(header.id)?;
+    data.write_u8(header.flags)?;
+    let check = header_check(&data[start..]);
+    data.write_u8(check)?;
 
-    // Read packet id
-    let packet_id = reader.read_u8()?;
+    if let Some(sequence) = header.sequence {
+        endianness.write_u32(data, sequence)?;
+    }
+
+    if let Some(timestamp) = header.timestamp {
+        endianness.write_u64(data, timestamp)?;
+    }
+
+    if let Some(channel) = header.channel {
+        data.write_u8(channel)?;
+    }
+
+    Ok(())
+}
+
+/// Read the header at the front of `bytes`, including its sequence number if `FLAG_SEQUENCE` is set, its timestamp
+/// if `FLAG_TIMESTAMP` is, and its channel if `FLAG_CHANNEL` is.
+/// Only the check byte is checked, which is a `HeaderChecksumMismatch` error if it's wrong, and there being enough
+/// bytes, which is an `Io` error otherwise.
+pub fn read_header(
+    bytes: &[u8],
+    framing: Framing,
+    endianness: Endianness,
+) -> Result<PacketHeader, Error> {
+    let header_size = framing.header_size();
+    if bytes.len() >= header_size {
+        check_header(bytes, framing, bytes[header_size - 1])?;
+    }
+
+    // Too few bytes fail in here instead
+    decode_header(bytes, framing, endianness)
+}
+
+/// Read the header at the front of `bytes` without looking at its check byte, for headers that have already been
+/// checked.
+#[inline]
+fn decode_header(
+    bytes: &[u8],
+    framing: Framing,
+    endianness: Endianness,
+) -> Result<PacketHeader, Error> {
+    let mut reader = Cursor::new(bytes);
+    let size = read_body_size(&mut reader, framing, endianness)?;
+    let id = reader.read_u8()?;
+    let flags = reader.read_u8()?;
+    reader.read_u8()?;
+    let sequence = if flags & FLAG_SEQUENCE != 0 {
+        Some(endianness.read_u32(&mut reader)?)
+    } else {
+        None
+    };
+    let timestamp = if flags & FLAG_TIMESTAMP != 0 {
+        Some(endianness.read_u64(&mut reader)?)
+    } else {
+        None
+    };
+    let channel = if flags & FLAG_CHANNEL != 0 {
+        Some(reader.read_u8()?)
+    } else {
+        None
+    };
+
+    Ok(PacketHeader {
+        size,
+        id,
+        flags,
+        sequence,
+        timestamp,
+        channel,
+    })
+}
+
+/// Make sure `received` is the right check byte for the header at the front of `bytes`.
+#[inline]
+fn check_header(bytes: &[u8], framing: Framing, received: u8) -> Result<(), Error> {
+    let expected = header_check(&bytes[..framing.header_size() - 1]);
+    if received != expected {
+        return Err(Error::HeaderChecksumMismatch { expected, received });
+    }
+
+    Ok(())
+}
 
-    let header = PacketHeader {
-        size: body_size as u16,
-        id: packet_id,
+pub(crate) fn read_body_size(
+    reader: &mut Cursor<&[u8]>,
+    framing: Framing,
+    endianness: Endianness,
+) -> Result<u32, Error> {
+    let size = match framing {
+        Framing::Standard => endianness.read_u16(reader)? as u32,
+        Framing::Extended => endianness.read_u32(reader)?,
     };
 
-    Ok(header)
+    Ok(size)
+}
+
+/// Read the header at the front of `buffer`, without draining it.
+/// Headers advertising a body larger than `max_body_size` are rejected, like in `parse_packets`. Unknown flags are
+/// left on the header for the caller to check.
+/// Returns `Ok(None)` if the whole header hasn't arrived yet, including its sequence number if it has one, so the
+/// caller should wait for more. Headers are read big endian, the same way `PacketHeader::decode` reads them from a
+/// plain slice.
+pub fn deserialize_packet_header(
+    buffer: &mut NetworkBuffer,
+    framing: Framing,
+    max_body_size: usize,
+) -> Result<Option<PacketHeader>, Error> {
+    peek_header(
+        buffer.make_contiguous(),
+        framing,
+        Endianness::Big,
+        max_body_size,
+    )
+}
+
+/// Parses as many complete packets as are available at the front of `buffer`, handing each one to `on_packet`.
+/// The packets are copied out of the buffer together, and their bodies share that one allocation. Draining them only
+/// moves the ring's head.
+/// Headers advertising a body larger than `max_body_size` are rejected. Headers are read big endian.
+/// Returns the number of packets parsed, or an `Error` if the buffer contains an invalid header. Packets parsed
+/// before the invalid header are still handed out and drained; the connection should be closed afterwards since
+/// the framing can't be recovered.
+pub fn parse_packets<F>(
+    buffer: &mut NetworkBuffer,
+    framing: Framing,
+    max_body_size: usize,
+    on_packet: F,
+) -> Result<usize, Error>
+where
+    F: FnMut(Packet),
+{
+    parse_frames(
+        buffer,
+        framing,
+        Endianness::Big,
+        max_body_size,
+        usize::MAX,
+        on_packet,
+    )
+}
+
+/// Same as `parse_packets`, with headers read in `endianness`, stopping after `max_packets` packets and leaving the
+/// rest in the buffer.
+pub(crate) fn parse_frames<F>(
+    buffer: &mut NetworkBuffer,
+    framing: Framing,
+    endianness: Endianness,
+    max_body_size: usize,
+    max_packets: usize,
+    mut on_packet: F,
+) -> Result<usize, Error>
+where
+    F: FnMut(Packet),
+{
+    // Find every complete packet first, so their bodies can be copied out in one go
+    let bytes = buffer.make_contiguous();
+    let mut frames = Vec::new();
+    let mut parsed = 0;
+    let mut error = None;
+    while frames.len() < max_packets {
+        let header = match peek_header(&bytes[parsed..], framing, endianness, max_body_size) {
+            Ok(Some(header)) => header,
+            Ok(None) => break,
+            Err(e) => {
+                error = Some(e);
+                break;
+            }
+        };
+
+        // Now make sure we have enough bytes for the rest of this packet
+        let start = parsed + header.header_size(framing);
+        let end = start + (header.size as usize);
+        if end > bytes.len() {
+            break;
+        }
+
+        frames.push((header, start..end));
+        parsed = end;
+    }
+
+    // Every body shares this one allocation, instead of each getting its own
+    let shared = Bytes::copy_from_slice(&bytes[..parsed]);
+    buffer.drain(parsed)?;
+
+    let count = frames.len();
+    for (header, range) in frames {
+        on_packet(Packet {
+            header,
+            body: shared.slice(range),
+        });
+    }
+
+    match error {
+        Some(e) => Err(e),
+        None => Ok(count),
+    }
+}
+
+/// Read the header at the front of `bytes`, or return `Ok(None)` if they don't hold all of it yet.
+/// Inlined along with what it calls, since `parse_packets` is generic and so is compiled into the caller's crate.
+#[inline]
+pub(crate) fn peek_header(
+    bytes: &[u8],
+    framing: Framing,
+    endianness: Endianness,
+    max_body_size: usize,
+) -> Result<Option<PacketHeader>, Error> {
+    let header_size = framing.header_size();
+    if bytes.len() < header_size {
+        return Ok(None);
+    }
+
+    // Nothing in a damaged header can be trusted, least of all the size
+    check_header(bytes, framing, bytes[header_size - 1])?;
+
+    // If the packet is too big, kick the client so we have some basic protection from being overloaded
+    let body_size = read_body_size(&mut Cursor::new(bytes), framing, endianness)? as usize;
+    if body_size > max_body_size {
+        return Err(Error::PacketTooLarge {
+            size: body_size,
+            max: max_body_size,
+        });
+    }
+
+    // The flags come just before the check byte, and say whether a sequence number, timestamp or channel follows
+    let flags = bytes[header_size - 2];
+    if bytes.len() < header_size + trailer_size(flags) {
+        return Ok(None);
+    }
+
+    decode_header(bytes, framing, endianness).map(Some)
 }
@@ -0,0 +1,81 @@
+use crate::{
+    control::is_control_packet,
+    fragment::FRAGMENT_PACKET_ID,
+    packet::{PacketHeader, FLAG_SEQUENCE},
+    relay::RELAY_PACKET_ID,
+    slab::ConnectionId,
+};
+
+/// A hook that sees every packet sent or received. See `Server::set_packet_logger`.
+pub type PacketLogger = Box<dyn FnMut(PacketLogEntry) + Send>;
+
+/// Which way a logged packet was going.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PacketDirection {
+    Incoming,
+    Outgoing,
+}
+
+/// PacketLogEntry
+/// A packet handed to a packet logger. The header and body are borrowed, so nothing is copied unless the logger does it.
+pub struct PacketLogEntry<'a> {
+    pub direction: PacketDirection,
+    /// The connection the packet came from or went to. Always `None` on a client.
+    pub connection: Option<ConnectionId>,
+    pub header: &'a PacketHeader,
+    pub body: &'a [u8],
+    /// Whether the packet is one of grubbnet's own (heartbeats, pings, relays...) rather than an application packet.
+    pub internal: bool,
+}
+
+/// Hand a received packet to the logger, if there is one.
+pub(crate) fn log_incoming(
+    logger: &mut Option<PacketLogger>,
+    connection: Option<ConnectionId>,
+    header: &PacketHeader,
+    body: &[u8],
+) {
+    if let Some(logger) = logger.as_mut() {
+        logger(PacketLogEntry {
+            direction: PacketDirection::Incoming,
+            connection,
+            header,
+            body,
+            internal: is_internal(header.id),
+        });
+    }
+}
+
+/// Hand a packet's serialized body to the logger, if there is one.
+/// Bodies that get fragmented are logged whole, like they are once reassembled on the other side, and compressed
+/// bodies are logged before they're compressed.
+pub(crate) fn log_outgoing(
+    logger: &mut Option<PacketLogger>,
+    connection: Option<ConnectionId>,
+    id: u8,
+    sequence: Option<u32>,
+    body: &[u8],
+) {
+    if let Some(logger) = logger.as_mut() {
+        let header = PacketHeader {
+            size: body.len() as u32,
+            id,
+            flags: if sequence.is_some() { FLAG_SEQUENCE } else { 0 },
+            sequence,
+            timestamp: None,
+            channel: None,
+        };
+
+        logger(PacketLogEntry {
+            direction: PacketDirection::Outgoing,
+            connection,
+            header: &header,
+            body,
+            internal: is_internal(header.id),
+        });
+    }
+}
+
+fn is_internal(id: u8) -> bool {
+    is_control_packet(id) || id == RELAY_PACKET_ID || id == FRAGMENT_PACKET_ID
+}
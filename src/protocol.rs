@@ -0,0 +1,49 @@
+use crate::{
+    error::{Error, Result},
+    packet::PacketBody,
+};
+
+/// Protocol
+/// The set of messages a typed `Server<P>` or `Client<P>` can send and receive, usually an enum with one variant
+/// per packet. Only messages belonging to the protocol can be sent, and incoming packets are decoded into it
+/// during the tick, so malformed or unknown packets show up as `ProtocolError` events instead of reaching the application.
+pub trait Protocol: Clone + Send + Sync + 'static {
+    /// The packet id this message is sent with.
+    fn id(&self) -> u8;
+
+    /// Serialize this message into a packet body.
+    fn encode(&self) -> Result<Vec<u8>>;
+
+    /// Decode a received packet body with the given id.
+    /// Return `Error::UnknownPacket` for ids that aren't part of the protocol, and `Error::InvalidData` for bodies that
+    /// don't decode.
+    fn decode(id: u8, body: &[u8]) -> Result<Self>;
+}
+
+/// RawProtocol
+/// The untyped protocol `Server` and `Client` use when no other is given.
+/// Any `PacketBody` can be sent, and packets are handed back undecoded through `drain_incoming_packets`.
+pub struct RawProtocol;
+
+/// Lets a protocol message go through the same outgoing queues as any other packet.
+#[derive(Clone)]
+pub(crate) struct Encoded<P>(pub(crate) P);
+
+impl<P: Protocol> PacketBody for Encoded<P> {
+    fn box_clone(&self) -> Box<dyn PacketBody> {
+        Box::new((*self).clone())
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        self.0.encode()
+    }
+
+    fn deserialize(_data: &[u8]) -> Result<Self> {
+        // Decoding needs the packet id, so it always goes through `Protocol::decode` instead
+        Err(Error::InvalidData)
+    }
+
+    fn id(&self) -> u8 {
+        self.0.id()
+    }
+}
@@ -0,0 +1,372 @@
+use crate::{
+    buffer::NetworkBuffer,
+    compression::decompress,
+    control::is_swapped_handshake,
+    error::Error,
+    fragment::{Reassembly, FRAGMENT_PACKET_ID},
+    packet::{parse_frames, peek_header, Endianness, Framing, Packet, PacketHeader},
+};
+use std::{
+    io::{ErrorKind, Read},
+    mem,
+};
+
+/// Bodies at least this big are read from the socket straight into their own allocation, rather than being staged
+/// through the connection's `NetworkBuffer` and copied out of it.
+/// Anything left in the buffer between reads is the start of a smaller packet, so this has to stay well under
+/// `INITIAL_BUFFER_SIZE` for there to always be room for the next read.
+pub(crate) const DIRECT_READ_THRESHOLD: usize = 512;
+
+/// What a connection accepts from its peer, taken from its config.
+#[derive(Clone, Copy)]
+pub(crate) struct ReadLimits {
+    pub(crate) framing: Framing,
+    pub(crate) endianness: Endianness,
+    pub(crate) max_body_size: usize,
+    pub(crate) max_reassembly_size: usize,
+    /// Whether compressed bodies are accepted, which they are when compression is enabled.
+    pub(crate) decompress: bool,
+    /// Whether header flags this version doesn't know about are ignored, rather than treated as a protocol error.
+    pub(crate) ignore_unknown_flags: bool,
+    /// The id of the handshake packet the peer should send first, while waiting for it.
+    pub(crate) handshake: Option<u8>,
+    /// How many packets to take per read, leaving the rest buffered for the next one.
+    pub(crate) max_packets: Option<usize>,
+    /// How many bytes to take from the socket per read, leaving the rest in the socket for the next one.
+    pub(crate) max_bytes: Option<usize>,
+    /// How many bytes to take from the socket per read before stopping to give other connections a turn. Unlike
+    /// `max_bytes`, the peer isn't doing anything wrong by sending more than this.
+    pub(crate) read_budget: Option<usize>,
+}
+
+impl ReadLimits {
+    /// The biggest a compressed body may inflate to, which is the biggest body the peer could have sent without it.
+    fn max_inflated_size(&self) -> usize {
+        self.max_body_size.max(self.max_reassembly_size)
+    }
+}
+
+/// Where a connection is in the packet that's currently arriving.
+pub(crate) enum ReadState {
+    /// Waiting for a header. Small packets are read whole into the buffer while in this state.
+    ReadingHeader,
+    /// Partway through a large body, which is being read directly into `body` over as many reads (and ticks) as it
+    /// takes to arrive.
+    ReadingBody {
+        header: PacketHeader,
+        body: Vec<u8>,
+        remaining: usize,
+    },
+}
+
+/// Why `read_packets` stopped reading a socket that should now be closed.
+pub(crate) enum ReadError {
+    /// The peer closed the socket.
+    Closed,
+    /// Reading from the socket failed.
+    Io(std::io::Error),
+    /// A header was invalid or had unknown flags set, so the framing can't be recovered.
+    InvalidHeader(Error),
+    /// A fragment was malformed, out of order, or part of a packet bigger than `max_reassembly_size`.
+    InvalidFragment(Error),
+    /// A compressed body was corrupt or inflated too big, or compression isn't enabled on this side.
+    InvalidCompression(Error),
+    /// The buffer is full and can't grow, so nothing more can be read.
+    BufferFull,
+}
+
+/// What happened during a call to `read_packets`.
+#[derive(Default)]
+pub(crate) struct ReadOutcome {
+    pub(crate) bytes_read: usize,
+    pub(crate) packets: usize,
+    pub(crate) error: Option<ReadError>,
+    /// Whether the peer sent more than `max_packets` or `max_bytes`, and reading stopped with the rest left over.
+    pub(crate) is_over_limit: bool,
+    /// Whether reading stopped at the `read_budget`, possibly with more left in the socket.
+    pub(crate) is_out_of_budget: bool,
+}
+
+/// Hands packets on to `on_packet`, putting fragmented ones back together and decompressing them first.
+struct Delivery<'a, F> {
+    reassembly: &'a mut Reassembly,
+    limits: ReadLimits,
+    on_packet: F,
+    delivered: usize,
+    error: Option<ReadError>,
+}
+
+impl<F: FnMut(Packet)> Delivery<'_, F> {
+    fn deliver(&mut self, packet: Packet) {
+        // Nothing after a bad header, fragment or compressed body can be trusted
+        if self.error.is_some() {
+            return;
+        }
+
+        let unknown = packet.header.unknown_flags();
+        if unknown != 0 && !self.limits.ignore_unknown_flags {
+            self.error = Some(ReadError::InvalidHeader(Error::UnknownPacketFlags(unknown)));
+            return;
+        }
+
+        let mut packet = if packet.header.id == FRAGMENT_PACKET_ID {
+            match self
+                .reassembly
+                .push(&packet, self.limits.max_reassembly_size)
+            {
+                Ok(Some(whole)) => whole,
+                Ok(None) => return,
+                Err(e) => {
+                    self.error = Some(ReadError::InvalidFragment(e));
+                    return;
+                }
+            }
+        } else {
+            packet
+        };
+
+        if packet.header.is_compressed() {
+            let body = if self.limits.decompress {
+                decompress(&packet.body, self.limits.max_inflated_size())
+            } else {
+                Err(Error::UnexpectedCompression)
+            };
+
+            match body {
+                Ok(body) => {
+                    packet.header.size = body.len() as u32;
+                    packet.body = body.into();
+                }
+                Err(e) => {
+                    self.error = Some(ReadError::InvalidCompression(e));
+                    return;
+                }
+            }
+        }
+
+        self.delivered += 1;
+        (self.on_packet)(packet);
+    }
+}
+
+/// Read everything a socket has to offer, passing each complete packet to `on_packet` in the order it arrived.
+/// Fragments are reassembled and compressed bodies decompressed along the way, so `on_packet` only sees whole packets.
+/// Reading stops once the socket would block, at the first error, once the peer has gone over `max_packets` or
+/// `max_bytes`, or once `read_budget` is used up. Whatever is left over is picked back up by the next call, even if the
+/// socket has nothing new.
+pub(crate) fn read_packets<R, F>(
+    socket: &mut R,
+    buffer: &mut NetworkBuffer,
+    state: &mut ReadState,
+    reassembly: &mut Reassembly,
+    limits: ReadLimits,
+    on_packet: F,
+) -> ReadOutcome
+where
+    R: Read,
+    F: FnMut(Packet),
+{
+    let mut delivery = Delivery {
+        reassembly,
+        limits,
+        on_packet,
+        delivered: 0,
+        error: None,
+    };
+
+    let mut outcome = read_frames(socket, buffer, state, limits, |packet| {
+        delivery.deliver(packet)
+    });
+
+    outcome.packets = delivery.delivered;
+    if outcome.error.is_none() {
+        outcome.error = delivery.error;
+    }
+
+    outcome
+}
+
+/// Read everything a socket has to offer, passing each packet to `on_frame` as it comes off the wire.
+fn read_frames<R, F>(
+    socket: &mut R,
+    buffer: &mut NetworkBuffer,
+    state: &mut ReadState,
+    limits: ReadLimits,
+    mut on_frame: F,
+) -> ReadOutcome
+where
+    R: Read,
+    F: FnMut(Packet),
+{
+    let ReadLimits {
+        framing,
+        endianness,
+        max_body_size,
+        ..
+    } = limits;
+    let max_packets = limits.max_packets.unwrap_or(usize::MAX);
+    // One byte past the limit is read to find out whether the peer sent more than it, and is kept for the next call
+    let max_bytes = limits
+        .max_bytes
+        .map_or(usize::MAX, |max| max.saturating_add(1));
+    let max_read = max_bytes.min(limits.read_budget.unwrap_or(usize::MAX));
+    let is_done = |outcome: &mut ReadOutcome| {
+        if outcome.bytes_read >= max_bytes {
+            outcome.is_over_limit = true;
+        } else if outcome.bytes_read >= max_read {
+            outcome.is_out_of_budget = true;
+        }
+
+        outcome.is_over_limit || outcome.is_out_of_budget
+    };
+    let mut packets = 0;
+    let mut outcome = ReadOutcome::default();
+    loop {
+        // Finish off a large body before going back to the buffer
+        if let ReadState::ReadingBody {
+            body, remaining, ..
+        } = state
+        {
+            let filled = body.len() - *remaining;
+            let end = body
+                .len()
+                .min(filled.saturating_add(max_read - outcome.bytes_read));
+            match socket.read(&mut body[filled..end]) {
+                Ok(0) => {
+                    outcome.error = Some(ReadError::Closed);
+                    break;
+                }
+                Ok(read_bytes) => {
+                    outcome.bytes_read += read_bytes;
+                    *remaining -= read_bytes;
+                    if *remaining == 0 {
+                        if let ReadState::ReadingBody { header, body, .. } =
+                            mem::replace(state, ReadState::ReadingHeader)
+                        {
+                            packets += 1;
+                            on_frame(Packet {
+                                header,
+                                body: body.into(),
+                            });
+                        }
+                    }
+
+                    if is_done(&mut outcome) {
+                        break;
+                    }
+
+                    continue;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    outcome.error = Some(ReadError::Io(e));
+                    break;
+                }
+            }
+        }
+
+        // Hand out what's already complete first, which may have been left behind by the last call
+        match parse_frames(
+            buffer,
+            framing,
+            endianness,
+            max_body_size,
+            max_packets - packets,
+            &mut on_frame,
+        ) {
+            Ok(parsed) => packets += parsed,
+            Err(e) => {
+                outcome.error = Some(ReadError::InvalidHeader(e));
+                break;
+            }
+        }
+
+        // Anything past the last packet allowed is the start of one more than that
+        if packets >= max_packets && !buffer.is_empty() {
+            outcome.is_over_limit = true;
+            break;
+        }
+
+        if is_done(&mut outcome) {
+            break;
+        }
+
+        if let Some(partial) = start_direct_read(buffer, framing, endianness, max_body_size) {
+            *state = partial;
+            continue;
+        }
+
+        // Everything complete has been parsed out by now, so a full buffer is holding part of one packet. Reading
+        // into an empty slice would return Ok(0) and look like the socket closed, so grow or give up instead.
+        if buffer.writable_slice().is_empty() && !buffer.grow() {
+            outcome.error = Some(ReadError::BufferFull);
+            break;
+        }
+
+        let writable = buffer.writable_slice();
+        let free = writable.len();
+        let allowed = free.min(max_read - outcome.bytes_read);
+
+        match socket.read(&mut writable[..allowed]) {
+            Ok(0) => {
+                // "Read" 0 bytes, which means the socket has closed
+                outcome.error = Some(ReadError::Closed);
+                break;
+            }
+            Ok(read_bytes) => {
+                // The read can't return more than the slice it was given
+                let _ = buffer.advance_write(read_bytes);
+                outcome.bytes_read += read_bytes;
+
+                // There's probably more where that came from, so read more at a time from now on
+                if read_bytes == free {
+                    buffer.grow();
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(e) => {
+                outcome.error = Some(ReadError::Io(e));
+                break;
+            }
+        }
+
+        // A handshake with its header in the other byte order looks like the start of a much bigger packet, which would
+        // be waited on until the handshake times out
+        if let Some(id) = limits.handshake {
+            if is_swapped_handshake(buffer.make_contiguous(), framing, endianness, id) {
+                outcome.error = Some(ReadError::InvalidHeader(Error::EndiannessMismatch));
+                break;
+            }
+        }
+    }
+
+    outcome
+}
+
+/// If the buffer holds the start of a large body, move what's arrived so far into the body's own allocation.
+/// `parse_packets` has already taken every complete packet, so whatever is at the front of the buffer is incomplete.
+fn start_direct_read(
+    buffer: &mut NetworkBuffer,
+    framing: Framing,
+    endianness: Endianness,
+    max_body_size: usize,
+) -> Option<ReadState> {
+    let bytes = buffer.make_contiguous();
+    let header = peek_header(bytes, framing, endianness, max_body_size).ok()??;
+    let header_size = header.header_size(framing);
+    let size = header.size as usize;
+    if size < DIRECT_READ_THRESHOLD {
+        return None;
+    }
+
+    let staged = bytes.len() - header_size;
+    let mut body = vec![0; size];
+    body[..staged].copy_from_slice(&bytes[header_size..]);
+    buffer.clear();
+
+    Some(ReadState::ReadingBody {
+        header,
+        body,
+        remaining: size - staged,
+    })
+}
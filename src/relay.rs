@@ -0,0 +1,144 @@
+use crate::{
+    error::Result,
+    packet::{PacketBody, MAX_PACKET_BODY_SIZE},
+    slab::ConnectionId,
+};
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use std::{
+    io::Cursor,
+    time::{Duration, Instant},
+};
+
+/// Reserved packet id used for relay envelopes.
+pub const RELAY_PACKET_ID: u8 = 0xF0;
+
+/// Size of the envelope fields in front of the payload (4 bytes each for the peer's index and generation).
+pub const RELAY_ENVELOPE_OVERHEAD: usize = 8;
+
+/// RelayEnvelope
+/// Opaque bytes addressed to another client on the same server.
+/// When sent by a client, `peer` is the destination. When delivered by the server, `peer` is the sender.
+#[derive(Clone)]
+pub struct RelayEnvelope {
+    pub peer: ConnectionId,
+    pub payload: Vec<u8>,
+}
+
+impl RelayEnvelope {
+    pub fn new(peer: ConnectionId, payload: Vec<u8>) -> Self {
+        RelayEnvelope { peer, payload }
+    }
+}
+
+impl PacketBody for RelayEnvelope {
+    fn box_clone(&self) -> Box<dyn PacketBody> {
+        Box::new((*self).clone())
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        let mut data: Vec<u8> = Vec::with_capacity(RELAY_ENVELOPE_OVERHEAD + self.payload.len());
+        data.write_u32::<NetworkEndian>(self.peer.index)?;
+        data.write_u32::<NetworkEndian>(self.peer.generation)?;
+        data.extend_from_slice(&self.payload);
+
+        Ok(data)
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self> {
+        let mut reader = Cursor::new(data);
+        let peer = ConnectionId {
+            index: reader.read_u32::<NetworkEndian>()?,
+            generation: reader.read_u32::<NetworkEndian>()?,
+        };
+
+        Ok(RelayEnvelope {
+            peer,
+            payload: data[RELAY_ENVELOPE_OVERHEAD..].to_vec(),
+        })
+    }
+
+    fn id(&self) -> u8 {
+        RELAY_PACKET_ID
+    }
+}
+
+/// RelayPolicy
+/// Controls which clients may relay to each other through the server, and how much.
+pub struct RelayPolicy {
+    filter: Box<dyn FnMut(ConnectionId, ConnectionId) -> bool + Send>,
+    max_payload_size: usize,
+    max_relays_per_second: u32,
+}
+
+impl RelayPolicy {
+    /// Create a policy. `filter` is called with the sender and destination of every relay request,
+    /// and the request is dropped if it returns false.
+    pub fn new<F>(filter: F) -> Self
+    where
+        F: FnMut(ConnectionId, ConnectionId) -> bool + Send + 'static,
+    {
+        RelayPolicy {
+            filter: Box::new(filter),
+            max_payload_size: MAX_PACKET_BODY_SIZE - RELAY_ENVELOPE_OVERHEAD,
+            max_relays_per_second: 60,
+        }
+    }
+
+    /// Create a policy that lets any client relay to any other client.
+    pub fn allow_all() -> Self {
+        RelayPolicy::new(|_, _| true)
+    }
+
+    /// Set the largest payload that will be relayed.
+    pub fn max_payload_size(mut self, size: usize) -> Self {
+        self.max_payload_size = size;
+        self
+    }
+
+    /// Set how many relay requests a single client may make per second.
+    pub fn max_relays_per_second(mut self, count: u32) -> Self {
+        self.max_relays_per_second = count;
+        self
+    }
+
+    /// Check a relay request against the size limit and filter.
+    pub(crate) fn allows(
+        &mut self,
+        from: ConnectionId,
+        to: ConnectionId,
+        payload_size: usize,
+    ) -> bool {
+        payload_size <= self.max_payload_size && (self.filter)(from, to)
+    }
+}
+
+/// Tracks how many relays a single connection has made in the current one second window.
+pub(crate) struct RelayLimiter {
+    window_start: Instant,
+    count: u32,
+}
+
+impl RelayLimiter {
+    pub fn new() -> Self {
+        RelayLimiter {
+            window_start: Instant::now(),
+            count: 0,
+        }
+    }
+
+    /// Count a relay request, returning false if it's over the limit.
+    pub fn try_acquire(&mut self, policy: &RelayPolicy) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.count = 0;
+        }
+
+        if self.count >= policy.max_relays_per_second {
+            return false;
+        }
+
+        self.count += 1;
+        true
+    }
+}
@@ -0,0 +1,88 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Instant,
+};
+
+/// ScheduledId
+/// Identifies a packet scheduled with `send_after`, so it can be cancelled before it's sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ScheduledId(u64);
+
+/// Packets waiting for their send time, in the order they're due.
+/// Packets due at the same instant come out in the order they were scheduled.
+pub(crate) struct PacketSchedule<T> {
+    entries: BTreeMap<(Instant, ScheduledId), T>,
+    due_at: HashMap<ScheduledId, Instant>,
+    next_id: u64,
+}
+
+impl<T> PacketSchedule<T> {
+    pub(crate) fn new() -> Self {
+        PacketSchedule {
+            entries: BTreeMap::new(),
+            due_at: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// When the next packet is due, if anything is scheduled.
+    pub(crate) fn next_due(&self) -> Option<Instant> {
+        self.entries.keys().next().map(|(due, _)| *due)
+    }
+
+    pub(crate) fn insert(&mut self, due: Instant, entry: T) -> ScheduledId {
+        let id = ScheduledId(self.next_id);
+        self.next_id += 1;
+        self.entries.insert((due, id), entry);
+        self.due_at.insert(id, due);
+
+        id
+    }
+
+    /// Remove a scheduled packet. Returns false if it was already sent or cancelled.
+    pub(crate) fn cancel(&mut self, id: ScheduledId) -> bool {
+        match self.due_at.remove(&id) {
+            Some(due) => self.entries.remove(&(due, id)).is_some(),
+            None => false,
+        }
+    }
+
+    /// Take every packet that's due by `now`, in the order they're due.
+    pub(crate) fn take_due(&mut self, now: Instant) -> Vec<T> {
+        let mut due = Vec::new();
+        while let Some(&(at, id)) = self.entries.keys().next() {
+            if at > now {
+                break;
+            }
+
+            self.due_at.remove(&id);
+            if let Some(entry) = self.entries.remove(&(at, id)) {
+                due.push(entry);
+            }
+        }
+
+        due
+    }
+
+    /// Keep only the packets `f` returns true for.
+    pub(crate) fn retain<F: FnMut(&T) -> bool>(&mut self, mut f: F) {
+        let due_at = &mut self.due_at;
+        self.entries.retain(|(_, id), entry| {
+            let keep = f(entry);
+            if !keep {
+                due_at.remove(id);
+            }
+
+            keep
+        });
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.due_at.clear();
+    }
+}
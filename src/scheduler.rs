@@ -0,0 +1,138 @@
+use std::time::{Duration, Instant};
+
+/// How many missed ticks a `TickScheduler` runs back to back before giving up on them.
+pub const DEFAULT_MAX_CATCH_UP: u32 = 5;
+
+/// TickInfo
+/// Describes one tick handed out by a `TickScheduler`.
+#[derive(Clone, Copy, Debug)]
+pub struct TickInfo {
+    /// Counts up from 0.
+    pub number: u64,
+    /// Time since the previous tick started. This is the period on the first tick.
+    pub delta: Duration,
+    /// Whether this tick started late enough that the next one is already due.
+    pub is_behind: bool,
+}
+
+/// TickStats
+/// Totals kept by a `TickScheduler`, for spotting a loop that can't keep up with its rate.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TickStats {
+    pub ticks: u64,
+    /// The average time spent between a tick starting and asking for the next one.
+    pub average_tick_duration: Duration,
+    /// Ticks that took longer than the period.
+    pub overruns: u64,
+    /// Missed ticks that were dropped rather than caught up on.
+    pub skipped_ticks: u64,
+}
+
+/// TickScheduler
+/// Runs a loop at a steady rate, like 30 ticks a second, for wrapping `server.tick()` or `client.tick()` calls.
+/// Deadlines are kept on a fixed schedule instead of sleeping a fixed amount after each tick, so the rate doesn't drift.
+/// A loop that falls behind runs the missed ticks back to back to catch up, but only up to `max_catch_up` of them. Past
+/// that (after a long stall, or the process being suspended) the missed ticks are dropped and the schedule restarts from
+/// now, so a slow loop never ends up in a burst it can't get out of.
+pub struct TickScheduler {
+    period: Duration,
+    max_catch_up: u32,
+    next_deadline: Instant,
+    last_tick: Option<Instant>,
+    stats: TickStats,
+    total_tick_duration: Duration,
+}
+
+impl TickScheduler {
+    /// Create a scheduler that ticks `rate_hz` times a second. The first tick is due straight away.
+    /// Panics if `rate_hz` is zero, or over 1GHz, where a tick would take less than a nanosecond.
+    pub fn new(rate_hz: u32) -> Self {
+        assert!(rate_hz > 0, "tick rate must be at least 1Hz");
+        assert!(rate_hz <= 1_000_000_000, "tick rate must be at most 1GHz");
+
+        TickScheduler {
+            period: Duration::from_secs(1) / rate_hz,
+            max_catch_up: DEFAULT_MAX_CATCH_UP,
+            next_deadline: Instant::now(),
+            last_tick: None,
+            stats: TickStats::default(),
+            total_tick_duration: Duration::from_secs(0),
+        }
+    }
+
+    /// Set how many missed ticks are caught up on before the rest are dropped.
+    /// Zero never catches up, and restarts the schedule whenever a tick is late.
+    pub fn max_catch_up(mut self, ticks: u32) -> Self {
+        self.max_catch_up = ticks;
+        self
+    }
+
+    /// The time between ticks.
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// When the next tick is due. This can be in the past if the loop is behind.
+    pub fn next_deadline(&self) -> Instant {
+        self.next_deadline
+    }
+
+    pub fn stats(&self) -> TickStats {
+        self.stats
+    }
+
+    /// Sleep until the next tick is due (not at all if it already is), then start it.
+    pub fn sleep_until_next(&mut self) -> TickInfo {
+        let now = Instant::now();
+        if let Some(start) = self.last_tick {
+            let took = now - start;
+            self.total_tick_duration += took;
+            self.stats.average_tick_duration = Duration::from_nanos(
+                (self.total_tick_duration.as_nanos() / self.stats.ticks as u128) as u64,
+            );
+            if took > self.period {
+                self.stats.overruns += 1;
+            }
+        }
+
+        if self.next_deadline > now {
+            std::thread::sleep(self.next_deadline - now);
+        }
+
+        self.start_tick()
+    }
+
+    /// Tick forever, sleeping between each one. Break out of the loop to stop.
+    pub fn ticks(&mut self) -> impl Iterator<Item = TickInfo> + '_ {
+        std::iter::from_fn(move || Some(self.sleep_until_next()))
+    }
+
+    fn start_tick(&mut self) -> TickInfo {
+        let now = Instant::now();
+        let late_by = now.saturating_duration_since(self.next_deadline);
+        let missed = late_by.as_nanos() / self.period.as_nanos();
+
+        // Too far behind to catch up without a burst, so drop the missed ticks and restart the schedule from now
+        if missed > self.max_catch_up as u128 {
+            self.stats.skipped_ticks += missed as u64;
+            self.next_deadline = now;
+        }
+
+        let delta = match self.last_tick {
+            Some(last) => now - last,
+            None => self.period,
+        };
+
+        let info = TickInfo {
+            number: self.stats.ticks,
+            delta,
+            is_behind: missed > 0,
+        };
+
+        self.last_tick = Some(now);
+        self.next_deadline += self.period;
+        self.stats.ticks += 1;
+
+        info
+    }
+}
@@ -0,0 +1,76 @@
+use crate::{
+    delivery::Payload,
+    error::{Error, Result},
+    packet::{PacketBody, WriteFormat, DEFAULT_CHANNEL},
+    slab::ConnectionId,
+    PacketRecipient,
+};
+use mio::Waker;
+use std::sync::{mpsc::Sender, Arc};
+
+/// Work queued by a `PacketSender`, applied by the server at the start of its next tick.
+pub(crate) enum ServerCommand {
+    Send(PacketRecipient, Payload),
+    Kick(ConnectionId),
+}
+
+/// PacketSender
+/// A cheap, cloneable handle for queuing packets on a `Server` from other threads.
+/// Packets are moved into the normal outgoing queues on the server's next tick, and the server's poll is woken
+/// so a blocking tick notices them promptly. Packets for connections that are gone by then are dropped.
+#[derive(Clone)]
+pub struct PacketSender {
+    commands: Sender<ServerCommand>,
+    waker: Arc<Waker>,
+    /// The server's write format, to check packets against before they're queued.
+    format: WriteFormat,
+}
+
+impl PacketSender {
+    pub(crate) fn new(
+        commands: Sender<ServerCommand>,
+        waker: Arc<Waker>,
+        format: WriteFormat,
+    ) -> Self {
+        PacketSender {
+            commands,
+            waker,
+            format,
+        }
+    }
+
+    /// Queue a packet to be sent on the server's next tick.
+    /// The packet is serialized and checked on the calling thread. Returns the error if that fails, the same as
+    /// `Server::send` would, or `Error::ServerClosed` if the server has been dropped.
+    pub fn send(&self, recipient: PacketRecipient, packet: impl PacketBody) -> Result<()> {
+        self.send_boxed(recipient, Box::new(packet))
+    }
+
+    /// Queue a boxed packet to be sent on the server's next tick.
+    pub fn send_boxed(
+        &self,
+        recipient: PacketRecipient,
+        packet_boxed: Box<dyn PacketBody>,
+    ) -> Result<()> {
+        let payload = Payload::serialized(packet_boxed.as_ref())?;
+        self.format
+            .check_sendable(payload.id(), DEFAULT_CHANNEL, payload.len())?;
+        self.push(ServerCommand::Send(recipient, payload))
+    }
+
+    /// Kick a connection on the server's next tick.
+    /// Kicking a connection that has already gone away does nothing.
+    pub fn kick(&self, connection_id: ConnectionId) -> Result<()> {
+        self.push(ServerCommand::Kick(connection_id))
+    }
+
+    fn push(&self, command: ServerCommand) -> Result<()> {
+        if self.commands.send(command).is_err() {
+            return Err(Error::ServerClosed);
+        }
+
+        self.waker.wake()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+/// SequenceCounter
+/// Numbers the packets sent over a connection, if sequence numbers are enabled.
+pub(crate) struct SequenceCounter {
+    next: Option<u32>,
+}
+
+impl SequenceCounter {
+    pub(crate) fn new(enabled: bool) -> Self {
+        SequenceCounter {
+            next: if enabled { Some(0) } else { None },
+        }
+    }
+
+    /// Get the number for the next packet, or `None` if sequence numbers are disabled.
+    pub(crate) fn peek(&self) -> Option<u32> {
+        self.next
+    }
+
+    /// Move on once a packet has been sent with the number from `peek`. Wraps around to 0 after `u32::MAX`.
+    pub(crate) fn advance(&mut self) {
+        if let Some(next) = self.next.as_mut() {
+            *next = next.wrapping_add(1);
+        }
+    }
+}
+
+/// SequenceTracker
+/// Checks the sequence numbers on the packets received over a connection.
+pub(crate) struct SequenceTracker {
+    expected: u32,
+}
+
+impl SequenceTracker {
+    pub(crate) fn new() -> Self {
+        SequenceTracker { expected: 0 }
+    }
+
+    /// Record the sequence number of a received packet. Returns the number that was expected instead, if this isn't it.
+    /// Either way, the next packet is expected to follow on from this one, so a single gap is only reported once.
+    pub(crate) fn observe(&mut self, sequence: u32) -> Option<u32> {
+        let expected = self.expected;
+        self.expected = sequence.wrapping_add(1);
+
+        if sequence == expected {
+            None
+        } else {
+            Some(expected)
+        }
+    }
+}
@@ -1,325 +1,2551 @@
 use crate::{
-    buffer::NetworkBuffer,
+    buffer::{BufferPool, NetworkBuffer},
+    cancel_queued,
+    config::{RateLimitPolicy, ServerConfig, SlowConsumerPolicy},
+    control::{
+        is_control_packet, Heartbeat, Hello, HelloAck, Kick, Ping, Pong, ServerFull,
+        ShutdownNotice, HEARTBEAT_PACKET_ID, HELLO_ACK_PACKET_ID, PING_PACKET_ID, PONG_PACKET_ID,
+    },
+    decode_pool::DecodePool,
+    delivery::{MessageId, MessageIds, Outgoing, OutgoingQueue, PartialWrite, Payload, Priority},
+    diagnostics::{DiagnosticsRecorder, ServerDiagnostics, TickSample},
+    disconnect::DisconnectReason,
+    dispatch::{Dispatched, PacketRegistry},
     error::{Error, Result},
-    packet::{deserialize_packet_header, serialize_packet, Packet, PacketBody, PACKET_HEADER_SIZE},
-    send_bytes, PacketRecipient,
+    flush_packets,
+    fragment::Reassembly,
+    handler::{ServerHandler, Wakeup},
+    health::{tcp_health, TcpHealth},
+    into_events,
+    packet::{
+        is_application_packet_id, serialize_packet_with, time_since, Packet, PacketBody,
+        PacketInfo, RawPacket, WriteFormat, DEFAULT_CHANNEL,
+    },
+    packet_log::{log_incoming, log_outgoing, PacketLogger},
+    poll_events,
+    protocol::{Encoded, Protocol, RawProtocol},
+    receive::{read_packets, ReadError, ReadState},
+    relay::{RelayEnvelope, RelayLimiter, RelayPolicy, RELAY_PACKET_ID},
+    schedule::{PacketSchedule, ScheduledId},
+    sender::{PacketSender, ServerCommand},
+    sequence::{SequenceCounter, SequenceTracker},
+    slab::{ConnectionId, ConnectionSlab},
+    stats::{
+        AcceptState, ConnectionSnapshot, ConnectionStats, RttEstimator, ServerSnapshot,
+        ServerStats, ShutdownSummary, TrafficStats,
+    },
+    PacketRecipient, TimedEvent, CLOSE_FLUSH_TIMEOUT, RUN_POLL_TIMEOUT,
 };
 use mio::{
     net::{TcpListener, TcpStream},
-    Events, Interest, Poll, Token,
+    Events, Interest, Poll, Registry, Token, Waker,
 };
 use std::{
     collections::{HashMap, VecDeque},
-    io::Read,
-    net::SocketAddr,
+    fmt,
+    io::Write,
+    net::{IpAddr, Shutdown, SocketAddr},
+    ops::ControlFlow,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 const LOCAL_TOKEN: Token = Token(0);
+const WAKER_TOKEN: Token = Token(usize::MAX);
 const EVENTS_CAPACITY: usize = 4096;
 
+/// Why an incoming connection was turned away.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RejectReason {
+    /// The server already has `connection_limit` connections.
+    ServerFull,
+    /// The address already has `connection_limit_per_ip` connections.
+    TooManyFromAddress,
+    /// The address is banned for a protocol error, see `ServerConfig::protocol_error_ban`.
+    Banned,
+    /// The connection couldn't be registered for socket events, usually because the process is out of file
+    /// descriptors.
+    RegistrationFailed,
+}
+
+impl RejectReason {
+    /// A stable byte for this reason, for sending it over the wire.
+    pub fn code(self) -> u8 {
+        match self {
+            RejectReason::ServerFull => 0,
+            RejectReason::TooManyFromAddress => 1,
+            RejectReason::Banned => 2,
+            RejectReason::RegistrationFailed => 3,
+        }
+    }
+
+    /// Get the reason for a byte written by `code`, if it's a known one.
+    pub fn from_code(code: u8) -> Option<RejectReason> {
+        match code {
+            0 => Some(RejectReason::ServerFull),
+            1 => Some(RejectReason::TooManyFromAddress),
+            2 => Some(RejectReason::Banned),
+            3 => Some(RejectReason::RegistrationFailed),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RejectReason::ServerFull => write!(f, "server is full"),
+            RejectReason::TooManyFromAddress => write!(f, "too many connections from this address"),
+            RejectReason::Banned => write!(f, "address is banned"),
+            RejectReason::RegistrationFailed => write!(f, "couldn't register the connection"),
+        }
+    }
+}
+
+/// ServerEvent
+/// Something that happened during a tick.
+/// The order of events is deterministic for a given sequence of socket activity. Socket events are handled in the order
+/// mio reports them, and everywhere the server walks all of its connections (broadcasts, the idle and ping sweeps,
+/// relays and the disconnect sweep) it goes in `ConnectionId` index order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum ServerEvent {
-    ConnectionRejected(SocketAddr),
-    ClientConnected(Token, SocketAddr),
-    ClientDisconnected(Token),
-    ReceivedPacket(Token, usize),
-    SentPacket(Token, usize),
-
-    #[doc(hidden)]
-    __Nonexhaustive,
+    ConnectionRejected(SocketAddr, RejectReason),
+    ClientConnected(ConnectionId, SocketAddr),
+    /// A connection was closed, and why.
+    ClientDisconnected(ConnectionId, DisconnectReason),
+    /// A shutdown started with `Server::begin_shutdown` finished, after the `ClientDisconnected` events for the last
+    /// connections. The server is shut down and won't emit anything else.
+    ShutdownComplete(ShutdownSummary),
+    /// A connection was disconnected for not sending anything within `idle_timeout`, or the timeout given to
+    /// `heartbeat`. `ClientDisconnected` follows in the same tick.
+    TimedOut(ConnectionId),
+    ReceivedPacket(ConnectionId, PacketInfo),
+    SentPacket(ConnectionId, usize),
+    Relayed {
+        from: ConnectionId,
+        to: ConnectionId,
+        bytes: usize,
+    },
+    RelayRejected {
+        from: ConnectionId,
+        to: Option<ConnectionId>,
+    },
+    /// Packets were dropped for a connection with a full outgoing queue, or it was disconnected for falling behind.
+    /// Emitted at most once per tick per connection, with the totals since the last one.
+    SlowConsumer {
+        id: ConnectionId,
+        dropped_packets: usize,
+        dropped_bytes: usize,
+    },
+    /// The unread incoming packet queue reached `max_incoming`, and the server stopped reading from sockets until it
+    /// drains. Emitted once each time the cap is reached.
+    IncomingQueueFull,
+    /// A connection sent more than `max_packets_per_tick` or `max_bytes_per_tick` this tick, and was throttled or
+    /// disconnected according to the `rate_limit_policy`. Emitted at most once per tick per connection.
+    RateLimited(ConnectionId),
+    /// A typed server received a packet that didn't decode into its protocol, or a packet run through
+    /// `dispatch_incoming` didn't deserialize into the type registered for its id. The packet is dropped.
+    ProtocolError {
+        from: ConnectionId,
+        id: u8,
+    },
+    /// A connection sent a packet on a channel past the ones set with `ServerConfig::channels`. The packet is dropped.
+    UnknownChannel {
+        id: ConnectionId,
+        channel: u8,
+    },
+    /// A connection sent a packet header that couldn't be parsed, like one advertising a body bigger than
+    /// `max_packet_size` or with flags the server doesn't know about, or a hello ack written in the other `Endianness`.
+    /// The framing can't be recovered, so the connection is disconnected right after this.
+    /// `error` is the parse error's message.
+    InvalidHeader {
+        id: ConnectionId,
+        error: String,
+    },
+    /// A connection sent a packet fragment that was malformed, out of order, or part of a packet bigger than
+    /// `max_reassembly_size`. The connection is disconnected right after this. `error` is the error's message.
+    InvalidFragment {
+        id: ConnectionId,
+        error: String,
+    },
+    /// A connection sent a compressed packet that was corrupt or inflated bigger than the server accepts, or sent one
+    /// without compression enabled on the server. The connection is disconnected right after this. `error` is the
+    /// error's message.
+    InvalidCompression {
+        id: ConnectionId,
+        error: String,
+    },
+    /// A connection sent a packet with a body of `size` bytes, over the `max` set for its id with
+    /// `Server::set_packet_size_limit`. The packet is dropped, and the connection is disconnected right after this.
+    PacketSizeExceeded {
+        id: ConnectionId,
+        packet_id: u8,
+        size: usize,
+        max: usize,
+    },
+    /// An address was banned for this long after one of its connections sent something invalid, which follows the
+    /// `InvalidHeader`, `InvalidFragment` or `InvalidCompression` or `PacketSizeExceeded`
+    /// event. Only with `ServerConfig::protocol_error_ban`.
+    AddressBanned(IpAddr, Duration),
+    /// A connection sent a packet whose sequence number wasn't the one after its last packet's. A `received` number
+    /// past `expected` means packets were skipped, and one before it means the numbers went backwards. Only packets
+    /// that carry sequence numbers are checked, and the connection stays open.
+    OutOfSequence {
+        id: ConnectionId,
+        expected: u32,
+        received: u32,
+    },
+    /// A connection sent a packet stamped with the time it was queued, which was this long ago by the server's clock.
+    /// Only sent by clients with `timestamps` enabled, and only as accurate as the two clocks are in sync.
+    PacketLatency(ConnectionId, Duration),
+    /// A packet sat in this connection's outgoing queue for this long before it was written. Emitted after
+    /// `SentPacket`, with `timestamps` enabled.
+    QueueDelay(ConnectionId, Duration),
+    /// A connection answered a ping, sent with `Server::ping` or for `ping_interval`, after this round trip time.
+    Pong(ConnectionId, Duration),
+    /// A connection didn't answer a ping within `ping_timeout`. The ping is given up on, but the connection stays open.
+    PingTimedOut(ConnectionId),
+    /// A connection sent a pong that doesn't answer any outstanding ping, because it was given up on or never sent.
+    /// The pong is ignored.
+    UnexpectedPong(ConnectionId),
+    /// A packet sent with `send_tracked` was fully written to this connection's socket.
+    Delivered(ConnectionId, MessageId),
+    /// A packet sent with `send_tracked` will never be written to this connection's socket. It was dropped by the slow
+    /// consumer policy, cancelled, failed to serialize, or the connection closed with it still queued.
+    DeliveryFailed(ConnectionId, MessageId),
+    /// A packet queued for a connection couldn't be framed, so it was never sent. Nothing of it was written, so the
+    /// packets after it still arrive intact. `error` is the error's message, which is `Error::PacketTooLarge` for bodies
+    /// that only outgrow the limit once compressed. Bodies that fail to serialize at all, or are too big before
+    /// compression, come back from `send` instead.
+    SerializeFailed {
+        id: ConnectionId,
+        packet_id: u8,
+        error: String,
+    },
+}
+
+/// ServerItem
+/// An event or a received packet, handed out by `Server::poll_next` in the order the server observed them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServerItem {
+    Event(ServerEvent),
+    Packet(ConnectionId, Packet),
+}
+
+pub struct Connection {
+    pub(crate) id: ConnectionId,
+    token: Token,
+    socket: TcpStream,
+    addr: SocketAddr,
+    connected_at: Instant,
+    last_read: Instant,
+    last_write: Instant,
+    is_disconnected: bool,
+    disconnect_reason: DisconnectReason,
+    /// Set once the connection is kicked or the server begins shutting down, to the number of ticks it has left to
+    /// flush its outgoing queue and the reason it's closed with. Nothing more is read from it in the meantime.
+    draining: Option<(u32, DisconnectReason)>,
+    traffic: TrafficStats,
+    buffer: NetworkBuffer,
+    read_state: ReadState,
+    reassembly: Reassembly,
+    format: WriteFormat,
+    handshake_deadline: Option<Instant>,
+    sequence: SequenceCounter,
+    received_sequence: SequenceTracker,
+    outgoing_packets: OutgoingQueue,
+    /// The packet that was partway out when the socket last filled up, which has to finish before the next one starts.
+    unsent: Option<PartialWrite>,
+    /// Tracked messages dropped or cancelled since the last tick, to be reported as `DeliveryFailed`.
+    failed_messages: Vec<MessageId>,
+    relay_limiter: RelayLimiter,
+    rtt: RttEstimator,
+    policy: Option<SlowConsumerPolicy>,
+    dropped_packets: usize,
+    dropped_bytes: usize,
+    read_pending: bool,
+    is_write_armed: bool,
+    /// Whether the socket has taken everything written to it since its last writable event. Queued packets are written
+    /// every tick while this holds, whether or not the tick brought a writable event, and only a write that would block
+    /// clears it to wait for the next one.
+    is_writable: bool,
+}
+
+impl Connection {
+    pub fn new(
+        id: ConnectionId,
+        socket: TcpStream,
+        addr: SocketAddr,
+        buffer: NetworkBuffer,
+        format: WriteFormat,
+    ) -> Self {
+        let now = Instant::now();
+        Connection {
+            id,
+            token: id.token(),
+            socket,
+            addr,
+            connected_at: now,
+            last_read: now,
+            last_write: now,
+            is_disconnected: false,
+            disconnect_reason: DisconnectReason::Closed,
+            draining: None,
+            traffic: TrafficStats::default(),
+            buffer,
+            read_state: ReadState::ReadingHeader,
+            reassembly: Reassembly::new(),
+            format,
+            handshake_deadline: None,
+            sequence: SequenceCounter::new(false),
+            received_sequence: SequenceTracker::new(),
+            outgoing_packets: OutgoingQueue::new(),
+            unsent: None,
+            failed_messages: Vec::new(),
+            relay_limiter: RelayLimiter::new(),
+            rtt: RttEstimator::new(),
+            policy: None,
+            dropped_packets: 0,
+            dropped_bytes: 0,
+            read_pending: false,
+            is_write_armed: false,
+            // A freshly accepted socket has an empty send buffer, and it's only registered for writable events once
+            // there's something to write
+            is_writable: true,
+        }
+    }
+
+    /// Write queued packets until the queue is empty, the socket fills up, or the connection fails.
+    /// A packet the socket only takes part of is finished on the next writable event, before anything else is written.
+    /// Returns the number of packets written.
+    fn write_queued(
+        &mut self,
+        packet_logger: &mut Option<PacketLogger>,
+        stats: &mut ServerStats,
+        net_events: &mut Vec<TimedEvent<ServerEvent>>,
+    ) -> usize {
+        let mut written = 0;
+        loop {
+            let mut unsent = match self.unsent.take() {
+                Some(unsent) => unsent,
+                None => match self.outgoing_packets.pop_front() {
+                    Some(outgoing) => match self.serialize(outgoing, packet_logger, net_events) {
+                        Some(unsent) => unsent,
+                        None => continue,
+                    },
+                    None => break,
+                },
+            };
+
+            match unsent.write_to(&mut self.socket) {
+                Ok(true) => {
+                    written += 1;
+                    self.last_write = Instant::now();
+                    stats.record_sent(unsent.len());
+                    self.traffic.record_sent(unsent.len());
+                    if !is_control_packet(unsent.packet_id) {
+                        net_events.push(TimedEvent::now(ServerEvent::SentPacket(
+                            self.id,
+                            unsent.len(),
+                        )));
+                        if self.format.timestamps {
+                            net_events.push(TimedEvent::now(ServerEvent::QueueDelay(
+                                self.id,
+                                unsent.queued_at.elapsed(),
+                            )));
+                        }
+                    }
+                    if let Some(message) = unsent.message {
+                        net_events.push(TimedEvent::now(ServerEvent::Delivered(self.id, message)));
+                    }
+                }
+                // The socket is full, so pick this packet back up once it's writable again
+                Ok(false) => {
+                    self.unsent = Some(unsent);
+                    self.is_writable = false;
+                    break;
+                }
+                Err(e) => {
+                    eprintln!(
+                        "Unexpected error when sending bytes to connection {}! {}",
+                        self.id, e
+                    );
+                    // The rest of the queue is failed when the connection is cleaned up, but this one is already out
+                    self.failed_messages.extend(unsent.message);
+                    self.is_writable = false;
+                    self.disconnect(DisconnectReason::SendFailed);
+                    break;
+                }
+            }
+        }
+
+        written
+    }
+
+    /// Serialize a queued packet, ready to be written. Packets that fail to serialize are reported, and skipped.
+    fn serialize(
+        &mut self,
+        outgoing: Outgoing,
+        packet_logger: &mut Option<PacketLogger>,
+        net_events: &mut Vec<TimedEvent<ServerEvent>>,
+    ) -> Option<PartialWrite> {
+        let Outgoing {
+            packet,
+            message,
+            channel,
+            queued_at,
+            ..
+        } = outgoing;
+        let packet_id = packet.id();
+        let id = self.id;
+        let sequence = self.sequence.peek();
+        let serialized = serialize_packet_with(
+            &packet,
+            &self.format,
+            sequence,
+            channel,
+            queued_at,
+            |body| log_outgoing(packet_logger, Some(id), packet_id, sequence, body),
+        );
+        match serialized {
+            Ok(data) => {
+                // The number is on the wire now, whenever the last of the bytes make it out
+                self.sequence.advance();
+                Some(PartialWrite::new(data, packet_id, message, queued_at))
+            }
+            Err(e) => {
+                eprintln!("Failed to serialize packet {}! {}", packet_id, e);
+                net_events.push(TimedEvent::now(ServerEvent::SerializeFailed {
+                    id: self.id,
+                    packet_id,
+                    error: e.to_string(),
+                }));
+                if let Some(message) = message {
+                    net_events.push(TimedEvent::now(ServerEvent::DeliveryFailed(
+                        self.id, message,
+                    )));
+                }
+                None
+            }
+        }
+    }
+
+    /// Whether there's anything left to write, whether it's queued or partway out.
+    fn has_unwritten(&self) -> bool {
+        self.unsent.is_some() || !self.outgoing_packets.is_empty()
+    }
+
+    /// Packets left to write, counting one that's partway out.
+    fn queued_packets(&self) -> usize {
+        self.outgoing_packets.len() + usize::from(self.unsent.is_some())
+    }
+
+    /// Mark the connection to be closed at the end of the tick. Only the first reason given is reported.
+    fn disconnect(&mut self, reason: DisconnectReason) {
+        if !self.is_disconnected {
+            self.is_disconnected = true;
+            self.disconnect_reason = reason;
+        }
+    }
+
+    /// Whether the socket is registered for writable events when there's nothing to write, or the other way around.
+    fn is_interest_stale(&self) -> bool {
+        self.is_write_armed != self.has_unwritten()
+    }
+
+    /// Reregister the socket for the next tick. Writable events are only asked for while there's something to write.
+    fn reregister(&mut self, registry: &Registry) -> std::io::Result<()> {
+        self.is_write_armed = self.has_unwritten();
+        let interest = if self.is_write_armed {
+            Interest::READABLE | Interest::WRITABLE
+        } else {
+            Interest::READABLE
+        };
+
+        registry.reregister(&mut self.socket, self.token, interest)
+    }
+
+    /// Queue a packet, applying the slow consumer policy if it doesn't fit under `max_outgoing` and
+    /// `max_outgoing_bytes`.
+    fn queue(&mut self, packet: Outgoing, config: &ServerConfig) {
+        // Every way of sending checks these before anything is queued, so this only guards against a bug in grubbnet
+        let packet_id = packet.packet.id();
+        if !is_application_packet_id(packet_id) {
+            eprintln!(
+                "Not sending packet {} to connection {}, its id is reserved for grubbnet!",
+                packet_id, self.id
+            );
+            self.failed_messages.extend(packet.message);
+            return;
+        }
+
+        if packet.channel >= self.format.channels {
+            eprintln!(
+                "Not sending packet {} to connection {}, channel {} isn't configured!",
+                packet_id, self.id, packet.channel
+            );
+            self.failed_messages.extend(packet.message);
+            return;
+        }
+
+        match self.policy.unwrap_or(config.slow_consumer_policy) {
+            // Make room by dropping the oldest packets, as long as they don't have a higher priority
+            SlowConsumerPolicy::DropOldest => {
+                while self.is_queue_full(&packet, config) {
+                    match self.outgoing_packets.lowest_priority() {
+                        Some(lowest) if lowest <= packet.priority => {
+                            if let Some(oldest) = self.outgoing_packets.pop_oldest(lowest) {
+                                self.drop_packet(oldest);
+                            }
+                        }
+                        _ => {
+                            self.drop_packet(packet);
+                            return;
+                        }
+                    }
+                }
+            }
+            SlowConsumerPolicy::DropNewest => {
+                if self.is_queue_full(&packet, config) {
+                    self.drop_packet(packet);
+                    return;
+                }
+            }
+            SlowConsumerPolicy::Disconnect => {
+                if self.is_queue_full(&packet, config) {
+                    self.disconnect(DisconnectReason::SlowConsumer);
+                    self.drop_packet(packet);
+                    return;
+                }
+            }
+        }
+
+        self.outgoing_packets.push_back(packet);
+    }
+
+    /// Whether queuing `packet` would go over `max_outgoing` or `max_outgoing_bytes`.
+    fn is_queue_full(&self, packet: &Outgoing, config: &ServerConfig) -> bool {
+        let is_count_full = match config.max_outgoing {
+            Some(max) => self.outgoing_packets.len() >= max,
+            None => false,
+        };
+
+        // A packet bigger than the whole limit can still go out on its own, rather than never being sent
+        let size = self.format.framing.header_size() + packet.packet.len();
+        let is_bytes_full = match config.max_outgoing_bytes {
+            Some(max) => self.has_unwritten() && self.queued_bytes() + size > max,
+            None => false,
+        };
+
+        is_count_full || is_bytes_full
+    }
+
+    /// Count a packet dropped by the slow consumer policy, for the next `SlowConsumer` event.
+    fn drop_packet(&mut self, dropped: Outgoing) {
+        self.dropped_packets += 1;
+        self.dropped_bytes += self.format.framing.header_size() + dropped.packet.len();
+        self.failed_messages.extend(dropped.message);
+    }
+
+    /// Get how many bytes are waiting to be written, including the rest of a packet that's partway out.
+    fn queued_bytes(&self) -> usize {
+        let queued = self.outgoing_packets.bytes()
+            + self.outgoing_packets.len() * self.format.framing.header_size();
+        queued + self.unsent.as_ref().map_or(0, PartialWrite::remaining)
+    }
+}
+
+pub struct Server<P = RawProtocol> {
+    tcp_listener: TcpListener,
+    events: Events,
+    poll: Poll,
+    connections: ConnectionSlab,
+    config: ServerConfig,
+    incoming_packets: VecDeque<(ConnectionId, Packet)>,
+    typed_incoming: VecDeque<(ConnectionId, P)>,
+    decode_pool: Option<DecodePool<P>>,
+    relay: Option<RelayPolicy>,
+    diagnostics: Option<DiagnosticsRecorder>,
+    is_shut_down: bool,
+    /// Set by `begin_shutdown` until the last connection closes.
+    shutting_down: Option<ShuttingDown>,
+    waker: Arc<Waker>,
+    command_sender: Sender<ServerCommand>,
+    command_receiver: Receiver<ServerCommand>,
+    packet_logger: Option<PacketLogger>,
+    started_at: Instant,
+    stats: ServerStats,
+    ready: Vec<(Token, bool, bool)>,
+    is_incoming_capped: bool,
+    pending_events: Vec<TimedEvent<ServerEvent>>,
+    message_ids: MessageIds,
+    /// Where each packet received this tick falls among the tick's events, for `poll_next`.
+    arrivals: Vec<usize>,
+    polled: VecDeque<ServerItem>,
+    scheduled: PacketSchedule<(PacketRecipient, Payload)>,
+    buffer_pool: BufferPool,
+    /// Banned addresses, and when each ban runs out. Bans with no end last until they're lifted.
+    bans: HashMap<IpAddr, Option<Instant>>,
+    /// The biggest body accepted for each packet id with a limit of its own.
+    packet_size_limits: HashMap<u8, usize>,
+}
+
+/// The progress of a graceful shutdown, see `Server::begin_shutdown`.
+struct ShuttingDown {
+    summary: ShutdownSummary,
+    packets_sent_before: u64,
+}
+
+impl ShuttingDown {
+    /// The summary so far, counting every packet written since the shutdown began.
+    fn finish(mut self, stats: &ServerStats) -> ShutdownSummary {
+        self.summary.packets_flushed += (stats.packets_sent - self.packets_sent_before) as usize;
+        self.summary
+    }
+}
+
+impl Server {
+    /// Begin hosting a TCP server.
+    /// This is shorthand for a default `ServerConfig` with the given connection limit.
+    pub fn host(ip: &str, port: u16, connection_limit: usize) -> Result<Server> {
+        ServerConfig::new()
+            .connection_limit(connection_limit)
+            .bind(format!("{}:{}", ip, port))
+    }
+
+    /// Begin hosting on a listener that's already bound, such as one passed in through systemd socket activation.
+    /// This is shorthand for `ServerConfig::bind_listener` with a default config and the given connection limit.
+    pub fn from_listener(
+        listener: std::net::TcpListener,
+        connection_limit: usize,
+    ) -> Result<Server> {
+        ServerConfig::new()
+            .connection_limit(connection_limit)
+            .bind_listener(listener)
+    }
+
+    /// Drain any incoming packets and return them.
+    pub fn drain_incoming_packets(&mut self) -> Vec<(ConnectionId, Packet)> {
+        self.incoming_packets.drain(..).collect()
+    }
+
+    /// Hand incoming packets to `f` one at a time, without collecting them into a `Vec` first.
+    /// Stops early if `f` returns `ControlFlow::Break`, leaving the rest queued.
+    pub fn for_each_incoming<F>(&mut self, mut f: F)
+    where
+        F: FnMut(ConnectionId, Packet) -> ControlFlow<()>,
+    {
+        while let Some((id, packet)) = self.incoming_packets.pop_front() {
+            if f(id, packet).is_break() {
+                break;
+            }
+        }
+    }
+
+    /// Take the next incoming packet, if there is one.
+    pub fn next_incoming(&mut self) -> Option<(ConnectionId, Packet)> {
+        self.incoming_packets.pop_front()
+    }
+
+    /// Drain incoming packets through `registry`, handing each one to the handler registered for its id.
+    /// Packets with no handler are returned. Packets that don't deserialize are dropped, with a `ProtocolError` event
+    /// for each on the next tick.
+    pub fn dispatch_incoming(
+        &mut self,
+        registry: &mut PacketRegistry,
+    ) -> Vec<(ConnectionId, Packet)> {
+        let mut unhandled = Vec::new();
+        while let Some((from, packet)) = self.incoming_packets.pop_front() {
+            match registry.dispatch(from, packet) {
+                Dispatched::Handled => {}
+                Dispatched::Unknown(packet) => unhandled.push((from, packet)),
+                Dispatched::Invalid(id) => {
+                    self.pending_events
+                        .push(TimedEvent::now(ServerEvent::ProtocolError { from, id }));
+                }
+            }
+        }
+
+        unhandled
+    }
+
+    /// Drain the incoming packets with this id, deserialized as `T`, along with who sent each one.
+    /// Packets with other ids stay queued in the order they arrived. A body that doesn't deserialize comes back as an
+    /// error in its place, rather than being dropped.
+    pub fn drain_incoming_as<T: PacketBody>(&mut self, id: u8) -> Vec<(ConnectionId, Result<T>)> {
+        let mut drained = Vec::new();
+        self.incoming_packets.retain(|(from, packet)| {
+            if packet.header.id != id {
+                return true;
+            }
+
+            drained.push((*from, T::deserialize(&packet.body)));
+            false
+        });
+
+        drained
+    }
+
+    /// Drain the incoming packets that arrived on `channel`, along with who sent each one.
+    /// Packets on other channels stay queued in the order they arrived.
+    pub fn drain_incoming_on(&mut self, channel: u8) -> Vec<(ConnectionId, Packet)> {
+        let mut drained = Vec::new();
+        let mut rest = VecDeque::with_capacity(self.incoming_packets.len());
+        for (from, packet) in self.incoming_packets.drain(..) {
+            if packet.header.channel() == channel {
+                drained.push((from, packet));
+            } else {
+                rest.push_back((from, packet));
+            }
+        }
+        self.incoming_packets = rest;
+
+        drained
+    }
+
+    /// Send a packet.
+    /// This function will serialize the packet once, then queue the bytes to be sent to every recipient on the next
+    /// server tick. Returns the error without queuing anything if the packet fails to serialize, or
+    /// `ConnectionNotFound` if the recipient is a `Single` connection that has closed.
+    /// Packet ids from `RESERVED_PACKET_IDS_START` up belong to grubbnet, and sending one returns `ReservedPacketId`.
+    /// A body too big to send even in fragments returns `PacketTooLarge`.
+    pub fn send(&mut self, recipient: PacketRecipient, packet: impl PacketBody) -> Result<()> {
+        let boxed: Box<dyn PacketBody> = Box::new(packet);
+        self.send_boxed(recipient, boxed)
+    }
+
+    /// Send a boxed packet.
+    /// Similar to `send`, but this is moreuseful when you have a boxed packet already and don't want
+    /// to cast it to a concrete type before sending it.
+    pub fn send_boxed(
+        &mut self,
+        recipient: PacketRecipient,
+        packet_boxed: Box<dyn PacketBody>,
+    ) -> Result<()> {
+        self.queue(
+            recipient,
+            packet_boxed,
+            None,
+            Priority::Normal,
+            DEFAULT_CHANNEL,
+        )
+    }
+
+    /// Send a packet with the given priority.
+    /// When a connection's socket can't keep up, its higher priority packets are written before anything with a lower
+    /// priority that was queued first. Packets with the same priority are still written in the order they were sent.
+    pub fn send_with_priority(
+        &mut self,
+        recipient: PacketRecipient,
+        packet: impl PacketBody,
+        priority: Priority,
+    ) -> Result<()> {
+        self.queue(recipient, Box::new(packet), None, priority, DEFAULT_CHANNEL)
+    }
+
+    /// Send a body that's already serialized with the given packet id, like one received from another connection or
+    /// server. The bytes are framed and sent as they are.
+    /// The packet is checked the same way as with `send`, so this returns `ReservedPacketId` if the id belongs to
+    /// grubbnet, or `PacketTooLarge` if the body is too big to send even in fragments, without queuing anything.
+    pub fn send_raw(&mut self, recipient: PacketRecipient, id: u8, body: Vec<u8>) -> Result<()> {
+        self.send(recipient, RawPacket::new(id, body))
+    }
+
+    /// Send a packet on one of the channels set with `ServerConfig::channels`.
+    /// Each connection's channels take turns writing their packets, so a channel with a lot queued can't hold up the
+    /// others, and the receiving side can tell them apart with `packet.header.channel()` or `drain_incoming_on`.
+    /// Packets on the same channel are still written in the order they were sent. Sending on a channel past the
+    /// configured ones returns `UnknownChannel` without queuing anything.
+    pub fn send_on_channel(
+        &mut self,
+        recipient: PacketRecipient,
+        channel: u8,
+        packet: impl PacketBody,
+    ) -> Result<()> {
+        self.queue(recipient, Box::new(packet), None, Priority::Normal, channel)
+    }
+
+    /// Send a packet, and find out when it's been handed to the OS for each recipient.
+    /// The returned `MessageId` comes back in a `Delivered` event for each connection once the packet has been fully
+    /// written to its socket, or a `DeliveryFailed` event if the packet is dropped, cancelled, or the connection closes
+    /// with it still queued. A packet that fails to serialize returns the error instead, like with `send`.
+    pub fn send_tracked(
+        &mut self,
+        recipient: PacketRecipient,
+        packet: impl PacketBody,
+    ) -> Result<MessageId> {
+        let message = self.message_ids.next();
+        self.queue(
+            recipient,
+            Box::new(packet),
+            Some(message),
+            Priority::Normal,
+            DEFAULT_CHANNEL,
+        )?;
+        Ok(message)
+    }
+
+    /// Queue a packet to be sent once `delay` has passed, at the start of the first tick after that.
+    /// Recipients are resolved when the packet is queued, so a broadcast reaches whoever is connected at that point, and
+    /// a packet for a single connection is dropped if it disconnects first.
+    /// The packet is serialized and checked straight away, so this fails if that does, the same as `send` would, or
+    /// with `ScheduleFull` if `ServerConfig::max_scheduled` packets are already waiting.
+    pub fn send_after(
+        &mut self,
+        recipient: PacketRecipient,
+        packet: impl PacketBody,
+        delay: Duration,
+    ) -> Result<ScheduledId> {
+        self.schedule(recipient, Box::new(packet), delay)
+    }
+
+    /// Runs a network tick, which sends/receives packets based on socket readiness, as well as accepts new connections.
+    /// Returns an error if polling fails. Problems with a single connection never fail the tick, and show up as events
+    /// instead.
+    pub fn tick(&mut self) -> Result<Vec<ServerEvent>> {
+        Ok(into_events(self.tick_timed()?))
+    }
+
+    /// Same as `tick`, but each event carries the time it was observed.
+    pub fn tick_timed(&mut self) -> Result<Vec<TimedEvent<ServerEvent>>> {
+        self.run_tick(Some(self.config.tick_timeout))
+    }
+
+    /// Same as `tick`, but waits in poll until there's socket activity or `timeout` passes, sleeping the thread
+    /// instead of spinning. `None` waits for as long as it takes. Timers the server keeps, like scheduled packets,
+    /// still cut the wait short, and `Wakeup::wake` ends it from another thread.
+    pub fn tick_blocking(&mut self, timeout: Option<Duration>) -> Result<Vec<ServerEvent>> {
+        Ok(into_events(self.run_tick(timeout)?))
+    }
+
+    /// Get the next event or received packet, in the order the server observed them. Each packet comes just before its
+    /// `ReceivedPacket` event, and packets left unread from earlier ticks come before anything new.
+    /// This ticks whenever everything from the last tick has been handed out, and returns `None` if that tick turned
+    /// up nothing, so a `while let` loop handles whatever is ready and then returns. Fails if the tick does.
+    /// `tick` and `drain_incoming_packets` still work alongside this, but only `poll_next` keeps the two in order.
+    pub fn poll_next(&mut self) -> Result<Option<ServerItem>> {
+        if self.polled.is_empty() {
+            let events = self.tick()?;
+            self.queue_polled(events);
+        }
+
+        Ok(self.polled.pop_front())
+    }
+
+    /// Interleave a tick's events with the packets received during it, and queue them up for `poll_next`.
+    fn queue_polled(&mut self, events: Vec<ServerEvent>) {
+        let incoming_packets = &mut self.incoming_packets;
+        let polled = &mut self.polled;
+        let mut arrivals = self.arrivals.drain(..).peekable();
+
+        // Whatever is ahead of this tick's packets was received earlier
+        let earlier = incoming_packets.len().saturating_sub(arrivals.len());
+        for (id, packet) in incoming_packets.drain(..earlier) {
+            polled.push_back(ServerItem::Packet(id, packet));
+        }
+
+        for (index, event) in events.into_iter().enumerate() {
+            while arrivals.next_if_eq(&index).is_some() {
+                if let Some((id, packet)) = incoming_packets.pop_front() {
+                    polled.push_back(ServerItem::Packet(id, packet));
+                }
+            }
+
+            polled.push_back(ServerItem::Event(event));
+        }
+
+        for (id, packet) in incoming_packets.drain(..) {
+            polled.push_back(ServerItem::Packet(id, packet));
+        }
+    }
+
+    /// Tick until `stop` is set, handing every event and packet to `handler`.
+    /// Each tick blocks in poll until there's something to do, so this doesn't spin while the server is idle. Call
+    /// `Wakeup::wake` after setting `stop` from another thread to have it noticed straight away.
+    /// Once `stop` is set, the server shuts down gracefully as with `begin_shutdown`, and this returns after the handler
+    /// gets `ShutdownComplete`. Returns an error if polling fails.
+    pub fn run<H: ServerHandler>(&mut self, handler: &mut H, stop: &AtomicBool) -> Result<()> {
+        if self.is_shut_down {
+            return Err(Error::ServerClosed);
+        }
+
+        while !self.is_shut_down {
+            if stop.load(Ordering::Acquire) {
+                self.begin_shutdown();
+            }
+
+            for event in into_events(self.run_tick(Some(RUN_POLL_TIMEOUT))?) {
+                handler.on_event(self, event);
+            }
+
+            while let Some((from, packet)) = self.incoming_packets.pop_front() {
+                handler.on_packet(self, from, packet);
+            }
+
+            handler.on_tick(self);
+
+            // Send whatever the handler queued without waiting for the next tick
+            self.flush();
+        }
+
+        Ok(())
+    }
+}
+
+impl<P: Protocol> Server<P> {
+    /// Queue a protocol message to be sent on the next server tick. Returns the error if it fails to encode.
+    pub fn send(&mut self, recipient: PacketRecipient, packet: P) -> Result<()> {
+        self.queue(
+            recipient,
+            Box::new(Encoded(packet)),
+            None,
+            Priority::Normal,
+            DEFAULT_CHANNEL,
+        )
+    }
+
+    /// Queue a protocol message with the given priority. This works just like `Server::send_with_priority`.
+    pub fn send_with_priority(
+        &mut self,
+        recipient: PacketRecipient,
+        packet: P,
+        priority: Priority,
+    ) -> Result<()> {
+        self.queue(
+            recipient,
+            Box::new(Encoded(packet)),
+            None,
+            priority,
+            DEFAULT_CHANNEL,
+        )
+    }
+
+    /// Queue a protocol message on one of the configured channels. This works just like `Server::send_on_channel`.
+    pub fn send_on_channel(
+        &mut self,
+        recipient: PacketRecipient,
+        channel: u8,
+        packet: P,
+    ) -> Result<()> {
+        self.queue(
+            recipient,
+            Box::new(Encoded(packet)),
+            None,
+            Priority::Normal,
+            channel,
+        )
+    }
+
+    /// Queue a protocol message, and find out when it's been handed to the OS for each recipient. This works just like
+    /// `Server::send_tracked`.
+    pub fn send_tracked(&mut self, recipient: PacketRecipient, packet: P) -> Result<MessageId> {
+        let message = self.message_ids.next();
+        self.queue(
+            recipient,
+            Box::new(Encoded(packet)),
+            Some(message),
+            Priority::Normal,
+            DEFAULT_CHANNEL,
+        )?;
+        Ok(message)
+    }
+
+    /// Queue a protocol message to be sent once `delay` has passed. This works just like `Server::send_after`.
+    pub fn send_after(
+        &mut self,
+        recipient: PacketRecipient,
+        packet: P,
+        delay: Duration,
+    ) -> Result<ScheduledId> {
+        self.schedule(recipient, Box::new(Encoded(packet)), delay)
+    }
+
+    /// Drain any incoming messages that decoded into the protocol, and return them.
+    /// With decode workers, this also picks up anything they've finished since the last tick.
+    pub fn drain_incoming_typed(&mut self) -> Vec<(ConnectionId, P)> {
+        let mut net_events = std::mem::take(&mut self.pending_events);
+        self.collect_decoded(&mut net_events);
+        self.pending_events = net_events;
+
+        self.typed_incoming.drain(..).collect()
+    }
+
+    /// Runs a network tick, then decodes the packets it received into the protocol.
+    /// Packets that don't decode are dropped, with a `ProtocolError` event for each.
+    pub fn tick(&mut self) -> Result<Vec<ServerEvent>> {
+        Ok(into_events(self.tick_timed()?))
+    }
+
+    /// Same as `tick`, but each event carries the time it was observed.
+    pub fn tick_timed(&mut self) -> Result<Vec<TimedEvent<ServerEvent>>> {
+        let mut net_events = self.run_tick(Some(self.config.tick_timeout))?;
+        self.decode_incoming(&mut net_events);
+
+        Ok(net_events)
+    }
+
+    /// Like the raw server's `tick_blocking`, then decodes the packets it received into the protocol.
+    pub fn tick_blocking(&mut self, timeout: Option<Duration>) -> Result<Vec<ServerEvent>> {
+        let mut net_events = self.run_tick(timeout)?;
+        self.decode_incoming(&mut net_events);
+
+        Ok(into_events(net_events))
+    }
+
+    /// Like the raw server's `run`, but received packets are decoded and handed to `ServerHandler::on_message`.
+    pub fn run<H: ServerHandler<P>>(&mut self, handler: &mut H, stop: &AtomicBool) -> Result<()> {
+        if self.is_shut_down {
+            return Err(Error::ServerClosed);
+        }
+
+        while !self.is_shut_down {
+            if stop.load(Ordering::Acquire) {
+                self.begin_shutdown();
+            }
+
+            let mut net_events = self.run_tick(Some(RUN_POLL_TIMEOUT))?;
+            self.decode_incoming(&mut net_events);
+
+            for event in into_events(net_events) {
+                handler.on_event(self, event);
+            }
+
+            while let Some((from, message)) = self.typed_incoming.pop_front() {
+                handler.on_message(self, from, message);
+            }
+
+            handler.on_tick(self);
+
+            // Send whatever the handler queued without waiting for the next tick
+            self.flush();
+        }
+
+        Ok(())
+    }
+
+    /// Decode the packets the last tick received into the protocol, or hand them to the decode workers if there are
+    /// any and pick up whatever they've finished.
+    fn decode_incoming(&mut self, net_events: &mut Vec<TimedEvent<ServerEvent>>) {
+        if self.config.decode_workers > 0 && self.decode_pool.is_none() {
+            match DecodePool::new(self.config.decode_workers, self.waker.clone()) {
+                Ok(pool) => self.decode_pool = Some(pool),
+                Err(e) => eprintln!("Failed to start decode workers, decoding inline! {}", e),
+            }
+        }
+
+        match self.decode_pool.as_mut() {
+            Some(pool) => {
+                for (id, packet) in self.incoming_packets.drain(..) {
+                    pool.submit(id, packet);
+                }
+
+                self.collect_decoded(net_events);
+            }
+            None => {
+                while let Some((id, packet)) = self.incoming_packets.pop_front() {
+                    let decoded = P::decode(packet.header.id, &packet.body);
+                    self.push_decoded(id, packet.header.id, decoded, net_events);
+                }
+            }
+        }
+    }
+
+    /// Pick up whatever the decode workers have finished.
+    fn collect_decoded(&mut self, net_events: &mut Vec<TimedEvent<ServerEvent>>) {
+        while let Some((id, packet_id, decoded)) =
+            self.decode_pool.as_mut().and_then(|pool| pool.try_recv())
+        {
+            self.push_decoded(id, packet_id, decoded, net_events);
+        }
+    }
+
+    fn push_decoded(
+        &mut self,
+        id: ConnectionId,
+        packet_id: u8,
+        decoded: Result<P>,
+        net_events: &mut Vec<TimedEvent<ServerEvent>>,
+    ) {
+        match decoded {
+            Ok(message) => self.typed_incoming.push_back((id, message)),
+            Err(e) => {
+                eprintln!(
+                    "Failed to decode packet {} from connection {}! {}",
+                    packet_id, id, e
+                );
+                net_events.push(TimedEvent::now(ServerEvent::ProtocolError {
+                    from: id,
+                    id: packet_id,
+                }));
+            }
+        }
+    }
 }
 
-pub struct Connection {
-    token: Token,
-    socket: TcpStream,
-    is_disconnected: bool,
-    buffer: NetworkBuffer,
-    outgoing_packets: VecDeque<Box<dyn PacketBody>>,
-}
+impl<P> Server<P> {
+    /// Begin hosting a TCP server with an already validated config.
+    pub(crate) fn with_config(address: SocketAddr, config: ServerConfig) -> Result<Server<P>> {
+        Server::with_listener(TcpListener::bind(address)?, config)
+    }
+
+    /// Begin hosting on an already bound listener, with an already validated config.
+    pub(crate) fn adopt_listener(
+        listener: std::net::TcpListener,
+        config: ServerConfig,
+    ) -> Result<Server<P>> {
+        // Catch listeners that can't accept now, rather than failing on the first tick
+        if listener.local_addr().is_err() {
+            return Err(Error::InvalidListener("listener isn't bound to an address"));
+        }
+
+        if let Some(e) = listener.take_error()? {
+            eprintln!("Adopted listener has a pending error! {}", e);
+            return Err(Error::InvalidListener(
+                "listener has a pending socket error",
+            ));
+        }
+
+        listener.set_nonblocking(true)?;
+        Server::with_listener(TcpListener::from_std(listener), config)
+    }
+
+    fn with_listener(mut tcp_listener: TcpListener, config: ServerConfig) -> Result<Server<P>> {
+        // Register to read events
+        let poll = Poll::new().unwrap();
+        poll.registry()
+            .register(&mut tcp_listener, LOCAL_TOKEN, Interest::READABLE)?;
+
+        // Lets other threads wake up the poll when they queue packets through a `PacketSender`
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER_TOKEN)?);
+        let (command_sender, command_receiver) = mpsc::channel();
+        let buffer_pool = BufferPool::new(config.buffer_pool_size, config.max_buffer_size);
+
+        Ok(Server {
+            tcp_listener,
+            events: Events::with_capacity(EVENTS_CAPACITY),
+            poll,
+            connections: ConnectionSlab::new(),
+            incoming_packets: VecDeque::new(),
+            typed_incoming: VecDeque::new(),
+            decode_pool: None,
+            relay: None,
+            diagnostics: if config.diagnostics {
+                Some(DiagnosticsRecorder::new())
+            } else {
+                None
+            },
+            config,
+            is_shut_down: false,
+            shutting_down: None,
+            waker,
+            command_sender,
+            command_receiver,
+            packet_logger: None,
+            started_at: Instant::now(),
+            stats: ServerStats::default(),
+            ready: Vec::new(),
+            is_incoming_capped: false,
+            pending_events: Vec::new(),
+            message_ids: MessageIds::new(),
+            arrivals: Vec::new(),
+            polled: VecDeque::new(),
+            scheduled: PacketSchedule::new(),
+            buffer_pool,
+            bans: HashMap::new(),
+            packet_size_limits: HashMap::new(),
+        })
+    }
+
+    /// Take over a connection accepted somewhere else, such as by an external acceptor.
+    /// The stream is switched to non-blocking mode and handled like any accepted connection, including the connection
+    /// limits. A `ClientConnected` event is emitted for it on the next tick.
+    pub fn adopt_connection(
+        &mut self,
+        stream: std::net::TcpStream,
+        addr: SocketAddr,
+    ) -> Result<ConnectionId> {
+        if self.is_shut_down || self.shutting_down.is_some() {
+            return Err(Error::ServerClosed);
+        }
+
+        if let Some(reason) = self.check_limits(addr) {
+            self.stats.connections_rejected = self.stats.connections_rejected.saturating_add(1);
+            return Err(Error::ConnectionRejected(reason));
+        }
+
+        stream.set_nonblocking(true)?;
+        let id = self.add_connection(TcpStream::from_std(stream), addr)?;
+        self.pending_events
+            .push(TimedEvent::now(ServerEvent::ClientConnected(id, addr)));
+
+        Ok(id)
+    }
+
+    /// Get the address the server is listening on.
+    /// Useful when hosting on port 0 and letting the OS pick a free port.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.tcp_listener.local_addr()?)
+    }
+
+    /// Get the current number of connections.
+    pub fn num_connections(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Get the maximum number of connections allowed.
+    pub fn connection_limit(&self) -> usize {
+        self.config.connection_limit
+    }
+
+    /// Get the config the server was hosted with.
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    /// Kick a connection from the server.
+    /// Nothing more is read from it, but whatever is already queued for it, like a packet saying why it was kicked,
+    /// is still written for up to `ServerConfig::kick_drain_ticks` ticks. It's closed, and `ClientDisconnected` is
+    /// emitted, once the queue is empty or the time is up.
+    pub fn kick(&mut self, connection_id: ConnectionId) -> Result<()> {
+        let drain_ticks = self.config.kick_drain_ticks;
+        let conn: &mut Connection = match self.connections.get_mut(connection_id) {
+            Some(c) => c,
+            None => {
+                return Err(Error::ConnectionNotFound);
+            }
+        };
+
+        // Kicking it again doesn't give it any more time
+        if conn.draining.is_none() {
+            conn.draining = Some((drain_ticks, DisconnectReason::Kicked));
+        }
+
+        Ok(())
+    }
+
+    /// Kick a connection from the server, telling it why.
+    /// The reason is queued behind everything else for the connection, cut short if it's longer than the largest packet
+    /// body, and then the connection is kicked as with `kick`. A grubbnet client reports it with
+    /// `ClientEvent::Kicked` before it disconnects. If the reason can't be written within `kick_drain_ticks` ticks, the
+    /// connection is closed without it.
+    pub fn kick_with_reason(
+        &mut self,
+        connection_id: ConnectionId,
+        reason: impl Into<String>,
+    ) -> Result<()> {
+        let max_body_size = self.config.max_body_size();
+        let conn: &mut Connection = match self.connections.get_mut(connection_id) {
+            Some(c) => c,
+            None => {
+                return Err(Error::ConnectionNotFound);
+            }
+        };
+
+        // A connection that's already been kicked is closing for whatever reason it was given first
+        if conn.draining.is_none() && !conn.is_disconnected {
+            let kick = Kick::truncated(reason.into(), max_body_size);
+            conn.outgoing_packets
+                .push_back(Outgoing::boxed(Box::new(kick), None, Priority::Low));
+        }
+
+        self.kick(connection_id)
+    }
+
+    /// Kick a connection from the server without writing anything else to it.
+    /// It's closed at the end of the next tick, and anything still queued for it is dropped.
+    pub fn kick_now(&mut self, connection_id: ConnectionId) -> Result<()> {
+        let conn: &mut Connection = match self.connections.get_mut(connection_id) {
+            Some(c) => c,
+            None => {
+                return Err(Error::ConnectionNotFound);
+            }
+        };
+
+        conn.disconnect(DisconnectReason::Kicked);
+
+        Ok(())
+    }
+
+    /// Ban an address, rejecting new connections from it with `RejectReason::Banned` for `duration`, or until
+    /// `unban_ip` if there's no duration. Connections it already has are left open, so kick them too if they should go.
+    /// Banning an address that's already banned replaces its ban.
+    pub fn ban_ip(&mut self, ip: IpAddr, duration: Option<Duration>) {
+        self.purge_bans();
+        self.bans
+            .insert(ip, duration.map(|duration| Instant::now() + duration));
+    }
+
+    /// Lift the ban on an address, whether it was banned with `ban_ip` or for a protocol error. Returns false if it
+    /// wasn't banned.
+    pub fn unban_ip(&mut self, ip: IpAddr) -> bool {
+        let was_banned = self.is_banned(ip);
+        self.bans.remove(&ip);
+        was_banned
+    }
+
+    /// Check whether new connections from an address are being rejected.
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        match self.bans.get(&ip) {
+            Some(Some(until)) => Instant::now() < *until,
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    /// Get every banned address, with when its ban runs out, or `None` if it lasts until it's lifted.
+    pub fn banned_ips(&self) -> impl Iterator<Item = (IpAddr, Option<Instant>)> + '_ {
+        let now = Instant::now();
+        self.bans
+            .iter()
+            .filter(move |(_, until)| until.is_none_or(|until| now < until))
+            .map(|(ip, until)| (*ip, *until))
+    }
+
+    /// Forget bans that have run out.
+    fn purge_bans(&mut self) {
+        let now = Instant::now();
+        self.bans
+            .retain(|_, until| until.is_none_or(|until| now < until));
+    }
+
+    /// Ping a connection to measure its round trip time, on top of any pings sent for `ServerConfig::ping_interval`.
+    /// Clients built on grubbnet answer pings without involving the application, and the answer comes back in a
+    /// `Pong` event, or a `PingTimedOut` event if it doesn't arrive within `ServerConfig::ping_timeout`. The answer
+    /// also feeds into `rtt`.
+    pub fn ping(&mut self, connection_id: ConnectionId) -> Result<()> {
+        let conn = self
+            .connections
+            .get_mut(connection_id)
+            .ok_or(Error::ConnectionNotFound)?;
+        let ping = conn.rtt.ping(Instant::now());
+        conn.outgoing_packets.push_back(Outgoing::new(ping));
+
+        Ok(())
+    }
+
+    /// Get the smoothed round trip time to a connection.
+    /// Returns `None` if the connection doesn't exist, or hasn't answered a ping yet. See `ServerConfig::ping_interval`.
+    pub fn rtt(&self, connection_id: ConnectionId) -> Option<Duration> {
+        self.connections.get(connection_id)?.rtt.rtt()
+    }
+
+    /// Get a snapshot of what the server knows about a connection.
+    pub fn connection_stats(&self, connection_id: ConnectionId) -> Option<ConnectionStats> {
+        let conn = self.connections.get(connection_id)?;
+
+        Some(ConnectionStats {
+            addr: conn.addr,
+            connected_at: conn.connected_at,
+            rtt: conn.rtt.rtt(),
+            jitter: conn.rtt.jitter(),
+            outgoing_queue_len: conn.outgoing_packets.len(),
+            health: tcp_health(&conn.socket),
+            traffic: conn.traffic,
+        })
+    }
+
+    /// Cancel a packet scheduled with `send_after`. Returns false if it has already been sent or cancelled.
+    pub fn cancel_scheduled(&mut self, id: ScheduledId) -> bool {
+        self.scheduled.cancel(id)
+    }
+
+    /// Get the number of packets scheduled with `send_after` that haven't been sent yet.
+    pub fn scheduled_count(&self) -> usize {
+        self.scheduled.len()
+    }
+
+    /// Get how many bytes are waiting to be written to a connection, including the rest of a packet that's partway out.
+    /// Useful for shedding load from a connection that's falling behind before `max_outgoing_bytes` does it for you.
+    pub fn queued_bytes(&self, connection_id: ConnectionId) -> Result<usize> {
+        match self.connections.get(connection_id) {
+            Some(conn) => Ok(conn.queued_bytes()),
+            None => Err(Error::ConnectionNotFound),
+        }
+    }
+
+    /// Get the address a connection came from.
+    pub fn peer_addr(&self, connection_id: ConnectionId) -> Result<SocketAddr> {
+        match self.connections.get(connection_id) {
+            Some(conn) => Ok(conn.addr),
+            None => Err(Error::ConnectionNotFound),
+        }
+    }
+
+    /// Get when a connection was accepted.
+    pub fn connected_at(&self, connection_id: ConnectionId) -> Result<Instant> {
+        match self.connections.get(connection_id) {
+            Some(conn) => Ok(conn.connected_at),
+            None => Err(Error::ConnectionNotFound),
+        }
+    }
+
+    /// Iterate over every open connection and the address it came from, in `ConnectionId` index order.
+    /// Connections that are still flushing after a kick are included until they close.
+    pub fn connections(&self) -> impl Iterator<Item = (ConnectionId, SocketAddr)> + '_ {
+        self.connections.iter().map(|c| (c.id, c.addr))
+    }
+
+    /// Get the kernel's view of a connection: its round trip time, retransmits and unacknowledged bytes.
+    /// Returns `None` if the connection doesn't exist, or the platform doesn't support this (anything but Linux).
+    pub fn connection_health(&self, connection_id: ConnectionId) -> Option<TcpHealth> {
+        tcp_health(&self.connections.get(connection_id)?.socket)
+    }
+
+    /// Cap how many received packets may sit unread, or remove the cap with `None`. See `ServerConfig::max_incoming`.
+    pub fn set_max_incoming_queue(&mut self, max: Option<usize>) {
+        self.config.max_incoming = max;
+    }
+
+    /// Disconnect connections that haven't sent any bytes for `timeout`, or remove the timeout with `None`. See
+    /// `ServerConfig::idle_timeout`.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.config.idle_timeout = timeout;
+    }
+
+    /// Only accept bodies up to `max` bytes for packets with this id, which may be far below `max_packet_size` for
+    /// packets that are always small. The limit applies to the body the application would see, after fragments are
+    /// put back together and compressed bodies are inflated. A connection that sends a bigger one gets a
+    /// `PacketSizeExceeded` event and is disconnected. Ids without a limit of their own only have to fit the global one.
+    pub fn set_packet_size_limit(&mut self, packet_id: u8, max: usize) {
+        self.packet_size_limits.insert(packet_id, max);
+    }
+
+    /// Remove the limit set for a packet id with `set_packet_size_limit`. Returns false if it didn't have one.
+    pub fn clear_packet_size_limit(&mut self, packet_id: u8) -> bool {
+        self.packet_size_limits.remove(&packet_id).is_some()
+    }
+
+    /// Set what happens when a connection's outgoing queue is full, for every connection without its own policy.
+    pub fn set_slow_consumer_policy(&mut self, policy: SlowConsumerPolicy) {
+        self.config.slow_consumer_policy = policy;
+    }
+
+    /// Override the slow consumer policy for a single connection, or go back to the server's policy with `None`.
+    pub fn set_connection_policy(
+        &mut self,
+        connection_id: ConnectionId,
+        policy: Option<SlowConsumerPolicy>,
+    ) -> Result<()> {
+        let conn = match self.connections.get_mut(connection_id) {
+            Some(c) => c,
+            None => return Err(Error::ConnectionNotFound),
+        };
+
+        conn.policy = policy;
+
+        Ok(())
+    }
+
+    /// Remove packets queued for a connection whose id matches `predicate`, and return how many were removed.
+    /// Queued packets haven't been written to the socket at all yet, so cancelling them never cuts a frame short.
+    /// Packets still on their way from a `PacketSender` aren't queued until the next tick, so they aren't affected.
+    pub fn cancel_pending<F>(&mut self, connection_id: ConnectionId, predicate: F) -> Result<usize>
+    where
+        F: Fn(u8) -> bool,
+    {
+        let conn = match self.connections.get_mut(connection_id) {
+            Some(c) => c,
+            None => return Err(Error::ConnectionNotFound),
+        };
+
+        Ok(cancel_queued(
+            &mut conn.outgoing_packets,
+            predicate,
+            &mut conn.failed_messages,
+        ))
+    }
+
+    /// Get totals since the server started hosting.
+    pub fn stats(&self) -> &ServerStats {
+        &self.stats
+    }
+
+    /// Summarize the server and every connection in one owned struct.
+    /// This only looks at what the server tracks and what the kernel reports, and never reads or writes sockets.
+    pub fn snapshot(&self) -> ServerSnapshot {
+        let connections = self
+            .connections
+            .iter()
+            .map(|c| ConnectionSnapshot {
+                id: c.id,
+                addr: c.addr,
+                connected_for: c.connected_at.elapsed(),
+                rtt: c.rtt.rtt(),
+                queued_out_packets: c.outgoing_packets.len(),
+                queued_out_bytes: c.queued_bytes(),
+                buffered_in_bytes: c.buffer.len(),
+                idle_for: c.last_read.max(c.last_write).elapsed(),
+                health: tcp_health(&c.socket),
+            })
+            .collect();
+
+        ServerSnapshot {
+            uptime: self.started_at.elapsed(),
+            num_connections: self.connections.len(),
+            peak_connections: self.stats.peak_connections,
+            connection_limit: self.config.connection_limit,
+            accept_state: if self.is_shut_down {
+                AcceptState::ShutDown
+            } else if self.shutting_down.is_some() {
+                AcceptState::ShuttingDown
+            } else {
+                AcceptState::Accepting
+            },
+            stats: self.stats.clone(),
+            connections,
+        }
+    }
+
+    /// Enable or disable diagnostics gathering.
+    /// While disabled, `tick` doesn't take any timings. Disabling clears any samples gathered so far.
+    pub fn set_diagnostics(&mut self, enabled: bool) {
+        if !enabled {
+            self.diagnostics = None;
+        } else if self.diagnostics.is_none() {
+            self.diagnostics = Some(DiagnosticsRecorder::new());
+        }
+    }
+
+    /// Get a snapshot of event loop health over the most recent ticks.
+    /// Timings and per-tick counters are only populated while diagnostics are enabled, but queue sizes are always current.
+    pub fn diagnostics(&self) -> ServerDiagnostics {
+        let mut diagnostics = match self.diagnostics.as_ref() {
+            Some(recorder) => recorder.summarize(),
+            None => ServerDiagnostics::default(),
+        };
 
-impl Connection {
-    pub fn new(token: Token, socket: TcpStream) -> Self {
-        Connection {
-            token,
-            socket,
-            is_disconnected: false,
-            buffer: NetworkBuffer::new(),
-            outgoing_packets: VecDeque::new(),
+        diagnostics.incoming_queue_len = self.queued_incoming();
+        diagnostics.outgoing_queue_lens = self
+            .connections
+            .iter()
+            .map(|c| (c.id, c.outgoing_packets.len()))
+            .collect();
+
+        diagnostics
+    }
+
+    /// Set a hook that sees every packet as it's received (after parsing) and sent (after serialization), or `None`
+    /// to remove it. Grubbnet's own packets are included, and marked as internal.
+    pub fn set_packet_logger(&mut self, logger: Option<PacketLogger>) {
+        self.packet_logger = logger;
+    }
+
+    /// Let clients send `RelayEnvelope`s to each other through the server.
+    /// Envelopes are forwarded without being handed to the application, as long as `policy` allows them.
+    pub fn enable_relay(&mut self, policy: RelayPolicy) {
+        self.relay = Some(policy);
+    }
+
+    /// Stop relaying `RelayEnvelope`s. Any that arrive afterwards are treated as normal packets.
+    pub fn disable_relay(&mut self) {
+        self.relay = None;
+    }
+
+    /// Start shutting down gracefully, and keep ticking until `ShutdownComplete` is emitted.
+    /// New connections stop being accepted straight away. Every open connection gets a shutdown notice queued behind
+    /// everything else for it (see `ServerConfig::shutdown_notice`), and nothing more is read from it. Each one is
+    /// closed with `DisconnectReason::ServerShutdown` once its queue is written, or after
+    /// `ServerConfig::shutdown_drain_ticks` ticks. Connections that were already kicked close as kicked.
+    /// Queue anything the clients should get before the notice before calling this.
+    pub fn begin_shutdown(&mut self) {
+        if self.is_shut_down || self.shutting_down.is_some() {
+            return;
+        }
+
+        let _ = self.poll.registry().deregister(&mut self.tcp_listener);
+        let drain_ticks = self.config.shutdown_drain_ticks;
+        for conn in self.connections.iter_mut() {
+            if conn.is_disconnected || conn.draining.is_some() {
+                continue;
+            }
+
+            if self.config.shutdown_notice {
+                conn.outgoing_packets.push_back(Outgoing::boxed(
+                    Box::new(ShutdownNotice),
+                    None,
+                    Priority::Low,
+                ));
+            }
+            conn.draining = Some((drain_ticks, DisconnectReason::ServerShutdown));
         }
+
+        self.shutting_down = Some(ShuttingDown {
+            summary: ShutdownSummary::default(),
+            packets_sent_before: self.stats.packets_sent,
+        });
+
+        // Don't wait out the poll timeout before starting, since there may be nothing left to wait for
+        let _ = self.waker.wake();
     }
-}
 
-pub struct Server {
-    tcp_listener: TcpListener,
-    events: Events,
-    poll: Poll,
-    connections: HashMap<Token, Connection>,
-    connection_limit: usize,
-    token_counter: usize,
-    incoming_packets: VecDeque<(Token, Packet)>,
-}
+    /// Whether `begin_shutdown` has been called, and the shutdown hasn't finished yet.
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.is_some()
+    }
 
-impl Server {
-    /// Begin hosting a TCP server.
-    pub fn host(ip: &str, port: u16, connection_limit: usize) -> Result<Server> {
-        let address = format!("{}:{}", ip, port).parse().unwrap();
-        let mut tcp_listener = TcpListener::bind(address)?;
+    /// Flush whatever can be sent to each connection without blocking, then close every connection and stop listening.
+    /// No `ClientDisconnected` events are emitted for connections closed this way, and no delivery events for tracked
+    /// packets. If `begin_shutdown` was called first, this cuts it short and the summary covers the whole shutdown.
+    /// Returns an empty summary if the server was already shut down.
+    /// Dropping the server does the same thing, but calling this first makes the intent explicit.
+    pub fn shutdown(&mut self) -> ShutdownSummary {
+        if self.is_shut_down {
+            return ShutdownSummary::default();
+        }
 
-        // Register to read events
-        let poll = Poll::new().unwrap();
-        poll.registry()
-            .register(&mut tcp_listener, LOCAL_TOKEN, Interest::READABLE)?;
+        let mut summary = match self.shutting_down.take() {
+            Some(shutting_down) => shutting_down.finish(&self.stats),
+            None => ShutdownSummary::default(),
+        };
 
-        Ok(Server {
-            tcp_listener,
-            events: Events::with_capacity(EVENTS_CAPACITY),
-            poll,
-            connections: HashMap::new(),
-            connection_limit,
-            token_counter: 0,
-            incoming_packets: VecDeque::new(),
-        })
+        // Every connection shares one deadline, so shutting down is bounded no matter how many there are
+        let deadline = Instant::now() + CLOSE_FLUSH_TIMEOUT;
+        let packet_logger = &mut self.packet_logger;
+        for mut conn in self.connections.drain() {
+            summary.connections_closed += 1;
+            let queued = conn.queued_packets();
+            let mut written = 0;
+            if !conn.is_disconnected {
+                let id = conn.id;
+                (_, written) = flush_packets(
+                    &mut conn.socket,
+                    conn.unsent.take(),
+                    &mut conn.outgoing_packets,
+                    &conn.format,
+                    &mut conn.sequence,
+                    deadline,
+                    |packet_id, sequence, body| {
+                        log_outgoing(packet_logger, Some(id), packet_id, sequence, body)
+                    },
+                );
+            }
+            summary.packets_flushed += written;
+            summary.packets_dropped += queued - written;
+
+            let _ = conn.socket.shutdown(Shutdown::Both);
+            let _ = self.poll.registry().deregister(&mut conn.socket);
+        }
+
+        let _ = self.poll.registry().deregister(&mut self.tcp_listener);
+        self.incoming_packets.clear();
+        self.typed_incoming.clear();
+        self.scheduled.clear();
+        self.decode_pool = None;
+        self.stats.open_connections = 0;
+        self.is_shut_down = true;
+
+        summary
     }
 
-    /// Get the current number of connections.
-    pub fn num_connections(&self) -> usize {
-        self.connections.len()
+    /// Whether `shutdown` has been called.
+    pub fn is_shut_down(&self) -> bool {
+        self.is_shut_down
     }
 
-    /// Get the maximum number of connections allowed.
-    pub fn connection_limit(&self) -> usize {
-        self.connection_limit
+    /// Get a handle for queuing packets and kicks from other threads.
+    /// See `PacketSender` for details.
+    pub fn sender(&self) -> PacketSender {
+        PacketSender::new(
+            self.command_sender.clone(),
+            self.waker.clone(),
+            self.config.write_format(),
+        )
     }
 
-    /// Drain any incoming packets and return them.
-    pub fn drain_incoming_packets(&mut self) -> Vec<(Token, Packet)> {
-        self.incoming_packets.drain(..).collect()
+    /// Get a handle for waking the server out of a blocking `run` from other threads.
+    pub fn wakeup(&self) -> Wakeup {
+        Wakeup::new(self.waker.clone())
     }
 
-    /// Kick a connection from the server.
-    pub fn kick(&mut self, connection_token: Token) -> Result<()> {
-        let conn: &mut Connection = match self.connections.get_mut(&connection_token) {
-            Some(c) => c,
-            None => {
+    /// Write queued packets straight away to every connection whose socket is known to be writable, rather than
+    /// waiting for the next tick. Call this after queuing responses to packets from the last tick to save a tick of
+    /// latency. `SentPacket` events for these writes come with the next tick.
+    pub fn flush(&mut self) {
+        let mut net_events = std::mem::take(&mut self.pending_events);
+        self.flush_writable(&mut net_events);
+        self.pending_events = net_events;
+    }
+
+    /// Write queued packets to the connections whose sockets have been writable since their last writable event.
+    /// Returns the number of packets written.
+    fn flush_writable(&mut self, net_events: &mut Vec<TimedEvent<ServerEvent>>) -> usize {
+        let mut written = 0;
+        for conn in self.connections.iter_mut() {
+            if conn.is_writable && !conn.is_disconnected && conn.has_unwritten() {
+                written += conn.write_queued(&mut self.packet_logger, &mut self.stats, net_events);
+            }
+        }
+
+        written
+    }
+
+    /// Received packets the application hasn't taken yet, including any still with the decode workers.
+    fn queued_incoming(&self) -> usize {
+        let decoding = self.decode_pool.as_ref().map_or(0, |pool| pool.in_flight);
+        self.incoming_packets.len() + self.typed_incoming.len() + decoding
+    }
+
+    /// Hold a packet until `delay` has passed, unless the schedule is full.
+    fn schedule(
+        &mut self,
+        recipient: PacketRecipient,
+        packet_boxed: Box<dyn PacketBody>,
+        delay: Duration,
+    ) -> Result<ScheduledId> {
+        if let Some(max) = self.config.max_scheduled {
+            if self.scheduled.len() >= max {
+                return Err(Error::ScheduleFull);
+            }
+        }
+
+        let payload = Payload::serialized(packet_boxed.as_ref())?;
+        self.config
+            .write_format()
+            .check_sendable(payload.id(), DEFAULT_CHANNEL, payload.len())?;
+        let due = Instant::now() + delay;
+        Ok(self.scheduled.insert(due, (recipient, payload)))
+    }
+
+    /// Serialize a packet and queue it for each of the recipients.
+    /// It's serialized once up front, so its size counts towards `max_outgoing_bytes`, a broadcast can share the body
+    /// between every queue it goes in, and a failure is returned to the caller rather than hit while writing. The same
+    /// goes for a reserved id, a channel that isn't configured, or a body too big to send.
+    fn queue(
+        &mut self,
+        recipient: PacketRecipient,
+        packet_boxed: Box<dyn PacketBody>,
+        message: Option<MessageId>,
+        priority: Priority,
+        channel: u8,
+    ) -> Result<()> {
+        // An id kept around after its connection closed must not reach whoever has its slot now
+        if let PacketRecipient::Single(id) = recipient {
+            if self.connections.get(id).is_none() {
                 return Err(Error::ConnectionNotFound);
             }
-        };
+        }
 
-        conn.is_disconnected = true;
+        let payload = Payload::serialized(packet_boxed.as_ref())?;
+        self.config
+            .write_format()
+            .check_sendable(payload.id(), channel, payload.len())?;
+        self.queue_payload(recipient, payload, message, priority, channel);
 
         Ok(())
     }
 
-    /// Send a packet.
-    /// This function will box the packet, then queue it to be sent on the next server tick.
-    pub fn send(&mut self, recipient: PacketRecipient, packet: impl PacketBody) {
-        let boxed: Box<dyn PacketBody> = Box::new(packet);
-        self.send_boxed(recipient, boxed);
+    /// Queue an already serialized packet for each of the recipients. The packet has already been checked with
+    /// `check_sendable`.
+    fn queue_payload(
+        &mut self,
+        recipient: PacketRecipient,
+        payload: Payload,
+        message: Option<MessageId>,
+        priority: Priority,
+        channel: u8,
+    ) {
+        debug_assert!(
+            is_application_packet_id(payload.id()),
+            "Packet id {:#04x} is reserved for grubbnet's own packets!",
+            payload.id()
+        );
+        debug_assert!(
+            channel < self.config.channels,
+            "Channel {} is past the {} channels in the config!",
+            channel,
+            self.config.channels
+        );
+        if let PacketRecipient::Single(id) = recipient {
+            if let Some(connection) = self.connections.get_mut(id) {
+                connection.queue(
+                    Outgoing::payload(payload, message, priority).on_channel(channel),
+                    &self.config,
+                );
+            }
+
+            return;
+        }
+
+        let recipients = self.connections.iter_mut().filter(|c| match &recipient {
+            PacketRecipient::All => true,
+            PacketRecipient::Single(id) => c.id == *id,
+            PacketRecipient::Exclude(id) => c.id != *id,
+            PacketRecipient::ExcludeMany(filter) => !filter.contains(&c.id),
+            PacketRecipient::Include(targets) => targets.contains(&c.id),
+            PacketRecipient::Where(predicate) => predicate(c.id),
+        });
+        for connection in recipients {
+            connection.queue(
+                Outgoing::payload(payload.clone(), message, priority).on_channel(channel),
+                &self.config,
+            );
+        }
     }
 
-    /// Send a boxed packet.
-    /// Similar to `send`, but this is moreuseful when you have a boxed packet already and don't want
-    /// to cast it to a concrete type before sending it.
-    pub fn send_boxed(&mut self, recipient: PacketRecipient, packet_boxed: Box<dyn PacketBody>) {
-        match recipient {
-            PacketRecipient::All => {
-                for (_, connection) in self.connections.iter_mut() {
-                    connection.outgoing_packets.push_back(packet_boxed.clone());
-                }
+    /// Check whether a new connection from `addr` would go over a connection limit.
+    fn check_limits(&self, addr: SocketAddr) -> Option<RejectReason> {
+        if self.is_banned(addr.ip()) {
+            return Some(RejectReason::Banned);
+        }
+
+        if self.num_connections() >= self.connection_limit() {
+            return Some(RejectReason::ServerFull);
+        }
+
+        if let Some(limit) = self.config.connection_limit_per_ip {
+            let ip = addr.ip();
+            let count = self
+                .connections
+                .iter()
+                .filter(|c| c.addr.ip() == ip)
+                .count();
+            if count >= limit {
+                return Some(RejectReason::TooManyFromAddress);
             }
-            PacketRecipient::Single(t) => {
-                if let Some(connection) = self.connections.get_mut(&t) {
-                    connection.outgoing_packets.push_back(packet_boxed);
-                }
+        }
+
+        None
+    }
+
+    /// Tell a connection turned away for `reason` that the server is full, if the config asks for it.
+    /// This is a single write that doesn't wait for the socket, since the connection is closed right after either way.
+    fn notify_rejected(&self, socket: &mut TcpStream, reason: RejectReason) {
+        if !self.config.server_full_notice || reason != RejectReason::ServerFull {
+            return;
+        }
+
+        let notice = ServerFull {
+            connections: self.num_connections() as u32,
+            limit: self.connection_limit() as u32,
+        };
+        let serialized = serialize_packet_with(
+            &Payload::Packet(Box::new(notice)),
+            &self.config.write_format(),
+            SequenceCounter::new(self.config.sequence_numbers).peek(),
+            DEFAULT_CHANNEL,
+            Instant::now(),
+            |_| {},
+        );
+        if let Ok(data) = serialized {
+            let _ = socket.write(&data);
+        }
+    }
+
+    /// Register a socket and start tracking it as a connection.
+    fn add_connection(&mut self, mut socket: TcpStream, addr: SocketAddr) -> Result<ConnectionId> {
+        if self.config.nodelay {
+            if let Err(e) = socket.set_nodelay(true) {
+                eprintln!("Failed to set nodelay for connection from {}! {}", addr, e);
             }
-            PacketRecipient::Exclude(t) => {
-                let filtered = self.connections.iter_mut().filter(|(tok, _c)| tok.0 != t.0);
-                for (_token, connection) in filtered {
-                    connection.outgoing_packets.push_back(packet_boxed.clone());
+        }
+
+        // Take the next free slot. Its token may have belonged to an old connection, but the id won't match
+        let id = self.connections.next_id();
+
+        // Register the new socket to receive events. Writable events are asked for at the start of the next tick if
+        // there's anything queued for it by then, like the hello.
+        self.poll
+            .registry()
+            .register(&mut socket, id.token(), Interest::READABLE)?;
+
+        // Insert the new connection
+        let buffer = self.buffer_pool.check_out();
+        self.stats.buffers_allocated = self.buffer_pool.allocated();
+        let mut conn = Connection::new(id, socket, addr, buffer, self.config.write_format());
+        conn.sequence = SequenceCounter::new(self.config.sequence_numbers);
+
+        // The hello goes out before anything else, so the client can check it first
+        if let Some(version) = self.config.protocol_version {
+            let max_body_size = self.config.max_body_size() as u32;
+            conn.outgoing_packets.push_back(Outgoing::new(Hello {
+                version,
+                max_body_size,
+            }));
+            conn.handshake_deadline = Some(Instant::now() + self.config.handshake_timeout);
+        }
+
+        self.connections.insert(conn);
+
+        self.stats.connections_accepted = self.stats.connections_accepted.saturating_add(1);
+        self.stats.open_connections = self.connections.len();
+        self.stats.peak_connections = self.stats.peak_connections.max(self.connections.len());
+
+        Ok(id)
+    }
+
+    /// Sends/receives packets based on socket readiness, and accepts new connections.
+    /// Received packets are left undecoded in `incoming_packets`.
+    /// Waits in poll for up to `timeout`, or until there's activity if it's `None`.
+    fn run_tick(&mut self, timeout: Option<Duration>) -> Result<Vec<TimedEvent<ServerEvent>>> {
+        // Only pay for timings when diagnostics are enabled
+        let tick_start = self.diagnostics.as_ref().map(|_| Instant::now());
+        let mut sample = TickSample::default();
+        self.arrivals.clear();
+
+        // Apply anything queued from other threads before handling socket events
+        while let Ok(command) = self.command_receiver.try_recv() {
+            match command {
+                ServerCommand::Send(recipient, payload) => {
+                    self.queue_payload(recipient, payload, None, Priority::Normal, DEFAULT_CHANNEL)
                 }
-            }
-            PacketRecipient::ExcludeMany(filter) => {
-                let filtered = self
-                    .connections
-                    .iter_mut()
-                    .filter(|(tok, _c)| !filter.contains(tok));
-                for (_token, connection) in filtered {
-                    connection.outgoing_packets.push_back(packet_boxed.clone());
+                ServerCommand::Kick(id) => {
+                    // The connection may have gone away since the kick was queued
+                    let _ = self.kick(id);
                 }
             }
-            PacketRecipient::Include(targets) => {
-                let filtered = self
-                    .connections
-                    .iter_mut()
-                    .filter(|(tok, _c)| targets.contains(tok));
-                for (_token, connection) in filtered {
-                    connection.outgoing_packets.push_back(packet_boxed.clone());
-                }
+        }
+
+        for (recipient, payload) in self.scheduled.take_due(Instant::now()) {
+            self.queue_payload(recipient, payload, None, Priority::Normal, DEFAULT_CHANNEL);
+        }
+
+        // Writable events are only asked for while there's something to write, so an idle server can block in poll.
+        // Packets queued since the last tick need the interest added back, and queues that were flushed since need it
+        // taken away.
+        for conn in self.connections.iter_mut() {
+            if conn.is_disconnected || !conn.is_interest_stale() {
+                continue;
+            }
+
+            sample.reregisters += 1;
+            if let Err(e) = conn.reregister(self.poll.registry()) {
+                eprintln!(
+                    "Failed to reregister poll for connection (Id {}). {}",
+                    conn.id, e
+                );
+                conn.disconnect(DisconnectReason::IoError(e.kind()));
             }
         }
-    }
 
-    /// Runs a network tick, which sends/receives packets based on socket readiness, as well as accepts new connections.
-    pub fn tick(&mut self) -> Vec<ServerEvent> {
-        let timeout_dur = std::time::Duration::from_millis(1);
-        self.poll
-            .poll(&mut self.events, Some(timeout_dur))
-            .unwrap_or_else(|e| panic!("Failed to poll for new events! {}", e));
+        // Don't sleep through the next scheduled packet
+        let timeout = match self.scheduled.next_due() {
+            Some(due) => {
+                let until_due = due.saturating_duration_since(Instant::now());
+                Some(timeout.map_or(until_due, |timeout| timeout.min(until_due)))
+            }
+            None => timeout,
+        };
+
+        // Don't sleep while a connection has bytes left over from its last turn, unless it's waiting on the application
+        let is_read_pending = self.connections.iter().any(|c| c.read_pending);
+        let is_incoming_full =
+            matches!(self.config.max_incoming, Some(max) if self.queued_incoming() >= max);
+        let timeout = if is_read_pending && !is_incoming_full {
+            Some(Duration::ZERO)
+        } else {
+            timeout
+        };
+
+        let poll_start = Instant::now();
+        poll_events(&mut self.poll, &mut self.events, timeout)?;
 
-        let mut net_events: Vec<ServerEvent> = Vec::new();
+        if tick_start.is_some() {
+            sample.poll_duration = poll_start.elapsed();
+        }
+
+        let relay_enabled = self.relay.is_some();
+        let mut relay_requests: Vec<(ConnectionId, Packet)> = Vec::new();
+
+        // Gather readiness up front, so connections that skipped reading while the incoming queue was full can be
+        // retried without waiting for another edge from the poll
+        let mut ready = std::mem::take(&mut self.ready);
+        ready.clear();
         for event in self.events.iter() {
-            match event.token() {
+            sample.events += 1;
+            ready.push((event.token(), event.is_readable(), event.is_writable()));
+        }
+
+        let max_incoming = self.config.max_incoming;
+        let is_incoming_full = |queued: usize| matches!(max_incoming, Some(max) if queued >= max);
+        let decoding = self.decode_pool.as_ref().map_or(0, |pool| pool.in_flight);
+        if !is_incoming_full(self.queued_incoming()) {
+            self.is_incoming_capped = false;
+            for conn in self.connections.iter_mut().filter(|c| c.read_pending) {
+                conn.read_pending = false;
+                // Only read once per tick, even if the poll reported the socket too
+                match ready.iter_mut().find(|(token, ..)| *token == conn.token) {
+                    Some((_, readable, _)) => *readable = true,
+                    None => ready.push((conn.token, true, false)),
+                }
+            }
+        }
+
+        let mut net_events: Vec<TimedEvent<ServerEvent>> = self.pending_events.drain(..).collect();
+        for &(token, readable, writable) in ready.iter() {
+            match token {
                 // Local socket is ready to accept
                 LOCAL_TOKEN => loop {
+                    // Several connections can be behind one event, so keep accepting until there are none left
                     let (mut socket, addr) = match self.tcp_listener.accept() {
                         Ok((socket, addr)) => (socket, addr),
+                        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
                         Err(e) => {
-                            if e.kind() == std::io::ErrorKind::WouldBlock {
-                                break;
-                            }
-
-                            println!("{}", e);
+                            eprintln!("Failed to accept a connection! {}", e);
                             break;
                         }
                     };
 
-                    if self.num_connections() >= self.connection_limit() {
-                        println!("Rejecting connection from {}, server is full!", addr.ip());
+                    if let Some(reason) = self.check_limits(addr) {
+                        println!("Rejecting connection from {}, {}!", addr.ip(), reason);
+                        self.notify_rejected(&mut socket, reason);
 
-                        net_events.push(ServerEvent::ConnectionRejected(addr));
+                        self.stats.connections_rejected =
+                            self.stats.connections_rejected.saturating_add(1);
+                        net_events.push(TimedEvent::now(ServerEvent::ConnectionRejected(
+                            addr, reason,
+                        )));
                         continue;
                     }
 
-                    // Increment our token counter, then create a new token for this connection
-                    self.token_counter += 1;
-                    let token = Token(self.token_counter);
-
-                    // Register the new socket to receive events
-                    self.poll.registry().register(
-                        &mut socket,
-                        token,
-                        Interest::READABLE | Interest::WRITABLE,
-                    ).unwrap_or_else(|e| panic!("Failed to register poll for new connection (Token {}, Address {}). {}", token.0, addr, e));
-
-                    // Insert the new connection
-                    self.connections
-                        .insert(token, Connection::new(token, socket));
+                    // Dropping the socket closes it, so the peer isn't left hanging
+                    let id = match self.add_connection(socket, addr) {
+                        Ok(id) => id,
+                        Err(e) => {
+                            eprintln!(
+                                "Failed to register poll for new connection (Address {}). {}",
+                                addr, e
+                            );
+                            let reason = RejectReason::RegistrationFailed;
+                            self.stats.connections_rejected =
+                                self.stats.connections_rejected.saturating_add(1);
+                            net_events.push(TimedEvent::now(ServerEvent::ConnectionRejected(
+                                addr, reason,
+                            )));
+                            continue;
+                        }
+                    };
 
-                    net_events.push(ServerEvent::ClientConnected(token, addr));
+                    net_events.push(TimedEvent::now(ServerEvent::ClientConnected(id, addr)));
                 },
+                // Another thread queued something, which we've already handled above
+                WAKER_TOKEN => {}
                 // Connection socket is ready to read/write
                 token => {
-                    // Get the connection
-                    let conn: &mut Connection =
-                        self.connections.get_mut(&token).unwrap_or_else(|| {
-                            panic!(
-                                "Attempted to handle socket event for non-existent connection {}!",
-                                token.0
-                            )
-                        });
+                    // Get the connection. Slots are only freed at the end of a tick, after their sockets are closed, so an
+                    // event for an empty one is stale and there's nothing to handle.
+                    let conn: &mut Connection = match self.connections.get_by_token_mut(token) {
+                        Some(conn) => conn,
+                        None => {
+                            eprintln!("Got a socket event for empty connection slot {}!", token.0);
+                            continue;
+                        }
+                    };
+                    let id = conn.id;
+
+                    // Leave the bytes in the socket while the application is behind, and let TCP push back on the client
+                    let queued = self.incoming_packets.len() + self.typed_incoming.len() + decoding;
+                    if readable && is_incoming_full(queued) {
+                        conn.read_pending = true;
+                        if !self.is_incoming_capped {
+                            self.is_incoming_capped = true;
+                            net_events.push(TimedEvent::now(ServerEvent::IncomingQueueFull));
+                        }
+                    }
 
                     // Handle reading
-                    if event.is_readable() {
-                        // Loop and read bytes into this connections buffer, until there are no more incoming bytes
-                        let buffer = &mut conn.buffer.data[conn.buffer.offset..];
-                        loop {
-                            match conn.socket.read(buffer) {
-                                Ok(0) => {
-                                    // "Read" 0 bytes, which means the socket has closed
-                                    conn.is_disconnected = true;
-                                    break;
+                    if readable && !conn.read_pending && conn.draining.is_none() {
+                        // Read incoming bytes and process them into packets, until there are no more incoming bytes
+                        let incoming_packets = &mut self.incoming_packets;
+                        let arrivals = &mut self.arrivals;
+                        let outgoing_packets = &mut conn.outgoing_packets;
+                        let rtt = &mut conn.rtt;
+                        let handshake_deadline = &mut conn.handshake_deadline;
+                        let received_sequence = &mut conn.received_sequence;
+                        let protocol_version = self.config.protocol_version;
+                        let channels = self.config.channels;
+                        let packet_size_limits = &self.packet_size_limits;
+                        let mut is_oversized = false;
+                        let packet_logger = &mut self.packet_logger;
+                        let mut limits = self.config.read_limits();
+                        limits.handshake = handshake_deadline.map(|_| HELLO_ACK_PACKET_ID);
+                        limits.max_packets = self.config.max_packets_per_tick;
+                        limits.max_bytes = self.config.max_bytes_per_tick;
+                        limits.read_budget = Some(self.config.read_budget);
+                        let outcome = read_packets(
+                            &mut conn.socket,
+                            &mut conn.buffer,
+                            &mut conn.read_state,
+                            &mut conn.reassembly,
+                            limits,
+                            |packet| {
+                                // Nothing after an oversized packet is handed on, since the connection is on its way out
+                                if is_oversized {
+                                    return;
+                                }
+
+                                log_incoming(packet_logger, Some(id), &packet.header, &packet.body);
+
+                                if let Some(received) = packet.header.sequence {
+                                    if let Some(expected) = received_sequence.observe(received) {
+                                        net_events.push(TimedEvent::now(
+                                            ServerEvent::OutOfSequence {
+                                                id,
+                                                expected,
+                                                received,
+                                            },
+                                        ));
+                                    }
+                                }
+
+                                // Heartbeats and pings are answered straight away, and never reach the application
+                                match packet.header.id {
+                                    HEARTBEAT_PACKET_ID => {
+                                        if let Ok(heartbeat) = Heartbeat::deserialize(&packet.body)
+                                        {
+                                            if !heartbeat.reply {
+                                                let reply = Heartbeat { reply: true };
+                                                outgoing_packets.push_back(Outgoing::new(reply));
+                                            }
+                                        }
+                                        return;
+                                    }
+                                    PING_PACKET_ID => {
+                                        if let Ok(ping) = Ping::deserialize(&packet.body) {
+                                            let pong = Pong { nonce: ping.nonce };
+                                            outgoing_packets.push_back(Outgoing::new(pong));
+                                        }
+                                        return;
+                                    }
+                                    PONG_PACKET_ID => {
+                                        if let Ok(pong) = Pong::deserialize(&packet.body) {
+                                            let event = match rtt
+                                                .on_pong(pong.nonce, Instant::now())
+                                            {
+                                                Some(rtt) => ServerEvent::Pong(id, rtt),
+                                                None => {
+                                                    eprintln!(
+                                                        "Connection {} sent a pong for a ping it wasn't sent!",
+                                                        id
+                                                    );
+                                                    ServerEvent::UnexpectedPong(id)
+                                                }
+                                            };
+                                            net_events.push(TimedEvent::now(event));
+                                        }
+                                        return;
+                                    }
+                                    HELLO_ACK_PACKET_ID => {
+                                        if let Ok(ack) = HelloAck::deserialize(&packet.body) {
+                                            if Some(ack.version) == protocol_version {
+                                                *handshake_deadline = None;
+                                            }
+                                        }
+                                        return;
+                                    }
+                                    _ => {}
                                 }
-                                Ok(read_bytes) => {
-                                    // Read some bytes
-                                    conn.buffer.offset += read_bytes;
+
+                                // The rest of the reserved ids aren't used yet, and nothing from the connection can
+                                // be trusted until it's answered the hello
+                                if !is_application_packet_id(packet.header.id)
+                                    || handshake_deadline.is_some()
+                                {
+                                    return;
+                                }
+
+                                let channel = packet.header.channel();
+                                if channel >= channels {
+                                    net_events.push(TimedEvent::now(ServerEvent::UnknownChannel {
+                                        id,
+                                        channel,
+                                    }));
+                                    return;
                                 }
-                                Err(e) => {
-                                    // Socket is not ready anymore, stop reading
-                                    if e.kind() == std::io::ErrorKind::WouldBlock {
-                                        break;
-                                    } else {
-                                        eprintln!("Unexpected error when reading bytes from connection {}! {}", conn.token.0, e);
-                                        conn.is_disconnected = true;
-                                        break;
+
+                                if let Some(&max) = packet_size_limits.get(&packet.header.id) {
+                                    if packet.body.len() > max {
+                                        net_events.push(TimedEvent::now(
+                                            ServerEvent::PacketSizeExceeded {
+                                                id,
+                                                packet_id: packet.header.id,
+                                                size: packet.body.len(),
+                                                max,
+                                            },
+                                        ));
+                                        is_oversized = true;
+                                        return;
                                     }
                                 }
-                            }
+
+                                // Envelopes are handed to the relay rather than the application, and show up as
+                                // `Relayed` or `RelayRejected` instead
+                                let timestamp = packet.header.timestamp;
+                                if relay_enabled && packet.header.id == RELAY_PACKET_ID {
+                                    relay_requests.push((id, packet));
+                                } else {
+                                    let info = PacketInfo::new(&packet, limits.framing);
+                                    arrivals.push(net_events.len());
+                                    incoming_packets.push_back((id, packet));
+                                    net_events.push(TimedEvent::now(ServerEvent::ReceivedPacket(
+                                        id, info,
+                                    )));
+                                }
+
+                                if let Some(timestamp) = timestamp {
+                                    net_events.push(TimedEvent::now(ServerEvent::PacketLatency(
+                                        id,
+                                        time_since(timestamp),
+                                    )));
+                                }
+                            },
+                        );
+
+                        if outcome.bytes_read > 0 {
+                            conn.last_read = Instant::now();
                         }
 
-                        // Process incoming bytes into packets
-                        while let Ok(header) = deserialize_packet_header(&mut conn.buffer) {
-                            // Now make sure we have enough bytes for at the rest of this packet
-                            let packet_size = PACKET_HEADER_SIZE + (header.size as usize);
-                            if conn.buffer.offset < packet_size {
-                                break;
-                            }
+                        sample.packets_parsed += outcome.packets;
+                        self.stats
+                            .record_received(outcome.packets, outcome.bytes_read);
+                        conn.traffic
+                            .record_received(outcome.packets, outcome.bytes_read);
+
+                        // Give everyone else a turn, and carry on from here next tick
+                        if outcome.is_out_of_budget {
+                            conn.read_pending = true;
+                        }
 
-                            // Drain the packet bytes from the front of the buffer
-                            let bytes: &[u8] = &conn.buffer.data[PACKET_HEADER_SIZE..packet_size];
-                            let body = bytes.to_vec();
-                            conn.buffer.drain(packet_size);
+                        if outcome.is_over_limit {
+                            net_events.push(TimedEvent::now(ServerEvent::RateLimited(id)));
+                            match self.config.rate_limit_policy {
+                                // Whatever's left is read first thing next tick
+                                RateLimitPolicy::Throttle => conn.read_pending = true,
+                                RateLimitPolicy::Disconnect => {
+                                    eprintln!("Connection {} went over its rate limit!", id);
+                                    conn.disconnect(DisconnectReason::ProtocolError);
+                                }
+                            }
+                        }
 
-                            let packet = Packet { header, body };
+                        let is_protocol_error = is_oversized
+                            || matches!(
+                                outcome.error,
+                                Some(ReadError::InvalidHeader(_))
+                                    | Some(ReadError::InvalidFragment(_))
+                                    | Some(ReadError::InvalidCompression(_))
+                            );
+                        if is_oversized {
+                            eprintln!("Connection {} sent a packet over its id's size limit!", id);
+                            conn.disconnect(DisconnectReason::ProtocolError);
+                        }
 
-                            self.incoming_packets.push_back((token, packet));
+                        match outcome.error {
+                            None => {}
+                            Some(ReadError::Closed) => {
+                                conn.disconnect(DisconnectReason::RemoteClosed)
+                            }
+                            Some(ReadError::BufferFull) => {
+                                eprintln!("Receive buffer for connection {} is full!", id);
+                                conn.disconnect(DisconnectReason::ProtocolError);
+                            }
+                            Some(ReadError::Io(e)) => {
+                                eprintln!(
+                                    "Unexpected error when reading bytes from connection {}! {}",
+                                    id, e
+                                );
+                                conn.disconnect(DisconnectReason::IoError(e.kind()));
+                            }
+                            // The framing can't be recovered after a bad header, so drop the connection
+                            Some(ReadError::InvalidHeader(e)) => {
+                                eprintln!(
+                                    "Received an invalid packet header from connection {}! {} {:?}",
+                                    id, e, conn.buffer
+                                );
+                                net_events.push(TimedEvent::now(ServerEvent::InvalidHeader {
+                                    id,
+                                    error: e.to_string(),
+                                }));
+                                conn.disconnect(DisconnectReason::ProtocolError);
+                            }
+                            Some(ReadError::InvalidFragment(e)) => {
+                                eprintln!(
+                                    "Received an invalid packet fragment from connection {}! {}",
+                                    id, e
+                                );
+                                net_events.push(TimedEvent::now(ServerEvent::InvalidFragment {
+                                    id,
+                                    error: e.to_string(),
+                                }));
+                                conn.disconnect(DisconnectReason::ProtocolError);
+                            }
+                            Some(ReadError::InvalidCompression(e)) => {
+                                eprintln!(
+                                    "Received an invalid compressed packet from connection {}! {}",
+                                    id, e
+                                );
+                                net_events.push(TimedEvent::now(ServerEvent::InvalidCompression {
+                                    id,
+                                    error: e.to_string(),
+                                }));
+                                conn.disconnect(DisconnectReason::ProtocolError);
+                            }
+                        }
 
-                            net_events.push(ServerEvent::ReceivedPacket(conn.token, packet_size));
+                        if let (true, Some(duration)) =
+                            (is_protocol_error, self.config.protocol_error_ban)
+                        {
+                            let now = Instant::now();
+                            let ip = conn.addr.ip();
+                            self.bans
+                                .retain(|_, until| until.is_none_or(|until| now < until));
+                            self.bans.insert(ip, Some(now + duration));
+                            net_events
+                                .push(TimedEvent::now(ServerEvent::AddressBanned(ip, duration)));
                         }
                     }
 
                     // Handle writing
-                    if event.is_writable() {
-                        while let Some(packet) = conn.outgoing_packets.pop_front() {
-                            let data = match serialize_packet(packet) {
-                                Ok(d) => d,
-                                Err(e) => {
-                                    eprintln!("Failed to serialize packet! {}", e);
-                                    continue;
-                                }
-                            };
+                    if writable {
+                        conn.is_writable = true;
+                        sample.packets_written += conn.write_queued(
+                            &mut self.packet_logger,
+                            &mut self.stats,
+                            &mut net_events,
+                        );
+                    }
 
-                            match send_bytes(&mut conn.socket, &data) {
-                                Ok(sent_bytes) => {
-                                    net_events.push(ServerEvent::SentPacket(token, sent_bytes));
-                                }
-                                Err(e) => {
-                                    eprintln!(
-                                        "Unexpected error when sending bytes to connection {}! {}",
-                                        conn.token.0, e
-                                    );
-                                    conn.is_disconnected = true;
-                                    break;
-                                }
-                            }
+                    // We're done processing events for this connection for this tick. Registrations are edge triggered,
+                    // so they only need touching when writable events should start or stop.
+                    if conn.is_interest_stale() {
+                        sample.reregisters += 1;
+                        if let Err(e) = conn.reregister(self.poll.registry()) {
+                            eprintln!(
+                                "Failed to reregister poll for connection (Id {}). {}",
+                                id, e
+                            );
+                            conn.disconnect(DisconnectReason::IoError(e.kind()));
                         }
                     }
+                }
+            }
+        }
 
-                    // We're done processing events for this connection for this tick.
-                    // Reregister for next tick.
-                    self.poll
-                        .registry()
-                        .reregister(
-                            &mut conn.socket,
-                            conn.token,
-                            Interest::READABLE | Interest::WRITABLE,
-                        )
-                        .unwrap_or_else(|e| {
-                            panic!(
-                                "Failed to reregister poll for connection (Token {}). {}",
-                                token.0, e
-                            )
-                        });
+        // Disconnect anyone who hasn't sent us anything in too long
+        if let Some(timeout) = self.config.idle_timeout {
+            for conn in self.connections.iter_mut() {
+                if !conn.is_disconnected
+                    && conn.draining.is_none()
+                    && conn.last_read.elapsed() >= timeout
+                {
+                    net_events.push(TimedEvent::now(ServerEvent::TimedOut(conn.id)));
+                    conn.disconnect(DisconnectReason::TimedOut);
+                }
+            }
+        }
+
+        // Heartbeat anyone we haven't sent anything to in a while, and disconnect anyone who hasn't answered
+        if let Some((interval, timeout)) = self.config.heartbeat {
+            for conn in self.connections.iter_mut() {
+                if conn.is_disconnected || conn.draining.is_some() {
+                    continue;
+                }
+
+                if conn.last_read.elapsed() >= timeout {
+                    eprintln!(
+                        "Haven't heard from connection {} in {:?}, disconnecting!",
+                        conn.id, timeout
+                    );
+                    net_events.push(TimedEvent::now(ServerEvent::TimedOut(conn.id)));
+                    conn.disconnect(DisconnectReason::TimedOut);
+                } else if conn.last_write.elapsed() >= interval && !conn.has_unwritten() {
+                    conn.outgoing_packets
+                        .push_back(Outgoing::new(Heartbeat { reply: false }));
+                }
+            }
+        }
+
+        // Disconnect anyone who hasn't answered the version hello in time
+        let now = Instant::now();
+        for conn in self.connections.iter_mut() {
+            if conn
+                .handshake_deadline
+                .is_some_and(|deadline| now >= deadline)
+            {
+                eprintln!(
+                    "Connection {} didn't finish the version handshake in time!",
+                    conn.id
+                );
+                conn.disconnect(DisconnectReason::TimedOut);
+            }
+        }
+
+        // Give up on pings that have gone unanswered for too long, then ping anyone who's due for a round trip
+        // measurement
+        let now = Instant::now();
+        for conn in self.connections.iter_mut() {
+            if conn.is_disconnected || conn.draining.is_some() {
+                continue;
+            }
+
+            for _ in 0..conn.rtt.expire(now, self.config.ping_timeout) {
+                net_events.push(TimedEvent::now(ServerEvent::PingTimedOut(conn.id)));
+            }
+
+            if let Some(interval) = self.config.ping_interval {
+                if let Some(ping) = conn.rtt.poll_ping(now, interval) {
+                    conn.outgoing_packets.push_back(Outgoing::new(ping));
+                }
+            }
+        }
+
+        // Forward any relay requests we received this tick
+        self.process_relays(relay_requests, &mut net_events);
+
+        // Write whatever was queued while handling this tick (pongs, relays, pings...) now, instead of waiting for the
+        // next tick's writable event
+        sample.packets_written += self.flush_writable(&mut net_events);
+
+        // Close kicked and shutting down connections once they've written everything queued for them, or run out of
+        // time to
+        for conn in self.connections.iter_mut() {
+            match conn.draining {
+                Some((ticks, reason)) if ticks > 0 && conn.has_unwritten() => {
+                    conn.draining = Some((ticks - 1, reason))
                 }
+                Some((_, reason)) => conn.disconnect(reason),
+                None => {}
+            }
+        }
+
+        // Report anyone who had packets dropped since the last tick
+        for conn in self.connections.iter_mut() {
+            if conn.dropped_packets > 0 {
+                net_events.push(TimedEvent::now(ServerEvent::SlowConsumer {
+                    id: conn.id,
+                    dropped_packets: conn.dropped_packets,
+                    dropped_bytes: conn.dropped_bytes,
+                }));
+
+                conn.dropped_packets = 0;
+                conn.dropped_bytes = 0;
+            }
+
+            for message in conn.failed_messages.drain(..) {
+                net_events.push(TimedEvent::now(ServerEvent::DeliveryFailed(
+                    conn.id, message,
+                )));
             }
         }
 
         // Iterate through disconnected connections and send ClientDisconnected event
-        for (tok, _) in self.connections.iter().filter(|&(_, c)| c.is_disconnected) {
-            net_events.push(ServerEvent::ClientDisconnected(*tok));
+        for conn in self.connections.iter().filter(|c| c.is_disconnected) {
+            let unsent = conn.unsent.as_ref().and_then(|unsent| unsent.message);
+            for message in unsent
+                .into_iter()
+                .chain(conn.outgoing_packets.iter().filter_map(|o| o.message))
+            {
+                net_events.push(TimedEvent::now(ServerEvent::DeliveryFailed(
+                    conn.id, message,
+                )));
+            }
+
+            net_events.push(TimedEvent::now(ServerEvent::ClientDisconnected(
+                conn.id,
+                conn.disconnect_reason,
+            )));
+
+            let id = conn.id;
+            self.scheduled.retain(
+                |(recipient, _)| !matches!(recipient, PacketRecipient::Single(r) if *r == id),
+            );
         }
 
         // Retain any connections which aren't disconnected
-        self.connections.retain(|_, v| !v.is_disconnected);
+        for conn in self.connections.retain(|c| !c.is_disconnected) {
+            if let Some(shutting_down) = self.shutting_down.as_mut() {
+                shutting_down.summary.connections_closed += 1;
+                shutting_down.summary.packets_dropped += conn.queued_packets();
+            }
+            self.buffer_pool.check_in(conn.buffer);
+        }
+        self.stats.open_connections = self.connections.len();
+
+        // Finish a graceful shutdown once the last connection is gone
+        if self.shutting_down.is_some() && self.connections.len() == 0 {
+            let summary = self.shutdown();
+            net_events.push(TimedEvent::now(ServerEvent::ShutdownComplete(summary)));
+        }
+
+        self.ready = ready;
+
+        if let (Some(recorder), Some(start)) = (self.diagnostics.as_mut(), tick_start) {
+            sample.duration = start.elapsed();
+            recorder.record(sample);
+        }
+
+        Ok(net_events)
+    }
+
+    /// Validate relay requests against the relay policy, and queue the allowed ones to their destinations.
+    fn process_relays(
+        &mut self,
+        requests: Vec<(ConnectionId, Packet)>,
+        net_events: &mut Vec<TimedEvent<ServerEvent>>,
+    ) {
+        let policy = match self.relay.as_mut() {
+            Some(p) => p,
+            None => return,
+        };
+
+        for (from, packet) in requests {
+            let envelope = match RelayEnvelope::deserialize(&packet.body) {
+                Ok(e) => e,
+                Err(_) => {
+                    net_events.push(TimedEvent::now(ServerEvent::RelayRejected {
+                        from,
+                        to: None,
+                    }));
+                    continue;
+                }
+            };
+
+            // Check the rate limit before the filter, so a flooding client can't spam the callback either
+            let to = envelope.peer;
+            let within_limit = match self.connections.get_mut(from) {
+                Some(c) => c.relay_limiter.try_acquire(policy),
+                None => false,
+            };
+
+            let destination_exists = match self.connections.get(to) {
+                Some(c) => !c.is_disconnected,
+                None => false,
+            };
+
+            if !within_limit
+                || !destination_exists
+                || !policy.allows(from, to, envelope.payload.len())
+            {
+                net_events.push(TimedEvent::now(ServerEvent::RelayRejected {
+                    from,
+                    to: Some(to),
+                }));
+                continue;
+            }
+
+            let bytes = envelope.payload.len();
+            if let Some(connection) = self.connections.get_mut(to) {
+                let forwarded =
+                    Payload::shared(Box::new(RelayEnvelope::new(from, envelope.payload)));
+                connection.queue(
+                    Outgoing::payload(forwarded, None, Priority::Normal),
+                    &self.config,
+                );
+            }
+
+            net_events.push(TimedEvent::now(ServerEvent::Relayed { from, to, bytes }));
+        }
+    }
+}
 
-        net_events
+impl<P> Drop for Server<P> {
+    fn drop(&mut self) {
+        self.shutdown();
     }
 }
@@ -0,0 +1,157 @@
+use crate::server::Connection;
+use mio::Token;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// ConnectionId
+/// Identifies a connection on a `Server`.
+/// Indices are reused once a connection closes, but the generation is bumped each time, so an id kept around after its
+/// connection is gone never refers to a different connection. Server APIs given a stale id return `ConnectionNotFound`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ConnectionId {
+    pub index: u32,
+    pub generation: u32,
+}
+
+impl ConnectionId {
+    /// The mio token the connection's socket is registered with.
+    /// Tokens are reused along with indices, so they don't identify a connection on their own.
+    pub fn token(self) -> Token {
+        // Token 0 belongs to the listener
+        Token(self.index as usize + 1)
+    }
+}
+
+impl From<ConnectionId> for Token {
+    fn from(id: ConnectionId) -> Token {
+        id.token()
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}v{}", self.index, self.generation)
+    }
+}
+
+struct Slot {
+    generation: u32,
+    connection: Option<Connection>,
+}
+
+/// Connection storage that reuses indices, and checks generations on every lookup.
+/// Iteration is always in index order, which keeps the server's event ordering deterministic.
+pub(crate) struct ConnectionSlab {
+    slots: Vec<Slot>,
+    free: Vec<u32>,
+    len: usize,
+}
+
+impl ConnectionSlab {
+    pub(crate) fn new() -> Self {
+        ConnectionSlab {
+            slots: Vec::new(),
+            free: Vec::new(),
+            len: 0,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The id the next inserted connection will get.
+    pub(crate) fn next_id(&self) -> ConnectionId {
+        match self.free.last() {
+            Some(&index) => ConnectionId {
+                index,
+                generation: self.slots[index as usize].generation,
+            },
+            None => ConnectionId {
+                index: self.slots.len() as u32,
+                generation: 0,
+            },
+        }
+    }
+
+    /// Insert a connection built for the id returned by `next_id`.
+    pub(crate) fn insert(&mut self, connection: Connection) -> ConnectionId {
+        let id = self.next_id();
+        debug_assert_eq!(connection.id, id);
+
+        match self.free.pop() {
+            Some(index) => self.slots[index as usize].connection = Some(connection),
+            None => self.slots.push(Slot {
+                generation: 0,
+                connection: Some(connection),
+            }),
+        }
+
+        self.len += 1;
+        id
+    }
+
+    pub(crate) fn get(&self, id: ConnectionId) -> Option<&Connection> {
+        match self.slots.get(id.index as usize) {
+            Some(slot) if slot.generation == id.generation => slot.connection.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self, id: ConnectionId) -> Option<&mut Connection> {
+        match self.slots.get_mut(id.index as usize) {
+            Some(slot) if slot.generation == id.generation => slot.connection.as_mut(),
+            _ => None,
+        }
+    }
+
+    /// Get whichever connection currently holds the index a poll token maps to.
+    pub(crate) fn get_by_token_mut(&mut self, token: Token) -> Option<&mut Connection> {
+        let index = token.0.checked_sub(1)?;
+        self.slots.get_mut(index)?.connection.as_mut()
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Connection> {
+        self.slots.iter().filter_map(|s| s.connection.as_ref())
+    }
+
+    pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item = &mut Connection> {
+        self.slots.iter_mut().filter_map(|s| s.connection.as_mut())
+    }
+
+    /// Remove every connection `keep` returns false for, freeing their indices for reuse.
+    /// Returns the removed connections.
+    pub(crate) fn retain<F: FnMut(&Connection) -> bool>(&mut self, mut keep: F) -> Vec<Connection> {
+        let mut removed = Vec::new();
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            let remove = match slot.connection.as_ref() {
+                Some(c) => !keep(c),
+                None => false,
+            };
+
+            if remove {
+                removed.extend(slot.connection.take());
+                slot.generation = slot.generation.wrapping_add(1);
+                self.free.push(index as u32);
+                self.len -= 1;
+            }
+        }
+
+        removed
+    }
+
+    /// Remove every connection.
+    pub(crate) fn drain(&mut self) -> Vec<Connection> {
+        let mut drained = Vec::with_capacity(self.len);
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            if let Some(c) = slot.connection.take() {
+                slot.generation = slot.generation.wrapping_add(1);
+                self.free.push(index as u32);
+                drained.push(c);
+            }
+        }
+
+        self.len = 0;
+        drained
+    }
+}
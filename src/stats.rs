@@ -0,0 +1,234 @@
+use crate::{control::Ping, health::TcpHealth, slab::ConnectionId};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// Samples are capped at this many times the current smoothed RTT, so one slow tick on the other side
+/// doesn't poison the average.
+const MAX_SAMPLE_FACTOR: u32 = 4;
+
+/// Whether the server is taking new connections.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum AcceptState {
+    Accepting,
+    /// `Server::begin_shutdown` has been called, and connections are still flushing their outgoing queues.
+    ShuttingDown,
+    /// `Server::shutdown` has been called, or a graceful shutdown has finished.
+    ShutDown,
+}
+
+/// ServerStats
+/// Totals since the server started hosting.
+/// Byte counts are what went through the sockets, and packet counts include grubbnet's own packets. Every counter
+/// saturates rather than wrapping.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ServerStats {
+    pub connections_accepted: u64,
+    pub connections_rejected: u64,
+    /// Connections open as of the end of the last tick, or since the last one was accepted.
+    pub open_connections: usize,
+    /// The most connections the server has had open at once.
+    pub peak_connections: usize,
+    pub packets_received: u64,
+    pub packets_sent: u64,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    /// Receive buffers allocated for new connections. Buffers from closed connections are reused where possible, see
+    /// `ServerConfig::buffer_pool_size`.
+    pub buffers_allocated: u64,
+}
+
+impl ServerStats {
+    pub(crate) fn record_sent(&mut self, bytes: usize) {
+        self.packets_sent = self.packets_sent.saturating_add(1);
+        self.bytes_sent = self.bytes_sent.saturating_add(bytes as u64);
+    }
+
+    pub(crate) fn record_received(&mut self, packets: usize, bytes: usize) {
+        self.packets_received = self.packets_received.saturating_add(packets as u64);
+        self.bytes_received = self.bytes_received.saturating_add(bytes as u64);
+    }
+}
+
+/// TrafficStats
+/// Totals for a single connection: one of the server's connections, or a client's connection to its server across any
+/// reconnects. Like `ServerStats`, byte counts are what went through the socket, packet counts include grubbnet's own
+/// packets, and every counter saturates.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrafficStats {
+    pub packets_received: u64,
+    pub packets_sent: u64,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+}
+
+impl TrafficStats {
+    pub(crate) fn record_sent(&mut self, bytes: usize) {
+        self.packets_sent = self.packets_sent.saturating_add(1);
+        self.bytes_sent = self.bytes_sent.saturating_add(bytes as u64);
+    }
+
+    pub(crate) fn record_received(&mut self, packets: usize, bytes: usize) {
+        self.packets_received = self.packets_received.saturating_add(packets as u64);
+        self.bytes_received = self.bytes_received.saturating_add(bytes as u64);
+    }
+}
+
+/// ShutdownSummary
+/// What happened to the connections that were open when the server shut down. See `Server::begin_shutdown`.
+/// Flushed packet counts include grubbnet's own packets, like the shutdown notice.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShutdownSummary {
+    pub connections_closed: usize,
+    /// Packets written after the shutdown began.
+    pub packets_flushed: usize,
+    /// Packets still queued, or partway out, when their connection was closed.
+    pub packets_dropped: usize,
+}
+
+/// ServerSnapshot
+/// An owned summary of the whole server, for admin and inspection tools. See `Server::snapshot`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ServerSnapshot {
+    pub uptime: Duration,
+    pub num_connections: usize,
+    /// The most connections the server has had open at once.
+    pub peak_connections: usize,
+    pub connection_limit: usize,
+    pub accept_state: AcceptState,
+    pub stats: ServerStats,
+    /// Every open connection, in `ConnectionId` index order.
+    pub connections: Vec<ConnectionSnapshot>,
+}
+
+/// ConnectionSnapshot
+/// One connection's entry in a `ServerSnapshot`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConnectionSnapshot {
+    pub id: ConnectionId,
+    pub addr: SocketAddr,
+    pub connected_for: Duration,
+    /// Smoothed round trip time, or `None` if the connection hasn't answered a ping yet.
+    pub rtt: Option<Duration>,
+    /// Packets waiting to be sent.
+    pub queued_out_packets: usize,
+    /// Bytes waiting to be sent, including the rest of a packet that's partway out, like `Server::queued_bytes`.
+    pub queued_out_bytes: usize,
+    /// Received bytes that don't make up a complete packet yet.
+    pub buffered_in_bytes: usize,
+    /// Time since anything was last read from or written to the connection.
+    pub idle_for: Duration,
+    /// The kernel's view of the connection, or `None` where that isn't available.
+    pub health: Option<TcpHealth>,
+}
+
+/// ConnectionStats
+/// A snapshot of what the server knows about a single connection.
+#[derive(Clone, Debug)]
+pub struct ConnectionStats {
+    pub addr: SocketAddr,
+    pub connected_at: Instant,
+    /// Smoothed round trip time, or `None` if the connection hasn't answered a ping yet.
+    pub rtt: Option<Duration>,
+    /// How much the round trip time varies between samples, or `None` if the connection hasn't answered a ping yet.
+    pub jitter: Option<Duration>,
+    pub outgoing_queue_len: usize,
+    /// The kernel's view of the connection, or `None` where that isn't available.
+    pub health: Option<TcpHealth>,
+    pub traffic: TrafficStats,
+}
+
+/// Tracks the round trip time of a connection from ping/pong exchanges.
+/// Several pings can be outstanding at once. Pings that haven't been answered within the ping timeout are forgotten
+/// by `expire`, and a late pong for one is treated as unexpected.
+pub(crate) struct RttEstimator {
+    next_nonce: u32,
+    /// Pings that haven't been answered yet, oldest first.
+    outstanding: VecDeque<(u32, Instant)>,
+    last_ping: Option<Instant>,
+    srtt: Option<Duration>,
+    jitter: Duration,
+}
+
+impl RttEstimator {
+    pub(crate) fn new() -> Self {
+        RttEstimator {
+            next_nonce: 0,
+            outstanding: VecDeque::new(),
+            last_ping: None,
+            srtt: None,
+            jitter: Duration::from_secs(0),
+        }
+    }
+
+    pub(crate) fn rtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    pub(crate) fn jitter(&self) -> Option<Duration> {
+        self.srtt.map(|_| self.jitter)
+    }
+
+    /// Get a new ping to send, and start waiting for its pong.
+    pub(crate) fn ping(&mut self, now: Instant) -> Ping {
+        let nonce = self.next_nonce;
+        self.next_nonce = self.next_nonce.wrapping_add(1);
+        self.outstanding.push_back((nonce, now));
+        self.last_ping = Some(now);
+
+        Ping { nonce }
+    }
+
+    /// Get the next ping to send, if one is due.
+    pub(crate) fn poll_ping(&mut self, now: Instant, interval: Duration) -> Option<Ping> {
+        if let Some(last) = self.last_ping {
+            if now.duration_since(last) < interval {
+                return None;
+            }
+        }
+
+        Some(self.ping(now))
+    }
+
+    /// Forget the pings sent more than `timeout` ago, and return how many there were.
+    pub(crate) fn expire(&mut self, now: Instant, timeout: Duration) -> usize {
+        let len = self.outstanding.len();
+        self.outstanding
+            .retain(|&(_, sent)| now.duration_since(sent) < timeout);
+
+        len - self.outstanding.len()
+    }
+
+    /// Take a sample from a pong, and return the round trip time it measured.
+    /// Returns `None` if the pong doesn't answer an outstanding ping.
+    pub(crate) fn on_pong(&mut self, nonce: u32, now: Instant) -> Option<Duration> {
+        let index = self
+            .outstanding
+            .iter()
+            .position(|&(outstanding, _)| outstanding == nonce)?;
+        let (_, sent) = self.outstanding.remove(index)?;
+
+        let measured = now.duration_since(sent);
+        let mut sample = measured;
+        match self.srtt {
+            Some(srtt) => {
+                sample = sample.min(srtt * MAX_SAMPLE_FACTOR);
+
+                // Same smoothing as TCP's retransmission timer (RFC 6298)
+                let deviation = srtt.abs_diff(sample);
+                self.jitter = (self.jitter * 3 + deviation) / 4;
+                self.srtt = Some((srtt * 7 + sample) / 8);
+            }
+            None => {
+                self.jitter = sample / 2;
+                self.srtt = Some(sample);
+            }
+        }
+
+        Some(measured)
+    }
+}
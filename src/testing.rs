@@ -0,0 +1,186 @@
+use crate::{
+    client::{Client, ClientEvent},
+    packet::{Packet, PacketBody},
+    server::{Server, ServerEvent},
+};
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// An event from either side of a `pair`, in the order `tick_until` saw them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TestEvent {
+    Server(ServerEvent),
+    Client(ClientEvent),
+}
+
+/// Host a server on an ephemeral localhost port, and start connecting a client to it. Panics if either fails.
+/// The connection isn't established yet, so tick until `ClientConnected` before relying on it.
+pub fn pair() -> (Server, Client) {
+    let server = Server::host("127.0.0.1", 0, 8).expect("Failed to host test server");
+    let port = server
+        .local_addr()
+        .expect("Failed to get test server address")
+        .port();
+    let client = Client::connect("127.0.0.1", port).expect("Failed to connect test client");
+
+    (server, client)
+}
+
+/// Tick the server and client in turn until `predicate` passes for the events seen so far, then return them.
+/// Panics with every event seen if `timeout` passes first, or if either tick fails.
+pub fn tick_until<F>(
+    server: &mut Server,
+    client: &mut Client,
+    timeout: Duration,
+    mut predicate: F,
+) -> Vec<TestEvent>
+where
+    F: FnMut(&[TestEvent]) -> bool,
+{
+    let deadline = Instant::now() + timeout;
+    let mut events = Vec::new();
+    loop {
+        let server_events = server.tick().expect("Failed to tick test server");
+        events.extend(server_events.into_iter().map(TestEvent::Server));
+        let client_events = client.tick().expect("Failed to tick test client");
+        events.extend(client_events.into_iter().map(TestEvent::Client));
+        if predicate(&events) {
+            return events;
+        }
+
+        if Instant::now() >= deadline {
+            panic!(
+                "Condition not met within {:?}. Events seen:\n{:#?}",
+                timeout, events
+            );
+        }
+    }
+}
+
+/// Find the first packet with the given id, and deserialize it.
+/// Panics if there's no such packet, or it doesn't deserialize.
+pub fn expect_packet<'a, T, I>(packets: I, id: u8) -> T
+where
+    T: PacketBody,
+    I: IntoIterator<Item = &'a Packet>,
+{
+    let mut seen = Vec::new();
+    for packet in packets {
+        if packet.header.id == id {
+            return T::deserialize(&packet.body).unwrap_or_else(|e| {
+                panic!("Packet {} didn't deserialize! {}", id, e);
+            });
+        }
+
+        seen.push(packet.header.id);
+    }
+
+    panic!(
+        "Expected a packet with id {}, but only got ids {:?}",
+        id, seen
+    );
+}
+
+/// EventPattern
+/// Matches events for `EventRecorder::assert_sequence`. Build one with `event_pattern!`, using `_` for any field that
+/// shouldn't be checked, such as byte counts.
+#[derive(Clone, Copy)]
+pub struct EventPattern {
+    description: &'static str,
+    matches: fn(&TestEvent) -> bool,
+}
+
+impl EventPattern {
+    /// Use `event_pattern!` instead of calling this directly.
+    pub fn new(description: &'static str, matches: fn(&TestEvent) -> bool) -> Self {
+        EventPattern {
+            description,
+            matches,
+        }
+    }
+
+    pub fn matches(&self, event: &TestEvent) -> bool {
+        (self.matches)(event)
+    }
+}
+
+impl fmt::Debug for EventPattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description)
+    }
+}
+
+/// Build an `EventPattern` from a pattern over `TestEvent`,
+/// like `event_pattern!(TestEvent::Server(ServerEvent::ReceivedPacket(_, _)))`.
+#[macro_export]
+macro_rules! event_pattern {
+    ($pattern:pat) => {
+        $crate::testing::EventPattern::new(stringify!($pattern), |event| matches!(event, $pattern))
+    };
+}
+
+/// EventRecorder
+/// Collects events from both sides across ticks, so a test can assert on the order they happened in.
+#[derive(Default)]
+pub struct EventRecorder {
+    events: Vec<TestEvent>,
+}
+
+impl EventRecorder {
+    pub fn new() -> Self {
+        EventRecorder::default()
+    }
+
+    /// Tick the server then the client, and record what each of them reports. Panics if either tick fails.
+    pub fn tick(&mut self, server: &mut Server, client: &mut Client) {
+        self.record_server(server.tick().expect("Failed to tick test server"));
+        self.record_client(client.tick().expect("Failed to tick test client"));
+    }
+
+    pub fn record_server<I: IntoIterator<Item = ServerEvent>>(&mut self, events: I) {
+        self.events
+            .extend(events.into_iter().map(TestEvent::Server));
+    }
+
+    pub fn record_client<I: IntoIterator<Item = ClientEvent>>(&mut self, events: I) {
+        self.events
+            .extend(events.into_iter().map(TestEvent::Client));
+    }
+
+    pub fn events(&self) -> &[TestEvent] {
+        &self.events
+    }
+
+    pub fn clear(&mut self) {
+        self.events.clear();
+    }
+
+    /// Whether the patterns match recorded events in order. Other events may come between the matches.
+    pub fn contains_sequence(&self, patterns: &[EventPattern]) -> bool {
+        let mut patterns = patterns.iter().peekable();
+        for event in self.events.iter() {
+            match patterns.peek() {
+                Some(pattern) if pattern.matches(event) => {
+                    patterns.next();
+                }
+                Some(_) => {}
+                None => break,
+            }
+        }
+
+        patterns.peek().is_none()
+    }
+
+    /// Panic with the recorded events unless the patterns match them in order. Other events may come between the
+    /// matches, so only the events a test cares about need patterns.
+    pub fn assert_sequence(&self, patterns: &[EventPattern]) {
+        if !self.contains_sequence(patterns) {
+            panic!(
+                "Events didn't match the sequence {:#?}. Events recorded:\n{:#?}",
+                patterns, self.events
+            );
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use grubbnet::{
+    packet::RawPacket,
+    testing::{tick_until, TestEvent},
+    Client, ClientEvent, PacketRecipient, Server, ServerEvent,
+};
+use std::{net::TcpListener, time::Duration};
+
+#[test]
+fn packets_flow_between_adopted_streams() {
+    // Both ends of the connection are set up outside grubbnet, like by a launcher with its own proxy handshake
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (accepted, addr) = listener.accept().unwrap();
+
+    let mut server = Server::host("127.0.0.1", 0, 4).unwrap();
+    let id = server.adopt_connection(accepted, addr).unwrap();
+    let mut client = Client::from_stream(stream).unwrap();
+
+    // Nothing has to be waited for before sending
+    server
+        .send(PacketRecipient::Single(id), RawPacket::new(7, vec![9]))
+        .unwrap();
+    client.send(RawPacket::new(7, vec![3])).unwrap();
+
+    let events = tick_until(&mut server, &mut client, Duration::from_secs(2), |events| {
+        let server_got = events
+            .iter()
+            .any(|e| matches!(e, TestEvent::Server(ServerEvent::ReceivedPacket(..))));
+        let client_got = events
+            .iter()
+            .any(|e| matches!(e, TestEvent::Client(ClientEvent::ReceivedPacket(..))));
+        server_got && client_got
+    });
+    assert!(events.contains(&TestEvent::Server(ServerEvent::ClientConnected(id, addr))));
+    assert!(events.contains(&TestEvent::Client(ClientEvent::Connected)));
+
+    let received = server.drain_incoming_packets();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].0, id);
+    assert_eq!(&received[0].1.body[..], &[3]);
+    let received = client.drain_incoming_packets();
+    assert_eq!(received.len(), 1);
+    assert_eq!(&received[0].body[..], &[9]);
+}
+
+#[test]
+fn adopted_client_talks_to_hosted_server() {
+    let mut server = Server::host("127.0.0.1", 0, 4).unwrap();
+    let stream = std::net::TcpStream::connect(server.local_addr().unwrap()).unwrap();
+    let mut client = Client::from_stream(stream).unwrap();
+    client.send(RawPacket::new(5, vec![1, 2, 3])).unwrap();
+
+    tick_until(&mut server, &mut client, Duration::from_secs(2), |events| {
+        events
+            .iter()
+            .any(|e| matches!(e, TestEvent::Server(ServerEvent::ReceivedPacket(..))))
+    });
+    let received = server.drain_incoming_packets();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].1.header.id, 5);
+    assert_eq!(&received[0].1.body[..], &[1, 2, 3]);
+}
@@ -0,0 +1,52 @@
+use grubbnet::buffer::{NetworkBuffer, MAX_BUFFER_SIZE};
+use grubbnet::Error;
+
+fn filled(bytes: &[u8]) -> NetworkBuffer {
+    let mut buffer = NetworkBuffer::new();
+    buffer.append(bytes).unwrap();
+    buffer
+}
+
+#[test]
+fn drain_nothing_leaves_the_buffer_alone() {
+    let mut buffer = filled(&[1, 2, 3, 4, 5]);
+
+    buffer.drain(0).unwrap();
+
+    assert_eq!(buffer.len(), 5);
+    assert_eq!(buffer.make_contiguous(), &[1, 2, 3, 4, 5]);
+}
+
+#[test]
+fn drain_everything_empties_the_buffer() {
+    let mut buffer = filled(&[1, 2, 3, 4, 5]);
+
+    buffer.drain(5).unwrap();
+
+    assert!(buffer.is_empty());
+    assert_eq!(buffer.make_contiguous(), &[] as &[u8]);
+}
+
+#[test]
+fn drain_past_the_end_is_rejected() {
+    let mut buffer = filled(&[1, 2, 3, 4, 5]);
+    buffer.drain(2).unwrap();
+
+    assert!(matches!(buffer.drain(4), Err(Error::InvalidData)));
+    assert!(matches!(
+        buffer.drain(MAX_BUFFER_SIZE + 1),
+        Err(Error::InvalidData)
+    ));
+    assert!(matches!(buffer.drain_into(4), Err(Error::InvalidData)));
+
+    // A rejected drain doesn't take anything
+    assert_eq!(buffer.make_contiguous(), &[3, 4, 5]);
+}
+
+#[test]
+fn drain_into_returns_the_drained_bytes() {
+    let mut buffer = filled(&[1, 2, 3, 4, 5]);
+
+    assert_eq!(buffer.drain_into(3).unwrap(), vec![1, 2, 3]);
+    assert_eq!(buffer.make_contiguous(), &[4, 5]);
+}
@@ -0,0 +1,109 @@
+use grubbnet::buffer::{NetworkBuffer, INITIAL_BUFFER_SIZE};
+use grubbnet::packet::serialize_packet;
+use grubbnet::{ClientConfig, RawPacket, ServerConfig, ServerEvent};
+use std::io::Write;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+#[test]
+fn buffer_doubles_up_to_its_max_capacity() {
+    let mut buffer = NetworkBuffer::with_max_capacity(3000);
+    assert_eq!(buffer.capacity(), INITIAL_BUFFER_SIZE);
+
+    assert!(buffer.grow());
+    assert_eq!(buffer.capacity(), 2048);
+    assert!(buffer.grow());
+    assert_eq!(buffer.capacity(), 3000);
+    assert!(!buffer.grow());
+    assert_eq!(buffer.capacity(), 3000);
+}
+
+#[test]
+fn small_max_capacity_starts_at_the_max() {
+    let mut buffer = NetworkBuffer::with_max_capacity(1);
+    assert_eq!(buffer.capacity(), 1);
+    assert!(!buffer.grow());
+
+    buffer.append(&[7]).unwrap();
+    assert!(buffer.writable_slice().is_empty());
+    assert!(buffer.append(&[8]).is_err());
+    assert_eq!(buffer.make_contiguous(), &[7]);
+}
+
+#[test]
+#[should_panic(expected = "buffer capacity must be at least 1")]
+fn zero_max_capacity_is_rejected() {
+    NetworkBuffer::with_max_capacity(0);
+}
+
+#[test]
+fn wrapped_bytes_stay_in_order_when_the_buffer_grows() {
+    let mut buffer = NetworkBuffer::with_max_capacity(3000);
+    buffer.append(&[1; 1000]).unwrap();
+    buffer.drain(900).unwrap();
+    buffer.append(&[2; 24]).unwrap();
+    buffer.append(&[3; 10]).unwrap();
+    assert!(!buffer.readable().1.is_empty());
+
+    assert!(buffer.grow());
+
+    let bytes = buffer.make_contiguous().to_vec();
+    assert_eq!(bytes.len(), 134);
+    assert!(bytes[..100].iter().all(|&b| b == 1));
+    assert!(bytes[100..124].iter().all(|&b| b == 2));
+    assert!(bytes[124..].iter().all(|&b| b == 3));
+}
+
+#[test]
+fn append_past_the_max_capacity_is_rejected() {
+    let mut buffer = NetworkBuffer::with_max_capacity(2048);
+    buffer.append(&[1; 2000]).unwrap();
+    assert_eq!(buffer.capacity(), 2048);
+
+    assert!(buffer.append(&[2; 49]).is_err());
+    assert_eq!(buffer.len(), 2000);
+    buffer.append(&[2; 48]).unwrap();
+    assert_eq!(buffer.len(), 2048);
+}
+
+#[test]
+fn max_buffer_size_below_the_initial_size_is_rejected() {
+    assert!(ServerConfig::new().max_buffer_size(100).validate().is_err());
+    assert!(ClientConfig::new().max_buffer_size(100).validate().is_err());
+}
+
+#[test]
+fn burst_bigger_than_the_buffer_arrives_over_several_ticks() {
+    let mut server = ServerConfig::new()
+        .max_buffer_size(4096)
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let port = server.local_addr().unwrap().port();
+    let mut socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+
+    // Many small packets written at once, far more than the buffer's max capacity
+    let count = 200;
+    let mut bytes = Vec::new();
+    for i in 0..count {
+        let body = vec![(i % 251) as u8; 300 + (i % 200)];
+        bytes.extend(serialize_packet(Box::new(RawPacket::new(5, body))).unwrap());
+    }
+    assert!(bytes.len() > 4096 * 10);
+    socket.write_all(&bytes).unwrap();
+
+    let mut received = 0;
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while received < count {
+        assert!(Instant::now() < deadline, "only got {} packets", received);
+
+        let events = server.tick().unwrap();
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, ServerEvent::ClientDisconnected(..))));
+        for (_, packet) in server.drain_incoming_packets() {
+            assert_eq!(packet.body.len(), 300 + (received % 200));
+            assert!(packet.body.iter().all(|&b| b == (received % 251) as u8));
+            received += 1;
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! Nothing reachable from safe code can push a `NetworkBuffer`'s readable length past what was written, or past its
+//! capacity.
+
+use grubbnet::buffer::{NetworkBuffer, INITIAL_BUFFER_SIZE, MAX_BUFFER_SIZE};
+use grubbnet::Error;
+
+/// Check that the buffer's view of itself still adds up.
+fn assert_consistent(buffer: &mut NetworkBuffer) {
+    assert!(buffer.len() <= buffer.capacity());
+    assert!(buffer.capacity() <= buffer.max_capacity());
+    assert_eq!(
+        buffer.remaining_capacity(),
+        buffer.capacity() - buffer.len()
+    );
+
+    let (front, back) = buffer.readable();
+    assert_eq!(front.len() + back.len(), buffer.len());
+    let joined = [front, back].concat();
+    assert_eq!(buffer.make_contiguous(), &joined[..]);
+}
+
+#[test]
+fn advance_write_past_the_free_space_is_rejected() {
+    let mut buffer = NetworkBuffer::new();
+    buffer.append(&[1; 10]).unwrap();
+
+    let free = buffer.remaining_capacity();
+    assert!(matches!(
+        buffer.advance_write(free + 1),
+        Err(Error::InvalidData)
+    ));
+    assert!(matches!(
+        buffer.advance_write(usize::MAX),
+        Err(Error::InvalidData)
+    ));
+    assert_eq!(buffer.len(), 10);
+    assert_consistent(&mut buffer);
+
+    buffer.advance_write(free).unwrap();
+    assert_eq!(buffer.len(), buffer.capacity());
+    assert!(buffer.writable_slice().is_empty());
+    assert!(matches!(buffer.advance_write(1), Err(Error::InvalidData)));
+    assert_consistent(&mut buffer);
+}
+
+#[test]
+fn drain_past_the_readable_bytes_is_rejected() {
+    let mut buffer = NetworkBuffer::new();
+    buffer.append(&[1, 2, 3]).unwrap();
+
+    assert!(matches!(buffer.drain(4), Err(Error::InvalidData)));
+    assert!(matches!(buffer.drain(usize::MAX), Err(Error::InvalidData)));
+    assert!(matches!(
+        buffer.drain_into(MAX_BUFFER_SIZE),
+        Err(Error::InvalidData)
+    ));
+    assert_eq!(buffer.make_contiguous(), &[1, 2, 3]);
+    assert_consistent(&mut buffer);
+}
+
+#[test]
+fn append_past_the_max_capacity_is_rejected() {
+    let mut buffer = NetworkBuffer::with_max_capacity(INITIAL_BUFFER_SIZE);
+    buffer.append(&[1; INITIAL_BUFFER_SIZE - 1]).unwrap();
+
+    assert!(matches!(buffer.append(&[2, 2]), Err(Error::InvalidData)));
+    assert_eq!(buffer.len(), INITIAL_BUFFER_SIZE - 1);
+    assert_consistent(&mut buffer);
+}
+
+#[test]
+fn mixed_operations_keep_the_buffer_consistent() {
+    let mut buffer = NetworkBuffer::with_max_capacity(4096);
+    let mut expected = Vec::new();
+    let mut next = 0u8;
+
+    // A fixed mix of writes, drains, grows and clears, including ones that are meant to fail
+    for step in 0..2000usize {
+        match step % 7 {
+            0..=2 => {
+                let count = (step * 37) % 300;
+                let bytes: Vec<u8> = (0..count)
+                    .map(|_| {
+                        next = next.wrapping_add(1);
+                        next
+                    })
+                    .collect();
+                if buffer.append(&bytes).is_ok() {
+                    expected.extend(bytes);
+                }
+            }
+            3 | 4 => {
+                let count = (step * 53) % 400;
+                match buffer.drain_into(count) {
+                    Ok(drained) => {
+                        assert_eq!(drained, expected.drain(..count).collect::<Vec<u8>>())
+                    }
+                    Err(_) => assert!(count > expected.len()),
+                }
+            }
+            5 => {
+                let writable = buffer.writable_slice();
+                let count = writable.len().min(16);
+                for byte in writable[..count].iter_mut() {
+                    next = next.wrapping_add(1);
+                    *byte = next;
+                    expected.push(next);
+                }
+                buffer.advance_write(count).unwrap();
+                assert!(buffer
+                    .advance_write(buffer.remaining_capacity() + 1)
+                    .is_err());
+            }
+            _ => {
+                if step % 200 == 6 {
+                    buffer.clear();
+                    expected.clear();
+                } else {
+                    buffer.grow();
+                }
+            }
+        }
+
+        assert_eq!(buffer.len(), expected.len());
+        let (front, back) = buffer.readable();
+        assert_eq!([front, back].concat(), expected);
+        assert_consistent(&mut buffer);
+    }
+}
@@ -0,0 +1,65 @@
+use grubbnet::buffer::{NetworkBuffer, INITIAL_BUFFER_SIZE};
+use grubbnet::packet::{parse_packets, serialize_packet, MAX_PACKET_BODY_SIZE};
+use grubbnet::{Framing, RawPacket};
+
+fn frame(id: u8, body: Vec<u8>) -> Vec<u8> {
+    serialize_packet(Box::new(RawPacket::new(id, body))).unwrap()
+}
+
+fn parse(buffer: &mut NetworkBuffer) -> Vec<(u8, Vec<u8>)> {
+    let mut packets = Vec::new();
+    parse_packets(buffer, Framing::Standard, MAX_PACKET_BODY_SIZE, |packet| {
+        packets.push((packet.header.id, packet.body.to_vec()))
+    })
+    .unwrap();
+
+    packets
+}
+
+#[test]
+fn packet_straddling_the_end_of_the_ring_parses_whole() {
+    // Pin the ring at its starting size, so nothing grows and unwraps it
+    let mut buffer = NetworkBuffer::with_max_capacity(INITIAL_BUFFER_SIZE);
+    let first = frame(1, vec![1; INITIAL_BUFFER_SIZE - 100]);
+    let body: Vec<u8> = (0..200).map(|i| i as u8).collect();
+    let second = frame(2, body.clone());
+
+    // The first packet fills most of the ring, and the start of the second is left behind after it's parsed
+    buffer.append(&first).unwrap();
+    buffer.append(&second[..10]).unwrap();
+    assert_eq!(
+        parse(&mut buffer),
+        vec![(1, vec![1; INITIAL_BUFFER_SIZE - 100])]
+    );
+    assert_eq!(buffer.len(), 10);
+
+    // The rest of the second packet runs off the end of the ring and carries on at the front
+    buffer.append(&second[10..]).unwrap();
+    let (front, back) = buffer.readable();
+    assert!(!back.is_empty());
+    assert_eq!([front, back].concat(), second);
+    assert_eq!(buffer.capacity(), INITIAL_BUFFER_SIZE);
+
+    assert_eq!(parse(&mut buffer), vec![(2, body)]);
+    assert!(buffer.is_empty());
+}
+
+#[test]
+fn header_straddling_the_end_of_the_ring_parses_whole() {
+    let mut buffer = NetworkBuffer::with_max_capacity(INITIAL_BUFFER_SIZE);
+    let second = frame(2, vec![9; 20]);
+    // Leave two bytes of room before the end of the ring, so the header itself is split
+    let first = frame(1, vec![1; INITIAL_BUFFER_SIZE - 2 - (second.len() - 20)]);
+
+    buffer.append(&first).unwrap();
+    buffer.append(&second[..1]).unwrap();
+    assert_eq!(parse(&mut buffer).len(), 1);
+
+    buffer.append(&second[1..]).unwrap();
+    let (front, back) = buffer.readable();
+    assert_eq!(front.len(), 2);
+    assert_eq!(back.len(), second.len() - 2);
+
+    assert_eq!(parse(&mut buffer), vec![(2, vec![9; 20])]);
+    assert!(buffer.is_empty());
+}
@@ -0,0 +1,76 @@
+use grubbnet::packet::PacketBody;
+use grubbnet::testing::{pair, tick_until, TestEvent};
+use grubbnet::{ClientConfig, Error, RawPacket, Result, Server, ServerEvent};
+use std::time::Duration;
+
+/// A packet that always fails to serialize.
+#[derive(Clone)]
+struct Unserializable;
+
+impl PacketBody for Unserializable {
+    fn box_clone(&self) -> Box<dyn PacketBody> {
+        Box::new(self.clone())
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        Err(Error::InvalidData)
+    }
+
+    fn deserialize(_data: &[u8]) -> Result<Self> {
+        Ok(Unserializable)
+    }
+
+    fn id(&self) -> u8 {
+        2
+    }
+}
+
+#[test]
+fn scheduled_packets_are_checked_before_they_are_held() {
+    let server = Server::host("127.0.0.1", 0, 1).unwrap();
+    let port = server.local_addr().unwrap().port();
+    let mut client = ClientConfig::new()
+        .max_packet_size(512)
+        .max_reassembly_size(2048)
+        .connect(("127.0.0.1", port))
+        .unwrap();
+    let delay = Duration::from_millis(1);
+
+    assert!(matches!(
+        client.send_after(RawPacket::new(0xFF, vec![1]), delay),
+        Err(Error::ReservedPacketId(0xFF))
+    ));
+    assert!(matches!(
+        client.send_after(RawPacket::new(1, vec![0; 4096]), delay),
+        Err(Error::PacketTooLarge { size: 4096, .. })
+    ));
+    assert!(matches!(
+        client.send_after(Unserializable, delay),
+        Err(Error::InvalidData)
+    ));
+    assert_eq!(client.scheduled_count(), 0);
+
+    // Bodies that only need fragmenting are still fine
+    client
+        .send_after(RawPacket::new(1, vec![0; 1024]), delay)
+        .unwrap();
+    assert_eq!(client.scheduled_count(), 1);
+}
+
+#[test]
+fn scheduled_packet_arrives_once_due() {
+    let (mut server, mut client) = pair();
+    client
+        .send_after(RawPacket::new(6, vec![1, 2]), Duration::from_millis(20))
+        .unwrap();
+
+    tick_until(&mut server, &mut client, Duration::from_secs(2), |events| {
+        events
+            .iter()
+            .any(|e| matches!(e, TestEvent::Server(ServerEvent::ReceivedPacket(..))))
+    });
+    let packets = server.drain_incoming_packets();
+    assert_eq!(packets.len(), 1);
+    assert_eq!(packets[0].1.header.id, 6);
+    assert_eq!(&packets[0].1.body[..], &[1, 2]);
+}
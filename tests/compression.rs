@@ -0,0 +1,206 @@
+#![cfg(feature = "compression")]
+
+use grubbnet::packet::{header_check, Packet, PacketBody};
+use grubbnet::{
+    Client, ClientConfig, Compression, ConnectionId, PacketRecipient, Result, Server, ServerConfig,
+    ServerEvent,
+};
+use std::io::Write;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+#[derive(Clone)]
+struct Blob(Vec<u8>);
+
+impl PacketBody for Blob {
+    fn box_clone(&self) -> Box<dyn PacketBody> {
+        Box::new(self.clone())
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(self.0.clone())
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self> {
+        Ok(Blob(data.to_vec()))
+    }
+
+    fn id(&self) -> u8 {
+        7
+    }
+}
+
+/// Repetitive text, which compresses well.
+fn compressible(size: usize) -> Vec<u8> {
+    let mut text = String::new();
+    let mut i = 0;
+    while text.len() < size {
+        text.push_str(&format!(
+            "{{\"id\":{},\"x\":1.5,\"y\":-2.25,\"name\":\"goblin\"}},",
+            i
+        ));
+        i += 1;
+    }
+
+    text.into_bytes()
+}
+
+/// Pseudo-random bytes, which don't compress at all.
+fn incompressible(size: usize) -> Vec<u8> {
+    let mut x: u64 = 0x1234_5678_9abc_def0;
+    (0..size)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x as u8
+        })
+        .collect()
+}
+
+fn connect(server: ServerConfig, client: ClientConfig) -> (Server, Client, ConnectionId) {
+    let mut server = server.bind("127.0.0.1:0").unwrap();
+    let port = server.local_addr().unwrap().port();
+    let mut client = client.connect(("127.0.0.1", port)).unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(2);
+    loop {
+        assert!(Instant::now() < deadline, "never connected");
+        client.tick().unwrap();
+        for event in server.tick().unwrap() {
+            if let ServerEvent::ClientConnected(id, _) = event {
+                return (server, client, id);
+            }
+        }
+    }
+}
+
+/// Send each body both ways, and check it arrives intact, compressed on the wire or not as expected.
+fn round_trip(server: ServerConfig, client: ClientConfig, bodies: &[(Vec<u8>, bool)]) {
+    let (mut server, mut client, id) = connect(server, client);
+    for (body, _) in bodies {
+        client.send(Blob(body.clone())).unwrap();
+        server
+            .send(PacketRecipient::Single(id), Blob(body.clone()))
+            .unwrap();
+    }
+
+    let mut at_server: Vec<Packet> = Vec::new();
+    let mut at_client: Vec<Packet> = Vec::new();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while at_server.len() < bodies.len() || at_client.len() < bodies.len() {
+        assert!(Instant::now() < deadline, "not everything arrived");
+        client.tick().unwrap();
+        server.tick().unwrap();
+        at_server.extend(server.drain_incoming_packets().into_iter().map(|(_, p)| p));
+        at_client.extend(client.drain_incoming_packets());
+    }
+
+    for received in [at_server, at_client].iter() {
+        for (packet, (body, compressed)) in received.iter().zip(bodies) {
+            assert_eq!(&packet.body, body);
+            assert_eq!(packet.header.size as usize, body.len());
+            assert_eq!(
+                packet.header.is_compressed(),
+                *compressed,
+                "body of {} bytes",
+                body.len()
+            );
+        }
+    }
+}
+
+fn mixed_bodies() -> Vec<(Vec<u8>, bool)> {
+    vec![
+        // Under the threshold
+        (compressible(100), false),
+        (compressible(4000), true),
+        // Compressing wouldn't make it any smaller, so it's sent as is
+        (incompressible(4000), false),
+        // Big enough to be fragmented too
+        (compressible(200_000), true),
+        (incompressible(20_000), false),
+    ]
+}
+
+#[test]
+fn lz4_round_trips() {
+    round_trip(
+        ServerConfig::new().compression(Compression::Lz4, 256),
+        ClientConfig::new().compression(Compression::Lz4, 256),
+        &mixed_bodies(),
+    );
+}
+
+#[test]
+fn deflate_round_trips_with_an_lz4_peer() {
+    round_trip(
+        ServerConfig::new().compression(Compression::Deflate, 256),
+        ClientConfig::new()
+            .compression(Compression::Lz4, 256)
+            .sequence_numbers(true),
+        &mixed_bodies(),
+    );
+}
+
+/// Write a packet flagged as compressed straight to a server, and collect its events until the connection is dropped.
+fn send_compressed(server: ServerConfig, body: &[u8]) -> Vec<ServerEvent> {
+    let mut server = server.bind("127.0.0.1:0").unwrap();
+    let port = server.local_addr().unwrap().port();
+    let mut socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let mut bytes = (body.len() as u16).to_be_bytes().to_vec();
+    bytes.extend([7, 0b10]);
+    bytes.push(header_check(&bytes));
+    bytes.extend(body);
+    socket.write_all(&bytes).unwrap();
+
+    let mut events = Vec::new();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !events
+        .iter()
+        .any(|e| matches!(e, ServerEvent::ClientDisconnected(..)))
+    {
+        assert!(
+            Instant::now() < deadline,
+            "never disconnected: {:?}",
+            events
+        );
+        events.extend(server.tick().unwrap());
+    }
+    assert!(server.drain_incoming_packets().is_empty());
+
+    events
+}
+
+fn compression_error(events: &[ServerEvent]) -> Option<&str> {
+    events.iter().find_map(|e| match e {
+        ServerEvent::InvalidCompression { error, .. } => Some(error.as_str()),
+        _ => None,
+    })
+}
+
+#[test]
+fn body_claiming_to_inflate_too_far_is_rejected() {
+    // An LZ4 body claiming to inflate to 100 MB
+    let mut body = vec![0];
+    body.extend(100_000_000u32.to_le_bytes());
+    body.extend([0x1f, 0, 1, 0]);
+
+    let events = send_compressed(ServerConfig::new().compression(Compression::Lz4, 0), &body);
+    assert_eq!(compression_error(&events), Some("DecompressedTooLarge"));
+}
+
+#[test]
+fn corrupt_body_is_rejected() {
+    let events = send_compressed(
+        ServerConfig::new().compression(Compression::Lz4, 0),
+        &[1, 0xff, 0xff, 0xff],
+    );
+    assert_eq!(compression_error(&events), Some("InvalidData"));
+}
+
+#[test]
+fn compressed_body_without_compression_enabled_is_rejected() {
+    let events = send_compressed(ServerConfig::new(), &[0, 1, 0, 0, 0, 0x10, 0x61]);
+    assert_eq!(compression_error(&events), Some("UnexpectedCompression"));
+}
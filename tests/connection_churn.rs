@@ -0,0 +1,107 @@
+use grubbnet::{ConnectionId, Error, PacketRecipient, RawPacket, Server, ServerEvent};
+use std::collections::HashSet;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+/// How many clients are connected at once.
+const BATCH: usize = 8;
+
+/// Tick until `count` events match `select`, and return what it picked out of them.
+fn tick_for(
+    server: &mut Server,
+    count: usize,
+    select: impl Fn(&ServerEvent) -> Option<ConnectionId>,
+) -> Vec<ConnectionId> {
+    let mut ids = Vec::new();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while ids.len() < count {
+        assert!(
+            Instant::now() < deadline,
+            "only saw {} of {}",
+            ids.len(),
+            count
+        );
+
+        let tick_ids: Vec<_> = server.tick().unwrap().iter().filter_map(&select).collect();
+        // No id shows up twice in one tick
+        let unique: HashSet<_> = tick_ids.iter().collect();
+        assert_eq!(unique.len(), tick_ids.len());
+        ids.extend(tick_ids);
+    }
+
+    ids
+}
+
+#[test]
+fn churn_reuses_small_indices_with_fresh_ids() {
+    let mut server = Server::host("127.0.0.1", 0, BATCH).unwrap();
+    let port = server.local_addr().unwrap().port();
+    let mut seen = HashSet::new();
+
+    // Thousands of connections, never more than a batch of them at once
+    for _ in 0..250 {
+        let sockets: Vec<_> = (0..BATCH)
+            .map(|_| TcpStream::connect(("127.0.0.1", port)).unwrap())
+            .collect();
+        let connected = tick_for(&mut server, BATCH, |e| match e {
+            ServerEvent::ClientConnected(id, _) => Some(*id),
+            _ => None,
+        });
+        for id in &connected {
+            assert!((id.index as usize) < BATCH, "index {} grew", id.index);
+            assert!(seen.insert(*id), "{} was handed out twice", id);
+        }
+
+        drop(sockets);
+        let mut disconnected = tick_for(&mut server, BATCH, |e| match e {
+            ServerEvent::ClientDisconnected(id, _) => Some(*id),
+            _ => None,
+        });
+        let mut connected = connected;
+        connected.sort();
+        disconnected.sort();
+        assert_eq!(connected, disconnected);
+    }
+
+    assert_eq!(seen.len(), 250 * BATCH);
+    assert_eq!(server.num_connections(), 0);
+}
+
+#[test]
+fn stale_ids_are_not_found() {
+    let mut server = Server::host("127.0.0.1", 0, BATCH).unwrap();
+    let port = server.local_addr().unwrap().port();
+    let connected = |e: &ServerEvent| match e {
+        ServerEvent::ClientConnected(id, _) => Some(*id),
+        _ => None,
+    };
+
+    let first = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let old = tick_for(&mut server, 1, connected)[0];
+    drop(first);
+    tick_for(&mut server, 1, |e| match e {
+        ServerEvent::ClientDisconnected(id, _) => Some(*id),
+        _ => None,
+    });
+
+    // The next connection takes the same slot, under a new generation
+    let _second = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let new = tick_for(&mut server, 1, connected)[0];
+    assert_eq!(new.index, old.index);
+    assert_eq!(new.token(), old.token());
+    assert_ne!(new, old);
+
+    // Nothing aimed at the old id reaches the new connection
+    assert!(matches!(
+        server.send(PacketRecipient::Single(old), RawPacket::new(1, vec![1])),
+        Err(Error::ConnectionNotFound)
+    ));
+    assert!(matches!(server.kick(old), Err(Error::ConnectionNotFound)));
+    assert!(matches!(
+        server.queued_bytes(old),
+        Err(Error::ConnectionNotFound)
+    ));
+    assert!(server.connection_stats(old).is_none());
+    assert_eq!(server.queued_bytes(new).unwrap(), 0);
+    assert!(server.kick(new).is_ok());
+}
@@ -0,0 +1,165 @@
+use grubbnet::packet::PacketBody;
+use grubbnet::{
+    Client, ClientConfig, ClientEvent, ConnectionId, Error, PacketRecipient, Result, Server,
+    ServerConfig, ServerEvent,
+};
+use std::time::{Duration, Instant};
+
+/// A packet that can be made to fail serialization.
+#[derive(Clone)]
+struct Blob(Vec<u8>, bool);
+
+impl PacketBody for Blob {
+    fn box_clone(&self) -> Box<dyn PacketBody> {
+        Box::new(self.clone())
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        if self.1 {
+            Err(Error::InvalidData)
+        } else {
+            Ok(self.0.clone())
+        }
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self> {
+        Ok(Blob(data.to_vec(), false))
+    }
+
+    fn id(&self) -> u8 {
+        7
+    }
+}
+
+fn connect() -> (Server, Client, ConnectionId) {
+    let mut server = ServerConfig::new().bind("127.0.0.1:0").unwrap();
+    let port = server.local_addr().unwrap().port();
+    let mut client = ClientConfig::new().connect(("127.0.0.1", port)).unwrap();
+
+    let mut id = None;
+    let mut connected = false;
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while id.is_none() || !connected {
+        assert!(Instant::now() < deadline, "never connected");
+        for event in server.tick().unwrap() {
+            if let ServerEvent::ClientConnected(connection, _) = event {
+                id = Some(connection);
+            }
+        }
+        connected |= client.tick().unwrap().contains(&ClientEvent::Connected);
+    }
+
+    (server, client, id.unwrap())
+}
+
+fn count<T>(events: &[T], predicate: impl Fn(&T) -> bool) -> usize {
+    events.iter().filter(|e| predicate(e)).count()
+}
+
+#[test]
+fn tracked_packets_are_reported_delivered_both_ways() {
+    let (mut server, mut client, id) = connect();
+
+    let to_client = server
+        .send_tracked(PacketRecipient::Single(id), Blob(vec![1], false))
+        .unwrap();
+    server
+        .send(PacketRecipient::Single(id), Blob(vec![2], false))
+        .unwrap();
+    let to_server = client.send_tracked(Blob(vec![3], false)).unwrap();
+
+    // A packet that fails to serialize is never queued, so there's nothing to report about it later
+    assert!(matches!(
+        client.send_tracked(Blob(vec![3], true)),
+        Err(Error::InvalidData)
+    ));
+
+    let mut server_events = Vec::new();
+    let mut client_events = Vec::new();
+    let deadline = Instant::now() + Duration::from_millis(200);
+    while Instant::now() < deadline {
+        server_events.extend(server.tick().unwrap());
+        client_events.extend(client.tick().unwrap());
+    }
+
+    // Only the tracked packet is reported
+    assert_eq!(
+        count(&server_events, |e| matches!(e, ServerEvent::Delivered(..))),
+        1
+    );
+    assert!(server_events.contains(&ServerEvent::Delivered(id, to_client)));
+    assert!(client_events.contains(&ClientEvent::Delivered(to_server)));
+    assert!(!client_events
+        .iter()
+        .any(|e| matches!(e, ClientEvent::DeliveryFailed(_))));
+    assert_eq!(client.drain_incoming_packets().len(), 2);
+    assert_eq!(server.drain_incoming_packets().len(), 1);
+}
+
+#[test]
+fn client_leaving_with_packets_queued_fails_them() {
+    let (mut server, client, id) = connect();
+
+    // Far more than the socket takes in one go, so plenty are still queued when the client goes away
+    let sent = 2000;
+    for _ in 0..sent {
+        server
+            .send_tracked(PacketRecipient::Single(id), Blob(vec![0; 8000], false))
+            .unwrap();
+    }
+    let mut events = Vec::new();
+    events.extend(server.tick().unwrap());
+    drop(client);
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !events
+        .iter()
+        .any(|e| matches!(e, ServerEvent::ClientDisconnected(..)))
+    {
+        assert!(Instant::now() < deadline, "never disconnected");
+        events.extend(server.tick().unwrap());
+    }
+
+    // Every message is accounted for exactly once, and the failures come before the disconnect
+    let delivered = count(&events, |e| matches!(e, ServerEvent::Delivered(..)));
+    let failed = count(&events, |e| matches!(e, ServerEvent::DeliveryFailed(..)));
+    assert!(failed > 0);
+    assert_eq!(delivered + failed, sent);
+    let last_failed = events
+        .iter()
+        .rposition(|e| matches!(e, ServerEvent::DeliveryFailed(i, _) if *i == id))
+        .unwrap();
+    let disconnected = events
+        .iter()
+        .position(|e| matches!(e, ServerEvent::ClientDisconnected(i, _) if *i == id))
+        .unwrap();
+    assert!(last_failed < disconnected);
+}
+
+#[test]
+fn server_leaving_with_packets_queued_fails_them() {
+    let (mut server, mut client, _) = connect();
+
+    let sent = 2000;
+    let messages: Vec<_> = (0..sent)
+        .map(|_| client.send_tracked(Blob(vec![0; 8000], false)).unwrap())
+        .collect();
+    let mut events = client.tick().unwrap();
+    server.tick().unwrap();
+    drop(server);
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !events
+        .iter()
+        .any(|e| matches!(e, ClientEvent::Disconnected(_)))
+    {
+        assert!(Instant::now() < deadline, "never disconnected");
+        events.extend(client.tick().unwrap());
+    }
+
+    let failed = count(&events, |e| matches!(e, ClientEvent::DeliveryFailed(_)));
+    let delivered = count(&events, |e| matches!(e, ClientEvent::Delivered(_)));
+    assert!(failed > 0);
+    assert_eq!(delivered + failed, sent);
+    assert!(events.contains(&ClientEvent::DeliveryFailed(messages[sent - 1])));
+}
@@ -0,0 +1,73 @@
+use grubbnet::{packet::RawPacket, Client, ConnectionId, PacketRecipient, Server, ServerEvent};
+use std::time::{Duration, Instant};
+
+/// Connect four clients one at a time, broadcast to them, and kick two in the opposite order to the one they joined in.
+/// Returns every server event after the connections were made, which doesn't depend on timing or socket addresses.
+fn scenario() -> Vec<ServerEvent> {
+    let mut server = Server::host("127.0.0.1", 0, 8).unwrap();
+    let port = server.local_addr().unwrap().port();
+    let mut clients = Vec::new();
+    let mut ids: Vec<ConnectionId> = Vec::new();
+    for _ in 0..4 {
+        let mut client = Client::connect("127.0.0.1", port).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while ids.len() < clients.len() + 1 {
+            assert!(Instant::now() < deadline, "Timed out connecting");
+            client.tick().unwrap();
+            for event in server.tick().unwrap() {
+                if let ServerEvent::ClientConnected(id, _) = event {
+                    ids.push(id);
+                }
+            }
+        }
+        clients.push(client);
+    }
+
+    server
+        .send(PacketRecipient::All, RawPacket::new(1, vec![7; 64]))
+        .unwrap();
+    server.kick(ids[3]).unwrap();
+    server.kick(ids[1]).unwrap();
+
+    let mut events = Vec::new();
+    let deadline = Instant::now() + Duration::from_secs(2);
+    let disconnects = |events: &[ServerEvent]| {
+        events
+            .iter()
+            .filter(|e| matches!(e, ServerEvent::ClientDisconnected(..)))
+            .count()
+    };
+    while disconnects(&events) < 2 {
+        assert!(Instant::now() < deadline, "Timed out kicking");
+        events.extend(server.tick().unwrap());
+        for client in clients.iter_mut() {
+            client.tick().unwrap();
+        }
+    }
+
+    events
+}
+
+#[test]
+fn same_scenario_gives_same_events() {
+    let first = scenario();
+
+    // Connections are visited in token order, not the order things were asked of them
+    let order = |wanted: fn(&ServerEvent) -> Option<ConnectionId>| -> Vec<u32> {
+        first.iter().filter_map(wanted).map(|id| id.index).collect()
+    };
+    let sent = order(|e| match e {
+        ServerEvent::SentPacket(id, _) => Some(*id),
+        _ => None,
+    });
+    let disconnected = order(|e| match e {
+        ServerEvent::ClientDisconnected(id, _) => Some(*id),
+        _ => None,
+    });
+    assert_eq!(sent, vec![0, 1, 2, 3]);
+    assert_eq!(disconnected, vec![1, 3]);
+
+    for _ in 0..5 {
+        assert_eq!(scenario(), first);
+    }
+}
@@ -0,0 +1,75 @@
+use grubbnet::{
+    event_pattern,
+    packet::{Packet, PacketHeader, RawPacket},
+    testing::{pair, EventRecorder, TestEvent},
+    ClientEvent, DisconnectReason, ServerEvent,
+};
+use std::{thread::sleep, time::Duration};
+
+fn tick_for(
+    recorder: &mut EventRecorder,
+    server: &mut grubbnet::Server,
+    client: &mut grubbnet::Client,
+) {
+    for _ in 0..20 {
+        recorder.tick(server, client);
+        sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn recorder_pins_down_event_order() {
+    let (mut server, mut client) = pair();
+    let mut recorder = EventRecorder::new();
+    tick_for(&mut recorder, &mut server, &mut client);
+    client.send(RawPacket::new(7, vec![1])).unwrap();
+    tick_for(&mut recorder, &mut server, &mut client);
+
+    // Byte counts and ids are left as wildcards
+    recorder.assert_sequence(&[
+        event_pattern!(TestEvent::Server(ServerEvent::ClientConnected(_, _))),
+        event_pattern!(TestEvent::Client(ClientEvent::Connected)),
+        event_pattern!(TestEvent::Client(ClientEvent::SentPacket(_))),
+        event_pattern!(TestEvent::Server(ServerEvent::ReceivedPacket(_, _))),
+    ]);
+    assert!(!recorder.contains_sequence(&[
+        event_pattern!(TestEvent::Server(ServerEvent::ReceivedPacket(_, _))),
+        event_pattern!(TestEvent::Client(ClientEvent::SentPacket(_))),
+    ]));
+
+    recorder.clear();
+    assert!(recorder.events().is_empty());
+}
+
+#[test]
+#[should_panic(expected = "Events didn't match the sequence")]
+fn recorder_fails_the_test_on_a_missing_event() {
+    let (mut server, mut client) = pair();
+    let mut recorder = EventRecorder::new();
+    tick_for(&mut recorder, &mut server, &mut client);
+    recorder.assert_sequence(&[event_pattern!(TestEvent::Client(
+        ClientEvent::Disconnected(_)
+    ))]);
+}
+
+#[test]
+fn events_and_packets_compare_and_print() {
+    let event = ClientEvent::Disconnected(DisconnectReason::Kicked);
+    assert_eq!(event.clone(), event);
+    assert_ne!(event, ClientEvent::Connected);
+    assert_eq!(format!("{:?}", event), "Disconnected(Kicked)");
+
+    let packet = Packet {
+        header: PacketHeader {
+            size: 1,
+            id: 7,
+            flags: 0,
+            sequence: None,
+            timestamp: None,
+            channel: None,
+        },
+        body: vec![1].into(),
+    };
+    assert_eq!(packet.clone(), packet);
+    assert!(format!("{:?}", packet).contains("id: 7"));
+}
@@ -0,0 +1,105 @@
+use grubbnet::packet::PacketBody;
+use grubbnet::{
+    ClientConfig, ClientEvent, Framing, PacketRecipient, Result, ServerConfig, ServerEvent,
+};
+use std::time::{Duration, Instant};
+
+#[derive(Clone)]
+struct Blob(Vec<u8>);
+
+impl PacketBody for Blob {
+    fn box_clone(&self) -> Box<dyn PacketBody> {
+        Box::new(self.clone())
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(self.0.clone())
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self> {
+        Ok(Blob(data.to_vec()))
+    }
+
+    fn id(&self) -> u8 {
+        7
+    }
+}
+
+#[test]
+fn large_packet_arrives_whole_with_extended_framing() {
+    let mut server = ServerConfig::new()
+        .framing(Framing::Extended)
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let port = server.local_addr().unwrap().port();
+    let mut client = ClientConfig::new()
+        .framing(Framing::Extended)
+        .connect(("127.0.0.1", port))
+        .unwrap();
+
+    // 200 KiB, well past what a 16 bit length prefix can describe, between two small packets
+    let large: Vec<u8> = (0..200 * 1024u32).map(|i| (i * 31 % 251) as u8).collect();
+    let small = vec![9; 100];
+    client.send(Blob(small.clone())).unwrap();
+    client.send(Blob(large.clone())).unwrap();
+    client.send(Blob(small.clone())).unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut received = Vec::new();
+    let mut connection = None;
+    while received.len() < 3 {
+        assert!(
+            Instant::now() < deadline,
+            "only got {} packets",
+            received.len()
+        );
+
+        client.tick().unwrap();
+        for event in server.tick().unwrap() {
+            if let ServerEvent::ClientConnected(id, _) = event {
+                connection = Some(id);
+            }
+        }
+        received.extend(server.drain_incoming_packets().into_iter().map(|(_, p)| p));
+    }
+
+    assert_eq!(received[0].body, small);
+    assert_eq!(received[1].body, large);
+    // Sent as one packet, not split into fragments
+    assert_eq!(received[1].header.size as usize, large.len());
+    assert_eq!(received[2].body, small);
+
+    // And back the other way
+    server
+        .send(
+            PacketRecipient::Single(connection.unwrap()),
+            Blob(large.clone()),
+        )
+        .unwrap();
+    let mut echoed = Vec::new();
+    while echoed.is_empty() {
+        assert!(Instant::now() < deadline, "nothing came back");
+
+        server.tick().unwrap();
+        for event in client.tick().unwrap() {
+            if let ClientEvent::Disconnected(reason) = event {
+                panic!("Disconnected: {:?}", reason);
+            }
+        }
+        echoed.extend(client.drain_incoming_packets());
+    }
+    assert_eq!(echoed[0].body, large);
+}
+
+#[test]
+fn large_max_packet_size_needs_extended_framing() {
+    assert!(ServerConfig::new()
+        .max_packet_size(100_000)
+        .validate()
+        .is_err());
+    assert!(ServerConfig::new()
+        .framing(Framing::Extended)
+        .max_packet_size(100_000)
+        .validate()
+        .is_ok());
+}
@@ -0,0 +1,42 @@
+use grubbnet::{
+    packet::RawPacket,
+    testing::{pair, tick_until, TestEvent},
+    ClientEvent, PacketRecipient, ServerEvent,
+};
+use std::{thread::sleep, time::Duration};
+
+/// Long enough for loopback to carry a write across, so each tick sees what the other side just wrote.
+const WIRE_TIME: Duration = Duration::from_millis(5);
+
+#[test]
+fn flushed_reply_arrives_within_two_client_ticks() {
+    let (mut server, mut client) = pair();
+    tick_until(&mut server, &mut client, Duration::from_secs(2), |events| {
+        events
+            .iter()
+            .any(|e| matches!(e, TestEvent::Server(ServerEvent::ClientConnected(..))))
+            && events.contains(&TestEvent::Client(ClientEvent::Connected))
+    });
+
+    for round in 0..10u8 {
+        // Client tick one writes the ping
+        client.send(RawPacket::new(1, vec![round])).unwrap();
+        client.tick().unwrap();
+        sleep(WIRE_TIME);
+
+        // The server answers within the same tick it read the ping in
+        server.tick().unwrap();
+        for (id, ping) in server.drain_incoming_packets() {
+            let pong = RawPacket::new(2, vec![ping.body[0] + 1]);
+            server.send(PacketRecipient::Single(id), pong).unwrap();
+        }
+        server.flush();
+        sleep(WIRE_TIME);
+
+        // Client tick two reads the pong
+        client.tick().unwrap();
+        let received = client.drain_incoming_packets();
+        assert_eq!(received.len(), 1, "No pong in round {}", round);
+        assert_eq!(&received[0].body[..], &[round + 1]);
+    }
+}
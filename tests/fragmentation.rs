@@ -0,0 +1,181 @@
+use grubbnet::packet::{header_check, PacketBody};
+use grubbnet::{
+    ClientConfig, PacketRecipient, Result, Server, ServerConfig, ServerEvent, FRAGMENT_PACKET_ID,
+};
+use std::io::Write;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+#[derive(Clone)]
+struct Blob(Vec<u8>);
+
+impl PacketBody for Blob {
+    fn box_clone(&self) -> Box<dyn PacketBody> {
+        Box::new(self.clone())
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(self.0.clone())
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self> {
+        Ok(Blob(data.to_vec()))
+    }
+
+    fn id(&self) -> u8 {
+        7
+    }
+}
+
+/// Build a fragment packet by hand, carrying `chunk` from `offset` into a packet of `total` bytes with id `id`.
+fn fragment(id: u8, total: u32, offset: u32, chunk: &[u8]) -> Vec<u8> {
+    let size = (9 + chunk.len()) as u16;
+    let mut bytes = size.to_be_bytes().to_vec();
+    bytes.extend([FRAGMENT_PACKET_ID, 0]);
+    bytes.push(header_check(&bytes));
+    bytes.push(id);
+    bytes.extend(total.to_be_bytes());
+    bytes.extend(offset.to_be_bytes());
+    bytes.extend(chunk);
+    bytes
+}
+
+/// Send raw bytes over a new connection, optionally closing it straight after, and collect the server's events until
+/// the connection is dropped.
+fn send_raw(server: &mut Server, bytes: &[u8], close: bool) -> Vec<ServerEvent> {
+    let port = server.local_addr().unwrap().port();
+    let mut socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    socket.write_all(bytes).unwrap();
+    if close {
+        drop(socket);
+    }
+
+    let mut events = Vec::new();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !events
+        .iter()
+        .any(|e| matches!(e, ServerEvent::ClientDisconnected(..)))
+    {
+        assert!(
+            Instant::now() < deadline,
+            "never disconnected: {:?}",
+            events
+        );
+        events.extend(server.tick().unwrap());
+    }
+    assert!(server.drain_incoming_packets().is_empty());
+
+    events
+}
+
+fn has_invalid_fragment(events: &[ServerEvent]) -> bool {
+    events
+        .iter()
+        .any(|e| matches!(e, ServerEvent::InvalidFragment { .. }))
+}
+
+#[test]
+fn large_packets_are_split_and_put_back_together() {
+    let mut server = ServerConfig::new().bind("127.0.0.1:0").unwrap();
+    let port = server.local_addr().unwrap().port();
+    let mut client = ClientConfig::new().connect(("127.0.0.1", port)).unwrap();
+
+    let large: Vec<u8> = (0..300 * 1024u32).map(|i| (i * 31 % 251) as u8).collect();
+    let bodies = vec![vec![1; 10], large.clone(), vec![2; 8192], vec![3; 8193]];
+    for body in &bodies {
+        client.send(Blob(body.clone())).unwrap();
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut received = Vec::new();
+    let mut connection = None;
+    while received.len() < bodies.len() {
+        assert!(
+            Instant::now() < deadline,
+            "only got {} packets",
+            received.len()
+        );
+
+        client.tick().unwrap();
+        for event in server.tick().unwrap() {
+            if let ServerEvent::ClientConnected(id, _) = event {
+                connection = Some(id);
+            }
+        }
+        received.extend(server.drain_incoming_packets().into_iter().map(|(_, p)| p));
+    }
+    for (packet, body) in received.iter().zip(&bodies) {
+        assert_eq!(packet.header.id, 7);
+        assert_eq!(&packet.body, body);
+    }
+
+    server
+        .send(
+            PacketRecipient::Single(connection.unwrap()),
+            Blob(large.clone()),
+        )
+        .unwrap();
+    let mut echoed = Vec::new();
+    while echoed.is_empty() {
+        assert!(Instant::now() < deadline, "nothing came back");
+
+        server.tick().unwrap();
+        client.tick().unwrap();
+        echoed.extend(client.drain_incoming_packets());
+    }
+    assert_eq!(echoed[0].body, large);
+}
+
+#[test]
+fn interleaved_fragments_are_rejected() {
+    let mut server = ServerConfig::new().bind("127.0.0.1:0").unwrap();
+
+    let mut bytes = fragment(7, 100, 0, &[0; 50]);
+    bytes.extend(fragment(8, 100, 0, &[0; 50]));
+    assert!(has_invalid_fragment(&send_raw(&mut server, &bytes, false)));
+}
+
+#[test]
+fn fragments_with_a_gap_are_rejected() {
+    let mut server = ServerConfig::new().bind("127.0.0.1:0").unwrap();
+
+    let mut bytes = fragment(7, 100, 0, &[0; 50]);
+    bytes.extend(fragment(7, 100, 60, &[0; 40]));
+    assert!(has_invalid_fragment(&send_raw(&mut server, &bytes, false)));
+}
+
+#[test]
+fn reassembly_over_the_cap_is_rejected() {
+    let mut server = ServerConfig::new()
+        .max_reassembly_size(1000)
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let events = send_raw(&mut server, &fragment(7, 1001, 0, &[0; 50]), false);
+    assert!(events.iter().any(|e| matches!(
+        e,
+        ServerEvent::InvalidFragment { error, .. } if error == "ReassemblyTooLarge"
+    )));
+
+    // A size that could never be allocated is turned away before anything is allocated for it
+    let mut server = ServerConfig::new().bind("127.0.0.1:0").unwrap();
+    let events = send_raw(&mut server, &fragment(7, u32::MAX, 0, &[0; 50]), false);
+    assert!(has_invalid_fragment(&events));
+
+    assert!(ServerConfig::new()
+        .max_reassembly_size(0)
+        .validate()
+        .is_err());
+}
+
+#[test]
+fn disconnecting_mid_reassembly_drops_the_partial_packet() {
+    let mut server = ServerConfig::new().bind("127.0.0.1:0").unwrap();
+
+    let events = send_raw(&mut server, &fragment(7, 10000, 0, &[0; 5000]), true);
+    assert!(!has_invalid_fragment(&events));
+    assert_eq!(server.num_connections(), 0);
+
+    // The next connection takes over the same slot, and doesn't inherit the first half of the packet
+    let events = send_raw(&mut server, &fragment(7, 10000, 5000, &[0; 5000]), false);
+    assert!(has_invalid_fragment(&events));
+}
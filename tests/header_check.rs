@@ -0,0 +1,128 @@
+use grubbnet::buffer::NetworkBuffer;
+use grubbnet::packet::{
+    deserialize_packet_header, header_check, read_header, write_header, PacketHeader,
+    FLAG_SEQUENCE, MAX_PACKET_BODY_SIZE,
+};
+use grubbnet::{DisconnectReason, Endianness, Error, Framing, Server, ServerEvent};
+use std::io::Write;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+fn header(framing: Framing, endianness: Endianness) -> Vec<u8> {
+    let header = PacketHeader {
+        size: 0x0102,
+        id: 0x07,
+        flags: FLAG_SEQUENCE,
+        sequence: Some(42),
+        timestamp: None,
+        channel: None,
+    };
+    let mut bytes = Vec::new();
+    write_header(&mut bytes, &header, framing, endianness).unwrap();
+    bytes
+}
+
+#[test]
+fn check_byte_is_crc8() {
+    // The standard check value for CRC-8 with polynomial 0x07
+    assert_eq!(header_check(b"123456789"), 0xF4);
+    assert_eq!(
+        header(Framing::Standard, Endianness::Big)[..5],
+        [
+            0x01,
+            0x02,
+            0x07,
+            0x01,
+            header_check(&[0x01, 0x02, 0x07, 0x01])
+        ]
+    );
+}
+
+#[test]
+fn every_corrupted_header_byte_is_caught() {
+    for &framing in &[Framing::Standard, Framing::Extended] {
+        for &endianness in &[Endianness::Big, Endianness::Little] {
+            let bytes = header(framing, endianness);
+            assert!(read_header(&bytes, framing, endianness).is_ok());
+
+            // The size, id, flags and the check byte itself, each replaced with every other value
+            for position in 0..framing.header_size() {
+                for value in 0..=u8::MAX {
+                    if value == bytes[position] {
+                        continue;
+                    }
+
+                    let mut corrupted = bytes.clone();
+                    corrupted[position] = value;
+                    assert!(
+                        matches!(
+                            read_header(&corrupted, framing, endianness),
+                            Err(Error::HeaderChecksumMismatch { .. })
+                        ),
+                        "{:?} {:?} header with byte {} set to {:#04x} wasn't caught",
+                        framing,
+                        endianness,
+                        position,
+                        value
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn corrupted_size_is_caught_before_it_is_trusted() {
+    let bytes = header(Framing::Standard, Endianness::Big);
+    for bit in 0..16 {
+        let mut corrupted = bytes.clone();
+        corrupted[bit / 8] ^= 1 << (bit % 8);
+
+        let mut buffer = NetworkBuffer::new();
+        buffer.append(&corrupted).unwrap();
+        assert!(matches!(
+            deserialize_packet_header(&mut buffer, Framing::Standard, MAX_PACKET_BODY_SIZE),
+            Err(Error::HeaderChecksumMismatch { .. })
+        ));
+    }
+}
+
+#[test]
+fn corrupted_header_disconnects_the_peer() {
+    let mut server = Server::host("127.0.0.1", 0, 4).unwrap();
+    let port = server.local_addr().unwrap().port();
+    let mut socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+
+    // One good packet, then one with a bit flipped in its size
+    let mut good = vec![0, 1, 7, 0];
+    good.push(header_check(&good));
+    good.push(9);
+    let mut bad = good.clone();
+    bad[1] ^= 0b100;
+    socket.write_all(&[good, bad].concat()).unwrap();
+
+    let mut events = Vec::new();
+    let mut packets = Vec::new();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !events
+        .iter()
+        .any(|e| matches!(e, ServerEvent::ClientDisconnected(..)))
+    {
+        assert!(
+            Instant::now() < deadline,
+            "never disconnected: {:?}",
+            events
+        );
+        events.extend(server.tick().unwrap());
+        packets.extend(server.drain_incoming_packets());
+    }
+
+    assert_eq!(packets.len(), 1);
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, ServerEvent::InvalidHeader { .. })));
+    assert!(events.iter().any(|e| matches!(
+        e,
+        ServerEvent::ClientDisconnected(_, DisconnectReason::ProtocolError)
+    )));
+}
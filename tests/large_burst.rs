@@ -0,0 +1,61 @@
+use grubbnet::packet::PacketBody;
+use grubbnet::testing::{pair, tick_until, TestEvent};
+use grubbnet::{Result, ServerEvent};
+use std::time::{Duration, Instant};
+
+/// A numbered packet, padded out so a few hundred of them add up to far more than a receive buffer holds.
+#[derive(Clone)]
+struct Numbered(u16);
+
+impl PacketBody for Numbered {
+    fn box_clone(&self) -> Box<dyn PacketBody> {
+        Box::new(self.clone())
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        let mut bytes = vec![0; 200];
+        bytes[..2].copy_from_slice(&self.0.to_be_bytes());
+        Ok(bytes)
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self> {
+        Ok(Numbered(u16::from_be_bytes([data[0], data[1]])))
+    }
+
+    fn id(&self) -> u8 {
+        3
+    }
+}
+
+#[test]
+fn burst_bigger_than_the_receive_buffer_is_delivered_in_order() {
+    let (mut server, mut client) = pair();
+    tick_until(&mut server, &mut client, Duration::from_secs(2), |events| {
+        events
+            .iter()
+            .any(|e| matches!(e, TestEvent::Server(ServerEvent::ClientConnected(..))))
+    });
+
+    // ~80 KiB sent in a single tick, against a 16 KiB receive buffer
+    let count = 400;
+    for i in 0..count {
+        client.send(Numbered(i)).unwrap();
+    }
+    client.tick().unwrap();
+
+    let mut received = 0;
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while received < count {
+        assert!(Instant::now() < deadline, "only got {} packets", received);
+
+        let events = server.tick().unwrap();
+        client.tick().unwrap();
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, ServerEvent::ClientDisconnected(..))));
+        for (_, packet) in server.drain_incoming_packets() {
+            assert_eq!(Numbered::deserialize(&packet.body).unwrap().0, received);
+            received += 1;
+        }
+    }
+}
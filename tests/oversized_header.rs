@@ -0,0 +1,74 @@
+use grubbnet::packet::{header_check, MAX_PACKET_BODY_SIZE};
+use grubbnet::{DisconnectReason, Server, ServerEvent};
+use std::io::Write;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+/// Send raw bytes to a fresh server, and collect its events until the connection is dropped.
+fn events_after_sending(bytes: &[u8]) -> Vec<ServerEvent> {
+    let mut server = Server::host("127.0.0.1", 0, 4).unwrap();
+    let port = server.local_addr().unwrap().port();
+    let mut socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    socket.write_all(bytes).unwrap();
+
+    let mut events = Vec::new();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !events
+        .iter()
+        .any(|e| matches!(e, ServerEvent::ClientDisconnected(..)))
+    {
+        assert!(
+            Instant::now() < deadline,
+            "never disconnected: {:?}",
+            events
+        );
+        events.extend(server.tick().unwrap());
+    }
+
+    events
+}
+
+#[test]
+fn oversized_length_prefix_closes_the_connection() {
+    // A well formed header that advertises a 64 KiB body, followed by a few bytes of it
+    let header = [0xFF, 0xFF, 1, 0];
+    let mut bytes = header.to_vec();
+    bytes.push(header_check(&header));
+    bytes.extend([1, 2, 3]);
+
+    let events = events_after_sending(&bytes);
+
+    let invalid = events
+        .iter()
+        .position(|e| matches!(e, ServerEvent::InvalidHeader { .. }))
+        .expect("no InvalidHeader event");
+    let disconnected = events
+        .iter()
+        .position(|e| {
+            matches!(
+                e,
+                ServerEvent::ClientDisconnected(_, DisconnectReason::ProtocolError)
+            )
+        })
+        .expect("not disconnected for a protocol error");
+    assert!(invalid < disconnected);
+
+    if let ServerEvent::InvalidHeader { error, .. } = &events[invalid] {
+        let expected = format!("over the limit of {} bytes", MAX_PACKET_BODY_SIZE);
+        assert!(error.contains(&expected), "{}", error);
+    }
+}
+
+#[test]
+fn damaged_length_prefix_closes_the_connection() {
+    // The same header with a check byte that doesn't match it
+    let header = [0xFF, 0xFF, 1, 0];
+    let mut bytes = header.to_vec();
+    bytes.push(header_check(&header) ^ 1);
+
+    let events = events_after_sending(&bytes);
+
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, ServerEvent::InvalidHeader { .. })));
+}
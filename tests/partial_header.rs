@@ -0,0 +1,79 @@
+use grubbnet::buffer::NetworkBuffer;
+use grubbnet::packet::{deserialize_packet_header, serialize_packet, MAX_PACKET_BODY_SIZE};
+use grubbnet::{Framing, RawPacket, Server};
+use std::io::Write;
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+fn frame(id: u8, body: Vec<u8>) -> Vec<u8> {
+    serialize_packet(Box::new(RawPacket::new(id, body))).unwrap()
+}
+
+#[test]
+fn partial_header_is_waited_on() {
+    let bytes = frame(4, vec![1]);
+    let header_size = bytes.len() - 1;
+    let mut buffer = NetworkBuffer::new();
+
+    for &byte in &bytes[..header_size] {
+        assert!(matches!(
+            deserialize_packet_header(&mut buffer, Framing::Standard, MAX_PACKET_BODY_SIZE),
+            Ok(None)
+        ));
+        buffer.append(&[byte]).unwrap();
+    }
+
+    let header = deserialize_packet_header(&mut buffer, Framing::Standard, MAX_PACKET_BODY_SIZE)
+        .unwrap()
+        .unwrap();
+    assert_eq!((header.size, header.id), (1, 4));
+}
+
+#[test]
+fn packets_arriving_one_byte_per_tick_are_delivered_once() {
+    let mut server = Server::host("127.0.0.1", 0, 4).unwrap();
+    let port = server.local_addr().unwrap().port();
+    let mut socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    socket.set_nodelay(true).unwrap();
+    for _ in 0..10 {
+        server.tick().unwrap();
+    }
+
+    let empty = frame(9, vec![]);
+    let mut bytes = empty.clone();
+    bytes.extend(frame(8, vec![1, 2]));
+
+    let mut received = Vec::new();
+    for (i, &byte) in bytes.iter().enumerate() {
+        socket.write_all(&[byte]).unwrap();
+        thread::sleep(Duration::from_millis(3));
+        server.tick().unwrap();
+        received.extend(
+            server
+                .drain_incoming_packets()
+                .into_iter()
+                .map(|(_, packet)| (packet.header.id, packet.body.to_vec())),
+        );
+
+        // Nothing shows up until a packet's last byte does
+        let sent = i + 1;
+        let complete = usize::from(sent >= empty.len()) + usize::from(sent == bytes.len());
+        assert!(
+            received.len() <= complete,
+            "phantom packet after {} bytes",
+            sent
+        );
+    }
+
+    for _ in 0..5 {
+        server.tick().unwrap();
+        received.extend(
+            server
+                .drain_incoming_packets()
+                .into_iter()
+                .map(|(_, packet)| (packet.header.id, packet.body.to_vec())),
+        );
+    }
+    assert_eq!(received, vec![(9, vec![]), (8, vec![1, 2])]);
+}
@@ -0,0 +1,48 @@
+use grubbnet::packet::PACKET_HEADER_SIZE;
+use grubbnet::{ConnectionId, PacketRecipient, RawPacket, Server, ServerEvent};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+#[test]
+fn predicate_broadcast_only_queues_for_matching_connections() {
+    let mut server = Server::host("127.0.0.1", 0, 8).unwrap();
+    let port = server.local_addr().unwrap().port();
+    let _sockets: Vec<_> = (0..6)
+        .map(|_| TcpStream::connect(("127.0.0.1", port)).unwrap())
+        .collect();
+
+    let mut ids: Vec<ConnectionId> = Vec::new();
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while ids.len() < 6 {
+        assert!(Instant::now() < deadline, "only {} connected", ids.len());
+        for event in server.tick().unwrap() {
+            if let ServerEvent::ClientConnected(id, _) = event {
+                ids.push(id);
+            }
+        }
+    }
+    for &id in &ids {
+        assert_eq!(server.queued_bytes(id).unwrap(), 0);
+    }
+
+    server
+        .send(
+            PacketRecipient::filter(|id| id.index % 2 == 1),
+            RawPacket::new(7, vec![1, 2, 3]),
+        )
+        .unwrap();
+
+    let (odd, even): (Vec<_>, Vec<_>) = ids.iter().partition(|id| id.index % 2 == 1);
+    assert_eq!((odd.len(), even.len()), (3, 3));
+    for id in odd {
+        assert_eq!(
+            server.queued_bytes(id).unwrap(),
+            PACKET_HEADER_SIZE + 3,
+            "{}",
+            id
+        );
+    }
+    for id in even {
+        assert_eq!(server.queued_bytes(id).unwrap(), 0, "{}", id);
+    }
+}
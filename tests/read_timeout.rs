@@ -0,0 +1,40 @@
+use grubbnet::testing::*;
+use grubbnet::*;
+use std::time::{Duration, Instant};
+
+#[test]
+fn silent_server_times_the_client_out() {
+    let (mut server, mut client) = pair();
+    client
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+
+    let start = Instant::now();
+    let events = tick_until(&mut server, &mut client, Duration::from_secs(2), |events| {
+        events
+            .iter()
+            .any(|e| matches!(e, TestEvent::Client(ClientEvent::Disconnected(_))))
+    });
+
+    assert!(
+        events.contains(&TestEvent::Client(ClientEvent::Disconnected(
+            DisconnectReason::TimedOut
+        )))
+    );
+    assert!(start.elapsed() >= Duration::from_millis(200));
+}
+
+#[test]
+fn zero_read_timeout_is_rejected() {
+    let (_server, mut client) = pair();
+
+    assert!(matches!(
+        client.set_read_timeout(Some(Duration::from_secs(0))),
+        Err(Error::InvalidConfig(_))
+    ));
+    assert!(client.set_read_timeout(None).is_ok());
+    assert!(ClientConfig::new()
+        .read_timeout(Duration::from_secs(0))
+        .validate()
+        .is_err());
+}
@@ -0,0 +1,60 @@
+use grubbnet::{
+    Client, ConnectionId, RawPacket, RelayEnvelope, RelayPolicy, Server, ServerEvent,
+    RELAY_PACKET_ID,
+};
+use std::time::{Duration, Instant};
+
+#[test]
+fn relayed_envelope_is_not_reported_as_a_received_packet() {
+    let mut server = Server::host("127.0.0.1", 0, 4).unwrap();
+    server.enable_relay(RelayPolicy::allow_all());
+    let port = server.local_addr().unwrap().port();
+    let mut sender = Client::connect("127.0.0.1", port).unwrap();
+    let mut receiver = Client::connect("127.0.0.1", port).unwrap();
+
+    let mut ids: Vec<ConnectionId> = Vec::new();
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while ids.len() < 2 {
+        assert!(Instant::now() < deadline, "only {} connected", ids.len());
+        for event in server.tick().unwrap() {
+            if let ServerEvent::ClientConnected(id, _) = event {
+                ids.push(id);
+            }
+        }
+        sender.tick().unwrap();
+        receiver.tick().unwrap();
+    }
+
+    sender
+        .send(RelayEnvelope::new(ids[1], vec![1, 2, 3]))
+        .unwrap();
+    sender.send(RawPacket::new(4, vec![5])).unwrap();
+
+    let mut events = Vec::new();
+    let mut drained = Vec::new();
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while drained.is_empty()
+        || !events
+            .iter()
+            .any(|e| matches!(e, ServerEvent::Relayed { .. }))
+    {
+        assert!(Instant::now() < deadline, "got {:?}", events);
+        sender.tick().unwrap();
+        receiver.tick().unwrap();
+        events.extend(server.tick().unwrap());
+        drained.extend(server.drain_incoming_packets());
+    }
+
+    // Only the ordinary packet is the application's, and it's the only one reported
+    let received: Vec<u8> = events
+        .iter()
+        .filter_map(|e| match e {
+            ServerEvent::ReceivedPacket(_, info) => Some(info.id),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(received, vec![4]);
+    assert!(!received.contains(&RELAY_PACKET_ID));
+    assert_eq!(drained.len(), 1);
+    assert_eq!(drained[0].1.header.id, 4);
+}
@@ -0,0 +1,98 @@
+use grubbnet::{send_bytes, Error};
+use std::io::{self, ErrorKind, Write};
+
+/// A socket that only takes a few bytes per write, and can be made to be interrupted or to stop taking anything.
+struct Trickle {
+    received: Vec<u8>,
+    per_write: usize,
+    writes: usize,
+    interrupt_every: Option<usize>,
+    full_after: Option<usize>,
+}
+
+impl Trickle {
+    fn new(per_write: usize) -> Self {
+        Trickle {
+            received: Vec::new(),
+            per_write,
+            writes: 0,
+            interrupt_every: None,
+            full_after: None,
+        }
+    }
+}
+
+impl Write for Trickle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writes += 1;
+        if let Some(every) = self.interrupt_every {
+            if self.writes.is_multiple_of(every) {
+                return Err(ErrorKind::Interrupted.into());
+            }
+        }
+
+        if let Some(full_after) = self.full_after {
+            if self.received.len() >= full_after {
+                return Ok(0);
+            }
+        }
+
+        let count = buf.len().min(self.per_write);
+        self.received.extend_from_slice(&buf[..count]);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn data() -> Vec<u8> {
+    (0..1000).map(|i| (i * 7) as u8).collect()
+}
+
+#[test]
+fn partial_writes_send_each_byte_once() {
+    let data = data();
+    for &per_write in &[1, 3, 7, 999, 1000, 5000] {
+        let mut socket = Trickle::new(per_write);
+
+        assert_eq!(send_bytes(&mut socket, &data).unwrap(), data.len());
+        assert_eq!(socket.received, data, "{} bytes per write", per_write);
+        assert_eq!(socket.writes, data.len().div_ceil(per_write));
+    }
+}
+
+#[test]
+fn interrupted_writes_are_retried() {
+    let data = data();
+    let mut socket = Trickle::new(7);
+    socket.interrupt_every = Some(4);
+
+    assert_eq!(send_bytes(&mut socket, &data).unwrap(), data.len());
+    assert_eq!(socket.received, data);
+}
+
+#[test]
+fn socket_that_stops_taking_bytes_is_an_error() {
+    let data = data();
+    let mut socket = Trickle::new(10);
+    socket.full_after = Some(50);
+
+    assert!(matches!(
+        send_bytes(&mut socket, &data),
+        Err(Error::FailedToSendBytes)
+    ));
+    assert_eq!(socket.received, &data[..50]);
+}
+
+#[test]
+fn nothing_to_send_is_an_error() {
+    let mut socket = Trickle::new(10);
+
+    assert!(matches!(
+        send_bytes(&mut socket, &[]),
+        Err(Error::InvalidData)
+    ));
+    assert_eq!(socket.writes, 0);
+}
@@ -0,0 +1,140 @@
+use grubbnet::packet::RESERVED_PACKET_IDS_START;
+use grubbnet::{
+    ClientConfig, ConnectionId, Error, PacketRecipient, RawPacket, Server, ServerConfig,
+    ServerEvent,
+};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+/// Host a server with two channels and connect one raw socket to it.
+fn connected_server() -> (Server, ConnectionId, TcpStream) {
+    let mut server = ServerConfig::new().channels(2).bind("127.0.0.1:0").unwrap();
+    let port = server.local_addr().unwrap().port();
+    let socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(2);
+    loop {
+        assert!(Instant::now() < deadline, "never connected");
+        for event in server.tick().unwrap() {
+            if let ServerEvent::ClientConnected(id, _) = event {
+                return (server, id, socket);
+            }
+        }
+    }
+}
+
+#[test]
+fn reserved_id_is_rejected_before_queuing() {
+    let (mut server, id, _socket) = connected_server();
+    let reserved = RESERVED_PACKET_IDS_START + 1;
+
+    assert!(matches!(
+        server.send(PacketRecipient::Single(id), RawPacket::new(reserved, vec![1])),
+        Err(Error::ReservedPacketId(r)) if r == reserved
+    ));
+    assert!(matches!(
+        server.send(PacketRecipient::All, RawPacket::new(0xFF, vec![1])),
+        Err(Error::ReservedPacketId(0xFF))
+    ));
+    assert!(matches!(
+        server.send_tracked(PacketRecipient::All, RawPacket::new(0xFF, vec![1])),
+        Err(Error::ReservedPacketId(0xFF))
+    ));
+    assert!(matches!(
+        server.send_raw(PacketRecipient::All, 0xFF, vec![1]),
+        Err(Error::ReservedPacketId(0xFF))
+    ));
+    assert_eq!(server.queued_bytes(id).unwrap(), 0);
+
+    // An application id still goes through
+    server
+        .send(PacketRecipient::Single(id), RawPacket::new(1, vec![1]))
+        .unwrap();
+    assert!(server.queued_bytes(id).unwrap() > 0);
+}
+
+#[test]
+fn unknown_channel_is_rejected_before_queuing() {
+    let (mut server, id, _socket) = connected_server();
+
+    assert!(matches!(
+        server.send_on_channel(PacketRecipient::Single(id), 2, RawPacket::new(1, vec![1])),
+        Err(Error::UnknownChannel(2))
+    ));
+    assert!(matches!(
+        server.send_on_channel(PacketRecipient::All, 200, RawPacket::new(1, vec![1])),
+        Err(Error::UnknownChannel(200))
+    ));
+    assert_eq!(server.queued_bytes(id).unwrap(), 0);
+
+    server
+        .send_on_channel(PacketRecipient::Single(id), 1, RawPacket::new(1, vec![1]))
+        .unwrap();
+    assert!(server.queued_bytes(id).unwrap() > 0);
+}
+
+#[test]
+fn scheduled_and_cross_thread_sends_are_checked_up_front() {
+    let (mut server, id, _socket) = connected_server();
+
+    assert!(matches!(
+        server.send_after(
+            PacketRecipient::Single(id),
+            RawPacket::new(0xFF, vec![1]),
+            Duration::from_millis(1)
+        ),
+        Err(Error::ReservedPacketId(0xFF))
+    ));
+
+    let sender = server.sender();
+    assert!(matches!(
+        sender.send(PacketRecipient::Single(id), RawPacket::new(0xFF, vec![1])),
+        Err(Error::ReservedPacketId(0xFF))
+    ));
+
+    // Nothing turns up once the schedule and the sender's commands have been applied either
+    std::thread::sleep(Duration::from_millis(5));
+    for _ in 0..3 {
+        server.tick().unwrap();
+    }
+    assert_eq!(server.queued_bytes(id).unwrap(), 0);
+}
+
+#[test]
+fn client_sends_are_checked_before_queuing() {
+    let (server, _id, _socket) = connected_server();
+    let port = server.local_addr().unwrap().port();
+    let mut client = ClientConfig::new()
+        .channels(2)
+        .max_packet_size(512)
+        .max_reassembly_size(2048)
+        .connect(("127.0.0.1", port))
+        .unwrap();
+
+    assert!(matches!(
+        client.send(RawPacket::new(0xFF, vec![1])),
+        Err(Error::ReservedPacketId(0xFF))
+    ));
+    assert!(matches!(
+        client.send_tracked(RawPacket::new(0xFF, vec![1])),
+        Err(Error::ReservedPacketId(0xFF))
+    ));
+    assert!(matches!(
+        client.send_raw(0xFF, vec![1]),
+        Err(Error::ReservedPacketId(0xFF))
+    ));
+    assert!(matches!(
+        client.send_on_channel(2, RawPacket::new(1, vec![1])),
+        Err(Error::UnknownChannel(2))
+    ));
+    assert!(matches!(
+        client.send(RawPacket::new(1, vec![0; 4096])),
+        Err(Error::PacketTooLarge { size: 4096, .. })
+    ));
+    assert_eq!(client.queued_bytes(), 0);
+
+    client
+        .send_on_channel(1, RawPacket::new(1, vec![1]))
+        .unwrap();
+    assert!(client.queued_bytes() > 0);
+}
@@ -0,0 +1,171 @@
+use grubbnet::packet::{header_check, Packet, PacketBody};
+use grubbnet::{ClientConfig, ClientEvent, Result, Server, ServerConfig, ServerEvent};
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+#[derive(Clone)]
+struct Blob(Vec<u8>);
+
+impl PacketBody for Blob {
+    fn box_clone(&self) -> Box<dyn PacketBody> {
+        Box::new(self.clone())
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(self.0.clone())
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self> {
+        Ok(Blob(data.to_vec()))
+    }
+
+    fn id(&self) -> u8 {
+        7
+    }
+}
+
+/// Build a packet by hand with its sequence flag set, so the numbers can be anything.
+fn sequenced(sequence: u32, body: &[u8]) -> Vec<u8> {
+    let mut bytes = (body.len() as u16).to_be_bytes().to_vec();
+    bytes.extend([7, 0b01]);
+    bytes.push(header_check(&bytes));
+    bytes.extend(sequence.to_be_bytes());
+    bytes.extend(body);
+    bytes
+}
+
+/// Write raw packets to a fresh server, and tick it until `count` of them have arrived.
+fn server_receiving(bytes: &[u8], count: usize) -> (Vec<ServerEvent>, Vec<Packet>) {
+    let mut server = Server::host("127.0.0.1", 0, 4).unwrap();
+    let port = server.local_addr().unwrap().port();
+    let mut socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    socket.write_all(bytes).unwrap();
+
+    let mut events = Vec::new();
+    let mut packets = Vec::new();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while packets.len() < count {
+        assert!(
+            Instant::now() < deadline,
+            "only got {} packets",
+            packets.len()
+        );
+        events.extend(server.tick().unwrap());
+        packets.extend(server.drain_incoming_packets().into_iter().map(|(_, p)| p));
+    }
+    assert!(!events
+        .iter()
+        .any(|e| matches!(e, ServerEvent::ClientDisconnected(..))));
+
+    (events, packets)
+}
+
+fn out_of_sequence(events: &[ServerEvent]) -> Vec<(u32, u32)> {
+    events
+        .iter()
+        .filter_map(|e| match e {
+            ServerEvent::OutOfSequence {
+                expected, received, ..
+            } => Some((*expected, *received)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn packets_in_order_are_numbered_without_gaps() {
+    let mut server = ServerConfig::new()
+        .sequence_numbers(true)
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let port = server.local_addr().unwrap().port();
+    let mut client = ClientConfig::new()
+        .sequence_numbers(true)
+        .connect(("127.0.0.1", port))
+        .unwrap();
+
+    // The big one is fragmented, and still only takes one number
+    client.send(Blob(vec![1])).unwrap();
+    client.send(Blob(vec![2])).unwrap();
+    client.send(Blob(vec![3; 20_000])).unwrap();
+    client.send(Blob(vec![4])).unwrap();
+
+    let mut events = Vec::new();
+    let mut packets = Vec::new();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while packets.len() < 4 {
+        assert!(
+            Instant::now() < deadline,
+            "only got {} packets",
+            packets.len()
+        );
+        client.tick().unwrap();
+        events.extend(server.tick().unwrap());
+        packets.extend(server.drain_incoming_packets().into_iter().map(|(_, p)| p));
+    }
+
+    let sequences: Vec<_> = packets.iter().map(|p| p.header.sequence).collect();
+    assert_eq!(sequences, vec![Some(0), Some(1), Some(2), Some(3)]);
+    assert_eq!(packets[2].body.len(), 20_000);
+    assert!(out_of_sequence(&events).is_empty());
+}
+
+#[test]
+fn skipped_and_repeated_numbers_fire_an_event_each() {
+    let mut bytes = sequenced(0, b"a");
+    bytes.extend(sequenced(2, b"b"));
+    bytes.extend(sequenced(1, b"c"));
+    bytes.extend(sequenced(2, b"d"));
+
+    let (events, packets) = server_receiving(&bytes, 4);
+
+    // The gap, then the numbers going backwards; after that they carry on from the one received
+    assert_eq!(out_of_sequence(&events), vec![(1, 2), (3, 1)]);
+    // Every packet is still delivered
+    let bodies: Vec<_> = packets.iter().map(|p| p.body.to_vec()).collect();
+    assert_eq!(bodies, vec![b"a", b"b", b"c", b"d"]);
+}
+
+#[test]
+fn numbers_wrap_around_without_an_event() {
+    let mut bytes = sequenced(u32::MAX - 1, b"a");
+    bytes.extend(sequenced(u32::MAX, b"b"));
+    bytes.extend(sequenced(0, b"c"));
+    bytes.extend(sequenced(1, b"d"));
+
+    let (events, _) = server_receiving(&bytes, 4);
+
+    // Only the first number is unexpected, since a new connection starts from zero
+    assert_eq!(out_of_sequence(&events), vec![(0, u32::MAX - 1)]);
+}
+
+#[test]
+fn client_sees_a_gap_from_the_server() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let mut client = ClientConfig::new().connect(("127.0.0.1", port)).unwrap();
+    let (mut socket, _) = listener.accept().unwrap();
+
+    let mut bytes = sequenced(0, b"a");
+    bytes.extend(sequenced(5, b"b"));
+    socket.write_all(&bytes).unwrap();
+
+    let mut events = Vec::new();
+    let mut packets = Vec::new();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while packets.len() < 2 {
+        assert!(
+            Instant::now() < deadline,
+            "only got {} packets",
+            packets.len()
+        );
+        events.extend(client.tick().unwrap());
+        packets.extend(client.drain_incoming_packets());
+    }
+
+    assert!(events.contains(&ClientEvent::OutOfSequence {
+        expected: 1,
+        received: 5
+    }));
+}
@@ -0,0 +1,69 @@
+use grubbnet::{
+    Client, ClientConfig, ClientEvent, DisconnectReason, RejectReason, ServerConfig, ServerEvent,
+};
+use std::time::{Duration, Instant};
+
+/// Fill a one slot server, then connect a second client, and return what the server and that client saw.
+fn connect_to_full_server(notice: bool) -> (Vec<ServerEvent>, Vec<ClientEvent>) {
+    let mut server = ServerConfig::new()
+        .connection_limit(1)
+        .server_full_notice(notice)
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let port = server.local_addr().unwrap().port();
+
+    let mut first = ClientConfig::new().connect(("127.0.0.1", port)).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while server.num_connections() < 1 {
+        assert!(Instant::now() < deadline, "first client never connected");
+        server.tick().unwrap();
+        first.tick().unwrap();
+    }
+
+    let mut second = ClientConfig::new().connect(("127.0.0.1", port)).unwrap();
+    let mut server_events = Vec::new();
+    let mut client_events = Vec::new();
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while !is_disconnected(&client_events) {
+        assert!(
+            Instant::now() < deadline,
+            "second client never disconnected"
+        );
+        server_events.extend(server.tick().unwrap());
+        first.tick().unwrap();
+        client_events.extend(second.tick().unwrap());
+    }
+    assert_eq!(server.num_connections(), 1);
+    assert_still_connected(&mut first);
+
+    (server_events, client_events)
+}
+
+fn is_disconnected(events: &[ClientEvent]) -> bool {
+    events
+        .iter()
+        .any(|e| matches!(e, ClientEvent::Disconnected(_)))
+}
+
+fn assert_still_connected(client: &mut Client) {
+    let events = client.tick().unwrap();
+    assert!(!is_disconnected(&events));
+}
+
+#[test]
+fn client_turned_away_from_a_full_server_is_told_why() {
+    let (server_events, client_events) = connect_to_full_server(true);
+
+    assert!(server_events.iter().any(|e| matches!(
+        e,
+        ServerEvent::ConnectionRejected(_, RejectReason::ServerFull)
+    )));
+    assert!(client_events.contains(&ClientEvent::Disconnected(DisconnectReason::ServerFull)));
+}
+
+#[test]
+fn client_turned_away_without_the_notice_only_sees_the_close() {
+    let (_, client_events) = connect_to_full_server(false);
+
+    assert!(!client_events.contains(&ClientEvent::Disconnected(DisconnectReason::ServerFull)));
+}
@@ -0,0 +1,76 @@
+use grubbnet::{
+    packet::{PacketBody, RawPacket},
+    testing::{expect_packet, pair, tick_until, TestEvent},
+    ClientEvent, Result, ServerEvent,
+};
+use std::time::Duration;
+
+#[derive(Clone, Debug, PartialEq)]
+struct Score(u8);
+
+impl PacketBody for Score {
+    fn box_clone(&self) -> Box<dyn PacketBody> {
+        Box::new(self.clone())
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(vec![self.0])
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self> {
+        Ok(Score(data[0]))
+    }
+
+    fn id(&self) -> u8 {
+        7
+    }
+}
+
+#[test]
+fn pair_connects_and_carries_packets() {
+    let (mut server, mut client) = pair();
+    tick_until(&mut server, &mut client, Duration::from_secs(2), |events| {
+        events
+            .iter()
+            .any(|e| matches!(e, TestEvent::Server(ServerEvent::ClientConnected(..))))
+            && events.contains(&TestEvent::Client(ClientEvent::Connected))
+    });
+
+    client.send(RawPacket::new(3, vec![0])).unwrap();
+    client.send(Score(5)).unwrap();
+    tick_until(&mut server, &mut client, Duration::from_secs(2), |events| {
+        events
+            .iter()
+            .filter(|e| matches!(e, TestEvent::Server(ServerEvent::ReceivedPacket(..))))
+            .count()
+            == 2
+    });
+
+    let packets = server.drain_incoming_packets();
+    let score: Score = expect_packet(packets.iter().map(|(_, packet)| packet), 7);
+    assert_eq!(score, Score(5));
+}
+
+#[test]
+#[should_panic(expected = "Condition not met")]
+fn tick_until_fails_the_test_on_timeout() {
+    let (mut server, mut client) = pair();
+    tick_until(&mut server, &mut client, Duration::from_millis(100), |_| {
+        false
+    });
+}
+
+#[test]
+#[should_panic(expected = "only got ids [3]")]
+fn expect_packet_fails_the_test_when_missing() {
+    let (mut server, mut client) = pair();
+    client.send(RawPacket::new(3, vec![0])).unwrap();
+    tick_until(&mut server, &mut client, Duration::from_secs(2), |events| {
+        events
+            .iter()
+            .any(|e| matches!(e, TestEvent::Server(ServerEvent::ReceivedPacket(..))))
+    });
+
+    let packets = server.drain_incoming_packets();
+    let _: Score = expect_packet(packets.iter().map(|(_, packet)| packet), 7);
+}
@@ -0,0 +1,146 @@
+use grubbnet::buffer::{NetworkBuffer, MAX_BUFFER_SIZE};
+use grubbnet::packet::{parse_packets, MAX_PACKET_BODY_SIZE, PACKET_HEADER_SIZE};
+use grubbnet::{
+    ClientConfig, ClientEvent, Framing, PacketRecipient, RawPacket, ServerConfig, ServerEvent,
+};
+use std::io::Read;
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Enough to fill the kernel's socket buffers on both ends many times over.
+const PACKETS: usize = 1500;
+const BODY_SIZE: usize = 8000;
+
+fn body(i: usize) -> Vec<u8> {
+    (0..BODY_SIZE).map(|j| (i * 31 + j) as u8).collect()
+}
+
+/// Read everything from the other end of a connection as fast as it arrives, ticking the sender between reads, and
+/// check every packet comes through whole and in order.
+fn read_all(socket: &mut TcpStream, mut tick: impl FnMut()) {
+    socket.set_nonblocking(true).unwrap();
+    let mut buffer = NetworkBuffer::new();
+    while buffer.grow() {}
+    let mut chunk = vec![0; MAX_BUFFER_SIZE];
+    let mut received = 0;
+    let deadline = Instant::now() + Duration::from_secs(20);
+    while received < PACKETS {
+        assert!(Instant::now() < deadline, "only got {} packets", received);
+
+        tick();
+        while buffer.len() < MAX_BUFFER_SIZE {
+            let room = MAX_BUFFER_SIZE - buffer.len();
+            match socket.read(&mut chunk[..room]) {
+                Ok(0) => panic!("Closed after {} packets", received),
+                Ok(count) => buffer.append(&chunk[..count]).unwrap(),
+                Err(_) => break,
+            }
+        }
+
+        parse_packets(
+            &mut buffer,
+            Framing::Standard,
+            MAX_PACKET_BODY_SIZE,
+            |packet| {
+                assert_eq!(packet.header.id, 1);
+                assert_eq!(&packet.body[..], &body(received)[..], "packet {}", received);
+                received += 1;
+            },
+        )
+        .unwrap();
+    }
+}
+
+#[test]
+fn server_waits_for_a_reader_that_stops_reading() {
+    let mut server = ServerConfig::new().bind("127.0.0.1:0").unwrap();
+    let port = server.local_addr().unwrap().port();
+    let mut socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let mut events = Vec::new();
+    while !events
+        .iter()
+        .any(|e| matches!(e, ServerEvent::ClientConnected(..)))
+    {
+        events.extend(server.tick().unwrap());
+    }
+
+    for i in 0..PACKETS {
+        server
+            .send(PacketRecipient::All, RawPacket::new(1, body(i)))
+            .unwrap();
+    }
+
+    // Don't read anything for a while, so the socket fills up and writes would block
+    for _ in 0..30 {
+        events.extend(server.tick().unwrap());
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert!(!events
+        .iter()
+        .any(|e| matches!(e, ServerEvent::ClientDisconnected(..))));
+    let sent = events
+        .iter()
+        .filter(|e| matches!(e, ServerEvent::SentPacket(..)))
+        .count();
+    assert!(sent < PACKETS, "the socket never filled up");
+
+    read_all(&mut socket, || events.extend(server.tick().unwrap()));
+
+    // Each packet is reported sent once, when its last byte is written
+    assert_eq!(
+        events
+            .iter()
+            .filter(
+                |e| matches!(e, ServerEvent::SentPacket(_, size) if *size == PACKET_HEADER_SIZE + BODY_SIZE)
+            )
+            .count(),
+        PACKETS
+    );
+    assert!(!events
+        .iter()
+        .any(|e| matches!(e, ServerEvent::ClientDisconnected(..))));
+    assert_eq!(
+        server.stats().bytes_sent as usize,
+        PACKETS * (PACKET_HEADER_SIZE + BODY_SIZE)
+    );
+}
+
+#[test]
+fn client_waits_for_a_reader_that_stops_reading() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let mut client = ClientConfig::new().connect(("127.0.0.1", port)).unwrap();
+    let (mut socket, _) = listener.accept().unwrap();
+
+    for i in 0..PACKETS {
+        client.send(RawPacket::new(1, body(i))).unwrap();
+    }
+
+    let mut events = Vec::new();
+    for _ in 0..30 {
+        events.extend(client.tick().unwrap());
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert!(!events
+        .iter()
+        .any(|e| matches!(e, ClientEvent::Disconnected(_))));
+    let sent = events
+        .iter()
+        .filter(|e| matches!(e, ClientEvent::SentPacket(_)))
+        .count();
+    assert!(sent < PACKETS, "the socket never filled up");
+
+    read_all(&mut socket, || events.extend(client.tick().unwrap()));
+
+    assert_eq!(
+        events
+            .iter()
+            .filter(|e| matches!(e, ClientEvent::SentPacket(_)))
+            .count(),
+        PACKETS
+    );
+    assert!(!events
+        .iter()
+        .any(|e| matches!(e, ClientEvent::Disconnected(_))));
+}